@@ -0,0 +1,111 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use mesh_to_vox::fixtures::generate_test_mesh;
+use mesh_to_vox::voxelizer::{VoxelizeOptions, voxelize};
+
+const RESOLUTIONS: [u32; 3] = [62, 126, 254];
+const MESH_DETAIL: u32 = 64;
+
+fn bench_voxelize(c: &mut Criterion) {
+    let mesh = generate_test_mesh(MESH_DETAIL);
+    let mut group = c.benchmark_group("voxelize");
+    group.throughput(Throughput::Elements(mesh.triangles.len() as u64));
+
+    for size in RESOLUTIONS {
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter(|| voxelize(&mesh, size, &VoxelizeOptions::default(), None).unwrap());
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_voxelize_threads(c: &mut Criterion) {
+    let mesh = generate_test_mesh(MESH_DETAIL);
+    let size = RESOLUTIONS[RESOLUTIONS.len() - 1];
+    let mut group = c.benchmark_group("voxelize_threads");
+    group.throughput(Throughput::Elements(mesh.triangles.len() as u64));
+
+    let mut thread_counts = vec![1, rayon::current_num_threads()];
+    thread_counts.dedup();
+
+    for threads in thread_counts {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, _| {
+            b.iter(|| {
+                pool.install(|| voxelize(&mesh, size, &VoxelizeOptions::default(), None))
+                    .unwrap()
+            });
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_sparse_meshing(c: &mut Criterion) {
+    let mesh = generate_test_mesh(MESH_DETAIL);
+    let mut group = c.benchmark_group("fill_space");
+
+    for size in RESOLUTIONS {
+        let tree = voxelize(&mesh, size, &VoxelizeOptions::default(), None).unwrap();
+        group.throughput(Throughput::Elements(tree.collect_nodes().len() as u64));
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter(|| tree.fill_space(size - 1, None).unwrap());
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_collect_nodes(c: &mut Criterion) {
+    let mesh = generate_test_mesh(MESH_DETAIL);
+    let mut group = c.benchmark_group("collect_nodes");
+
+    for size in RESOLUTIONS {
+        let tree = voxelize(&mesh, size, &VoxelizeOptions::default(), None).unwrap();
+        group.throughput(Throughput::Elements(tree.collect_nodes().len() as u64));
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter(|| tree.collect_nodes());
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_vox_export(c: &mut Criterion) {
+    let mesh = generate_test_mesh(MESH_DETAIL);
+    let path = std::env::temp_dir().join("mesh_to_vox_bench.vox");
+    let path = path.to_str().unwrap();
+    let mut group = c.benchmark_group("save_as_magica_voxel");
+
+    for size in RESOLUTIONS {
+        let tree = voxelize(&mesh, size, &VoxelizeOptions::default(), None).unwrap();
+        group.throughput(Throughput::Elements(tree.collect_nodes().len() as u64));
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter(|| {
+                tree.save_as_magica_voxel(path, &mesh_to_vox::io::VoxOptions::default())
+                    .unwrap()
+            });
+        });
+    }
+
+    group.finish();
+
+    let _ = std::fs::remove_file(path);
+}
+
+criterion_group!(
+    benches,
+    bench_voxelize,
+    bench_voxelize_threads,
+    bench_sparse_meshing,
+    bench_collect_nodes,
+    bench_vox_export
+);
+criterion_main!(benches);
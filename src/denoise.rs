@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+
+use crate::error::{Result, check_cancelled};
+use crate::octree::{Octree, OctreePos, octree_header};
+use glam::IVec3;
+use rayon::prelude::*;
+
+/// Which voxels are connected when the model is split into connected components
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Connectivity {
+    /// Voxels sharing a face
+    #[value(name = "6")]
+    Faces,
+    /// Voxels sharing a face, an edge or a corner
+    #[default]
+    #[value(name = "26")]
+    Corners,
+}
+
+/// The number of nodes whose neighbors are looked up in parallel before they're joined,
+/// which bounds the memory taken by the pairs of neighbors
+const CHUNK_SIZE: usize = 1 << 16;
+
+/// A union-find over the nodes of a tree (or the triangles of a mesh), which tracks the size
+/// of every set, i.e. its number of voxels
+pub(crate) struct DisjointSet {
+    parents: Vec<u32>,
+    pub sizes: Vec<u64>,
+}
+
+impl DisjointSet {
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "the elements are indexed by `u32`, like the nodes of a tree"
+    )]
+    pub fn new(sizes: Vec<u64>) -> Self {
+        Self {
+            parents: (0..sizes.len() as u32).collect(),
+            sizes,
+        }
+    }
+
+    pub fn find(&mut self, mut node: u32) -> u32 {
+        while self.parents[node as usize] != node {
+            let parent = self.parents[node as usize];
+
+            // path halving
+            self.parents[node as usize] = self.parents[parent as usize];
+            node = parent;
+        }
+
+        node
+    }
+
+    pub fn union(&mut self, a: u32, b: u32) {
+        let (a, b) = (self.find(a), self.find(b));
+
+        if a == b {
+            return;
+        }
+
+        let (larger, smaller) = if self.sizes[a as usize] >= self.sizes[b as usize] {
+            (a, b)
+        } else {
+            (b, a)
+        };
+
+        self.parents[smaller as usize] = larger;
+        self.sizes[larger as usize] += self.sizes[smaller as usize];
+    }
+}
+
+impl Octree {
+    /// Removes every connected component of the model made of fewer than `min_voxels`
+    /// voxels (i.e. the stray voxels left by thin, grazing triangles), and returns the number
+    /// of removed components and voxels. `cancel` is checked between chunks of nodes
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Cancelled`] once `cancel` is set
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "the nodes of a tree are indexed by `u32`, like its offsets"
+    )]
+    pub fn remove_small_components(
+        &mut self,
+        min_voxels: u64,
+        connectivity: Connectivity,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<(usize, u64)> {
+        let nodes = self.collect_nodes();
+        let node_size = |node: &OctreePos| 1_i32 << (self.depth - node.depth);
+
+        let indices = nodes
+            .iter()
+            .enumerate()
+            .map(|(index, (node, _))| (node.coords, index as u32))
+            .collect::<HashMap<_, _>>();
+
+        let mut components = DisjointSet::new(
+            nodes
+                .iter()
+                .map(|(node, _)| u64::from(node_size(node).unsigned_abs()).pow(3))
+                .collect(),
+        );
+
+        for (chunk_index, chunk) in nodes.chunks(CHUNK_SIZE).enumerate() {
+            check_cancelled(cancel)?;
+
+            let pairs = chunk
+                .par_iter()
+                .enumerate()
+                .flat_map_iter(|(index, (node, _))| {
+                    let index = (chunk_index * CHUNK_SIZE + index) as u32;
+                    let size = node_size(node);
+                    let mut neighbors = Vec::new();
+
+                    // the voxels in the shell around the node, skipping its inside
+                    for x in -1..=size {
+                        for y in -1..=size {
+                            let inside = (0..size).contains(&x) && (0..size).contains(&y);
+                            let step = if inside { size + 1 } else { 1 };
+
+                            for z in (-1..=size).step_by(step.unsigned_abs() as usize) {
+                                let offset = IVec3::new(x, y, z);
+                                let outside = (offset.cmplt(IVec3::ZERO)
+                                    | offset.cmpge(IVec3::splat(size)))
+                                .bitmask()
+                                .count_ones();
+
+                                if connectivity == Connectivity::Faces && outside > 1 {
+                                    continue;
+                                }
+
+                                let Some((neighbor, _)) = self.node_at(node.coords + offset) else {
+                                    continue;
+                                };
+
+                                // every pair is found from both sides, so only one side is kept
+                                let neighbor = indices[&neighbor.coords];
+                                if neighbor < index {
+                                    neighbors.push((index, neighbor));
+                                }
+                            }
+                        }
+                    }
+
+                    neighbors
+                })
+                .collect::<Vec<_>>();
+
+            for (a, b) in pairs {
+                components.union(a, b);
+            }
+        }
+
+        let mut removed_components = 0;
+        let mut removed_voxels = 0;
+        let mut kept = Vec::with_capacity(nodes.len());
+
+        for (index, (node, color)) in nodes.into_iter().enumerate() {
+            let root = components.find(index as u32);
+            let voxels = components.sizes[root as usize];
+
+            if voxels >= min_voxels {
+                kept.push((node, color));
+                continue;
+            }
+
+            if root == index as u32 {
+                removed_components += 1;
+            }
+
+            removed_voxels += u64::from(node_size(&node).unsigned_abs()).pow(3);
+        }
+
+        if removed_voxels > 0 {
+            let mut tree = Self::new(self.depth);
+
+            for (node, color) in &kept {
+                tree.insert(node, octree_header::to_color(*color));
+            }
+
+            tree.shrink();
+            *self = tree;
+        }
+
+        Ok((removed_components, removed_voxels))
+    }
+}
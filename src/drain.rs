@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+
+use crate::error::{Result, check_cancelled};
+use crate::io::UpAxis;
+use crate::octree::{Octree, octree_header};
+use glam::IVec3;
+
+/// The thickest wall that a drain hole is punched through, in voxels. Thicker walls are
+/// usually solid parts of the model, which a hole wouldn't drain
+const MAX_WALL_THICKNESS: i32 = 8;
+
+impl Octree {
+    /// Punches `count` vertical holes, `radius` voxels wide, through the bottom of a hollow
+    /// model (i.e. one that wasn't filled with `fill_interior`), so that the resin trapped
+    /// inside can drain out when it's printed. The holes are placed at the lowest voxels of the
+    /// surface that face down, each as far from the previous ones as possible among the voxels
+    /// as low as it, and at least `2 * radius + 1` voxels apart. Every column of a hole is
+    /// cleared through the wall it meets, so holes are only placed where that wall is at most
+    /// `MAX_WALL_THICKNESS` voxels thick. Returns the number of punched holes (which can be
+    /// fewer than `count` if there's no room for more) and removed voxels. `cancel` is checked
+    /// between the holes
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Cancelled`] once `cancel` is set
+    #[allow(
+        clippy::cast_possible_wrap,
+        clippy::cast_possible_truncation,
+        reason = "holes are a few voxels wide, and there are at most `count` of them"
+    )]
+    pub fn punch_drain_holes(
+        &mut self,
+        count: u32,
+        radius: u32,
+        up: UpAxis,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<(u32, u64)> {
+        let mut voxels = HashMap::new();
+
+        // the holes are found in the Z-up convention, and the voxels are turned back after
+        for (node, color) in self.collect_nodes() {
+            let node_size = 1 << (self.depth - node.depth);
+
+            for x in 0..node_size {
+                for y in 0..node_size {
+                    for z in 0..node_size {
+                        let position = up.to_z_up(node.coords + IVec3::new(x, y, z));
+                        voxels.insert(position, color);
+                    }
+                }
+            }
+        }
+
+        let radius = radius as i32;
+
+        // the walls that every column of a hole has to go through, as their lowest voxel and
+        // their thickness. Holes along side walls would cut them down, so they're skipped
+        let walls = |center: IVec3| {
+            let mut walls = Vec::new();
+
+            for x in -radius..=radius {
+                for y in -radius..=radius {
+                    if x * x + y * y > radius * radius {
+                        continue;
+                    }
+
+                    // the wall is looked for around the height of the hole, as the bottom of
+                    // the model can be curved
+                    let column = center + IVec3::new(x, y, 0);
+                    let bottom = (-radius..=radius)
+                        .map(|z| column + IVec3::new(0, 0, z))
+                        .find(|position| voxels.contains_key(position))?;
+
+                    let thickness = (0..=MAX_WALL_THICKNESS)
+                        .find(|z| !voxels.contains_key(&(bottom + IVec3::new(0, 0, *z))))?;
+
+                    walls.push((bottom, thickness));
+                }
+            }
+
+            Some(walls)
+        };
+
+        let mut candidates = voxels
+            .keys()
+            .copied()
+            .filter(|position| !voxels.contains_key(&(*position - IVec3::Z)))
+            .filter_map(|position| walls(position).map(|walls| (position, walls)))
+            .collect::<Vec<_>>();
+
+        let spacing = (2 * radius + 1).pow(2);
+        let mut holes = Vec::<(IVec3, Vec<(IVec3, i32)>)>::new();
+
+        while holes.len() < count as usize {
+            check_cancelled(cancel)?;
+
+            let distance = |position: IVec3| {
+                holes
+                    .iter()
+                    .map(|(hole, _)| (position.truncate() - hole.truncate()).length_squared())
+                    .min()
+                    .unwrap_or(i32::MAX)
+            };
+
+            candidates.retain(|(position, _)| distance(*position) >= spacing);
+
+            let next = candidates
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (position, _))| {
+                    (
+                        position.z,
+                        std::cmp::Reverse(distance(*position)),
+                        position.to_array(),
+                    )
+                })
+                .map(|(index, _)| index);
+
+            let Some(next) = next else {
+                break;
+            };
+
+            holes.push(candidates.swap_remove(next));
+        }
+
+        let mut removed = 0_u64;
+
+        for (_, walls) in &holes {
+            for (bottom, thickness) in walls {
+                for z in 0..*thickness {
+                    voxels.remove(&(*bottom + IVec3::new(0, 0, z)));
+                    removed += 1;
+                }
+            }
+        }
+
+        if removed > 0 {
+            let voxels = voxels
+                .into_iter()
+                .map(|(position, color)| (up.from_z_up(position), octree_header::to_color(color)));
+
+            *self = Self::from_voxels(voxels, self.depth);
+        }
+
+        Ok((holes.len() as u32, removed))
+    }
+}
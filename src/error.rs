@@ -0,0 +1,109 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The ways loading, voxelizing or saving a model can fail
+#[derive(Debug, thiserror::Error)]
+pub enum MeshToVoxError {
+    /// Reading or writing a file failed
+    #[error("failed to access `{}`", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The `.gltf` or `.glb` file couldn't be parsed
+    #[error("failed to parse the gltf file")]
+    Gltf(#[from] gltf::Error),
+
+    /// A buffer or an image referenced by a `.gltf` or `.glb` file doesn't exist
+    #[error("the resource `{uri}` referenced by the gltf file doesn't exist (looked for `{}`)", path.display())]
+    MissingResource { uri: String, path: PathBuf },
+
+    /// An image file referenced by a material couldn't be loaded
+    #[error("failed to load the texture `{}` used by the mesh", path.display())]
+    MissingTexture {
+        path: PathBuf,
+        #[source]
+        source: image::ImageError,
+    },
+
+    /// A rendered image couldn't be saved
+    #[error("failed to save the image `{}`", path.display())]
+    ImageSave {
+        path: PathBuf,
+        #[source]
+        source: image::ImageError,
+    },
+
+    /// A lookup table couldn't be loaded
+    #[error("failed to load the lookup table `{}`", path.display())]
+    LutLoad {
+        path: PathBuf,
+        #[source]
+        source: image::ImageError,
+    },
+
+    /// The input uses a format or feature that can't be voxelized
+    #[error("{0} is unsupported")]
+    UnsupportedFormat(String),
+
+    /// An index in the input file points outside of the data it refers to
+    #[error("{0} is out of range")]
+    OutOfRange(String),
+
+    /// The requested resolution is outside of the range supported by the voxelizer
+    #[error(
+        "the resolution {0} is unsupported, it has to be between {min} and {max}",
+        min = crate::voxelizer::MIN_SIZE,
+        max = crate::voxelizer::MAX_SIZE
+    )]
+    InvalidResolution(u32),
+
+    /// Two of the options can't be used together
+    #[error("{0}")]
+    ConflictingOptions(&'static str),
+
+    /// The input file has nothing to voxelize
+    #[error("the mesh is empty ({0})")]
+    EmptyMesh(&'static str),
+
+    /// The input file is malformed
+    #[error("{0}")]
+    InvalidData(String),
+
+    /// A `.mtvox` file is malformed
+    #[error("`{}` is not a valid `.mtvox` file ({reason})", path.display())]
+    InvalidOctree { path: PathBuf, reason: String },
+
+    /// Building the json of the `.gltf` output failed
+    #[error("failed to build the gltf json")]
+    Json(#[from] json::Error),
+
+    /// The cancellation flag passed to a long running function was set
+    #[error("the operation was cancelled")]
+    Cancelled,
+}
+
+impl MeshToVoxError {
+    /// Returns a function wrapping an i/o error on the file at `path`, for use with `map_err`
+    pub fn io(path: impl Into<PathBuf>) -> impl FnOnce(std::io::Error) -> Self {
+        let path = path.into();
+        move |source| Self::Io { path, source }
+    }
+}
+
+/// Checks the cancellation flag. Long running functions take the flag as
+/// `Option<&AtomicBool>`, which can be shared with another thread through an `Arc`
+///
+/// # Errors
+/// Returns [`MeshToVoxError::Cancelled`] if the flag is set
+pub fn check_cancelled(cancel: Option<&AtomicBool>) -> Result<()> {
+    if cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+        Err(MeshToVoxError::Cancelled)
+    } else {
+        Ok(())
+    }
+}
+
+pub type Result<T, E = MeshToVoxError> = std::result::Result<T, E>;
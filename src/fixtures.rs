@@ -0,0 +1,144 @@
+use crate::BoundingBox;
+use crate::io::{ImageOrColor, Material, Mesh, UnsupportedFeatures, VertexExtras, View};
+use glam::{EulerRot, Mat4, Quat, Vec3};
+
+/// Generates a torus made out of `2 * n * n` triangles, alternating between two materials
+/// on every ring. Used by the benchmarks as a mesh that doesn't depend on any files
+#[allow(
+    clippy::cast_precision_loss,
+    reason = "the torus has few enough segments to be counted exactly in `f32`"
+)]
+#[must_use]
+pub fn generate_test_mesh(n: u32) -> Mesh {
+    const MAJOR_RADIUS: f32 = 1.0;
+    const MINOR_RADIUS: f32 = 0.4;
+
+    let n = n.max(3);
+
+    // tilt the torus so that no face lines up with the voxel grid
+    let rotation = Quat::from_euler(EulerRot::XYZ, 0.3, 0.5, 0.7);
+
+    let point = |i: u32, j: u32| {
+        let u = (i % n) as f32 / n as f32 * std::f32::consts::TAU;
+        let v = (j % n) as f32 / n as f32 * std::f32::consts::TAU;
+
+        let ring = MINOR_RADIUS.mul_add(v.cos(), MAJOR_RADIUS);
+        let position = Vec3::new(ring * u.cos(), MINOR_RADIUS * v.sin(), ring * u.sin());
+
+        let center = Vec3::new(MAJOR_RADIUS * u.cos(), 0.0, MAJOR_RADIUS * u.sin());
+        let normal = (position - center).normalize();
+
+        (rotation * position, rotation * normal)
+    };
+
+    let mut triangles = Vec::with_capacity((2 * n * n) as usize);
+    let mut triangle_extras = Vec::with_capacity((2 * n * n) as usize);
+
+    for i in 0..n {
+        for j in 0..n {
+            let corners = [
+                point(i, j),
+                point(i + 1, j),
+                point(i + 1, j + 1),
+                point(i, j + 1),
+            ];
+            let material_idx = i % 2;
+
+            for [a, b, c] in [[0, 1, 2], [0, 2, 3]] {
+                let vertices = [corners[a], corners[b], corners[c]];
+
+                triangles.push(vertices.map(|(position, _)| position));
+                triangle_extras.push(
+                    vertices
+                        .map(|(_, normal)| VertexExtras::new(Some(normal), None, material_idx, 0)),
+                );
+            }
+        }
+    }
+
+    let bounds = BoundingBox::from_points(triangles.iter().flatten().copied());
+
+    Mesh {
+        triangles,
+        triangle_extras,
+        materials: vec![
+            Material::new(ImageOrColor::Color(image::Rgba([200, 40, 40, 255]))),
+            Material::new(ImageOrColor::Color(image::Rgba([40, 40, 200, 255]))),
+        ],
+        mesh_names: vec![Some("torus".to_string())],
+        bounds,
+        view: View {
+            camera: None,
+            model_view_projection: Mat4::IDENTITY,
+        },
+        unsupported: UnsupportedFeatures::default(),
+    }
+}
+
+/// Generates the unit cube `(0, 0, 0)..(1, 1, 1)` made out of 12 triangles with a single
+/// material. Used by the self-test and the tests, which rely on the faces being aligned with
+/// the grid
+#[must_use]
+pub fn generate_cube_mesh() -> Mesh {
+    let corner = |index: u8| {
+        Vec3::new(
+            f32::from(index & 1),
+            f32::from((index >> 1) & 1),
+            f32::from((index >> 2) & 1),
+        )
+    };
+
+    // the four corners of every face, counter-clockwise when seen from the outside
+    let faces: [[u8; 4]; 6] = [
+        [0, 4, 6, 2],
+        [1, 3, 7, 5],
+        [0, 1, 5, 4],
+        [2, 6, 7, 3],
+        [0, 2, 3, 1],
+        [4, 5, 7, 6],
+    ];
+
+    let mut triangles = Vec::with_capacity(12);
+    let mut triangle_extras = Vec::with_capacity(12);
+
+    for face in faces {
+        let normal = (corner(face[1]) - corner(face[0]))
+            .cross(corner(face[3]) - corner(face[0]))
+            .normalize();
+
+        for [a, b, c] in [[0, 1, 2], [0, 2, 3]] {
+            triangles.push([face[a], face[b], face[c]].map(corner));
+            triangle_extras.push([VertexExtras::new(Some(normal), None, 0, 0); 3]);
+        }
+    }
+
+    let bounds = BoundingBox::from_points(triangles.iter().flatten().copied());
+
+    Mesh {
+        triangles,
+        triangle_extras,
+        materials: vec![Material::new(ImageOrColor::Color(image::Rgba([
+            200, 120, 40, 255,
+        ])))],
+        mesh_names: vec![Some("cube".to_string())],
+        bounds,
+        view: View {
+            camera: None,
+            model_view_projection: Mat4::IDENTITY,
+        },
+        unsupported: UnsupportedFeatures::default(),
+    }
+}
+
+/// Returns an empty folder in the temporary directory for the files written by the test
+/// `name`, which is unique to the process, so concurrent runs of the tests don't share it
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("mesh_to_vox_{name}_{}", std::process::id()));
+
+    // a folder left behind by an earlier run with the same process id
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("failed to create the test directory");
+
+    dir
+}
@@ -1,12 +1,17 @@
-use crate::io::*;
-use crate::*;
+use crate::io::{
+    Camera, ColorSpace, DEFAULT_ALPHA_CUTOFF, Emission, FloatVertex, ImageOrColor, Material,
+    TangentSpace, TextureMapping, UnsupportedFeatures, Vertex, VertexExtras, View, WrapMode,
+    mpv_to_json,
+};
+use crate::{BoundingBox, LoadOptions, Mesh, MeshToVoxError, Result};
+use glam::{Mat3, Mat4, Quat, Vec2, Vec3};
 use image::ImageBuffer;
 use image::Rgb;
 use image::Rgba;
 use image::buffer::ConvertBuffer;
 use rayon::prelude::*;
 
-/// https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html 5.1.3. accessor.componentType
+/// <https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html>, 5.1.3. `accessor.componentType`
 trait AccessorComponentType {
     const ACCESSOR_COMPONENT_TYPE: i32;
 }
@@ -23,6 +28,10 @@ impl AccessorComponentType for i16 {
 impl AccessorComponentType for u16 {
     const ACCESSOR_COMPONENT_TYPE: i32 = 5123;
 }
+#[allow(
+    clippy::use_self,
+    reason = "the type of the constant, not the implementor"
+)]
 impl AccessorComponentType for i32 {
     const ACCESSOR_COMPONENT_TYPE: i32 = 5125;
 }
@@ -30,6 +39,17 @@ impl AccessorComponentType for f32 {
     const ACCESSOR_COMPONENT_TYPE: i32 = 5126;
 }
 
+/// Extensions that affect how the file is voxelized
+const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "EXT_mesh_gpu_instancing",
+    "KHR_materials_pbrSpecularGlossiness",
+    "KHR_texture_transform",
+];
+
+fn invalid_dimensions() -> MeshToVoxError {
+    MeshToVoxError::InvalidData("image has invalid dimensions".to_string())
+}
+
 #[profiling::function]
 fn convert_image(data: &gltf::image::Data) -> Result<image::RgbaImage> {
     match data.format {
@@ -37,7 +57,7 @@ fn convert_image(data: &gltf::image::Data) -> Result<image::RgbaImage> {
             let pixels: &[f32] = bytemuck::cast_slice(&data.pixels);
 
             ImageBuffer::<Rgb<f32>, _>::from_raw(data.width, data.height, pixels.to_vec())
-                .context("image has invalid dimensions")
+                .ok_or_else(invalid_dimensions)
                 .map(|img| img.convert())
         }
 
@@ -45,7 +65,7 @@ fn convert_image(data: &gltf::image::Data) -> Result<image::RgbaImage> {
             let pixels: &[u16] = bytemuck::cast_slice(&data.pixels);
 
             ImageBuffer::<Rgb<u16>, _>::from_raw(data.width, data.height, pixels.to_vec())
-                .context("image has invalid dimensions")
+                .ok_or_else(invalid_dimensions)
                 .map(|img| img.convert())
         }
 
@@ -53,7 +73,7 @@ fn convert_image(data: &gltf::image::Data) -> Result<image::RgbaImage> {
             let pixels = data.pixels.clone();
 
             ImageBuffer::<Rgb<u8>, _>::from_raw(data.width, data.height, pixels)
-                .context("image has invalid dimensions")
+                .ok_or_else(invalid_dimensions)
                 .map(|img| img.convert())
         }
 
@@ -61,7 +81,7 @@ fn convert_image(data: &gltf::image::Data) -> Result<image::RgbaImage> {
             let pixels: &[f32] = bytemuck::cast_slice(&data.pixels);
 
             ImageBuffer::<Rgba<f32>, _>::from_raw(data.width, data.height, pixels.to_vec())
-                .context("image has invalid dimensions")
+                .ok_or_else(invalid_dimensions)
                 .map(|img| img.convert())
         }
 
@@ -69,7 +89,7 @@ fn convert_image(data: &gltf::image::Data) -> Result<image::RgbaImage> {
             let pixels: &[u16] = bytemuck::cast_slice(&data.pixels);
 
             ImageBuffer::<Rgba<u16>, _>::from_raw(data.width, data.height, pixels.to_vec())
-                .context("image has invalid dimensions")
+                .ok_or_else(invalid_dimensions)
                 .map(|img| img.convert())
         }
 
@@ -77,10 +97,13 @@ fn convert_image(data: &gltf::image::Data) -> Result<image::RgbaImage> {
             let pixels = data.pixels.clone();
 
             ImageBuffer::<Rgba<u8>, _>::from_raw(data.width, data.height, pixels)
-                .context("image has invalid dimensions")
+                .ok_or_else(invalid_dimensions)
         }
 
-        _ => bail!("format {:?} is unsupported", data.format),
+        _ => Err(MeshToVoxError::UnsupportedFormat(format!(
+            "image format {:?}",
+            data.format
+        ))),
     }
 }
 
@@ -88,65 +111,109 @@ fn convert_image(data: &gltf::image::Data) -> Result<image::RgbaImage> {
 fn parse_image(
     image_data: &[gltf::image::Data],
     texture: gltf::Texture,
-    source_dir: &str,
+    source_dir: &std::path::Path,
 ) -> Result<image::RgbaImage> {
     let source = texture.source().source();
 
     match source {
         gltf::image::Source::Uri { uri, .. } => {
-            let path = format!("{source_dir}/{uri}");
+            let path = source_dir.join(percent_decode(uri));
 
-            image::open(path.as_str())
-                .with_context(|| format!("failed to fetch file `{path}` used by the mesh"))
-                .map(|img| img.into_rgba8())
+            image::open(&path)
+                .map_err(|source| MeshToVoxError::MissingTexture { path, source })
+                .map(image::DynamicImage::into_rgba8)
         }
 
         gltf::image::Source::View { .. } => {
-            let image = image_data
-                .get(texture.index())
-                .context("failed to fetch image data (index is out of bounds)")?;
+            let image = image_data.get(texture.index()).ok_or_else(|| {
+                MeshToVoxError::OutOfRange(format!("image index {}", texture.index()))
+            })?;
+
+            convert_image(image)
+        }
+    }
+}
+
+/// Returns the texture coordinate set, the wrap modes and the `KHR_texture_transform` that
+/// the texture of `info` is mapped with
+fn texture_mapping(info: &gltf::texture::Info) -> TextureMapping {
+    let sampler = info.texture().sampler();
+
+    let wrap = [sampler.wrap_s(), sampler.wrap_t()].map(|wrap| match wrap {
+        gltf::texture::WrappingMode::Repeat => WrapMode::Repeat,
+        gltf::texture::WrappingMode::MirroredRepeat => WrapMode::MirroredRepeat,
+        gltf::texture::WrappingMode::ClampToEdge => WrapMode::ClampToEdge,
+    });
+
+    let mut mapping = TextureMapping {
+        tex_coord: info.tex_coord(),
+        wrap,
+        transform: Mat3::IDENTITY,
+    };
+
+    if let Some(transform) = info.texture_transform() {
+        // the rotation of the extension is clockwise, unlike the one of `glam`
+        mapping.transform = Mat3::from_scale_angle_translation(
+            Vec2::from(transform.scale()),
+            -transform.rotation(),
+            Vec2::from(transform.offset()),
+        );
 
-            convert_image(image).context("failed to convert image")
+        if let Some(tex_coord) = transform.tex_coord() {
+            mapping.tex_coord = tex_coord;
         }
     }
+
+    mapping
 }
 
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    reason = "the color factors of glTF are between 0 and 1, and `as` saturates"
+)]
 #[profiling::function]
 fn parse_material(
     mat: &gltf::Material,
     image_data: &[gltf::image::Data],
-    source_dir: &str,
-) -> Result<ImageOrColor> {
-    if let Some(image) = mat
-        .pbr_metallic_roughness()
-        .base_color_texture()
-        .map(|texture_info| texture_info.texture())
+    source_dir: &std::path::Path,
+    options: &LoadOptions,
+) -> Result<Material> {
+    if options.screens
+        && let Some(info) = mat.emissive_texture()
     {
-        return parse_image(&image_data, image, source_dir)
-            .context("failed to parse the color image used by the material")
-            .map(ImageOrColor::Image);
+        let image = parse_image(image_data, info.texture(), source_dir)?;
+
+        return Ok(Material {
+            color: ImageOrColor::Image(image),
+            mapping: texture_mapping(&info),
+            emissive: true,
+            ..Material::new(ImageOrColor::Color(image::Rgba([0; 4])))
+        });
     }
 
-    if let Some(image) = mat
-        .emissive_texture()
-        .map(|texture_info| texture_info.texture())
-    {
-        return parse_image(&image_data, image, source_dir)
-            .context("failed to parse the emissive image used by the material")
-            .map(ImageOrColor::Image);
-    }
+    let emission = parse_emission(mat, image_data, source_dir)?;
 
-    if let Some(image) = mat
-        .pbr_specular_glossiness()
-        .and_then(|spectral| spectral.diffuse_texture())
-        .map(|texture_info| texture_info.texture())
-    {
-        return parse_image(&image_data, image, source_dir)
-            .context("failed to parse the color image of the spectral material")
-            .map(ImageOrColor::Image);
+    let info = mat
+        .pbr_metallic_roughness()
+        .base_color_texture()
+        .or_else(|| {
+            mat.pbr_specular_glossiness()
+                .and_then(|spectral| spectral.diffuse_texture())
+        });
+
+    if let Some(info) = info {
+        return parse_image(image_data, info.texture(), source_dir).map(|image| Material {
+            mapping: texture_mapping(&info),
+            emission,
+            ..Material::new(ImageOrColor::Image(image))
+        });
     }
 
-    let base_color = mat.pbr_metallic_roughness().base_color_factor();
+    let base_color = mat.pbr_specular_glossiness().map_or_else(
+        || mat.pbr_metallic_roughness().base_color_factor(),
+        |spectral| specular_glossiness_color(&spectral),
+    );
 
     let base_color = image::Rgba([
         (base_color[0] * 255.0) as u8,
@@ -155,24 +222,93 @@ fn parse_material(
         (base_color[3] * 255.0) as u8,
     ]);
 
-    Ok(ImageOrColor::Color(base_color))
+    Ok(Material {
+        emission,
+        ..Material::new(ImageOrColor::Color(base_color))
+    })
 }
 
+/// Returns the emission of the material, which is its emissive texture scaled by its emissive
+/// factor, or the factor alone. The factor is black by default, which emits no light
+fn parse_emission(
+    mat: &gltf::Material,
+    image_data: &[gltf::image::Data],
+    source_dir: &std::path::Path,
+) -> Result<Option<Emission>> {
+    let factor = Vec3::from(mat.emissive_factor());
+
+    if factor.max_element() <= 0.0 {
+        return Ok(None);
+    }
+
+    let image = match mat.emissive_texture() {
+        Some(info) => Some((
+            parse_image(image_data, info.texture(), source_dir)?,
+            texture_mapping(&info),
+        )),
+        None => None,
+    };
+
+    Ok(Some(Emission::scaled(image, factor)))
+}
+
+/// Returns the alpha cutoff of the material (see `Material::alpha_cutoff`). Opaque materials
+/// ignore the alpha, and blended ones keep the texels that are mostly opaque, as a voxel can't
+/// be partially transparent
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    reason = "the cutoff is clamped between 0 and 1 first"
+)]
+fn alpha_cutoff(mat: &gltf::Material) -> Option<u8> {
+    match mat.alpha_mode() {
+        gltf::material::AlphaMode::Opaque => None,
+        gltf::material::AlphaMode::Mask => {
+            // texels with an alpha of at least the cutoff are opaque
+            let cutoff = mat.alpha_cutoff().unwrap_or(0.5).clamp(0.0, 1.0);
+            Some((cutoff * 255.0).ceil() as u8)
+        }
+        gltf::material::AlphaMode::Blend => Some(DEFAULT_ALPHA_CUTOFF),
+    }
+}
+
+/// Returns the visible color of a specular-glossiness material without a diffuse texture.
+/// Metals of this workflow have a black diffuse color and carry their tint in the specular
+/// color, so the diffuse color is blended towards the specular one by the strength of the
+/// specular reflection. Non-metals reflect only a few percent, which barely changes them
+fn specular_glossiness_color(spectral: &gltf::material::PbrSpecularGlossiness) -> [f32; 4] {
+    let [r, g, b, a] = spectral.diffuse_factor();
+    let diffuse = Vec3::new(r, g, b);
+    let specular = Vec3::from(spectral.specular_factor());
+
+    let strength = specular.max_element().clamp(0.0, 1.0);
+    let color = diffuse.lerp(specular, strength);
+
+    [color.x, color.y, color.z, a]
+}
+
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    reason = "glTF indexes vertices and meshes by `u32`, and colors are clamped"
+)]
 #[profiling::function]
 fn parse_mesh(
     mesh: &gltf::Mesh,
-    bounds: &mut BoundingBox,
-    materials: &[ImageOrColor],
+    materials: &[Material],
     buffers: &[gltf::buffer::Data],
     triangles: &mut Vec<[Vec3; 3]>,
     extras: &mut Vec<[VertexExtras; 3]>,
+    unsupported: &mut UnsupportedFeatures,
 ) -> Result<()> {
     #[inline]
     fn get_extras(
         idx: usize,
         normals: Option<&[Vec3]>,
         uvs: Option<&[Vec2]>,
+        colors: Option<&[[u8; 4]]>,
         material_idx: u32,
+        mesh_idx: u32,
     ) -> VertexExtras {
         let normal = normals
             .as_ref()
@@ -181,81 +317,143 @@ fn parse_mesh(
 
         let uv = uvs.as_ref().and_then(|uvs| uvs.get(idx)).copied();
 
-        VertexExtras::new(normal, uv, material_idx)
+        let mut extras = VertexExtras::new(normal, uv, material_idx, mesh_idx);
+
+        if let Some(color) = colors.and_then(|colors| colors.get(idx)) {
+            extras.color = *color;
+        }
+
+        extras
     }
 
     for primitive in mesh.primitives() {
         let mode = primitive.mode();
 
         if mode != gltf::mesh::Mode::Triangles {
-            bail!("a mesh in the file uses non-triangle geometry");
+            unsupported.report(format!("non-triangle primitive ({mode:?}), skipped"));
+            continue;
         }
 
-        let bound = primitive.bounding_box();
-        bounds.extend(bound.min.into());
-        bounds.extend(bound.max.into());
+        for (semantic, _) in primitive.attributes() {
+            match semantic {
+                gltf::Semantic::Colors(set) if set > 0 => {
+                    unsupported.report("vertex color sets besides `COLOR_0`");
+                }
+                gltf::Semantic::Joints(_) | gltf::Semantic::Weights(_) => {
+                    unsupported.report("skinning attributes");
+                }
+                _ => {}
+            }
+        }
+
+        if primitive.morph_targets().len() > 0 {
+            unsupported.report("morph targets");
+        }
 
-        let material_idx = primitive.material().index().unwrap_or(materials.len());
+        // the default material is the last one (see `load_gltf`)
+        let material_idx = primitive.material().index().unwrap_or(materials.len() - 1);
+        let material = &materials[material_idx];
 
         let data = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
 
-        let mut indices = data
-            .read_indices()
-            .context("a mesh in the file has no vertex indices")?
-            .into_u32();
+        let position_accessor = primitive.get(&gltf::Semantic::Positions).ok_or_else(|| {
+            MeshToVoxError::InvalidData("a mesh in the file has no vertex positions".into())
+        })?;
 
         let vert_coords = data
             .read_positions()
-            .context("a mesh in the file has no vertex positions")?
+            .ok_or_else(|| unreadable_accessor(&position_accessor, "vertex positions"))?
             .map(Vec3::from)
             .collect::<Vec<_>>();
 
+        if vert_coords.len() != position_accessor.count() {
+            return Err(unreadable_accessor(&position_accessor, "vertex positions"));
+        }
+
+        // primitives without indices are a list of triangles of consecutive vertices
+        let indices = match primitive.indices() {
+            Some(index_accessor) => {
+                let indices = data
+                    .read_indices()
+                    .ok_or_else(|| unreadable_accessor(&index_accessor, "indices"))?
+                    .into_u32()
+                    .collect::<Vec<_>>();
+
+                if indices.len() != index_accessor.count() {
+                    return Err(unreadable_accessor(&index_accessor, "indices"));
+                }
+
+                indices
+            }
+            None => (0..vert_coords.len() as u32).collect(),
+        };
+
+        let vertex = |idx: u32| {
+            vert_coords
+                .get(idx as usize)
+                .copied()
+                .ok_or_else(|| MeshToVoxError::OutOfRange(format!("vertex index {idx}")))
+        };
+
         let normals = data
             .read_normals()
             .map(|normals| normals.map(Vec3::from).collect::<Vec<_>>());
 
+        // every primitive is read with the texture coordinate set of its own material
+        let tex_coord = material.tex_coord();
         let uvs = data
-            .read_tex_coords(0)
+            .read_tex_coords(tex_coord)
             .map(|uvs| uvs.into_f32().map(Vec2::from).collect::<Vec<_>>());
 
-        loop {
-            let i1 = indices.next();
-            let i2 = indices.next();
-            let i3 = indices.next();
-
-            if i1.is_none() {
-                break;
-            }
+        // the vertex colors are linear, unlike the colors of the voxels
+        let colors = data.read_colors(0).map(|colors| {
+            colors
+                .into_rgba_f32()
+                .map(|[r, g, b, a]| {
+                    let [r, g, b] = [r, g, b].map(|channel| ColorSpace::Linear.decode(channel));
+                    [r, g, b, a].map(|channel| (channel.clamp(0.0, 1.0) * 255.0).round() as u8)
+                })
+                .collect::<Vec<_>>()
+        });
+
+        if uvs.is_none() && material.is_textured() {
+            unsupported.report(format!(
+                "textured primitive without the texture coordinate set {tex_coord}"
+            ));
+        }
 
-            let (Some(i1), Some(i2), Some(i3)) = (i1, i2, i3) else {
-                eprintln!("found a non-full triangle ({i1:?}, {i2:?}, {i3:?})");
+        for triangle in indices.chunks(3) {
+            let &[i1, i2, i3] = triangle else {
+                unsupported.report("non-full triangle at the end of an index buffer");
                 break;
             };
 
-            triangles.push([
-                vert_coords[i1 as usize],
-                vert_coords[i2 as usize],
-                vert_coords[i3 as usize],
-            ]);
+            triangles.push([vertex(i1)?, vertex(i2)?, vertex(i3)?]);
 
             extras.push([
                 get_extras(
                     i1 as usize,
                     normals.as_deref(),
                     uvs.as_deref(),
+                    colors.as_deref(),
                     material_idx as u32,
+                    mesh.index() as u32,
                 ),
                 get_extras(
                     i2 as usize,
                     normals.as_deref(),
                     uvs.as_deref(),
+                    colors.as_deref(),
                     material_idx as u32,
+                    mesh.index() as u32,
                 ),
                 get_extras(
                     i3 as usize,
                     normals.as_deref(),
                     uvs.as_deref(),
+                    colors.as_deref(),
                     material_idx as u32,
+                    mesh.index() as u32,
                 ),
             ]);
         }
@@ -264,38 +462,324 @@ fn parse_mesh(
     Ok(())
 }
 
+/// Describes an accessor that yielded fewer values than its `count`. The reader of the `gltf`
+/// crate applies sparse substitutions itself, so a sparse accessor ends up here only if its
+/// values or indices can't be read
+fn unreadable_accessor(accessor: &gltf::Accessor, name: &str) -> MeshToVoxError {
+    if accessor.sparse().is_some() {
+        MeshToVoxError::UnsupportedFormat(format!("the sparse accessor of the mesh {name}"))
+    } else {
+        MeshToVoxError::InvalidData(format!(
+            "the accessor of the mesh {name} doesn't fit into its buffer"
+        ))
+    }
+}
+
+/// Returns the first camera attached to a node of the active scene (the default
+/// scene if the document specifies one, otherwise the first scene)
+fn find_scene_camera(document: &gltf::Document) -> Option<gltf::Camera<'_>> {
+    fn find_in_node<'a>(node: &gltf::Node<'a>) -> Option<gltf::Camera<'a>> {
+        node.camera()
+            .or_else(|| node.children().find_map(|child| find_in_node(&child)))
+    }
+
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())?;
+
+    scene.nodes().find_map(|node| find_in_node(&node))
+}
+
+/// Collects the meshes instanced by the node and its children, in depth-first order,
+/// paired with their world transforms. A node with `EXT_mesh_gpu_instancing` adds its mesh
+/// once for every instance
+fn collect_mesh_instances<'a>(
+    node: &gltf::Node<'a>,
+    parent: Mat4,
+    document: &gltf::Document,
+    buffers: &[gltf::buffer::Data],
+    instances: &mut Vec<(gltf::Mesh<'a>, Mat4)>,
+) -> Result<()> {
+    let transform = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+    if let Some(mesh) = node.mesh() {
+        match gpu_instances(node, document, buffers)? {
+            Some(gpu_instances) => instances.extend(
+                gpu_instances
+                    .into_iter()
+                    .map(|instance| (mesh.clone(), transform * instance)),
+            ),
+            None => instances.push((mesh, transform)),
+        }
+    }
+
+    for child in node.children() {
+        collect_mesh_instances(&child, transform, document, buffers, instances)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the transforms of the instances of `EXT_mesh_gpu_instancing` on the node, in
+/// the space of the node, or `None` if the node doesn't use the extension
+fn gpu_instances(
+    node: &gltf::Node,
+    document: &gltf::Document,
+    buffers: &[gltf::buffer::Data],
+) -> Result<Option<Vec<Mat4>>> {
+    let Some(attributes) = node
+        .extension_value("EXT_mesh_gpu_instancing")
+        .and_then(|extension| extension.get("attributes"))
+    else {
+        return Ok(None);
+    };
+
+    let accessor = |name: &str| {
+        let Some(index) = attributes.get(name) else {
+            return Ok(None);
+        };
+
+        index
+            .as_u64()
+            .and_then(|index| usize::try_from(index).ok())
+            .and_then(|index| document.accessors().nth(index))
+            .map(Some)
+            .ok_or_else(|| {
+                MeshToVoxError::InvalidData(format!(
+                    "the instance {name} of a node references a missing accessor"
+                ))
+            })
+    };
+
+    let translations = accessor("TRANSLATION")?
+        .map(|accessor| read_instance_vectors(&accessor, buffers, "translations"))
+        .transpose()?;
+    let rotations = accessor("ROTATION")?
+        .map(|accessor| read_instance_rotations(&accessor, buffers))
+        .transpose()?;
+    let scales = accessor("SCALE")?
+        .map(|accessor| read_instance_vectors(&accessor, buffers, "scales"))
+        .transpose()?;
+
+    // the extension requires every attribute to have the same count
+    let count = [
+        translations.as_ref().map(Vec::len),
+        rotations.as_ref().map(Vec::len),
+        scales.as_ref().map(Vec::len),
+    ]
+    .into_iter()
+    .flatten()
+    .min()
+    .unwrap_or(0);
+
+    let instances = (0..count)
+        .map(|index| {
+            Mat4::from_scale_rotation_translation(
+                scales.as_ref().map_or(Vec3::ONE, |scales| scales[index]),
+                rotations
+                    .as_ref()
+                    .map_or(Quat::IDENTITY, |rotations| rotations[index]),
+                translations
+                    .as_ref()
+                    .map_or(Vec3::ZERO, |translations| translations[index]),
+            )
+        })
+        .collect();
+
+    Ok(Some(instances))
+}
+
+/// Reads the `VEC3` float accessor of an attribute of `EXT_mesh_gpu_instancing`
+fn read_instance_vectors(
+    accessor: &gltf::Accessor,
+    buffers: &[gltf::buffer::Data],
+    name: &str,
+) -> Result<Vec<Vec3>> {
+    if accessor.data_type() != gltf::accessor::DataType::F32
+        || accessor.dimensions() != gltf::accessor::Dimensions::Vec3
+    {
+        return Err(MeshToVoxError::InvalidData(format!(
+            "the instance {name} have to be `VEC3` floats"
+        )));
+    }
+
+    let values = gltf::accessor::Iter::<[f32; 3]>::new(accessor.clone(), |buffer| {
+        buffers.get(buffer.index()).map(|data| &data[..])
+    })
+    .ok_or_else(|| unreadable_accessor(accessor, &format!("instance {name}")))?
+    .map(Vec3::from)
+    .collect::<Vec<_>>();
+
+    if values.len() != accessor.count() {
+        return Err(unreadable_accessor(accessor, &format!("instance {name}")));
+    }
+
+    Ok(values)
+}
+
+/// Reads the `VEC4` accessor of the `ROTATION` attribute of `EXT_mesh_gpu_instancing`,
+/// which holds floats or normalized integers
+fn read_instance_rotations(
+    accessor: &gltf::Accessor,
+    buffers: &[gltf::buffer::Data],
+) -> Result<Vec<Quat>> {
+    use gltf::accessor::{DataType, Iter};
+    use gltf::animation::util::Rotations;
+
+    let unreadable = || unreadable_accessor(accessor, "instance rotations");
+
+    if accessor.dimensions() != gltf::accessor::Dimensions::Vec4 {
+        return Err(MeshToVoxError::InvalidData(
+            "the instance rotations have to be `VEC4` values".into(),
+        ));
+    }
+
+    let get_buffer_data = |buffer: gltf::Buffer| buffers.get(buffer.index()).map(|data| &data[..]);
+    let accessor_copy = accessor.clone();
+
+    let rotations = match accessor.data_type() {
+        DataType::I8 => Iter::new(accessor_copy, get_buffer_data).map(Rotations::I8),
+        DataType::U8 => Iter::new(accessor_copy, get_buffer_data).map(Rotations::U8),
+        DataType::I16 => Iter::new(accessor_copy, get_buffer_data).map(Rotations::I16),
+        DataType::U16 => Iter::new(accessor_copy, get_buffer_data).map(Rotations::U16),
+        DataType::F32 => Iter::new(accessor_copy, get_buffer_data).map(Rotations::F32),
+        DataType::U32 => {
+            return Err(MeshToVoxError::InvalidData(
+                "the instance rotations can't be `UNSIGNED_INT` values".into(),
+            ));
+        }
+    }
+    .ok_or_else(unreadable)?
+    .into_f32()
+    .map(|rotation| Quat::from_array(rotation).normalize())
+    .collect::<Vec<_>>();
+
+    if rotations.len() != accessor.count() {
+        return Err(unreadable());
+    }
+
+    Ok(rotations)
+}
+
+/// Moves the triangles from the mesh's local space into world space. Non-uniform scales
+/// are handled by transforming the normals with the inverse transpose, and mirroring
+/// transforms swap two vertices of every triangle to keep the winding intact
+fn transform_triangles(
+    triangles: &mut [[Vec3; 3]],
+    extras: &mut [[VertexExtras; 3]],
+    transform: Mat4,
+) {
+    if transform == Mat4::IDENTITY {
+        return;
+    }
+
+    let normal_matrix = Mat3::from_mat4(transform).inverse().transpose();
+    let mirrored = transform.determinant() < 0.0;
+
+    for (triangle, extras) in triangles.iter_mut().zip(extras.iter_mut()) {
+        for position in triangle.iter_mut() {
+            *position = transform.transform_point3(*position);
+        }
+
+        for extra in extras.iter_mut() {
+            let normal = extra
+                .normal()
+                .filter(|normal| normal.is_finite())
+                .map(|normal| (normal_matrix * normal).normalize_or_zero());
+
+            *extra = extra.with_normal(normal);
+        }
+
+        if mirrored {
+            triangle.swap(1, 2);
+            extras.swap(1, 2);
+        }
+    }
+}
+
+/// Returns an error naming the first external buffer or image of the file that doesn't exist
+/// next to it, which `gltf::import` only reports as an i/o error without the file
+fn find_missing_resource(path: &str) -> Option<MeshToVoxError> {
+    let document = gltf::Gltf::open(path).ok()?;
+    let folder = std::path::Path::new(path).parent()?;
+
+    let buffers = document
+        .buffers()
+        .filter_map(|buffer| match buffer.source() {
+            gltf::buffer::Source::Uri(uri) => Some(uri),
+            gltf::buffer::Source::Bin => None,
+        });
+
+    let images = document.images().filter_map(|image| match image.source() {
+        gltf::image::Source::Uri { uri, .. } => Some(uri),
+        gltf::image::Source::View { .. } => None,
+    });
+
+    buffers
+        .chain(images)
+        // data URIs are embedded, and other schemes can't be resolved at all
+        .filter(|uri| !uri.contains(':'))
+        .find_map(|uri| {
+            let resource = folder.join(percent_decode(uri));
+
+            (!resource.exists()).then(|| MeshToVoxError::MissingResource {
+                uri: uri.to_string(),
+                path: resource,
+            })
+        })
+}
+
+/// Decodes the `%XX` escapes of a relative URI, i.e. `my%20texture.png`
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let escaped = bytes
+            .get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        if let Some(byte) = escaped {
+            decoded.push(byte);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
 #[profiling::function]
-pub fn load_gltf(path: &str) -> Result<Mesh> {
+pub fn load_gltf(path: &str, options: &LoadOptions) -> Result<Mesh> {
+    // the external resources are resolved relative to the folder of the file
     let (document, buffers, images) = {
         profiling::scope!("gltf::import");
-        gltf::import(path).context("failed to load the gltf file")
-    }?;
+        gltf::import(path)
+    }
+    .map_err(|err| find_missing_resource(path).unwrap_or(MeshToVoxError::Gltf(err)))?;
 
-    let folder = std::path::Path::new(path)
-        .parent()
-        .and_then(|file| file.as_os_str().to_str())
-        .context("failed to read the parent folder of the file")?;
+    let folder = std::path::Path::new(path).parent().ok_or_else(|| {
+        MeshToVoxError::InvalidData("failed to read the parent folder of the file".into())
+    })?;
 
-    let main_camera = document
-        .cameras()
-        .find(|cam| cam.index() != 0)
+    let main_camera = find_scene_camera(&document)
+        .or_else(|| document.cameras().next())
         .map(|camera| Camera::new(&camera.projection()));
 
-    let model_view_projection = Mat4::from_cols_array_2d(
-        &document
-            .scenes()
-            .next()
-            .context("file has no scenes")?
-            .nodes()
-            .next()
-            .context("scene has no root nodes")?
-            .transform()
-            .matrix(),
-    );
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or(MeshToVoxError::EmptyMesh("the file has no scenes"))?;
 
+    // the node transforms are baked into the triangles, so the output needs none
     let view = View {
         camera: main_camera,
-        model_view_projection,
+        model_view_projection: Mat4::IDENTITY,
     };
 
     let mut triangles = Vec::new();
@@ -305,38 +789,240 @@ pub fn load_gltf(path: &str) -> Result<Mesh> {
         .materials()
         .collect::<Vec<_>>()
         .par_iter()
-        .map(|material| parse_material(&material, &images, folder))
-        .collect::<Result<Vec<_>, _>>()
-        .context("failed to parse materials")?;
+        .map(|material| {
+            parse_material(material, &images, folder, options).map(|parsed| Material {
+                name: material.name().map(str::to_string),
+                alpha_cutoff: alpha_cutoff(material),
+                ..parsed
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
 
     // i.e. default material
-    materials.push(ImageOrColor::Color(image::Rgba([255, 255, 255, 255])));
+    materials.push(Material::new(ImageOrColor::Color(image::Rgba([
+        255, 255, 255, 255,
+    ]))));
+
+    let mut unsupported = UnsupportedFeatures::default();
+
+    for extension in document.extensions_used() {
+        if !SUPPORTED_EXTENSIONS.contains(&extension) {
+            unsupported.report(format!("extension `{extension}`"));
+        }
+    }
+
+    for node in document.nodes() {
+        if node.skin().is_some() {
+            unsupported.report("skins");
+        }
+    }
+
+    for _ in document.animations() {
+        unsupported.report("animations");
+    }
 
-    let mut bounds = BoundingBox::max();
+    // the triangles have a single set of texture coordinates, which the color is sampled with
+    for material in &materials {
+        if let (ImageOrColor::Image(_), Some(emission)) = (&material.color, &material.emission)
+            && matches!(emission.color, ImageOrColor::Image(_))
+            && emission.mapping.tex_coord != material.mapping.tex_coord
+        {
+            unsupported.report("emissive texture with another texture coordinate set");
+        }
+    }
+
+    let mut instances = Vec::new();
+
+    for node in scene.nodes() {
+        collect_mesh_instances(&node, Mat4::IDENTITY, &document, &buffers, &mut instances)?;
+    }
+
+    for (mesh, transform) in instances {
+        let start = triangles.len();
 
-    for mesh in document.meshes() {
         parse_mesh(
             &mesh,
-            &mut bounds,
             &materials,
             &buffers,
             &mut triangles,
             &mut triangle_extras,
+            &mut unsupported,
         )?;
+
+        transform_triangles(
+            &mut triangles[start..],
+            &mut triangle_extras[start..],
+            transform,
+        );
     }
 
-    Ok(Mesh {
-        materials,
+    if triangles.is_empty() {
+        return Err(MeshToVoxError::EmptyMesh("the file has no triangles"));
+    }
+
+    // computed after the node transforms, so scaled or rotated nodes are fully covered
+    let bounds = BoundingBox::from_points(triangles.iter().flatten().copied());
+
+    // meshes without a name are named after the first node that uses them
+    let mut mesh_names = document
+        .meshes()
+        .map(|mesh| mesh.name().map(str::to_string))
+        .collect::<Vec<_>>();
+
+    for node in document.nodes() {
+        if let Some(mesh) = node.mesh()
+            && mesh_names[mesh.index()].is_none()
+        {
+            mesh_names[mesh.index()] = node.name().map(str::to_string);
+        }
+    }
+
+    let mut mesh = Mesh {
         triangles,
         triangle_extras,
+        materials,
+        mesh_names,
         bounds,
         view,
-    })
+        unsupported,
+    };
+
+    // computed after the node transforms, which the normals from the file went through
+    if options.compute_normals {
+        mesh.compute_missing_normals();
+    }
+
+    Ok(mesh)
+}
+
+/// Returns the path of the binary buffer that `save_gltf` writes next to the `.gltf` file.
+/// The buffer is named after the `.gltf` file, so multiple files can share a folder
+#[must_use]
+pub fn buffer_path(gltf_path: &str) -> std::path::PathBuf {
+    std::path::Path::new(gltf_path).with_extension("bin")
+}
+
+/// Where the buffer of the `.gltf` output is stored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GltfBuffer {
+    /// A separate `.bin` file next to the `.gltf` file (see `buffer_path`)
+    #[default]
+    External,
+    /// A base64 data URI inside the `.gltf` file
+    Embedded,
+    /// The binary chunk of a `.glb` file, which holds the JSON and the buffer in one file
+    Binary,
+}
+
+/// The magic number at the start of a `.glb` file, `glTF` in ASCII
+const GLB_MAGIC: u32 = 0x4654_6C67;
+
+/// The types of the chunks of a `.glb` file, `JSON` and `BIN` in ASCII
+const GLB_JSON_CHUNK: u32 = 0x4E4F_534A;
+const GLB_BIN_CHUNK: u32 = 0x004E_4942;
+
+/// Returns a `.glb` file made of the JSON of a glTF file and its buffer, each in a chunk
+/// padded to four bytes (the JSON with spaces, the buffer with zeros)
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "the lengths of a `.glb` file are stored as `u32`"
+)]
+fn glb_container(json: &str, bin: &[u8]) -> Vec<u8> {
+    let padded = |len: usize| len.next_multiple_of(4);
+    let json_len = padded(json.len());
+    let bin_len = padded(bin.len());
+
+    // the header, and the header of every chunk, are 12 and 8 bytes long
+    let total = 12 + 8 + json_len + 8 + bin_len;
+    let mut glb = Vec::with_capacity(total);
+
+    for word in [GLB_MAGIC, 2, total as u32, json_len as u32, GLB_JSON_CHUNK] {
+        glb.extend_from_slice(&word.to_le_bytes());
+    }
+
+    glb.extend_from_slice(json.as_bytes());
+    glb.resize(glb.len() + json_len - json.len(), b' ');
+
+    for word in [bin_len as u32, GLB_BIN_CHUNK] {
+        glb.extend_from_slice(&word.to_le_bytes());
+    }
+
+    glb.extend_from_slice(bin);
+    glb.resize(total, 0);
+
+    glb
+}
+
+/// Optional attributes written for every vertex
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExtraAttributes<'a> {
+    /// Written as `NORMAL` and `TANGENT`
+    pub tangent_space: Option<&'a [TangentSpace]>,
+
+    /// Written as the custom `_AO` attribute
+    pub occlusion: Option<&'a [f32]>,
 }
 
+/// How the vertices of the `.gltf` output are encoded, and where they're stored
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Encoding {
+    /// Write the colors as floats instead of normalized bytes
+    pub float: bool,
+
+    /// Where the buffer is stored, which also decides between a `.gltf` and a `.glb` file
+    pub buffer: GltfBuffer,
+
+    /// The color space that the sRGB colors of the vertices and the primitives are written in
+    pub color_space: ColorSpace,
+}
+
+/// A range of vertices written as one primitive, with a material of its own
+#[derive(Debug, Clone)]
+pub struct Primitive {
+    pub range: core::ops::Range<usize>,
+
+    /// Written with an unlit material
+    pub emissive: bool,
+
+    /// Written as the base color of the material, in which case the primitive has no
+    /// `COLOR_0` attribute
+    pub color: Option<image::Rgba<u8>>,
+
+    /// Drawn as lines, two vertices per line, instead of triangles. Lines have no extra
+    /// attributes
+    pub lines: bool,
+}
+
+/// A node of the scene, with a mesh made of a range of the primitives
+#[derive(Debug, Clone)]
+pub struct Node<'a> {
+    pub name: Option<&'a str>,
+    pub matrix: Mat4,
+    pub primitives: core::ops::Range<usize>,
+}
+
+/// Saves the vertices as a `.gltf` file encoded as `encoding` says, which is a binary `.glb`
+/// file for `GltfBuffer::Binary`. Every node of `nodes` gets a mesh of its own, and every
+/// primitive of `primitives` gets its own material
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    reason = "the encoded colors stay between 0 and 1"
+)]
 #[profiling::function]
-pub fn save_gltf(vertices: &[Vertex], gltf_path: &str, view: View, float: bool) -> Result<()> {
-    let bb = BoundingBox::from_points(vertices.iter().map(|v| v.position));
+pub fn save_gltf(
+    vertices: &[Vertex],
+    extras: ExtraAttributes,
+    primitives: &[Primitive],
+    nodes: &[Node],
+    gltf_path: &str,
+    encoding: Encoding,
+) -> Result<()> {
+    let Encoding {
+        float,
+        buffer,
+        color_space,
+    } = encoding;
 
     let size_of_vertices = if float {
         size_of::<FloatVertex>()
@@ -344,38 +1030,58 @@ pub fn save_gltf(vertices: &[Vertex], gltf_path: &str, view: View, float: bool)
         size_of::<Vertex>()
     };
 
-    let num_bytes = vertices.len() * size_of_vertices;
-
-    let buffer = json::object! {
-        uri : "model.bin",
-        byteLength : num_bytes,
+    let mut bin = if float {
+        let vertices = vertices
+            .iter()
+            .map(|vert| {
+                let mut vert = FloatVertex::from(*vert);
+                vert.color = color_space.encode_color(vert.color);
+                vert
+            })
+            .collect::<Vec<_>>();
+        bytemuck::cast_slice::<_, u8>(&vertices).to_vec()
+    } else {
+        let vertices = vertices
+            .iter()
+            .map(|vert| {
+                let color = FloatVertex::from(*vert).color;
+                let color = color_space.encode_color(color);
+
+                Vertex {
+                    color: color.map(|channel| (channel * 255.0).round() as u8),
+                    ..*vert
+                }
+            })
+            .collect::<Vec<_>>();
+        bytemuck::cast_slice::<_, u8>(&vertices).to_vec()
     };
 
-    let vertex_view = json::object! {
-        buffer : 0,
-        byteOffset : 0,
-        byteLength : num_bytes,
-        byteStride : size_of_vertices,
-    };
+    let ExtraAttributes {
+        tangent_space,
+        occlusion,
+    } = extras;
+
+    let tangent_offset = bin.len();
 
-    let byte_offset = if float {
+    if let Some(tangent_space) = tangent_space {
+        bin.extend_from_slice(bytemuck::cast_slice(tangent_space));
+    }
+
+    // float accessors have to be aligned to 4 bytes
+    bin.resize(bin.len().next_multiple_of(4), 0);
+    let occlusion_offset = bin.len();
+
+    if let Some(occlusion) = occlusion {
+        bin.extend_from_slice(bytemuck::cast_slice(occlusion));
+    }
+
+    let position_offset = if float {
         core::mem::offset_of!(FloatVertex, position)
     } else {
         core::mem::offset_of!(Vertex, position)
     };
 
-    let position_accessor = json::object! {
-        bufferView : 0,
-        byteOffset : byte_offset,
-        componentType : f32::ACCESSOR_COMPONENT_TYPE,
-        count : vertices.len(),
-        type : "VEC3",
-
-        max : [bb.max.x, bb.max.y, bb.max.z],
-        min : [bb.min.x, bb.min.y, bb.min.z],
-    };
-
-    let byte_offset = if float {
+    let color_offset = if float {
         core::mem::offset_of!(FloatVertex, color)
     } else {
         core::mem::offset_of!(Vertex, color)
@@ -387,61 +1093,579 @@ pub fn save_gltf(vertices: &[Vertex], gltf_path: &str, view: View, float: bool)
     };
     let normalized = !float;
 
-    let color_accessor = json::object! {
-        bufferView : 0,
-        byteOffset : byte_offset,
-        componentType : component_type,
-        normalized : normalized,
-        count : vertices.len(),
-        type : "VEC3",
-    };
+    let mut buffer_views = Vec::new();
+    let mut accessors = Vec::new();
+    let mut materials = Vec::new();
+    let mut mesh_primitives = Vec::new();
 
-    let material = json::object! {
-        doubleSided : true,
-    };
+    for primitive in primitives {
+        let range = primitive.range.clone();
+
+        let mut material = json::object! {
+            doubleSided : true,
+        };
+
+        if let Some(color) = primitive.color {
+            let color = color.0.map(|channel| f32::from(channel) / 255.0);
+            let [r, g, b, _] = color_space.encode_color(color);
+
+            material["pbrMetallicRoughness"] = json::object! {
+                baseColorFactor : [r, g, b, 1.0],
+            };
+        }
+
+        if primitive.emissive {
+            material["extensions"] = json::object! {
+                KHR_materials_unlit : {},
+            };
+        }
+
+        let bb = BoundingBox::from_points(vertices[range.clone()].iter().map(|v| v.position));
 
-    let mesh = json::object! {
-        primitives : [{
+        let vertex_view = json::object! {
+            buffer : 0,
+            byteOffset : range.start * size_of_vertices,
+            byteLength : range.len() * size_of_vertices,
+            byteStride : size_of_vertices,
+        };
+
+        let position_accessor = json::object! {
+            bufferView : buffer_views.len(),
+            byteOffset : position_offset,
+            componentType : f32::ACCESSOR_COMPONENT_TYPE,
+            count : range.len(),
+            type : "VEC3",
+
+            max : [bb.max.x, bb.max.y, bb.max.z],
+            min : [bb.min.x, bb.min.y, bb.min.z],
+        };
+
+        let mut mesh_primitive = json::object! {
             attributes : {
-                POSITION : 0,
-                COLOR_0 : 1,
+                POSITION : accessors.len(),
             },
 
-            material : 0
-        }],
+            material : materials.len()
+        };
+
+        if primitive.lines {
+            mesh_primitive["mode"] = gltf::mesh::Mode::Lines.as_gl_enum().into();
+        }
+
+        accessors.push(position_accessor);
+
+        if primitive.color.is_none() {
+            let color_accessor = json::object! {
+                bufferView : buffer_views.len(),
+                byteOffset : color_offset,
+                componentType : component_type,
+                normalized : normalized,
+                count : range.len(),
+                type : "VEC3",
+            };
+
+            mesh_primitive["attributes"]["COLOR_0"] = accessors.len().into();
+            accessors.push(color_accessor);
+        }
+
+        buffer_views.push(vertex_view);
+        materials.push(material);
+
+        if tangent_space.is_some() && !primitive.lines {
+            let tangent_view = json::object! {
+                buffer : 0,
+                byteOffset : tangent_offset + range.start * size_of::<TangentSpace>(),
+                byteLength : range.len() * size_of::<TangentSpace>(),
+                byteStride : size_of::<TangentSpace>(),
+            };
+
+            let normal_accessor = json::object! {
+                bufferView : buffer_views.len(),
+                byteOffset : core::mem::offset_of!(TangentSpace, normal),
+                componentType : f32::ACCESSOR_COMPONENT_TYPE,
+                count : range.len(),
+                type : "VEC3",
+            };
+
+            let tangent_accessor = json::object! {
+                bufferView : buffer_views.len(),
+                byteOffset : core::mem::offset_of!(TangentSpace, tangent),
+                componentType : f32::ACCESSOR_COMPONENT_TYPE,
+                count : range.len(),
+                type : "VEC4",
+            };
+
+            let attributes = &mut mesh_primitive["attributes"];
+            attributes["NORMAL"] = accessors.len().into();
+            attributes["TANGENT"] = (accessors.len() + 1).into();
+
+            buffer_views.push(tangent_view);
+            accessors.push(normal_accessor);
+            accessors.push(tangent_accessor);
+        }
+
+        if occlusion.is_some() && !primitive.lines {
+            let occlusion_view = json::object! {
+                buffer : 0,
+                byteOffset : occlusion_offset + range.start * size_of::<f32>(),
+                byteLength : range.len() * size_of::<f32>(),
+            };
+
+            let occlusion_accessor = json::object! {
+                bufferView : buffer_views.len(),
+                componentType : f32::ACCESSOR_COMPONENT_TYPE,
+                count : range.len(),
+                type : "SCALAR",
+            };
+
+            mesh_primitive["attributes"]["_AO"] = accessors.len().into();
+
+            buffer_views.push(occlusion_view);
+            accessors.push(occlusion_accessor);
+        }
+
+        mesh_primitives.push(mesh_primitive);
+    }
+
+    let meshes = nodes
+        .iter()
+        .map(|node| {
+            json::object! {
+                primitives : mesh_primitives[node.primitives.clone()].to_vec(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let scene_nodes = nodes
+        .iter()
+        .enumerate()
+        .map(|(index, node)| {
+            let mut json_node = json::object! {
+                mesh : index,
+                matrix : mpv_to_json(&node.matrix),
+            };
+
+            if let Some(name) = node.name {
+                json_node["name"] = name.into();
+            }
+
+            json_node
+        })
+        .collect::<Vec<_>>();
+
+    let bin_path = buffer_path(gltf_path);
+    let bin_uri = match buffer {
+        GltfBuffer::External => Some(
+            bin_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| {
+                    MeshToVoxError::InvalidData("failed to get the name of the buffer file".into())
+                })?
+                .to_string(),
+        ),
+        GltfBuffer::Embedded => {
+            use base64::Engine;
+
+            let encoded = base64::engine::general_purpose::STANDARD.encode(&bin);
+            Some(format!("data:application/octet-stream;base64,{encoded}"))
+        }
+        // the buffer without a URI is the binary chunk of the file
+        GltfBuffer::Binary => None,
     };
 
-    let gltf = json::object! {
-        materials : [material],
-        scenes : [ {nodes : [ 0 ]} ],
-        nodes : [ {
-            mesh : 0,
-            matrix : mpv_to_json(&view.model_view_projection),
-        }],
-
-        meshes : [mesh],
-        buffers : [buffer],
-        bufferViews : [vertex_view],
-        accessors : [position_accessor, color_accessor],
+    let mut json_buffer = json::JsonValue::new_object();
+
+    if let Some(uri) = bin_uri {
+        json_buffer["uri"] = uri.into();
+    }
+
+    json_buffer["byteLength"] = bin.len().into();
+
+    let mut gltf = json::object! {
+        materials : materials,
+        scenes : [ {nodes : (0..nodes.len()).collect::<Vec<_>>()} ],
+        nodes : scene_nodes,
+
+        meshes : meshes,
+        buffers : [json_buffer],
+        bufferViews : buffer_views,
+        accessors : accessors,
         asset : {version : "2.0" }
     };
 
-    let folder = std::path::Path::new(gltf_path).parent().unwrap();
-    let folder = folder.as_os_str().to_str().unwrap();
+    if primitives.iter().any(|primitive| primitive.emissive) {
+        gltf["extensionsUsed"] = json::array!["KHR_materials_unlit"];
+    }
 
-    std::fs::create_dir(folder)?;
-    let bin_path = format!("{}/model.bin", folder);
+    // a bare file name has an empty parent, the current folder, which already exists
+    let folder = std::path::Path::new(gltf_path)
+        .parent()
+        .filter(|folder| !folder.as_os_str().is_empty());
 
-    std::fs::write(gltf_path, gltf.dump())?;
-    if float {
-        let vertices = vertices
+    if let Some(folder) = folder {
+        std::fs::create_dir_all(folder).map_err(MeshToVoxError::io(folder))?;
+    }
+
+    let file = match buffer {
+        GltfBuffer::Binary => glb_container(&gltf.dump(), &bin),
+        GltfBuffer::External | GltfBuffer::Embedded => gltf.dump().into_bytes(),
+    };
+
+    std::fs::write(gltf_path, file).map_err(MeshToVoxError::io(gltf_path))?;
+
+    if buffer == GltfBuffer::External {
+        std::fs::write(&bin_path, bin).map_err(MeshToVoxError::io(&bin_path))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use glam::IVec3;
+
+    /// The vertices of a triangle in the XY plane
+    const TRIANGLE: [[f32; 3]; 3] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+
+    /// Returns a buffer holding `bin`, embedded as a data URI
+    fn embedded_buffer(bin: &[u8]) -> json::JsonValue {
+        use base64::Engine;
+
+        let encoded = base64::engine::general_purpose::STANDARD.encode(bin);
+
+        json::object! {
+            byteLength : bin.len(),
+            uri : format!("data:application/octet-stream;base64,{encoded}"),
+        }
+    }
+
+    /// Returns a glTF file with the triangle in the only node of its scene, and the buffer
+    /// embedded as a data URI
+    fn triangle_gltf() -> json::JsonValue {
+        let bin = bytemuck::cast_slice::<_, u8>(&TRIANGLE);
+
+        json::object! {
+            asset : { version : "2.0" },
+            scene : 0,
+            scenes : [ { nodes : [0] } ],
+            nodes : [ { mesh : 0 } ],
+            meshes : [ { primitives : [ { attributes : { POSITION : 0 } } ] } ],
+            buffers : [ embedded_buffer(bin) ],
+            bufferViews : [ { buffer : 0, byteLength : bin.len() } ],
+            accessors : [ {
+                bufferView : 0,
+                componentType : 5126,
+                count : 3,
+                "type" : "VEC3",
+                min : [0.0, 0.0, 0.0],
+                max : [1.0, 1.0, 0.0],
+            } ],
+        }
+    }
+
+    /// Writes `gltf` into the folder of the test `name`, and loads it back
+    fn load_test_gltf(name: &str, gltf: &json::JsonValue) -> Mesh {
+        let path = fixtures::test_dir(name).join("model.gltf");
+        std::fs::write(&path, gltf.dump()).expect("failed to write the test file");
+
+        load_gltf(path.to_str().unwrap(), &LoadOptions::default()).expect("failed to load")
+    }
+
+    #[test]
+    fn only_transparent_materials_cut_out_voxels() {
+        let mut gltf = triangle_gltf();
+        gltf["materials"] = json::array![
+            {},
+            { alphaMode : "MASK", alphaCutoff : 0.25 },
+            { alphaMode : "BLEND" },
+        ];
+
+        let mesh = load_test_gltf("alpha_modes", &gltf);
+        let cutoffs = mesh
+            .materials
             .iter()
-            .map(|vert| FloatVertex::from(vert.clone()))
+            .map(|material| material.alpha_cutoff)
             .collect::<Vec<_>>();
-        std::fs::write(bin_path, bytemuck::cast_slice(&vertices))?;
-    } else {
-        std::fs::write(bin_path, bytemuck::cast_slice(vertices))?;
+
+        // the default material is opaque as well
+        assert_eq!(cutoffs, [None, Some(64), Some(DEFAULT_ALPHA_CUTOFF), None]);
     }
 
-    Ok(())
+    #[test]
+    fn loads_the_only_camera() {
+        let mut gltf = triangle_gltf();
+        gltf["cameras"] = json::array![ {
+            "type" : "perspective",
+            perspective : { yfov : 0.8, znear : 0.1 },
+        } ];
+        gltf["nodes"].push(json::object! { camera : 0 }).unwrap();
+        gltf["scenes"][0]["nodes"].push(1).unwrap();
+
+        let mesh = load_test_gltf("one_camera", &gltf);
+
+        assert!(mesh.view.camera.is_some());
+        assert_eq!(mesh.triangles, [TRIANGLE.map(Vec3::from)]);
+    }
+
+    #[test]
+    fn colors_specular_glossiness_by_factors() {
+        // a metal, whose tint is carried by the specular color
+        let mut gltf = triangle_gltf();
+        gltf["extensionsUsed"] = json::array!["KHR_materials_pbrSpecularGlossiness"];
+        gltf["materials"] = json::array![ {
+            extensions : {
+                KHR_materials_pbrSpecularGlossiness : {
+                    diffuseFactor : [0.0, 0.0, 0.0, 1.0],
+                    specularFactor : [1.0, 0.8, 0.2],
+                },
+            },
+        } ];
+        gltf["meshes"][0]["primitives"][0]["material"] = 0.into();
+
+        let mesh = load_test_gltf("specular_glossiness", &gltf);
+        let tree = crate::mesh_to_octree(&mesh, 30, crate::VoxelizationMode::Triangles).unwrap();
+
+        let nodes = tree.collect_nodes();
+        assert!(!nodes.is_empty());
+
+        for (node, color) in nodes {
+            let color = crate::octree::octree_header::to_color(color);
+            assert_eq!(color.0[..3], [255, 204, 51], "the voxel {}", node.coords);
+        }
+    }
+
+    #[test]
+    fn reads_sparse_positions() {
+        // the accessor has no buffer view, so all of its values come from the substitutions,
+        // whose indices follow the positions in the buffer
+        let positions = bytemuck::cast_slice::<_, u8>(&TRIANGLE);
+        let indices = bytemuck::cast_slice::<_, u8>(&[0_u32, 1, 2]);
+
+        let mut gltf = triangle_gltf();
+        gltf["buffers"][0] = embedded_buffer(&[positions, indices].concat());
+        gltf["bufferViews"] = json::array![
+            { buffer : 0, byteLength : positions.len() },
+            { buffer : 0, byteOffset : positions.len(), byteLength : indices.len() },
+        ];
+        gltf["accessors"][0].remove("bufferView");
+        gltf["accessors"][0]["sparse"] = json::object! {
+            count : 3,
+            indices : { bufferView : 1, componentType : 5125 },
+            values : { bufferView : 0 },
+        };
+
+        let mesh = load_test_gltf("sparse_accessor", &gltf);
+
+        assert_eq!(mesh.triangles, [TRIANGLE.map(Vec3::from)]);
+    }
+
+    #[test]
+    fn voxelizes_every_gpu_instance() {
+        // the second instance is moved away from the first one along X
+        let positions = bytemuck::cast_slice::<_, u8>(&TRIANGLE);
+        let translations = bytemuck::cast_slice::<_, u8>(&[[0.0_f32, 0.0, 0.0], [3.0, 0.0, 0.0]]);
+
+        let mut gltf = triangle_gltf();
+        gltf["extensionsUsed"] = json::array!["EXT_mesh_gpu_instancing"];
+        gltf["nodes"][0]["extensions"] = json::object! {
+            EXT_mesh_gpu_instancing : { attributes : { TRANSLATION : 1 } },
+        };
+        gltf["buffers"][0] = embedded_buffer(&[positions, translations].concat());
+        gltf["bufferViews"] = json::array![
+            { buffer : 0, byteLength : positions.len() },
+            { buffer : 0, byteOffset : positions.len(), byteLength : translations.len() },
+        ];
+        gltf["accessors"]
+            .push(json::object! {
+                bufferView : 1,
+                componentType : 5126,
+                count : 2,
+                "type" : "VEC3",
+            })
+            .unwrap();
+
+        let mesh = load_test_gltf("gpu_instancing", &gltf);
+        assert_eq!(mesh.triangles.len(), 2);
+
+        // the model is 4 units wide, so the instances are on the left and the right quarter
+        // of the grid, with nothing between them
+        let tree = crate::mesh_to_octree(&mesh, 30, crate::VoxelizationMode::Triangles).unwrap();
+        let columns = tree
+            .collect_nodes()
+            .iter()
+            .map(|(node, _)| node.coords.x)
+            .collect::<std::collections::HashSet<_>>();
+
+        assert!(
+            columns.iter().any(|x| *x < 10),
+            "the first instance is missing"
+        );
+        assert!(
+            columns.iter().any(|x| *x > 20),
+            "the second instance is missing"
+        );
+        assert!(columns.iter().all(|x| !(10..=20).contains(x)));
+    }
+
+    #[test]
+    fn names_the_missing_texture_of_a_glb() {
+        let dir = fixtures::test_dir("glb_texture");
+        let texture = dir.join("textures").join("red texel.png");
+        let path = dir.join("model.glb");
+        let path = path.to_str().unwrap();
+
+        // the `.glb` holds the buffer, but references its texture by a percent-encoded URI
+        let positions = bytemuck::cast_slice::<_, u8>(&TRIANGLE);
+        let uvs = bytemuck::cast_slice::<_, u8>(&[[0.5_f32; 2]; 3]);
+        let bin = [positions, uvs].concat();
+
+        let mut gltf = triangle_gltf();
+        gltf["buffers"][0] = json::object! { byteLength : bin.len() };
+        gltf["bufferViews"]
+            .push(json::object! {
+                buffer : 0,
+                byteOffset : positions.len(),
+                byteLength : uvs.len(),
+            })
+            .unwrap();
+        gltf["accessors"]
+            .push(json::object! {
+                bufferView : 1,
+                componentType : 5126,
+                count : 3,
+                "type" : "VEC2",
+            })
+            .unwrap();
+        gltf["meshes"][0]["primitives"][0]["attributes"]["TEXCOORD_0"] = 1.into();
+        gltf["meshes"][0]["primitives"][0]["material"] = 0.into();
+        gltf["materials"] = json::array![ {
+            pbrMetallicRoughness : { baseColorTexture : { index : 0 } },
+        } ];
+        gltf["textures"] = json::array![ { source : 0 } ];
+        gltf["images"] = json::array![ { uri : "textures/red%20texel.png" } ];
+
+        std::fs::write(path, glb_container(&gltf.dump(), &bin)).unwrap();
+
+        let result = load_gltf(path, &LoadOptions::default());
+        assert!(
+            matches!(
+                &result,
+                Err(MeshToVoxError::MissingResource { uri, path })
+                    if uri == "textures/red%20texel.png" && *path == texture
+            ),
+            "expected the missing `{}`, got {result:?}",
+            texture.display()
+        );
+
+        std::fs::create_dir_all(texture.parent().unwrap()).unwrap();
+        image::RgbaImage::from_pixel(1, 1, image::Rgba([255, 0, 0, 255]))
+            .save(&texture)
+            .unwrap();
+
+        let mesh = load_gltf(path, &LoadOptions::default()).unwrap();
+        assert!(matches!(mesh.materials[0].color, ImageOrColor::Image(_)));
+    }
+
+    #[test]
+    fn reads_consecutive_triangles_without_indices() {
+        // two triangles of six vertices, the second one moved along Z and colored blue
+        let moved = TRIANGLE.map(|[x, y, _]| [x, y, 1.0]);
+        let triangles = [TRIANGLE, moved];
+        let positions = bytemuck::cast_slice::<_, u8>(&triangles);
+        let colors = [[1.0_f32, 0.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0]].map(|color| [color; 3]);
+        let colors = bytemuck::cast_slice::<_, u8>(&colors);
+
+        let mut gltf = triangle_gltf();
+        gltf["buffers"][0] = embedded_buffer(&[positions, colors].concat());
+        gltf["bufferViews"] = json::array![
+            { buffer : 0, byteLength : positions.len() },
+            { buffer : 0, byteOffset : positions.len(), byteLength : colors.len() },
+        ];
+        gltf["accessors"][0]["count"] = 6.into();
+        gltf["accessors"][0]["max"] = json::array![1.0, 1.0, 1.0];
+        gltf["accessors"]
+            .push(json::object! {
+                bufferView : 1,
+                componentType : 5126,
+                count : 6,
+                "type" : "VEC4",
+            })
+            .unwrap();
+        gltf["meshes"][0]["primitives"][0]["attributes"]["COLOR_0"] = 1.into();
+
+        let mesh = load_test_gltf("non_indexed", &gltf);
+
+        assert_eq!(
+            mesh.triangles,
+            triangles.map(|triangle| triangle.map(Vec3::from))
+        );
+
+        // the colors are read for the same vertices as the positions
+        let colors = mesh
+            .triangle_extras
+            .iter()
+            .map(|extras| extras.map(|vertex| vertex.color))
+            .collect::<Vec<_>>();
+        assert_eq!(colors, [[[255, 0, 0, 255]; 3], [[0, 0, 255, 255]; 3]]);
+    }
+
+    #[test]
+    fn applies_non_uniform_node_scale() {
+        let mut gltf = triangle_gltf();
+        gltf["nodes"][0]["scale"] = json::array![2.0, 1.0, 1.0];
+
+        let mesh = load_test_gltf("node_scale", &gltf);
+        let tree = crate::mesh_to_octree(&mesh, 30, crate::VoxelizationMode::Triangles).unwrap();
+
+        let nodes = tree.collect_nodes();
+        let min = nodes.iter().map(|(node, _)| node.coords).reduce(IVec3::min);
+        let max = nodes.iter().map(|(node, _)| node.coords).reduce(IVec3::max);
+        let size = max.unwrap() - min.unwrap() + 1;
+
+        // the triangle is twice as wide as it's high, give or take a voxel of rounding
+        assert!(
+            (size.x - 2 * size.y).abs() <= 1,
+            "expected a model twice as wide as it's high, got {size}"
+        );
+        assert_eq!(size.z, 1);
+    }
+
+    #[test]
+    fn saves_twice_into_the_same_folder() {
+        let vertices = TRIANGLE.map(|position| Vertex {
+            position: Vec3::from(position),
+            color: [255; 4],
+        });
+        let primitives = [Primitive {
+            range: 0..vertices.len(),
+            emissive: false,
+            color: None,
+            lines: false,
+        }];
+        let nodes = [Node {
+            name: None,
+            matrix: Mat4::IDENTITY,
+            primitives: 0..1,
+        }];
+
+        // the folders of the file don't exist before the first save, and do before the second
+        let path = fixtures::test_dir("gltf_twice").join("nested/folders/model.gltf");
+        let path = path.to_str().unwrap();
+
+        for _ in 0..2 {
+            let result = save_gltf(
+                &vertices,
+                ExtraAttributes::default(),
+                &primitives,
+                &nodes,
+                path,
+                Encoding::default(),
+            );
+
+            assert!(result.is_ok(), "failed to save `{path}`: {result:?}");
+        }
+    }
 }
@@ -0,0 +1,47 @@
+use std::io::Write;
+
+use crate::io::UpAxis;
+use crate::octree::octree_header;
+use crate::{MeshToVoxError, Octree, Result};
+use glam::IVec3;
+
+impl Octree {
+    /// Saves the voxels in the text format of Goxel, one `x y z rrggbb` line per voxel. Goxel
+    /// is Z-up like MagicaVoxel, so the coordinates are converted the same way as for `.vox`
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Io`] if the file can't be written
+    pub fn save_as_goxel(&self, file_path: &str, up: UpAxis) -> Result<()> {
+        let write = || -> std::io::Result<()> {
+            let file = std::fs::File::create(file_path)?;
+            let mut writer = std::io::BufWriter::new(file);
+
+            writeln!(writer, "# Goxel")?;
+            writeln!(writer, "# One line per voxel")?;
+            writeln!(writer, "# X Y Z RRGGBB")?;
+
+            for (node, color) in self.collect_nodes() {
+                let [r, g, b, _] = octree_header::to_color(color).0;
+
+                // nodes above the lowest level (i.e. the blocks of a refined tree) are
+                // written as a cube of voxels
+                let node_size = 1 << (self.depth - node.depth);
+
+                for offset in 0..node_size * node_size * node_size {
+                    let offset = IVec3::new(
+                        offset % node_size,
+                        (offset / node_size) % node_size,
+                        offset / (node_size * node_size),
+                    );
+
+                    let IVec3 { x, y, z } = up.to_z_up(node.coords + offset);
+                    writeln!(writer, "{x} {y} {z} {r:02x}{g:02x}{b:02x}")?;
+                }
+            }
+
+            writer.flush()
+        };
+
+        write().map_err(MeshToVoxError::io(file_path))
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::io::Vertex;
+use crate::space_filling::MeshNode;
+use glam::{IVec2, IVec3, Vec3};
+use rayon::prelude::*;
+
+/// A rectangle of merged faces, as the face of its first voxel and its size along the two
+/// axes besides the normal axis of the face
+pub type Quad = (MeshNode, image::Rgba<u8>, IVec3);
+
+/// The axes along which `MeshNode::to_vertices` spans a face of the axis `dim`, in the order
+/// of its two corners
+const fn face_axes(dim: usize) -> [usize; 2] {
+    match dim {
+        0 => [1, 2],
+        1 => [0, 2],
+        _ => [0, 1],
+    }
+}
+
+/// Merges the faces of the same color facing the same way in the same plane into rectangles,
+/// which cover exactly the same area
+///
+/// Every face is split into the faces of its voxels first (i.e. for the blocks of a refined
+/// tree), which are grown into rectangles row by row, as wide as possible and then as tall as
+/// possible
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "the depth of a tree is at most 30"
+)]
+#[must_use]
+pub fn greedy_quads(faces: &[(MeshNode, image::Rgba<u8>)], depth: u32) -> Vec<Quad> {
+    // the voxel faces of every plane, by the axis and the direction of their normal and the
+    // position of the plane along it
+    let mut planes = HashMap::<(u8, bool, i32), HashMap<IVec2, image::Rgba<u8>>>::new();
+
+    for (node, color) in faces {
+        let dim = node.dim as usize;
+        let [a, b] = face_axes(dim);
+        let node_size = 1 << (depth - u32::from(node.depth));
+
+        let plane = node.cords[dim] + if node.positive { node_size - 1 } else { 0 };
+        let cells = planes.entry((node.dim, node.positive, plane)).or_default();
+
+        for offset_a in 0..node_size {
+            for offset_b in 0..node_size {
+                let cell = IVec2::new(node.cords[a] + offset_a, node.cords[b] + offset_b);
+                cells.insert(cell, *color);
+            }
+        }
+    }
+
+    let mut planes = planes.into_iter().collect::<Vec<_>>();
+
+    // the order of the planes doesn't depend on the hashing, so the output is deterministic
+    planes.sort_unstable_by_key(|(key, _)| *key);
+
+    planes
+        .into_par_iter()
+        .flat_map_iter(|((dim, positive, plane), cells)| {
+            let [a, b] = face_axes(dim as usize);
+
+            let mut order = cells.keys().copied().collect::<Vec<_>>();
+            order.sort_unstable_by_key(|cell| (cell.y, cell.x));
+
+            let mut merged = HashSet::with_capacity(cells.len());
+            let mut quads = Vec::new();
+
+            for start in order {
+                if merged.contains(&start) {
+                    continue;
+                }
+
+                let color = cells[&start];
+                let free =
+                    |cell: IVec2| cells.get(&cell) == Some(&color) && !merged.contains(&cell);
+
+                let mut width = 1;
+                while free(start + IVec2::new(width, 0)) {
+                    width += 1;
+                }
+
+                let mut height = 1;
+                while (0..width).all(|x| free(start + IVec2::new(x, height))) {
+                    height += 1;
+                }
+
+                for x in 0..width {
+                    for y in 0..height {
+                        merged.insert(start + IVec2::new(x, y));
+                    }
+                }
+
+                let mut cords = IVec3::ZERO;
+                cords[dim as usize] = plane;
+                cords[a] = start.x;
+                cords[b] = start.y;
+
+                let mut size = IVec3::ZERO;
+                size[a] = width;
+                size[b] = height;
+
+                let node = MeshNode {
+                    cords,
+                    dim,
+                    positive,
+                    depth: depth as u8,
+                };
+
+                quads.push((node, color, size));
+            }
+
+            quads
+        })
+        .collect()
+}
+
+/// Returns the two triangles of every rectangle, wound like the faces of
+/// `Octree::faces_to_vertices`, with the positions mapped the same way
+#[allow(
+    clippy::cast_precision_loss,
+    reason = "the resolution is exact in `f32` up to `2^24`, like for the faces"
+)]
+#[must_use]
+pub fn quads_to_vertices(quads: &[Quad], max_size: u32) -> Vec<Vertex> {
+    let mapping = |x: IVec3, color: [u8; 4]| {
+        let position = (x + IVec3::NEG_ONE).as_vec3() / max_size as f32;
+        let position = position.mul_add(Vec3::splat(2.0), Vec3::NEG_ONE);
+        Vertex { position, color }
+    };
+
+    quads
+        .iter()
+        .flat_map(|(node, color, size)| {
+            let dim = node.dim as usize;
+            let [a, b] = face_axes(dim);
+
+            let mut base = node.cords;
+            if node.positive {
+                base[dim] += 1;
+            }
+
+            let mut corner1 = base;
+            corner1[a] += size[a];
+
+            let mut corner2 = base;
+            corner2[b] += size[b];
+
+            let opposite = corner1 + corner2 - base;
+
+            // the same winding as `MeshNode::to_vertices`
+            let vertices = if (node.dim != 1) == node.positive {
+                [base, corner1, opposite, base, opposite, corner2]
+            } else {
+                [base, opposite, corner1, base, corner2, opposite]
+            };
+
+            vertices.map(|corner| mapping(corner, color.0))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cube_sides_are_single_rectangles() {
+        const SIZE: u32 = 32;
+
+        let mesh = crate::fixtures::generate_cube_mesh();
+        let tree = crate::mesh_to_octree(&mesh, SIZE, crate::VoxelizationMode::Triangles).unwrap();
+        let quads = greedy_quads(&tree.sparse_faces(None).unwrap(), tree.depth);
+
+        assert_eq!(quads.len(), 6);
+
+        let vertices = quads_to_vertices(&quads, SIZE - 1);
+
+        // the triangles are wound counter-clockwise when seen from the side the face faces
+        for ((node, _, _), vertices) in quads.iter().zip(vertices.chunks_exact(6)) {
+            for triangle in vertices.chunks_exact(3) {
+                let [a, b, c] = [0, 1, 2].map(|index| triangle[index].position);
+                let facing = (b - a).cross(c - a).dot(node.normal());
+
+                assert!(
+                    facing > 0.0,
+                    "a triangle facing {} is wound backwards",
+                    node.normal()
+                );
+            }
+        }
+    }
+}
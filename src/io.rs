@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 
-use crate::octree::*;
-use crate::*;
+use crate::octree::{OctreePos, octree_header};
+use crate::{BoundingBox, MeshToVoxError, Octree, Result, gltf2};
 use bytemuck::Pod;
 use bytemuck::Zeroable;
+use glam::{BVec3, IVec2, IVec3, Mat3, Mat4, U8Vec3, Vec2, Vec3, Vec4};
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
@@ -21,7 +22,7 @@ pub struct FloatVertex {
 
 impl From<Vertex> for FloatVertex {
     fn from(value: Vertex) -> Self {
-        let color = value.color.map(|c| c as f32 / 255.0);
+        let color = value.color.map(|c| f32::from(c) / 255.0);
         Self {
             position: value.position,
             color,
@@ -29,34 +30,62 @@ impl From<Vertex> for FloatVertex {
     }
 }
 
+/// The normal and tangent of a vertex, used for normal mapped rendering
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+pub struct TangentSpace {
+    pub normal: Vec3,
+    pub tangent: [f32; 4],
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
 pub struct VertexExtras {
     normal: Vec3,
     uv: Vec2,
 
+    /// The sRGB color of the vertex (i.e. its `COLOR_0`), which multiplies the color of its
+    /// material. Vertices without a color are white, which leaves the material color as is
+    pub color: [u8; 4],
+
     pub material_idx: u32,
+
+    /// The index of the mesh of the source file that the vertex belongs to
+    pub mesh_idx: u32,
 }
 
 impl VertexExtras {
-    pub fn new(normal: Option<Vec3>, uv: Option<Vec2>, material_idx: u32) -> Self {
+    #[must_use]
+    pub fn new(normal: Option<Vec3>, uv: Option<Vec2>, material_idx: u32, mesh_idx: u32) -> Self {
         Self {
             normal: normal.unwrap_or(Vec3::NAN),
             uv: uv.unwrap_or(Vec2::NAN),
+            color: [u8::MAX; 4],
             material_idx,
+            mesh_idx,
+        }
+    }
+
+    /// Returns the same vertex with the normal replaced by `normal`
+    #[inline]
+    #[must_use]
+    pub fn with_normal(self, normal: Option<Vec3>) -> Self {
+        Self {
+            normal: normal.unwrap_or(Vec3::NAN),
+            ..self
         }
     }
 
     #[inline]
     #[must_use]
     pub fn normal(&self) -> Option<Vec3> {
-        (self.normal != Vec3::NAN).then_some(self.normal)
+        (!self.normal.is_nan()).then_some(self.normal)
     }
 
     #[inline]
     #[must_use]
     pub fn uv(&self) -> Option<Vec2> {
-        (self.uv != Vec2::NAN).then_some(self.uv)
+        (!self.uv.is_nan()).then_some(self.uv)
     }
 }
 
@@ -66,14 +95,551 @@ pub enum ImageOrColor {
     Color(image::Rgba<u8>),
 }
 
+/// How texture coordinates outside of the `0..1` range are mapped into a texture
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WrapMode {
+    #[default]
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+}
+
+impl WrapMode {
+    /// Maps a texture coordinate into the `0..=1` range
+    #[must_use]
+    pub fn apply(self, coord: f32) -> f32 {
+        match self {
+            Self::Repeat => coord.rem_euclid(1.0),
+            Self::MirroredRepeat => {
+                let coord = coord.rem_euclid(2.0);
+                if coord > 1.0 { 2.0 - coord } else { coord }
+            }
+            Self::ClampToEdge => coord.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Maps the index of a texel into a texture `size` texels wide
+    #[must_use]
+    pub const fn apply_texel(self, index: i32, size: u32) -> u32 {
+        let size = size.cast_signed();
+
+        let index = match self {
+            Self::Repeat => index.rem_euclid(size),
+            Self::MirroredRepeat => {
+                let index = index.rem_euclid(2 * size);
+                if index >= size {
+                    2 * size - 1 - index
+                } else {
+                    index
+                }
+            }
+            Self::ClampToEdge => {
+                if index < 0 {
+                    0
+                } else if index >= size {
+                    size - 1
+                } else {
+                    index
+                }
+            }
+        };
+
+        index.cast_unsigned()
+    }
+}
+
+/// How the texture of a material is mapped onto the triangles using it
+#[derive(Debug, Clone, Copy)]
+pub struct TextureMapping {
+    /// The texture coordinate set of the triangles that the texture is sampled with
+    pub tex_coord: u32,
+
+    /// The wrap modes of the horizontal and the vertical texture coordinate
+    pub wrap: [WrapMode; 2],
+
+    /// Transforms the texture coordinates of the triangles before the texture is sampled
+    /// with them (i.e. the `KHR_texture_transform` of the texture)
+    pub transform: Mat3,
+}
+
+impl TextureMapping {
+    /// Samples the first texture coordinate set as is, with the texture repeated
+    pub const DEFAULT: Self = Self {
+        tex_coord: 0,
+        wrap: [WrapMode::Repeat; 2],
+        transform: Mat3::IDENTITY,
+    };
+}
+
+/// The light that a material emits, which is added to its color
+#[derive(Debug, Clone)]
+pub struct Emission {
+    pub color: ImageOrColor,
+
+    /// How `color` is mapped onto the triangles if it's an image. It's sampled with the
+    /// texture coordinates of the material (see `Material::tex_coord`)
+    pub mapping: TextureMapping,
+}
+
+impl Emission {
+    /// Returns the emission of `image` (or of white, without one) scaled by `factor`, as the
+    /// emissive textures of the materials are
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        reason = "the factor is clamped to 0..=1, so the channels stay u8"
+    )]
+    #[must_use]
+    pub fn scaled(image: Option<(image::RgbaImage, TextureMapping)>, factor: Vec3) -> Self {
+        let factor = factor.clamp(Vec3::ZERO, Vec3::ONE);
+        let scale = |color: &mut image::Rgba<u8>| {
+            for (channel, factor) in color.0.iter_mut().zip(factor.to_array()) {
+                *channel = (f32::from(*channel) * factor).round() as u8;
+            }
+        };
+
+        image.map_or_else(
+            || {
+                let mut color = image::Rgba([255; 4]);
+                scale(&mut color);
+
+                Self {
+                    color: ImageOrColor::Color(color),
+                    mapping: TextureMapping::DEFAULT,
+                }
+            },
+            |(mut image, mapping)| {
+                if factor != Vec3::ONE {
+                    image.pixels_mut().for_each(scale);
+                }
+
+                Self {
+                    color: ImageOrColor::Image(image),
+                    mapping,
+                }
+            },
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Material {
+    pub name: Option<String>,
+    pub color: ImageOrColor,
+
+    /// How `color` is mapped onto the triangles if it's an image
+    pub mapping: TextureMapping,
+
+    /// Voxels created from this material are tagged as emissive (see
+    /// `octree_header::EMISSIVE_ALPHA`), which the exporters turn into emissive materials
+    pub emissive: bool,
+
+    /// The light emitted by the material, added to its color (clamped to white). Unlike
+    /// `emissive`, it doesn't tag the voxels
+    pub emission: Option<Emission>,
+
+    /// The texels (or the color) with a lower alpha don't produce voxels, which cuts out the
+    /// transparent parts of masked textures (i.e. of leaves and fences). `None` ignores the
+    /// alpha, as opaque materials do, and is the default of `Material::new`. The loaders only
+    /// set it for materials that are marked as transparent (i.e. by the alpha mode of glTF)
+    pub alpha_cutoff: Option<u8>,
+}
+
+/// The alpha cutoff of transparent materials that don't specify one, half of the full alpha
+pub const DEFAULT_ALPHA_CUTOFF: u8 = 128;
+
+impl Material {
+    #[must_use]
+    pub const fn new(color: ImageOrColor) -> Self {
+        Self {
+            name: None,
+            color,
+            mapping: TextureMapping::DEFAULT,
+            emissive: false,
+            emission: None,
+            alpha_cutoff: None,
+        }
+    }
+
+    /// Returns whether the color or the emission of the material is an image, which the
+    /// triangles need texture coordinates for
+    #[must_use]
+    pub const fn is_textured(&self) -> bool {
+        matches!(self.color, ImageOrColor::Image(_))
+            || matches!(
+                self.emission,
+                Some(Emission {
+                    color: ImageOrColor::Image(_),
+                    ..
+                })
+            )
+    }
+
+    /// Returns the texture coordinate set that the images of the material are sampled with,
+    /// which is the one of the color, unless only the emission is an image
+    #[must_use]
+    pub const fn tex_coord(&self) -> u32 {
+        match (&self.color, &self.emission) {
+            (
+                ImageOrColor::Color(_),
+                Some(Emission {
+                    color: ImageOrColor::Image(_),
+                    mapping,
+                }),
+            ) => mapping.tex_coord,
+            _ => self.mapping.tex_coord,
+        }
+    }
+}
+
+/// Options that change how the input file is interpreted
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoadOptions {
+    /// Color materials with an emissive texture by that texture and mark them as emissive,
+    /// which is how screens and displays are usually modelled
+    pub screens: bool,
+
+    /// Give the vertices without a normal the face normal of their triangle (see
+    /// `Mesh::compute_missing_normals`)
+    pub compute_normals: bool,
+}
+
+/// Features of the source file that were ignored while loading it, along with the
+/// number of times each one was encountered
+#[derive(Debug, Clone, Default)]
+pub struct UnsupportedFeatures {
+    pub occurrences: std::collections::BTreeMap<String, usize>,
+}
+
+impl UnsupportedFeatures {
+    pub fn report(&mut self, feature: impl Into<String>) {
+        *self.occurrences.entry(feature.into()).or_default() += 1;
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.occurrences.is_empty()
+    }
+
+    #[must_use]
+    pub fn summary(&self) -> String {
+        self.occurrences
+            .iter()
+            .map(|(feature, count)| format!("  {feature} (x{count})"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Mesh {
     pub triangles: Vec<[Vec3; 3]>,
     pub triangle_extras: Vec<[VertexExtras; 3]>,
-    pub materials: Vec<ImageOrColor>,
+    pub materials: Vec<Material>,
+
+    /// The names of the meshes of the source file, indexed by `VertexExtras::mesh_idx`
+    pub mesh_names: Vec<Option<String>>,
 
     pub bounds: BoundingBox,
     pub view: View,
+
+    pub unsupported: UnsupportedFeatures,
+}
+
+/// How a mesh is split into parts that are voxelized and saved separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SplitBy {
+    /// One part per material
+    Material,
+    /// One part per mesh of the source file
+    Mesh,
+}
+
+impl SplitBy {
+    /// The name of the key, used to name the files of the parts
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Material => "material",
+            Self::Mesh => "mesh",
+        }
+    }
+
+    const fn key(self, extras: &VertexExtras) -> u32 {
+        match self {
+            Self::Material => extras.material_idx,
+            Self::Mesh => extras.mesh_idx,
+        }
+    }
+}
+
+/// How the model is fitted into the voxel grid
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Fit {
+    /// The largest side of the bounding box of the model spans the grid
+    #[default]
+    Box,
+    /// The bounding sphere of the model spans the grid, and the model is centered in it
+    Sphere,
+    /// Every side of the bounding box of the model spans the grid, which scales the axes
+    /// independently and doesn't keep the proportions of the model
+    Stretch,
+}
+
+/// The axis pointing up in the source model
+///
+/// MagicaVoxel is Z-up, so Y-up models are turned a quarter turn around X when saved as
+/// `.vox`, and glTF is Y-up, so Z-up models are turned back the other way when saved as
+/// `.gltf`. Both outputs rotate (rather than mirror) the model the same way, so it keeps its
+/// orientation and handedness in either
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum UpAxis {
+    /// The convention of glTF
+    #[default]
+    Y,
+    /// The convention of MagicaVoxel
+    Z,
+}
+
+impl UpAxis {
+    /// Converts voxel coordinates from this convention to the Z-up one of MagicaVoxel. Y-up
+    /// coordinates are rotated by `(x, y, z) -> (x, -z, y)`, which makes them negative along Y
+    #[must_use]
+    pub const fn to_z_up(self, coords: IVec3) -> IVec3 {
+        match self {
+            Self::Y => IVec3::new(coords.x, -coords.z, coords.y),
+            Self::Z => coords,
+        }
+    }
+
+    /// Converts voxel coordinates from the Z-up convention of the `.vox` output back to this
+    /// one, undoing `to_z_up`
+    #[must_use]
+    pub const fn from_z_up(self, coords: IVec3) -> IVec3 {
+        match self {
+            Self::Y => IVec3::new(coords.x, coords.z, -coords.y),
+            Self::Z => coords,
+        }
+    }
+
+    /// Returns the matrix that converts positions from this convention to the Y-up one of
+    /// glTF, the rotation `(x, y, z) -> (x, z, -y)` for Z-up ones, which undoes `to_z_up`
+    #[must_use]
+    pub const fn to_y_up_matrix(self) -> Mat4 {
+        match self {
+            Self::Y => Mat4::IDENTITY,
+            Self::Z => Mat4::from_cols(Vec4::X, Vec4::NEG_Z, Vec4::Y, Vec4::W),
+        }
+    }
+}
+
+impl Mesh {
+    /// Returns the center and the radius of a sphere enclosing every vertex of the mesh,
+    /// computed with Ritter's algorithm. The sphere isn't the smallest possible one, but
+    /// is usually within a few percent of it
+    #[must_use]
+    pub fn bounding_sphere(&self) -> (Vec3, f32) {
+        let mut points = self.triangles.iter().flatten().copied();
+
+        let Some(first) = points.next() else {
+            return (Vec3::ZERO, 0.0);
+        };
+
+        let farthest_from = |from: Vec3| {
+            self.triangles
+                .iter()
+                .flatten()
+                .copied()
+                .max_by(|a, b| {
+                    a.distance_squared(from)
+                        .total_cmp(&b.distance_squared(from))
+                })
+                .unwrap_or(from)
+        };
+
+        let a = farthest_from(first);
+        let b = farthest_from(a);
+
+        let mut center = (a + b) * 0.5;
+        let mut radius = a.distance(b) * 0.5;
+
+        for point in self.triangles.iter().flatten() {
+            let distance = point.distance(center);
+
+            if distance > radius {
+                // grow the sphere just enough to touch the point, moving the center towards it
+                let new_radius = (radius + distance) * 0.5;
+                center += (*point - center) * ((new_radius - radius) / distance);
+                radius = new_radius;
+            }
+        }
+
+        (center, radius)
+    }
+
+    /// Returns the bounds that the voxel grid is fitted to
+    pub fn fit_bounds(&self, fit: Fit) -> BoundingBox {
+        match fit {
+            Fit::Box | Fit::Stretch => self.bounds,
+            Fit::Sphere => {
+                let (center, radius) = self.bounding_sphere();
+
+                BoundingBox {
+                    min: center - radius,
+                    max: center + radius,
+                }
+            }
+        }
+    }
+
+    /// Returns the name of the part `key` of the mesh split by `by`
+    #[must_use]
+    pub fn part_name(&self, by: SplitBy, key: u32) -> String {
+        let name = match by {
+            SplitBy::Material => self
+                .materials
+                .get(key as usize)
+                .and_then(|material| material.name.clone()),
+            SplitBy::Mesh => self.mesh_names.get(key as usize).cloned().flatten(),
+        };
+
+        name.unwrap_or_else(|| format!("{} {key}", by.name()))
+    }
+
+    /// Splits the mesh into parts keyed by the material or mesh index of the triangles. Every
+    /// part keeps the bounds of the whole mesh, so the parts line up when voxelized separately.
+    /// Materials that a part doesn't use are replaced with a plain color to avoid copying
+    /// their textures
+    #[must_use]
+    pub fn split(&self, by: SplitBy) -> Vec<(u32, Self)> {
+        let mut parts = std::collections::BTreeMap::<u32, Vec<usize>>::new();
+
+        for (tri, extras) in self.triangle_extras.iter().enumerate() {
+            parts.entry(by.key(&extras[0])).or_default().push(tri);
+        }
+
+        parts
+            .into_iter()
+            .map(|(key, triangles)| {
+                let used = triangles
+                    .iter()
+                    .map(|tri| self.triangle_extras[*tri][0].material_idx as usize)
+                    .collect::<std::collections::HashSet<_>>();
+
+                let materials = self
+                    .materials
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, material)| {
+                        // triangles with an invalid material index fall back to the first one
+                        if used.contains(&idx) || idx == 0 {
+                            material.clone()
+                        } else {
+                            Material::new(ImageOrColor::Color(image::Rgba([0, 0, 0, 0])))
+                        }
+                    })
+                    .collect();
+
+                let part = Self {
+                    triangles: triangles.iter().map(|tri| self.triangles[*tri]).collect(),
+                    triangle_extras: triangles
+                        .iter()
+                        .map(|tri| self.triangle_extras[*tri])
+                        .collect(),
+                    materials,
+                    mesh_names: self.mesh_names.clone(),
+                    bounds: self.bounds,
+                    view: self.view.clone(),
+                    unsupported: UnsupportedFeatures::default(),
+                };
+
+                (key, part)
+            })
+            .collect()
+    }
+
+    /// Gives every vertex without a normal the normal of the face of its triangle, following
+    /// its winding. Degenerate triangles have no face normal, so their vertices are left
+    /// without one. Returns the number of vertices that got a normal
+    pub fn compute_missing_normals(&mut self) -> usize {
+        let mut computed = 0;
+
+        for (triangle, extras) in self.triangles.iter().zip(&mut self.triangle_extras) {
+            if extras.iter().all(|extra| extra.normal().is_some()) {
+                continue;
+            }
+
+            let normal = crate::math::get_normal(*triangle);
+
+            if !normal.is_finite() {
+                continue;
+            }
+
+            for extra in extras.iter_mut().filter(|extra| extra.normal().is_none()) {
+                *extra = extra.with_normal(Some(normal));
+                computed += 1;
+            }
+        }
+
+        computed
+    }
+
+    /// Keeps only the largest group of triangles connected through shared vertices (by the
+    /// number of triangles), and shrinks the bounds to it. Returns the number of removed
+    /// groups and triangles
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "triangle indices fit a u32"
+    )]
+    pub fn keep_largest_component(&mut self) -> (usize, usize) {
+        let mut components = crate::denoise::DisjointSet::new(vec![1; self.triangles.len()]);
+        let mut first_use = HashMap::<[u32; 3], u32>::new();
+
+        for (index, triangle) in self.triangles.iter().enumerate() {
+            for vertex in triangle {
+                // adding zero turns `-0.0` into `0.0`, so both are the same vertex
+                let key = (*vertex + 0.0).to_array().map(f32::to_bits);
+                let first = *first_use.entry(key).or_insert(index as u32);
+
+                components.union(first, index as u32);
+            }
+        }
+
+        let roots = (0..self.triangles.len() as u32)
+            .map(|index| components.find(index))
+            .collect::<Vec<_>>();
+
+        let Some(largest) = roots
+            .iter()
+            .copied()
+            .max_by_key(|&root| components.sizes[root as usize])
+        else {
+            return (0, 0);
+        };
+
+        let removed_components = roots
+            .iter()
+            .enumerate()
+            .filter(|&(index, &root)| index as u32 == root && root != largest)
+            .count();
+
+        let removed_triangles = self.triangles.len() - components.sizes[largest as usize] as usize;
+
+        let (triangles, triangle_extras) = self
+            .triangles
+            .iter()
+            .zip(&self.triangle_extras)
+            .zip(&roots)
+            .filter(|&(_, &root)| root == largest)
+            .map(|(triangle, _)| triangle)
+            .unzip();
+
+        self.triangles = triangles;
+        self.triangle_extras = triangle_extras;
+        self.bounds = BoundingBox::from_points(self.triangles.iter().flatten().copied());
+
+        (removed_components, removed_triangles)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -94,6 +660,7 @@ pub struct OrthographicCamera {
 }
 
 impl PerspectiveCamera {
+    #[must_use]
     pub fn new(value: &gltf::camera::Perspective<'_>) -> Self {
         Self {
             yfov: value.yfov(),
@@ -103,6 +670,7 @@ impl PerspectiveCamera {
         }
     }
 
+    #[must_use]
     pub fn to_json(&self) -> json::JsonValue {
         json::object! {
             "type" : "perspective",
@@ -116,6 +684,7 @@ impl PerspectiveCamera {
     }
 }
 impl OrthographicCamera {
+    #[must_use]
     pub fn new(value: &gltf::camera::Orthographic<'_>) -> Self {
         Self {
             xmag: value.xmag(),
@@ -125,6 +694,7 @@ impl OrthographicCamera {
         }
     }
 
+    #[must_use]
     pub fn to_json(&self) -> json::JsonValue {
         json::object! {
             "type": "orthographic",
@@ -145,16 +715,18 @@ pub enum Camera {
 }
 
 impl Camera {
+    #[must_use]
     pub fn new(cam: &gltf::camera::Projection<'_>) -> Self {
         match cam {
             gltf::camera::Projection::Orthographic(ort) => {
-                Camera::OrthographiCamera(OrthographicCamera::new(ort))
+                Self::OrthographiCamera(OrthographicCamera::new(ort))
             }
             gltf::camera::Projection::Perspective(per) => {
-                Camera::PerspectiveCamera(PerspectiveCamera::new(per))
+                Self::PerspectiveCamera(PerspectiveCamera::new(per))
             }
         }
     }
+    #[must_use]
     pub fn to_json(&self) -> json::JsonValue {
         match self {
             Self::PerspectiveCamera(ort) => ort.to_json(),
@@ -169,223 +741,1259 @@ pub struct View {
     pub model_view_projection: Mat4,
 }
 
+#[must_use]
 pub fn mpv_to_json(mvp: &Mat4) -> json::JsonValue {
-    let mut output: Vec<json::JsonValue> = Vec::with_capacity(16);
+    let output = mvp.to_cols_array().into_iter().map(Into::into).collect();
 
-    let mvp = mvp.to_cols_array();
+    json::JsonValue::Array(output)
+}
 
-    for i in 0..16 {
-        output.push(mvp[i].into());
-    }
+/// Options of the `.vox` output
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VoxOptions {
+    /// The axis pointing up in the source of the voxels
+    pub up: UpAxis,
 
-    json::JsonValue::Array(output)
+    /// Add a floor grid under the model with a line every this many voxels, as a separate
+    /// `grid` layer, which gives a sense of scale in MagicaVoxel
+    pub grid: Option<u32>,
+}
+
+/// Options of the `.gltf` output
+#[derive(Debug, Clone, Copy)]
+#[allow(
+    clippy::struct_excessive_bools,
+    reason = "every flag toggles an independent feature of the output"
+)]
+pub struct GltfOptions<'a> {
+    /// Only write the faces that border the outside of the model
+    pub sparse: bool,
+
+    /// Write the colors as floats instead of normalized bytes
+    pub float: bool,
+
+    /// Write `NORMAL` and `TANGENT` attributes
+    pub tangents: bool,
+
+    /// Where the buffer is stored, which also decides between a `.gltf` and a `.glb` file
+    pub buffer: gltf2::GltfBuffer,
+
+    /// Darken the colors of vertices in corners occluded by neighboring voxels
+    pub bake_ao: bool,
+
+    /// Write the number of voxels occluding the corner of every vertex as the custom `_AO`
+    /// attribute, leaving the colors untouched
+    pub ao_attribute: bool,
+
+    /// The normals of the source triangles recorded during voxelization. Faces facing against
+    /// the source surface are flipped, so that they keep its winding
+    pub winding: Option<&'a crate::voxelizer::VoxelNormals>,
+
+    /// Write a primitive with its own material for every color instead of the `COLOR_0`
+    /// attribute, for importers that assign materials per color
+    pub primitive_per_color: bool,
+
+    /// Merge the neighboring faces of the same color in the same plane into rectangles, which
+    /// takes far fewer triangles for flat surfaces. Can't be used with `bake_ao`,
+    /// `ao_attribute` or `winding`, which vary across the faces of a rectangle
+    pub greedy: bool,
+
+    /// The color space that the colors are written in
+    pub color_space: ColorSpace,
+
+    /// The axis pointing up in the source of the voxels, which is turned into the Y-up
+    /// convention of glTF before converting it to `coordinate_system`
+    pub up: UpAxis,
+
+    /// The coordinate system that the model is written in
+    pub coordinate_system: CoordinateSystem,
+
+    /// The bounds of the source mesh in the space of the grid (see
+    /// `voxelizer::grid_bounds`), written as a wireframe box for checking the alignment
+    pub bounds: Option<BoundingBox>,
+
+    /// A flag checked while meshing, which cancels the export once it's set
+    pub cancel: Option<&'a std::sync::atomic::AtomicBool>,
+}
+
+/// The color space of the colors of the `.gltf` output. The colors of the voxels are sRGB
+/// values, like the textures they're sampled from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorSpace {
+    /// Convert the colors to linear values, which the glTF specification requires for vertex
+    /// colors and material factors, so that compliant viewers show the colors of the source
+    #[default]
+    Linear,
+    /// Write the sRGB colors as they are, for viewers that don't follow the specification
+    Srgb,
 }
 
-mod magica {
-    pub const fn encode(color: image::Rgba<u8>) -> u8 {
-        let color = color.0;
-        (color[0] >> 5) | ((color[1] >> 5) << 3) | ((color[2] >> 6) << 6)
+impl ColorSpace {
+    /// Converts a channel of an sRGB color, in the `0..=1` range, into this color space
+    #[must_use]
+    pub fn encode(self, channel: f32) -> f32 {
+        match self {
+            Self::Linear if channel <= 0.040_45 => channel / 12.92,
+            Self::Linear => ((channel + 0.055) / 1.055).powf(2.4),
+            Self::Srgb => channel,
+        }
     }
 
-    pub const fn decode(byte: u8) -> image::Rgba<u8> {
-        let mask3 = (1 << 3) - 1;
-        let mask2 = (1 << 2) - 1;
+    /// Converts a channel of a color of this color space, in the `0..=1` range, into sRGB
+    #[must_use]
+    pub fn decode(self, channel: f32) -> f32 {
+        match self {
+            Self::Linear if channel <= 0.003_130_8 => channel * 12.92,
+            Self::Linear => channel.powf(1.0 / 2.4).mul_add(1.055, -0.055),
+            Self::Srgb => channel,
+        }
+    }
 
-        let r = (byte & mask3) << 5;
-        let g = ((byte >> 3) & mask3) << 5;
-        let b = ((byte >> 6) & mask2) << 6;
+    /// Converts the color channels (but not the alpha) of an sRGB color into this color space
+    #[must_use]
+    pub fn encode_color(self, color: [f32; 4]) -> [f32; 4] {
+        let [r, g, b, a] = color;
+        [self.encode(r), self.encode(g), self.encode(b), a]
+    }
+}
+
+/// The coordinate system of the engine that the `.gltf` output is made for
+///
+/// glTF is Y-up and right-handed, with the front of the model facing +Z. The conversion is
+/// written as the matrix of the node of the mesh, so the positions, the normals and the
+/// winding of the faces follow it in any viewer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CoordinateSystem {
+    /// Keep the coordinate system of glTF
+    #[default]
+    Gltf,
+    /// Y-up and left-handed, with the X axis mirrored
+    Unity,
+    /// Z-up and left-handed, with the front of the model facing +X
+    Unreal,
+    /// Y-up and right-handed like glTF, but with the front of the model facing -Z
+    Godot,
+}
 
-        image::Rgba([r, g, b, 255])
+impl CoordinateSystem {
+    /// Returns the matrix that converts glTF coordinates into this coordinate system
+    #[must_use]
+    pub const fn matrix(self) -> Mat4 {
+        match self {
+            Self::Gltf => Mat4::IDENTITY,
+            Self::Unity => Mat4::from_diagonal(Vec4::new(-1.0, 1.0, 1.0, 1.0)),
+            // the left side of the model (+X) goes to -Y, up (+Y) to +Z, and the front (+Z)
+            // to +X
+            Self::Unreal => Mat4::from_cols(
+                Vec4::new(0.0, -1.0, 0.0, 0.0),
+                Vec4::new(0.0, 0.0, 1.0, 0.0),
+                Vec4::new(1.0, 0.0, 0.0, 0.0),
+                Vec4::W,
+            ),
+            Self::Godot => Mat4::from_diagonal(Vec4::new(-1.0, 1.0, -1.0, 1.0)),
+        }
     }
+}
 
-    #[cfg(test)]
-    pub const fn _gather() {
-        let mut counter = 0;
-        loop {
-            if encode(decode(counter)) != counter {
-                panic!()
-            }
-            if counter == u8::MAX {
-                break;
-            }
-            counter += 1;
+/// How far the source normal of a voxel has to point against a face to flip it, which keeps
+/// faces perpendicular to the source surface from flipping due to rounding errors
+const WINDING_EPSILON: f32 = 1e-4;
+
+/// Reverses the winding of both triangles of a face, given the six values of its vertices
+const fn flip_face<T>(vertices: &mut [T]) {
+    vertices.swap(1, 2);
+    vertices.swap(4, 5);
+}
+
+/// The color of the wireframe box of `GltfOptions::bounds`
+const BOUNDS_COLOR: image::Rgba<u8> = image::Rgba([255, 0, 255, 255]);
+
+/// Returns the vertices of the twelve edges of a box in the space of the grid, mapped into
+/// the space of the `.gltf` output like the faces of the voxels (see `faces_to_vertices`)
+#[allow(
+    clippy::cast_precision_loss,
+    reason = "grid sizes are far below the f32 mantissa"
+)]
+fn bounds_lines(bounds: &BoundingBox, max_size: u32) -> Vec<Vertex> {
+    let corner = |index: usize| {
+        let position = Vec3::select(
+            BVec3::new(index & 1 != 0, index & 2 != 0, index & 4 != 0),
+            bounds.max,
+            bounds.min,
+        );
+
+        let position = (position - 1.0) / max_size as f32;
+        Vertex {
+            position: position.mul_add(Vec3::splat(2.0), Vec3::NEG_ONE),
+            color: BOUNDS_COLOR.0,
         }
+    };
+
+    // the corners that differ in one of the axes, i.e. the edges of the box
+    (0..8)
+        .flat_map(|a| [1, 2, 4].map(|axis| (a, a | axis)))
+        .filter(|(a, b)| a != b)
+        .flat_map(|(a, b)| [corner(a), corner(b)])
+        .collect()
+}
+
+/// Splits sorted faces into the primitives of the `.gltf` output, one for the regular and one
+/// for the emissive faces, or one for every color if `per_color` is set
+fn face_primitives(
+    faces: &[(crate::space_filling::MeshNode, image::Rgba<u8>)],
+    per_color: bool,
+) -> Vec<gltf2::Primitive> {
+    let mut primitives = Vec::<gltf2::Primitive>::new();
+
+    // every face is made out of six vertices
+    for (face, (_, color)) in faces.iter().enumerate() {
+        let emissive = octree_header::is_emissive(*color);
+        let color = per_color.then_some(*color);
+
+        match primitives.last_mut() {
+            Some(last) if last.emissive == emissive && last.color == color => last.range.end += 6,
+            _ => primitives.push(gltf2::Primitive {
+                range: face * 6..face * 6 + 6,
+                emissive,
+                color,
+                lines: false,
+            }),
+        }
+    }
+
+    // the mesh needs at least one primitive, even without faces
+    if primitives.is_empty() {
+        primitives.push(gltf2::Primitive {
+            range: 0..0,
+            emissive: false,
+            color: None,
+            lines: false,
+        });
     }
 
-    #[cfg(test)]
-    pub const _: () = _gather();
+    primitives
 }
 
-impl Octree {
-    pub fn save_as_magica_voxel(&self, file_path: &str) -> Result<()> {
-        use dot_vox::*;
+/// The color of the floor grid of `VoxOptions::grid`
+const VOX_GRID_COLOR: image::Rgba<u8> = image::Rgba([255, 0, 255, 255]);
+
+/// MagicaVoxel models are at most 256 voxels wide, so the `.vox` output is split into chunks
+/// of this size, every one saved as its own model
+const VOX_CHUNK_SIZE: i32 = 256;
+
+/// Returns the chunk of the `.vox` output that a voxel is saved in, and the position of the
+/// voxel in the model of the chunk. Rounding towards negative infinity keeps voxels with
+/// negative coordinates (i.e. of the grid under the model) in the right chunk
+fn vox_chunk(coords: IVec3) -> (IVec3, U8Vec3) {
+    let size = IVec3::splat(VOX_CHUNK_SIZE);
+
+    (coords.div_euclid(size), coords.rem_euclid(size).as_u8vec3())
+}
+
+/// The palette of a `.vox` file, which is shared by all of its layers
+struct VoxPalette {
+    /// All 256 colors of the palette, the unused ones are black
+    colors: Vec<dot_vox::Color>,
 
-        const CHUNK_SIZE: i32 = 256;
+    /// The emissive materials of the entries with emissive colors
+    materials: Vec<dot_vox::Material>,
+
+    /// The entry of every color of the model
+    indices: HashMap<[u8; 4], u8>,
+
+    /// The entry of the floor grid of `VoxOptions::grid`, after the colors of the model
+    grid_index: u8,
+}
+
+impl VoxPalette {
+    /// Builds the palette of the colors of every layer. It holds the colors of the model as
+    /// they are if there are few enough of them, and the colors of median cut quantization
+    /// otherwise. Emissive colors get their own entries, and the grid takes the last entry, so
+    /// that it can't share one with the model
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "the palette has at most 256 entries, so every index fits into a `u8`"
+    )]
+    fn new(layers: &[(Option<&str>, &Octree)], grid: bool) -> Self {
+        use rayon::prelude::*;
+
+        // magicavoxel reserves the color index `0` for empty space, so only 255 of the 256
+        // colors of the palette can be used by voxels
+        const PALETTE_SIZE: usize = 255;
+
+        let mut colors = HashMap::<[u8; 4], u32>::new();
+        for (_, tree) in layers {
+            for (color, count) in crate::palette::count_colors(tree) {
+                *colors.entry(color).or_default() += count;
+            }
+        }
 
-        let nodes = self.collect_nodes();
+        let mut entries = crate::palette::quantize(&colors, PALETTE_SIZE - usize::from(grid));
 
-        let mut chunks = HashMap::<IVec3, Vec<dot_vox::Voxel>>::new();
+        // the colors are matched before the grid is added, so that none of them uses its entry
+        let indices = colors
+            .par_iter()
+            .map(|(color, _)| {
+                let index = crate::palette::nearest(&entries, image::Rgba(*color));
+                (*color, index as u8)
+            })
+            .collect();
 
-        // the palette starts at index 1 and ends later because magicavoxel only allows for 254
-        // indices and reserves the first index for a black color. we can therefore skip the black
-        // color
-        let mut palette = Vec::with_capacity(256);
+        let grid_index = entries.len() as u8;
 
-        for index in 1..=255 {
-            let color = magica::decode(index);
-            palette.push(dot_vox::Color {
+        if grid {
+            entries.push(VOX_GRID_COLOR);
+        }
+
+        let materials = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, color)| octree_header::is_emissive(**color))
+            .map(|(index, _)| dot_vox::Material {
+                id: index as u32 + 1,
+                properties: [
+                    ("_type".to_string(), "_emit".to_string()),
+                    ("_emit".to_string(), "1".to_string()),
+                ]
+                .into(),
+            })
+            .collect();
+
+        let colors = entries
+            .iter()
+            .map(|color| dot_vox::Color {
                 r: color.0[0],
                 g: color.0[1],
                 b: color.0[2],
                 a: 255,
-            });
+            })
+            .chain(std::iter::repeat(dot_vox::Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 255,
+            }))
+            .take(PALETTE_SIZE + 1)
+            .collect();
+
+        Self {
+            colors,
+            materials,
+            indices,
+            grid_index,
         }
+    }
+}
+
+/// Returns the voxels of the floor grid of `VoxOptions::grid` under the model spanning `min`
+/// to `max`, by the chunk of the `.vox` output. The grid is one voxel below the model, and
+/// spans its footprint rounded out to the lines of the grid, which line up with the voxel
+/// coordinates
+fn vox_grid(
+    min: IVec3,
+    max: IVec3,
+    spacing: u32,
+    grid_index: u8,
+) -> HashMap<IVec3, Vec<dot_vox::Voxel>> {
+    let spacing = i32::try_from(spacing).unwrap_or(i32::MAX);
+    let mut chunks = HashMap::<IVec3, Vec<dot_vox::Voxel>>::new();
 
-        for (coords, color) in nodes {
-            let color = octree_header::to_color(color);
-            let color_idx = magica::encode(color);
+    let lines = IVec2::splat(spacing);
+    let start = min.truncate().div_euclid(lines) * spacing;
+    let end = (max.truncate().div_euclid(lines) + 1) * spacing;
+    let z = min.z - 1;
+
+    for x in start.x..=end.x {
+        for y in start.y..=end.y {
+            if x % spacing != 0 && y % spacing != 0 {
+                continue;
+            }
 
-            let chunk = coords.coords / CHUNK_SIZE;
-            let local_coords = (coords.coords % CHUNK_SIZE).as_u8vec3();
+            let coords = IVec3::new(x, y, z);
+            let (chunk, local_coords) = vox_chunk(coords);
 
             chunks.entry(chunk).or_default().push(dot_vox::Voxel {
                 x: local_coords.x,
-                y: local_coords.z,
-                z: local_coords.y,
-                // as said previously, the palette starts at index 1, and dot_vox
-                // will offset this index by adding one to it. we want black indices
-                // to be `0` after this operation, so they have to be `255` before
-                // this operation, we can perform a wrapping subtraction to achieve that
-                i: color_idx.wrapping_sub(1),
+                y: local_coords.y,
+                z: local_coords.z,
+                i: grid_index,
             });
         }
+    }
+
+    chunks
+}
+
+/// Adds the voxels of one chunk of a `.vox` file as a model, with a shape and a transform
+/// moving it to the chunk, which goes into the group of the scene (the node `1`)
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "a .vox file has far fewer than u32::MAX models and nodes"
+)]
+fn push_vox_model(
+    models: &mut Vec<dot_vox::Model>,
+    nodes: &mut Vec<dot_vox::SceneNode>,
+    chunk: IVec3,
+    voxels: Vec<dot_vox::Voxel>,
+    name: Option<&str>,
+    layer_id: usize,
+) {
+    use dot_vox::{Dict, Frame, Model, SceneNode, ShapeModel, Size};
+
+    let model_id = models.len() as u32;
+
+    models.push(Model {
+        size: Size {
+            x: VOX_CHUNK_SIZE as u32,
+            y: VOX_CHUNK_SIZE as u32,
+            z: VOX_CHUNK_SIZE as u32,
+        },
+        voxels,
+    });
+
+    let transform_index = nodes.len() as u32;
+    let shape_index = transform_index + 1;
+
+    nodes.push(SceneNode::Transform {
+        attributes: name
+            .map(|name| [("_name".to_string(), name.to_string())].into())
+            .unwrap_or_default(),
+        frames: vec![Frame {
+            attributes: [(
+                "_t".to_string(),
+                format!(
+                    "{} {} {}",
+                    chunk.x * VOX_CHUNK_SIZE,
+                    chunk.y * VOX_CHUNK_SIZE,
+                    chunk.z * VOX_CHUNK_SIZE
+                ),
+            )]
+            .into(),
+        }],
+        child: shape_index,
+        layer_id: layer_id as u32,
+    });
+
+    nodes.push(SceneNode::Shape {
+        attributes: Dict::default(),
+        models: vec![ShapeModel {
+            model_id,
+            attributes: Dict::default(),
+        }],
+    });
+
+    let SceneNode::Group { children, .. } = &mut nodes[1] else {
+        unreachable!()
+    };
+
+    children.push(transform_index);
+}
+
+/// The meshed voxels of a model of the `.gltf` output
+struct GltfMesh {
+    vertices: Vec<Vertex>,
+    tangent_space: Option<Vec<TangentSpace>>,
+    occlusion: Option<Vec<f32>>,
+    primitives: Vec<gltf2::Primitive>,
+}
+
+/// The brightness of a vertex with 0, 1, 2 and 3 occluding voxels around its corner
+const AO_BRIGHTNESS: [f32; 4] = [1.0, 0.8, 0.65, 0.5];
+
+impl Octree {
+    fn is_filled(&self, position: IVec3) -> bool {
+        let max_size = 1 << (self.depth + 1);
+
+        if position.min_element() < 0 || position.max_element() >= max_size {
+            return false;
+        }
+
+        self.contains_point(&OctreePos {
+            coords: position,
+            depth: self.depth,
+        })
+    }
+
+    /// Returns the number of occluding voxels around a corner of a face, which are the voxels
+    /// in front of the face that touch the corner, but not the face itself
+    fn corner_occlusion(&self, node: &crate::space_filling::MeshNode, corner: IVec3) -> usize {
+        let dim = node.dim as usize;
+        let [u, v] = [(dim + 1) % 3, (dim + 2) % 3];
+
+        let mut front = corner;
+        front[dim] = if node.positive {
+            corner[dim]
+        } else {
+            corner[dim] - 1
+        };
+
+        // the coordinates of the voxels next to the corner that are within the face, and
+        // the ones that are outside of it
+        let inside = |axis: usize| {
+            if corner[axis] == node.cords[axis] {
+                corner[axis]
+            } else {
+                corner[axis] - 1
+            }
+        };
+        let outside = |axis: usize| {
+            if corner[axis] == node.cords[axis] {
+                corner[axis] - 1
+            } else {
+                corner[axis]
+            }
+        };
+
+        let voxel = |a: i32, b: i32| {
+            let mut position = front;
+            position[u] = a;
+            position[v] = b;
+            self.is_filled(position)
+        };
+
+        let side1 = voxel(outside(u), inside(v));
+        let side2 = voxel(inside(u), outside(v));
+        let diagonal = voxel(outside(u), outside(v));
+
+        // a corner between two occluding voxels is fully occluded, regardless of the third one
+        if side1 && side2 {
+            3
+        } else {
+            usize::from(side1) + usize::from(side2) + usize::from(diagonal)
+        }
+    }
+
+    /// Darkens the colors of the vertices of every face by the number of voxels that occlude
+    /// their corner. `vertices` has to contain the six vertices of every face
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        reason = "the depth fits a u8, the scaled channels stay in 0..=255"
+    )]
+    fn bake_ambient_occlusion(
+        &self,
+        faces: &[(crate::space_filling::MeshNode, image::Rgba<u8>)],
+        vertices: &mut [Vertex],
+    ) {
+        use rayon::prelude::*;
+
+        vertices
+            .par_chunks_mut(6)
+            .zip(faces)
+            .for_each(|(vertices, (node, _))| {
+                let corners = node.to_vertices(self.depth as u8);
+
+                for (vertex, corner) in vertices.iter_mut().zip(corners) {
+                    let brightness = AO_BRIGHTNESS[self.corner_occlusion(node, corner)];
+
+                    for channel in &mut vertex.color[0..3] {
+                        *channel = (f32::from(*channel) * brightness).round() as u8;
+                    }
+                }
+            });
+    }
+
+    /// Returns the number of voxels occluding the corner of every vertex of the faces
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        reason = "the depth fits a u8, the occlusion is at most 3"
+    )]
+    fn vertex_occlusion(
+        &self,
+        faces: &[(crate::space_filling::MeshNode, image::Rgba<u8>)],
+    ) -> Vec<f32> {
+        use rayon::prelude::*;
+
+        faces
+            .par_iter()
+            .flat_map_iter(|(node, _)| {
+                node.to_vertices(self.depth as u8)
+                    .map(|corner| self.corner_occlusion(node, corner) as f32)
+            })
+            .collect()
+    }
+
+    /// Saves the voxels into a `.vox` file
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Io`] if the file can't be written
+    pub fn save_as_magica_voxel(&self, file_path: &str, options: &VoxOptions) -> Result<()> {
+        Self::save_layers_as_magica_voxel(&[(None, self)], file_path, options)
+    }
+
+    /// Saves multiple models sharing the same grid into one `.vox` file, every model in its
+    /// own layer. Named layers are shown with their names in MagicaVoxel
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Io`] if the file can't be written
+    pub fn save_layers_as_magica_voxel(
+        layers: &[(Option<&str>, &Self)],
+        file_path: &str,
+        options: &VoxOptions,
+    ) -> Result<()> {
+        use dot_vox::{Dict, Frame, Layer, SceneNode};
+
+        let palette = VoxPalette::new(layers, options.grid.is_some());
 
         let mut models = Vec::new();
         let mut nodes = Vec::new();
 
         nodes.push(SceneNode::Transform {
-            attributes: Default::default(),
+            attributes: Dict::default(),
             frames: vec![Frame {
-                attributes: Default::default(),
+                attributes: Dict::default(),
             }],
             child: 1,
             layer_id: 0,
         });
 
         nodes.push(SceneNode::Group {
-            attributes: Default::default(),
+            attributes: Dict::default(),
             children: Vec::new(),
         });
 
-        for (chunk, voxels) in chunks {
-            let model_id = models.len() as u32;
+        let up = options.up;
+        let mut min = IVec3::MAX;
+        let mut max = IVec3::MIN;
 
-            models.push(Model {
-                size: Size {
-                    x: CHUNK_SIZE as u32,
-                    y: CHUNK_SIZE as u32,
-                    z: CHUNK_SIZE as u32,
-                },
-                voxels,
-            });
+        for (layer_id, (name, tree)) in layers.iter().enumerate() {
+            let mut chunks = HashMap::<IVec3, Vec<dot_vox::Voxel>>::new();
 
-            let transform_index = nodes.len() as u32;
-            let shape_index = transform_index + 1;
-
-            nodes.push(SceneNode::Transform {
-                attributes: Default::default(),
-                frames: vec![Frame {
-                    attributes: [(
-                        "_t".to_string(),
-                        format!(
-                            "{} {} {}",
-                            chunk.x * CHUNK_SIZE,
-                            chunk.z * CHUNK_SIZE,
-                            chunk.y * CHUNK_SIZE
-                        ),
-                    )]
-                    .into(),
-                }],
-                child: shape_index,
-                layer_id: 0,
-            });
+            for (node, color) in tree.collect_nodes() {
+                let color = octree_header::to_color(color);
+                let color_idx = palette.indices[&color.0];
 
-            nodes.push(SceneNode::Shape {
-                attributes: Default::default(),
-                models: vec![ShapeModel {
-                    model_id,
-                    attributes: Default::default(),
-                }],
-            });
+                // nodes above the lowest level (i.e. the blocks of a refined tree) are
+                // written as a cube of voxels
+                let node_size = 1 << (tree.depth - node.depth);
 
-            let SceneNode::Group { children, .. } = &mut nodes[1] else {
-                unreachable!()
-            };
+                for offset in 0..node_size * node_size * node_size {
+                    let offset = IVec3::new(
+                        offset % node_size,
+                        (offset / node_size) % node_size,
+                        offset / (node_size * node_size),
+                    );
+
+                    let coords = up.to_z_up(node.coords + offset);
+                    let (chunk, local_coords) = vox_chunk(coords);
+
+                    min = min.min(coords);
+                    max = max.max(coords);
 
-            children.push(transform_index);
+                    chunks.entry(chunk).or_default().push(dot_vox::Voxel {
+                        x: local_coords.x,
+                        y: local_coords.y,
+                        z: local_coords.z,
+                        // dot_vox offsets this index by one, which skips the reserved index `0`
+                        i: color_idx,
+                    });
+                }
+            }
+
+            for (chunk, mut voxels) in chunks {
+                // magicavoxel misbehaves with multiple voxels at the same position, which a
+                // tree can't hold, but overlapping nodes could. The first one is kept, like the
+                // first triangle of `ConflictPolicy::First` (the sort is stable)
+                voxels.sort_by_key(|voxel| (voxel.z, voxel.y, voxel.x));
+                voxels.dedup_by_key(|voxel| (voxel.x, voxel.y, voxel.z));
+
+                push_vox_model(&mut models, &mut nodes, chunk, voxels, *name, layer_id);
+            }
+        }
+
+        let has_grid = options.grid.is_some() && min.cmple(max).all();
+
+        if let (Some(spacing), true) = (options.grid, has_grid) {
+            let chunks = vox_grid(min, max, spacing, palette.grid_index);
+
+            for (chunk, voxels) in chunks {
+                push_vox_model(
+                    &mut models,
+                    &mut nodes,
+                    chunk,
+                    voxels,
+                    Some("grid"),
+                    layers.len(),
+                );
+            }
         }
 
+        // files without named layers don't need any layers
+        let layers = if has_grid || layers.iter().any(|(name, _)| name.is_some()) {
+            let names = layers.iter().map(|(name, _)| *name);
+            let grid = has_grid.then_some(Some("grid"));
+
+            names
+                .chain(grid)
+                .map(|name| Layer {
+                    attributes: name
+                        .map(|name| [("_name".to_string(), name.to_string())].into())
+                        .unwrap_or_default(),
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
         // Construct the scene
         let data = dot_vox::DotVoxData {
             version: 150,
+            index_map: Vec::new(),
             models,
-            palette,
-            materials: Vec::new(),
-            layers: Vec::new(),
+            palette: palette.colors,
+            materials: palette.materials,
+            layers,
             scenes: nodes,
         };
 
         // Write the file
-        let mut file = std::fs::File::create(file_path)?;
+        let mut file = std::fs::File::create(file_path).map_err(MeshToVoxError::io(file_path))?;
 
-        data.write_vox(&mut file)?;
+        data.write_vox(&mut file)
+            .map_err(MeshToVoxError::io(file_path))?;
 
         Ok(())
     }
 
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Io`] if the file or its buffer can't be written
     pub fn save_as_gltf(
         &self,
         gltf_path: &str,
         view: View,
-        sparse: bool,
         size: u32,
-        float: bool,
+        options: &GltfOptions,
+    ) -> Result<()> {
+        Self::save_nodes_as_gltf(&[(None, self)], gltf_path, view, size, options)
+    }
+
+    /// Saves multiple models sharing the same grid into one `.gltf` file, every model as a
+    /// node with its own mesh. The bounds of `options` are added to the mesh of the last node,
+    /// as the lines have no extra attributes, and have to come after every face
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Io`] if the `.gltf` file or its buffer can't be
+    /// written, or [`crate::MeshToVoxError::Cancelled`] once `options.cancel` is set while
+    /// sparse meshing
+    #[allow(
+        clippy::needless_pass_by_value,
+        reason = "takes the view by value like `save_as_gltf`, which forwards its own"
+    )]
+    pub fn save_nodes_as_gltf(
+        nodes: &[(Option<&str>, &Self)],
+        gltf_path: &str,
+        view: View,
+        size: u32,
+        options: &GltfOptions,
     ) -> Result<()> {
+        let mut vertices = Vec::new();
+        let mut tangent_space = options.tangents.then(Vec::new);
+        let mut occlusion = options.ao_attribute.then(Vec::new);
+        let mut primitives = Vec::new();
+        let mut gltf_nodes = Vec::new();
+
+        let matrix = options.coordinate_system.matrix()
+            * options.up.to_y_up_matrix()
+            * view.model_view_projection;
+
+        for (index, (name, tree)) in nodes.iter().enumerate() {
+            let bounds = if index == nodes.len() - 1 {
+                options.bounds
+            } else {
+                None
+            };
+            let mesh = tree.gltf_mesh(size, options, bounds)?;
+
+            let start = primitives.len();
+            let offset = vertices.len();
+
+            primitives.extend(
+                mesh.primitives
+                    .into_iter()
+                    .map(|primitive| gltf2::Primitive {
+                        range: primitive.range.start + offset..primitive.range.end + offset,
+                        ..primitive
+                    }),
+            );
+
+            gltf_nodes.push(gltf2::Node {
+                name: *name,
+                matrix,
+                primitives: start..primitives.len(),
+            });
+
+            vertices.extend(mesh.vertices);
+
+            if let (Some(all), Some(mesh)) = (&mut tangent_space, mesh.tangent_space) {
+                all.extend(mesh);
+            }
+
+            if let (Some(all), Some(mesh)) = (&mut occlusion, mesh.occlusion) {
+                all.extend(mesh);
+            }
+        }
+
+        gltf2::save_gltf(
+            &vertices,
+            gltf2::ExtraAttributes {
+                tangent_space: tangent_space.as_deref(),
+                occlusion: occlusion.as_deref(),
+            },
+            &primitives,
+            &gltf_nodes,
+            gltf_path,
+            gltf2::Encoding {
+                float: options.float,
+                buffer: options.buffer,
+                color_space: options.color_space,
+            },
+        )
+    }
+
+    /// Meshes the voxels into the vertices, the extra attributes and the primitives of the
+    /// `.gltf` output, with the lines of `bounds` as the last primitive
+    fn gltf_mesh(
+        &self,
+        size: u32,
+        options: &GltfOptions,
+        bounds: Option<BoundingBox>,
+    ) -> Result<GltfMesh> {
         let max_size = size - 1;
 
-        let mesh = if sparse {
-            self.fill_space(max_size)
+        let mut faces = if options.sparse {
+            self.sparse_faces(options.cancel)?
         } else {
-            let nodes = self.collect_nodes();
-            let mut tris: Vec<Vertex> = Vec::with_capacity(nodes.len() * 36);
-            for (node, color) in &nodes {
-                let color = octree_header::to_color(*color).0;
-                for i in 0..6 {
-                    let node = crate::space_filling::MeshNode {
-                        cords: node.coords,
-                        dim: i / 2,
-                        positive: (i % 2) == 0,
-                        depth: node.depth as u8,
-                    };
-                    let verts = node.to_vertices(self.depth as u8);
+            self.all_faces()
+        };
 
-                    let verts = verts.map(|vert| {
-                        let position =
-                            ((vert + IVec3::NEG_ONE).as_dvec3() / max_size as f64).as_vec3();
-                        let position = position.mul_add(Vec3::splat(2.0), Vec3::NEG_ONE);
+        // emissive faces are moved to the end, where they are written as a separate primitive,
+        // and the faces of every color are grouped together if they get their own primitives
+        let order = |color: &image::Rgba<u8>| {
+            let grouped = if options.primitive_per_color {
+                color.0
+            } else {
+                [0; 4]
+            };
 
-                        Vertex { position, color }
-                    });
+            (octree_header::is_emissive(*color), grouped)
+        };
+
+        // the rectangles of greedy meshing take the place of the faces, as their first face
+        let quads = options.greedy.then(|| {
+            let mut quads = crate::greedy::greedy_quads(&faces, self.depth);
+            quads.sort_by_key(|(_, color, _)| order(color));
 
-                    for vert in verts {
-                        tris.push(vert);
+            faces = quads
+                .iter()
+                .map(|(node, color, _)| (node.clone(), *color))
+                .collect();
+
+            quads
+        });
+
+        if quads.is_none() {
+            faces.sort_by_key(|(_, color)| order(color));
+        }
+
+        let primitives = face_primitives(&faces, options.primitive_per_color);
+
+        let mut mesh = quads.as_ref().map_or_else(
+            || self.faces_to_vertices(&faces, max_size),
+            |quads| crate::greedy::quads_to_vertices(quads, max_size),
+        );
+
+        if options.bake_ao {
+            self.bake_ambient_occlusion(&faces, &mut mesh);
+        }
+
+        let flipped = faces
+            .iter()
+            .map(|(node, _)| {
+                options.winding.is_some_and(|normals| {
+                    normals
+                        .get(&node.cords)
+                        .is_some_and(|normal| normal.dot(node.normal()) < -WINDING_EPSILON)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut occlusion = options.ao_attribute.then(|| self.vertex_occlusion(&faces));
+
+        for face in (0..faces.len()).filter(|face| flipped[*face]) {
+            flip_face(&mut mesh[face * 6..][..6]);
+
+            if let Some(occlusion) = &mut occlusion {
+                flip_face(&mut occlusion[face * 6..][..6]);
+            }
+        }
+
+        let mut primitives = primitives;
+
+        if let Some(bounds) = &bounds {
+            let start = mesh.len();
+            mesh.extend(bounds_lines(bounds, max_size));
+
+            primitives.push(gltf2::Primitive {
+                range: start..mesh.len(),
+                emissive: true,
+                color: Some(BOUNDS_COLOR),
+                lines: true,
+            });
+        }
+
+        // every face is made out of six vertices
+        let tangent_space = options.tangents.then(|| {
+            faces
+                .iter()
+                .zip(&flipped)
+                .flat_map(|((node, _), flipped)| {
+                    let mut normal = node.normal();
+                    let mut tangent = node.tangent();
+
+                    // the handedness is flipped as well, which keeps the bitangent
+                    if *flipped {
+                        normal = -normal;
+                        tangent.w = -tangent.w;
                     }
-                }
+
+                    let tangent_space = TangentSpace {
+                        normal,
+                        tangent: tangent.to_array(),
+                    };
+
+                    [tangent_space; 6]
+                })
+                .collect::<Vec<_>>()
+        });
+
+        Ok(GltfMesh {
+            vertices: mesh,
+            tangent_space,
+            occlusion,
+            primitives,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxelizer::depth_for_size;
+
+    /// Saves the tree as a `.vox` file into the folder of the test `name`, and loads it back
+    fn vox_round_trip(name: &str, tree: &Octree, options: &VoxOptions) -> dot_vox::DotVoxData {
+        let path = crate::fixtures::test_dir(name).join("model.vox");
+        let path = path.to_str().unwrap();
+
+        tree.save_as_magica_voxel(path, options).unwrap();
+        dot_vox::load(path).unwrap()
+    }
+
+    /// Returns the positions of the voxels of a `.vox` file, with every model placed by the
+    /// translation of the transform node above its shape node
+    fn vox_positions(data: &dot_vox::DotVoxData) -> Vec<IVec3> {
+        let mut positions = Vec::new();
+
+        for node in &data.scenes {
+            let dot_vox::SceneNode::Transform { frames, child, .. } = node else {
+                continue;
+            };
+            let Some(dot_vox::SceneNode::Shape { models, .. }) = data.scenes.get(*child as usize)
+            else {
+                continue;
+            };
+
+            let translation = frames[0].position().expect("a model without a translation");
+            let translation = IVec3::new(translation.x, translation.y, translation.z);
+
+            for model in models {
+                let voxels = &data.models[model.model_id as usize].voxels;
+                positions.extend(voxels.iter().map(|voxel| {
+                    translation + IVec3::new(voxel.x.into(), voxel.y.into(), voxel.z.into())
+                }));
             }
+        }
+
+        positions
+    }
+
+    /// Returns the options of a plain `.gltf` output with an external buffer
+    fn gltf_options() -> GltfOptions<'static> {
+        GltfOptions {
+            sparse: true,
+            float: false,
+            tangents: false,
+            buffer: gltf2::GltfBuffer::External,
+            bake_ao: false,
+            ao_attribute: false,
+            winding: None,
+            primitive_per_color: false,
+            greedy: false,
+            color_space: ColorSpace::Linear,
+            up: UpAxis::Y,
+            coordinate_system: CoordinateSystem::Gltf,
+            bounds: None,
+            cancel: None,
+        }
+    }
+
+    #[test]
+    fn gltf_output_is_deterministic() {
+        let mesh = crate::fixtures::generate_test_mesh(16);
+        let tree = crate::mesh_to_octree(&mesh, 30, crate::VoxelizationMode::Triangles).unwrap();
+        let dir = crate::fixtures::test_dir("gltf_determinism");
+
+        for sparse in [true, false] {
+            let options = GltfOptions {
+                sparse,
+                ..gltf_options()
+            };
+
+            // the same file name in two folders, as the name of the buffer is in the file
+            let [first, second] = ["first", "second"].map(|folder| {
+                let path = dir.join(folder).join(format!("model_{sparse}.gltf"));
+                let path = path.to_str().unwrap();
 
-            tris
+                tree.save_as_gltf(path, mesh.view.clone(), 30, &options)
+                    .unwrap();
+
+                let gltf = std::fs::read(path).unwrap();
+                let bin = std::fs::read(gltf2::buffer_path(path)).unwrap();
+                (gltf, bin)
+            });
+
+            assert!(first == second, "the sparse: {sparse} outputs differ");
+        }
+    }
+
+    #[test]
+    fn gltf_files_round_trip() {
+        let mesh = crate::fixtures::generate_cube_mesh();
+        let tree = crate::mesh_to_octree(&mesh, 32, crate::VoxelizationMode::Triangles).unwrap();
+        let faces = tree.sparse_faces(None).unwrap().len();
+        let dir = crate::fixtures::test_dir("gltf_round_trip");
+
+        for (name, buffer) in [
+            ("cube.gltf", gltf2::GltfBuffer::External),
+            ("cube.glb", gltf2::GltfBuffer::Binary),
+        ] {
+            let path = dir.join(name);
+            let path = path.to_str().unwrap();
+            let options = GltfOptions {
+                buffer,
+                ..gltf_options()
+            };
+
+            tree.save_as_gltf(path, mesh.view.clone(), 32, &options)
+                .unwrap();
+
+            // the primitives are saved without indices, two triangles for every face of the
+            // sparse surface, and read back three consecutive vertices at a time
+            let (document, _, _) = gltf::import(path).unwrap();
+            let vertices = document
+                .meshes()
+                .flat_map(|mesh| mesh.primitives())
+                .filter_map(|primitive| primitive.get(&gltf::Semantic::Positions))
+                .map(|accessor| accessor.count())
+                .sum::<usize>();
+            let loaded = gltf2::load_gltf(path, &LoadOptions::default()).unwrap();
+
+            assert_eq!(vertices, faces * 6, "in `{name}`");
+            assert_eq!(loaded.triangles.len(), faces * 2, "in `{name}`");
+        }
+    }
+
+    #[test]
+    fn color_spaces_round_trip() {
+        for space in [ColorSpace::Linear, ColorSpace::Srgb] {
+            for channel in 0..=u8::MAX {
+                let value = f32::from(channel) / 255.0;
+                let decoded = space.decode(space.encode(value));
+
+                // rounds back to the same 8-bit value
+                let error = decoded.mul_add(255.0, -f32::from(channel)).abs();
+                assert!(error < 0.5, "{space:?} changes {channel} by {error}");
+            }
+        }
+
+        // the middle gray of sRGB is about a fifth of the linear light
+        assert!((ColorSpace::Linear.encode(0.5) - 0.214).abs() < 0.001);
+        assert!((ColorSpace::Srgb.encode(0.5) - 0.5).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn vox_models_have_unique_voxels() {
+        let depth = depth_for_size(510);
+        let mut tree = Octree::new(depth);
+        let color = image::Rgba([255, 255, 255, 255]);
+
+        // blocks of a refined tree, written as cubes of voxels, and voxels on both sides of
+        // the boundary of the chunks of the `.vox` output
+        let nodes = [
+            (IVec3::new(248, 8, 8), depth - 3),
+            (IVec3::splat(256), depth - 2),
+            (IVec3::new(255, 1, 1), depth),
+            (IVec3::new(256, 1, 1), depth),
+        ];
+
+        for (coords, depth) in nodes {
+            tree.insert(&OctreePos { coords, depth }, color).unwrap();
+        }
+
+        let data = vox_round_trip("vox_unique", &tree, &VoxOptions::default());
+        let mut count = 0;
+
+        for model in &data.models {
+            let mut positions = model
+                .voxels
+                .iter()
+                .map(|voxel| (voxel.x, voxel.y, voxel.z))
+                .collect::<Vec<_>>();
+            positions.sort_unstable();
+            positions.dedup();
+
+            assert_eq!(
+                positions.len(),
+                model.voxels.len(),
+                "a model repeats a voxel"
+            );
+            count += positions.len();
+        }
+
+        assert_eq!(count, 8 * 8 * 8 + 4 * 4 * 4 + 2);
+    }
+
+    #[test]
+    fn vox_palette_indices_are_valid() {
+        // as many colors as the palette holds, so the last one takes its top index
+        let colors = (0..255_u8)
+            .map(|index| image::Rgba([index, 255 - index, index / 2, 255]))
+            .collect::<Vec<_>>();
+        let voxels = (0_i32..)
+            .zip(&colors)
+            .map(|(index, color)| (IVec3::new(index % 16, index / 16, 0) + 1, *color));
+
+        let tree = Octree::from_voxels(voxels, depth_for_size(30));
+        let data = vox_round_trip("vox_palette", &tree, &VoxOptions::default());
+
+        assert_eq!(data.palette.len(), 256);
+
+        let mut saved = Vec::new();
+
+        for voxel in data.models.iter().flat_map(|model| &model.voxels) {
+            // the last entry of the palette is never used, the index `0` is empty space
+            assert!(
+                voxel.i < 255,
+                "the palette index {} is out of range",
+                voxel.i
+            );
+
+            let color = data.palette[usize::from(voxel.i)];
+            saved.push(image::Rgba([color.r, color.g, color.b, color.a]));
+        }
+
+        saved.sort_unstable_by_key(|color| color.0);
+        assert_eq!(saved, colors);
+    }
+
+    #[test]
+    fn vox_chunks_keep_their_voxels() {
+        // voxels on both sides of the boundaries of the chunks of the `.vox` output, which are
+        // 256 voxels wide, in a grid of four chunks along every axis
+        let color = image::Rgba([255, 255, 255, 255]);
+        let voxels = [0, 1, 255, 256, 511, 512, 767, 768, 1022]
+            .into_iter()
+            .flat_map(|x| [0, 255, 256, 1022].map(|y| IVec3::new(x, y, 1022 - x)))
+            .collect::<Vec<_>>();
+
+        let tree = Octree::from_voxels(
+            voxels.iter().map(|position| (*position, color)),
+            depth_for_size(1022),
+        );
+
+        let options = VoxOptions {
+            up: UpAxis::Y,
+            grid: None,
+        };
+        let data = vox_round_trip("vox_chunks", &tree, &options);
+
+        let mut saved = vox_positions(&data);
+        let mut expected = voxels
+            .iter()
+            .map(|position| options.up.to_z_up(*position))
+            .collect::<Vec<_>>();
+
+        saved.sort_unstable_by_key(IVec3::to_array);
+        expected.sort_unstable_by_key(IVec3::to_array);
+
+        assert_eq!(saved, expected);
+    }
+
+    #[test]
+    fn outputs_rotate_the_up_axis() {
+        // a bar along X at the top of a column along Y, with a voxel sticking out along Z, whose
+        // center of mass is off the center of its bounds along every axis, so a mirrored
+        // model leans the other way along one of them
+        let voxels = (0..8)
+            .map(|x| IVec3::new(x, 3, 0))
+            .chain((0..3).map(|y| IVec3::new(0, y, 0)))
+            .chain([IVec3::new(0, 3, 1)])
+            .map(|position| position + IVec3::ONE)
+            .collect::<Vec<_>>();
+
+        let color = image::Rgba([255, 255, 255, 255]);
+        let tree = Octree::from_voxels(
+            voxels.iter().map(|voxel| (*voxel, color)),
+            depth_for_size(14),
+        );
+
+        // the direction of the center of mass from the center of the bounds
+        let lean = |points: &[Vec3]| {
+            let bounds = BoundingBox::from_points(points.iter().copied());
+            let center = (bounds.min + bounds.max) * 0.5;
+            let offset = points.iter().map(|point| *point - center).sum::<Vec3>();
+
+            offset.signum().as_ivec3()
         };
 
-        gltf2::save_gltf(&mesh, gltf_path, view, float)
+        let source = lean(&voxels.iter().map(IVec3::as_vec3).collect::<Vec<_>>());
+        assert_eq!(source, IVec3::new(-1, 1, -1));
+
+        // turning a Y-up model Z-up keeps its handedness, unlike swapping the axes
+        let turned = |lean: IVec3| IVec3::new(lean.x, -lean.z, lean.y);
+
+        for up in [UpAxis::Y, UpAxis::Z] {
+            let name = format!("up_axis_{up:?}");
+            let data = vox_round_trip(&name, &tree, &VoxOptions { up, grid: None });
+            let vox_points = vox_positions(&data)
+                .iter()
+                .map(IVec3::as_vec3)
+                .collect::<Vec<_>>();
+
+            let path = crate::fixtures::test_dir(&name).join("model.gltf");
+            let path = path.to_str().unwrap();
+            let options = GltfOptions {
+                sparse: false,
+                up,
+                ..gltf_options()
+            };
+            let view = View {
+                camera: None,
+                model_view_projection: Mat4::IDENTITY,
+            };
+            tree.save_as_gltf(path, view, 14, &options).unwrap();
+
+            // the positions are moved by the transforms of the nodes into the space of glTF
+            let mesh = gltf2::load_gltf(path, &LoadOptions::default()).unwrap();
+            let gltf_points = mesh.triangles.into_iter().flatten().collect::<Vec<_>>();
+
+            let (vox, gltf) = (lean(&vox_points), lean(&gltf_points));
+
+            match up {
+                UpAxis::Y => assert_eq!((vox, gltf), (turned(source), source)),
+                UpAxis::Z => assert_eq!((vox, turned(gltf)), (source, source)),
+            }
+        }
     }
 }
@@ -0,0 +1,47 @@
+//! Voxelizes triangle meshes into sparse octrees, and saves them as `.vox`, `.gltf`, `.raw`,
+//! Goxel `.txt` or `.mtvox` files.
+//!
+//! ```no_run
+//! use mesh_to_vox::{LoadOptions, VoxelizationMode, load_gltf, mesh_to_octree};
+//!
+//! let mesh = load_gltf("model.glb", &LoadOptions::default())?;
+//! let tree = mesh_to_octree(&mesh, 126, VoxelizationMode::Triangles)?;
+//! tree.save_as_magica_voxel("model.vox", &mesh_to_vox::io::VoxOptions::default())?;
+//! # Ok::<(), mesh_to_vox::MeshToVoxError>(())
+//! ```
+#![warn(clippy::nursery)]
+#![warn(clippy::pedantic)]
+pub mod denoise;
+pub mod drain;
+pub mod error;
+pub mod fixtures;
+pub mod gltf2;
+pub mod goxel;
+pub mod greedy;
+pub mod io;
+pub mod lut;
+pub mod math;
+pub mod mtvox;
+pub mod obj;
+pub mod octree;
+pub mod palette;
+pub mod ply;
+pub mod raw;
+pub mod scanline;
+pub mod skeleton;
+pub mod space_filling;
+pub mod stl;
+pub mod thumbnail;
+pub mod voxelizer;
+
+pub use error::{MeshToVoxError, Result};
+pub use gltf2::load_gltf;
+pub use io::{LoadOptions, Mesh};
+pub use math::*;
+pub use mtvox::GridInfo;
+pub use obj::load_obj;
+pub use octree::Octree;
+pub use stl::load_stl;
+pub use voxelizer::{
+    VoxelizationMode, VoxelizeOptions, VoxelizeStats, mesh_to_octree, voxelize, voxelize_into,
+};
@@ -0,0 +1,103 @@
+use crate::{MeshToVoxError, Octree, Result};
+use glam::{UVec3, Vec3};
+
+/// A lookup table that the colors of the voxels are remapped through
+#[derive(Debug, Clone)]
+pub enum Lut {
+    /// A `256x1` image, whose pixel `x` holds the new value of every channel of the value `x`
+    Channels(image::RgbImage),
+    /// A Hald CLUT, which holds a cube of `size` colors per channel, with the red channel
+    /// changing the fastest. The image is square, and its side is the cube of a level `n`
+    /// with `size = n * n` (i.e. a `512x512` image holds a cube of 64 colors per channel)
+    Hald { image: image::RgbImage, size: u32 },
+}
+
+impl Lut {
+    /// Loads a lookup table from an image, and tells its kind apart by the size of the image
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::LutLoad`] if the image can't be loaded, or
+    /// [`crate::MeshToVoxError::InvalidData`] if its size matches no kind of lookup table
+    pub fn load(path: &str) -> Result<Self> {
+        let image = image::open(path)
+            .map_err(|source| MeshToVoxError::LutLoad {
+                path: path.into(),
+                source,
+            })?
+            .into_rgb8();
+
+        let (width, height) = image.dimensions();
+
+        if (width, height) == (256, 1) {
+            return Ok(Self::Channels(image));
+        }
+
+        let level = (1..=16).find(|level: &u32| level.pow(3) == width);
+
+        match level {
+            Some(level) if level >= 2 && width == height => Ok(Self::Hald {
+                image,
+                size: level * level,
+            }),
+            _ => Err(MeshToVoxError::InvalidData(format!(
+                "`{path}` is neither a 256x1 lookup table nor a Hald CLUT (a square image whose side is a cube, i.e. 512x512), but is {width}x{height}"
+            ))),
+        }
+    }
+
+    /// Remaps the color channels of `color`, keeping its alpha (i.e. whether it's emissive)
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        reason = "the interpolated channels stay between 0 and 255"
+    )]
+    #[must_use]
+    pub fn apply(&self, color: image::Rgba<u8>) -> image::Rgba<u8> {
+        let [r, g, b, a] = color.0;
+
+        let [r, g, b] = match self {
+            Self::Channels(image) => [
+                image.get_pixel(u32::from(r), 0)[0],
+                image.get_pixel(u32::from(g), 0)[1],
+                image.get_pixel(u32::from(b), 0)[2],
+            ],
+            Self::Hald { image, size } => {
+                let color =
+                    Self::sample_cube(image, *size, Vec3::new(r.into(), g.into(), b.into()));
+                color.round().to_array().map(|channel| channel as u8)
+            }
+        };
+
+        image::Rgba([r, g, b, a])
+    }
+
+    /// Interpolates the colors of the cube of a Hald CLUT around `color`, given in `0..=255`
+    #[allow(
+        clippy::cast_precision_loss,
+        reason = "a Hald CLUT is at most 256 colors wide"
+    )]
+    fn sample_cube(image: &image::RgbImage, size: u32, color: Vec3) -> Vec3 {
+        let position = color / 255.0 * (size - 1) as f32;
+        let low = position.floor().as_uvec3().min(UVec3::splat(size - 1));
+        let high = (low + 1).min(UVec3::splat(size - 1));
+        let t = position - low.as_vec3();
+
+        let texel = |r: u32, g: u32, b: u32| {
+            let index = r + g * size + b * size * size;
+            let pixel = image.get_pixel(index % image.width(), index / image.width());
+            Vec3::from_array(pixel.0.map(f32::from))
+        };
+
+        let lerp_r = |g, b| texel(low.x, g, b).lerp(texel(high.x, g, b), t.x);
+        let lerp_g = |b| lerp_r(low.y, b).lerp(lerp_r(high.y, b), t.y);
+
+        lerp_g(low.z).lerp(lerp_g(high.z), t.z)
+    }
+}
+
+impl Octree {
+    /// Remaps the color of every voxel through the lookup table
+    pub fn apply_lut(&mut self, lut: &Lut) {
+        self.map_colors(|color| lut.apply(color));
+    }
+}
@@ -1,20 +1,31 @@
 #![warn(clippy::nursery)]
 #![warn(clippy::pedantic)]
-pub mod gltf2;
-pub mod io;
-pub mod math;
-pub mod octree;
-pub mod space_filling;
-pub mod voxelizer;
-
-use crate::voxelizer::{VoxelizationMode, voxelize};
+use anyhow::{Context, Result, bail};
 use clap::Parser;
+use glam::{Mat4, Vec3};
+use mesh_to_vox::mtvox::GridInfo;
+use mesh_to_vox::octree::{FillColor, FillMethod, Octree};
+use mesh_to_vox::voxelizer::{
+    ConflictPolicy, MAX_SIZE, MIN_SIZE, VoxelNormals, VoxelizationMode, VoxelizeOptions,
+    VoxelizeStats, depth_for_size, grid_bounds, refine_levels, size_for_voxel_size, voxelize_into,
+    voxelize_refined,
+};
+use mesh_to_vox::{
+    BoundingBox, denoise, fixtures, gltf2, io, lut, mesh_to_octree, obj, palette, ply, raw, stl,
+    thumbnail, voxelizer,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-pub use anyhow::*;
-pub use math::*;
-
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
 enum InputType {
+    /// A `.gltf` file, or a binary `.glb` file, which is told apart by its content
+    #[value(name = "gltf", alias = "glb")]
     GlbGltf,
+    /// A Wavefront `.obj` file, with the materials of the `.mtl` files it references
+    Obj,
+    /// A binary or an ASCII `.stl` file, which is told apart by its content
+    Stl,
 }
 
 impl InputType {
@@ -23,14 +34,35 @@ impl InputType {
 
         match extension {
             "gltf" | "glb" => Ok(Self::GlbGltf),
-            _ => bail!("unknown file extension (only `.gltf` and `.glb` are supported)"),
+            "obj" => Ok(Self::Obj),
+            "stl" => Ok(Self::Stl),
+            _ => bail!(
+                "unknown file extension (only `.gltf`, `.glb`, `.obj` and `.stl` are supported)"
+            ),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
 enum OutputType {
+    /// A `.gltf` file with an external `.bin` buffer, or a binary `.glb` file holding the
+    /// buffer, if the output file has the `.glb` extension
     Gltf,
+    /// A binary `.glb` file holding the buffer, whatever the extension of the output file
+    Glb,
+    /// A MagicaVoxel `.vox` file
+    #[value(name = "vox")]
     MagicaVoxel,
+    /// A `.mtvox` octree file, which can be voxelized into again with `--append-to`
+    #[value(name = "mtvox")]
+    Octree,
+    /// A `.raw` dense grid of RGBA or occupancy bytes, described by a `.json` file next to it
+    Raw,
+    /// A Goxel `.txt` file, with one `x y z rrggbb` line per voxel
+    #[value(name = "txt")]
+    Goxel,
+    /// A `.ply` point cloud with a point per voxel, or a mesh of the faces of the voxels
+    Ply,
 }
 
 impl OutputType {
@@ -39,44 +71,1017 @@ impl OutputType {
 
         match extension {
             "gltf" => Ok(Self::Gltf),
+            "glb" => Ok(Self::Glb),
             "vox" => Ok(Self::MagicaVoxel),
-            _ => bail!("unknown file extension (only `.gltf` and `.vox` are supported)"),
+            "mtvox" => Ok(Self::Octree),
+            "raw" => Ok(Self::Raw),
+            "txt" => Ok(Self::Goxel),
+            "ply" => Ok(Self::Ply),
+            _ => bail!(
+                "unknown file extension (only `.gltf`, `.glb`, `.vox`, `.mtvox`, `.raw`, `.txt` and `.ply` are supported)"
+            ),
+        }
+    }
+
+    /// Returns every file that saving to `file` will write
+    pub fn output_files(self, file: &str, buffer: gltf2::GltfBuffer) -> Vec<std::path::PathBuf> {
+        match self {
+            Self::Gltf if buffer == gltf2::GltfBuffer::External => {
+                vec![file.into(), gltf2::buffer_path(file)]
+            }
+            Self::Raw => vec![file.into(), raw::descriptor_path(file)],
+            Self::Gltf | Self::Glb | Self::MagicaVoxel | Self::Octree | Self::Goxel | Self::Ply => {
+                vec![file.into()]
+            }
         }
     }
 }
 
-fn voxelize_mesh(args: &Args) -> Result<()> {
-    let input_type =
-        InputType::from_file(&args.input).context("failed to infer input file type")?;
-    let output_type =
-        OutputType::from_file(&args.output).context("failed to infer output file type")?;
+fn voxelize_mesh(args: &Args, save: &SaveArgs, profile: &mut Profile) -> Result<()> {
+    let input_type = args.input_type()?;
+    let output_type = save.output_type()?;
 
-    let mesh = match input_type {
-        InputType::GlbGltf => {
-            gltf2::load_gltf(&args.input).context("failed to load the input file")?
-        }
+    args.check(save, output_type)?;
+
+    if args.split_by.is_none() {
+        check_overwrite(save, output_type, &save.output)?;
+    }
+
+    let load_options = io::LoadOptions {
+        screens: args.screens,
+        compute_normals: args.compute_normals,
     };
 
+    let mut mesh = profile.time("load", || load_mesh(&args.input, input_type, load_options))?;
+    profile.triangles = mesh.triangles.len();
+
     println!("Mesh is loaded");
 
-    let data = voxelize(&mesh, args.dim, VoxelizationMode::Triangles);
+    if !mesh.unsupported.is_empty() {
+        let summary = mesh.unsupported.summary();
+
+        if args.strict {
+            bail!("the input file uses unsupported features:\n{summary}");
+        }
+
+        eprintln!("The input file uses features that were ignored:\n{summary}");
+    }
+
+    if args.largest_component {
+        let (components, triangles) = mesh.keep_largest_component();
+
+        println!(
+            "Removed {triangles} triangles in {components} components outside of the largest one"
+        );
+    }
+
+    let options = args.voxelize_options();
+    let voxelization = Voxelization {
+        args,
+        save,
+        output_type,
+        options,
+        dim: resolution(args, &mesh, &options)?,
+    };
+
+    if let Some(by) = args.split_by {
+        return voxelization.save_parts(&mesh, by, profile);
+    }
+
+    if let Some(by) = args.layers_by {
+        return voxelization.save_layers(&mesh, by, profile);
+    }
+
+    if let (Some(region), Some(refine_dim)) = (&args.refine, args.refine_dim) {
+        return voxelization.save_refined(&mesh, region, refine_dim, profile);
+    }
+
+    voxelization.save_whole(&mesh, profile)
+}
+
+/// Returns the resolution of `--dim`, or the one that makes the voxels `--voxel-size` wide
+#[allow(
+    clippy::cast_precision_loss,
+    reason = "the width of the voxels is only printed"
+)]
+fn resolution(args: &Args, mesh: &io::Mesh, options: &VoxelizeOptions) -> Result<u32> {
+    let dim = match args.voxel_size {
+        Some(_) if options.fit == io::Fit::Stretch => {
+            bail!("`--voxel-size` needs cubic voxels, which `--fit stretch` doesn't keep")
+        }
+        Some(voxel_size) => {
+            let extent = mesh.fit_bounds(options.fit).size().max_element();
+            let dim = size_for_voxel_size(extent, voxel_size, options.padding)?;
+
+            // the fitted bounds span `dim + 1` voxels, minus the padding on both sides
+            let spanned = dim + 1 - if options.padding { 2 } else { 0 };
+            println!(
+                "Voxels {voxel_size} wide need `--dim {dim}`, which makes them {} wide",
+                extent / spanned as f32
+            );
+
+            dim
+        }
+        None => args.dim,
+    };
+
+    if let Some(max_depth) = args.max_depth {
+        let levels = depth_for_size(dim).saturating_sub(max_depth);
+
+        if levels > 0 {
+            println!(
+                "Voxels are averaged into blocks {} voxels wide by `--max-depth {max_depth}`",
+                1 << levels
+            );
+        }
+    }
+
+    Ok(dim)
+}
+
+/// The arguments that the mesh is voxelized and saved with
+struct Voxelization<'a> {
+    args: &'a Args,
+    save: &'a SaveArgs,
+    output_type: OutputType,
+    options: VoxelizeOptions,
+    /// The resolution of the grid, before `--refine`
+    dim: u32,
+}
+
+impl Voxelization<'_> {
+    /// Voxelizes every part of `--split-by` into its own grid, and saves it into its own file
+    fn save_parts(&self, mesh: &io::Mesh, by: io::SplitBy, profile: &mut Profile) -> Result<()> {
+        let Self {
+            args,
+            save,
+            output_type,
+            options,
+            dim,
+        } = *self;
+
+        let parts = mesh
+            .split(by)
+            .into_iter()
+            .map(|(key, part)| (split_path(&save.output, by, key), part))
+            .collect::<Vec<_>>();
+
+        for (output, _) in &parts {
+            check_overwrite(save, output_type, output)?;
+        }
+
+        let grid = GridInfo {
+            size: dim,
+            padding: options.padding,
+            bounds: mesh.fit_bounds(options.fit),
+        };
+
+        for (output, part) in parts {
+            let mut data = Octree::new(depth_for_size(dim));
+            let mut normals = args.winding.then(VoxelNormals::new);
+            let stats = profile.time("voxelize", || {
+                voxelize_into(
+                    &mut data,
+                    normals.as_mut(),
+                    &part,
+                    &grid.bounds,
+                    dim,
+                    &options,
+                    Some(&CANCELLED),
+                )
+            })?;
+            report_stats(stats);
+
+            let source = Source {
+                bounds: emitted_bounds(args, &part, &grid, &options)?,
+                normals: normals.as_ref(),
+                view: part.view,
+            };
+
+            save_octree(
+                save,
+                output_type,
+                &output,
+                &mut data,
+                &grid,
+                source,
+                profile,
+            )?;
+
+            println!("Saved `{output}`");
+        }
+
+        Ok(())
+    }
+
+    /// Voxelizes every part of `--layers-by` into the same grid, and saves them into one file,
+    /// every part as its own layer
+    fn save_layers(&self, mesh: &io::Mesh, by: io::SplitBy, profile: &mut Profile) -> Result<()> {
+        let Self {
+            args,
+            save,
+            output_type,
+            options,
+            dim,
+        } = *self;
 
-    println!("Mesh is voxelized");
+        let grid = GridInfo {
+            size: dim,
+            padding: options.padding,
+            bounds: mesh.fit_bounds(options.fit),
+        };
+        let mut names = Vec::new();
+        let mut trees = Vec::new();
+
+        for (key, part) in mesh.split(by) {
+            let mut tree = Octree::new(depth_for_size(dim));
+            let stats = profile.time("voxelize", || {
+                voxelize_into(
+                    &mut tree,
+                    None,
+                    &part,
+                    &grid.bounds,
+                    dim,
+                    &options,
+                    Some(&CANCELLED),
+                )
+            })?;
+            report_stats(stats);
+
+            let name = mesh.part_name(by, key);
+
+            profile.time("process", || {
+                extract_color(save, &mut tree, &name);
+                remove_noise(save, &mut tree, &name)
+            })?;
+
+            if let Some(color) = save.fill_color {
+                profile.time("fill", || {
+                    tree.fill_interior(color, save.fill_method, Some(&CANCELLED))
+                })?;
+            }
+
+            profile.time("process", || {
+                thin(save, &mut tree, &name)?;
+                drain(save, &mut tree, &name)?;
+
+                if let Some(lut) = &save.lut {
+                    tree.apply_lut(lut);
+                }
+
+                anyhow::Ok(())
+            })?;
+
+            names.push(name);
+            trees.push(tree);
+        }
+
+        println!("Mesh is voxelized into {} layers", trees.len());
+
+        if let Some(max_colors) = save.max_colors {
+            profile.time("process", || {
+                palette::posterize_all(&mut trees, usize::from(max_colors));
+            });
+        }
+
+        for tree in &trees {
+            profile.record_tree(tree);
+        }
+
+        let layers = names
+            .iter()
+            .zip(&trees)
+            .map(|(name, tree)| (Some(name.as_str()), tree))
+            .collect::<Vec<_>>();
+
+        let bounds = emitted_bounds(args, mesh, &grid, &options)?;
+
+        profile.time("export", || {
+            if matches!(output_type, OutputType::Gltf | OutputType::Glb) {
+                let options = io::GltfOptions {
+                    bounds,
+                    ..save.gltf_options(None)
+                };
+
+                Octree::save_nodes_as_gltf(&layers, &save.output, mesh.view.clone(), dim, &options)
+            } else {
+                Octree::save_layers_as_magica_voxel(&layers, &save.output, &save.vox_options())
+            }
+        })?;
+
+        println!("Mesh is saved");
+
+        if save.print_hash {
+            for (name, tree) in names.iter().zip(&trees) {
+                println!("Hash of layer `{name}`: {:016x}", tree.content_hash());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Voxelizes the mesh at `--dim`, and the part of it in `region` at `refine_dim`
+    fn save_refined(
+        &self,
+        mesh: &io::Mesh,
+        region: &BoundingBox,
+        refine_dim: u32,
+        profile: &mut Profile,
+    ) -> Result<()> {
+        let Self {
+            save,
+            output_type,
+            options,
+            dim,
+            ..
+        } = *self;
+
+        let grid = GridInfo {
+            size: refine_dim,
+            padding: options.padding,
+            bounds: mesh.fit_bounds(options.fit),
+        };
+
+        let (mut data, stats) = profile.time("voxelize", || {
+            voxelize_refined(
+                mesh,
+                &grid.bounds,
+                dim,
+                refine_dim,
+                region,
+                &options,
+                Some(&CANCELLED),
+            )
+        })?;
+        report_stats(stats);
+
+        println!("Mesh is voxelized");
+
+        let source = Source {
+            view: mesh.view.clone(),
+            normals: None,
+            bounds: None,
+        };
+
+        save_octree(
+            save,
+            output_type,
+            &save.output,
+            &mut data,
+            &grid,
+            source,
+            profile,
+        )?;
+
+        println!("Mesh is saved");
+
+        Ok(())
+    }
+
+    /// Voxelizes the whole mesh into one grid (or into the one of `--append-to`)
+    fn save_whole(&self, mesh: &io::Mesh, profile: &mut Profile) -> Result<()> {
+        let Self {
+            args,
+            save,
+            output_type,
+            options,
+            dim,
+        } = *self;
+
+        let (mut data, grid) = if let Some(path) = &args.append_to {
+            let (tree, grid) =
+                Octree::load_from_file(path).context("failed to load the octree to append to")?;
+
+            if grid.size != dim || tree.depth != depth_for_size(dim) {
+                bail!(
+                    "`{path}` was voxelized with `--dim {}`, but `--dim {}` was requested",
+                    grid.size,
+                    dim
+                );
+            }
+
+            if grid.padding != options.padding {
+                bail!("`{path}` was voxelized with a different `--no-padding` setting");
+            }
+
+            let epsilon = grid.bounds.size().max_element() * 1e-4;
+            if mesh.bounds.min.cmplt(grid.bounds.min - epsilon).any()
+                || mesh.bounds.max.cmpgt(grid.bounds.max + epsilon).any()
+            {
+                bail!(
+                    "the mesh (bounds {:?} - {:?}) extends outside the grid of `{path}` (bounds {:?} - {:?})",
+                    mesh.bounds.min,
+                    mesh.bounds.max,
+                    grid.bounds.min,
+                    grid.bounds.max
+                );
+            }
+
+            (tree, grid)
+        } else {
+            let grid = GridInfo {
+                size: dim,
+                padding: options.padding,
+                bounds: mesh.fit_bounds(options.fit),
+            };
+
+            (Octree::new(depth_for_size(dim)), grid)
+        };
+
+        let mut normals = args.winding.then(VoxelNormals::new);
+        let stats = profile.time("voxelize", || {
+            voxelize_into(
+                &mut data,
+                normals.as_mut(),
+                mesh,
+                &grid.bounds,
+                dim,
+                &options,
+                Some(&CANCELLED),
+            )
+        })?;
+        report_stats(stats);
+
+        println!("Mesh is voxelized");
+
+        let source = Source {
+            bounds: emitted_bounds(args, mesh, &grid, &options)?,
+            normals: normals.as_ref(),
+            view: mesh.view.clone(),
+        };
+
+        save_octree(
+            save,
+            output_type,
+            &save.output,
+            &mut data,
+            &grid,
+            source,
+            profile,
+        )?;
+
+        println!("Mesh is saved");
+
+        Ok(())
+    }
+}
+
+fn load_mesh(input: &str, input_type: InputType, options: io::LoadOptions) -> Result<io::Mesh> {
+    let mesh = match input_type {
+        InputType::GlbGltf => gltf2::load_gltf(input, &options),
+        InputType::Obj => obj::load_obj(input, &options),
+        InputType::Stl => stl::load_stl(input),
+    };
+
+    mesh.context("failed to load the input file")
+}
+
+/// Prints the materials of the input file along with the number of triangles using each one,
+/// without voxelizing it
+fn list_materials(input: &str) -> Result<()> {
+    let input_type = InputType::from_file(input).context("failed to infer input file type")?;
+    let mesh = load_mesh(input, input_type, io::LoadOptions::default())?;
+
+    let mut triangles = vec![0_usize; mesh.materials.len()];
+    for [extras, ..] in &mesh.triangle_extras {
+        triangles[extras.material_idx as usize] += 1;
+    }
+
+    for (index, (material, triangles)) in mesh.materials.iter().zip(triangles).enumerate() {
+        // the loaders add the default material last, for the triangles without a material
+        let name = match &material.name {
+            Some(name) => format!("`{name}`"),
+            None if index == mesh.materials.len() - 1 => "(default)".to_string(),
+            None => "(unnamed)".to_string(),
+        };
+
+        let describe = |color: &io::ImageOrColor| match color {
+            io::ImageOrColor::Image(image) => {
+                format!("{}x{} texture", image.width(), image.height())
+            }
+            io::ImageOrColor::Color(color) => {
+                let [r, g, b, a] = color.0;
+                format!("color #{r:02x}{g:02x}{b:02x}{a:02x}")
+            }
+        };
+
+        let color = describe(&material.color);
+        let emissive = if material.emissive { ", emissive" } else { "" };
+        let emission = material
+            .emission
+            .as_ref()
+            .map_or_else(String::new, |emission| {
+                format!(", emits {}", describe(&emission.color))
+            });
+
+        println!("Material {index} {name}: {color}{emissive}{emission}, {triangles} triangles");
+    }
+
+    Ok(())
+}
+
+/// Converts a `.mtvox` file into the output format, reusing the voxels stored in the file
+fn convert(args: &ConvertArgs, profile: &mut Profile) -> Result<()> {
+    let output_type = args.save.output_type()?;
+
+    check_overwrite(&args.save, output_type, &args.save.output)?;
+
+    let (mut data, grid) = profile
+        .time("load", || Octree::load_from_file(&args.input))
+        .context("failed to load the octree to convert")?;
+
+    println!("Octree is loaded");
+
+    if !grid.padding
+        && ((args.save.fill_color.is_some() && args.save.fill_method == FillMethod::FloodFill)
+            || args.save.sparse_meshing(output_type))
+    {
+        bail!(
+            "`{}` was voxelized with `--no-padding`, which can't be used with `--fill-color` and the default `--fill-method flood-fill`, or with `--sparse true`",
+            args.input
+        );
+    }
+
+    // the node transforms of the source file are baked into the voxels
+    let source = Source {
+        view: io::View {
+            camera: None,
+            model_view_projection: Mat4::IDENTITY,
+        },
+        normals: None,
+        bounds: None,
+    };
+
+    save_octree(
+        &args.save,
+        output_type,
+        &args.save.output,
+        &mut data,
+        &grid,
+        source,
+        profile,
+    )?;
+
+    println!("Octree is saved");
+
+    Ok(())
+}
+
+/// The resolution that the cube of `--self-test` is voxelized at
+const SELF_TEST_SIZE: u32 = 32;
+
+/// Voxelizes a generated cube, saves it as a `.vox` file and loads it back, and prints `PASS`
+/// or `FAIL`. This tests a build without needing any input files, the invariants of every
+/// part of the pipeline are covered by the unit tests
+fn self_test() -> Result<()> {
+    match check_vox_round_trip() {
+        Ok(()) => println!("PASS `.vox` round trip of a cube"),
+        Err(err) => {
+            println!("FAIL `.vox` round trip of a cube: {err:#}");
+            bail!("the self-test failed");
+        }
+    }
+
+    Ok(())
+}
+
+fn check_vox_round_trip() -> Result<()> {
+    let mesh = fixtures::generate_cube_mesh();
+    let tree = mesh_to_octree(&mesh, SELF_TEST_SIZE, VoxelizationMode::Triangles)
+        .context("failed to voxelize the test cube")?;
+
+    let dir = std::env::temp_dir().join(format!("mesh_to_vox_self_test_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).context("failed to create the self-test directory")?;
+
+    let path = dir.join("cube.vox");
+    let path = path
+        .to_str()
+        .context("the self-test directory isn't valid UTF-8")?;
+
+    let loaded = tree
+        .save_as_magica_voxel(path, &io::VoxOptions::default())
+        .map_err(anyhow::Error::from)
+        .and_then(|()| {
+            dot_vox::load(path).map_err(|err| anyhow::anyhow!("failed to load `{path}`: {err}"))
+        });
+
+    // the file is only needed by the check
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let saved = loaded?
+        .models
+        .iter()
+        .map(|model| model.voxels.len() as u64)
+        .sum::<u64>();
+
+    // the surface of the cube is a shell one voxel thick
+    let side = u64::from(SELF_TEST_SIZE);
+    let shell = side.pow(3) - (side - 2).pow(3);
+
+    if saved != shell {
+        bail!("expected {shell} voxels on the surface of the cube, got {saved}");
+    }
+
+    Ok(())
+}
+
+/// Warns about the triangles that the voxelization had to skip
+fn report_stats(stats: VoxelizeStats) {
+    if stats.skipped_triangles > 0 {
+        eprintln!(
+            "Skipped {} triangles with non-finite vertex coordinates",
+            stats.skipped_triangles
+        );
+    }
+}
+
+/// Fails if `--no-overwrite` was passed and saving to `output` would replace a file
+fn check_overwrite(args: &SaveArgs, output_type: OutputType, output: &str) -> Result<()> {
+    if args.no_overwrite {
+        let frames =
+            (0..args.turntable.unwrap_or(0)).map(|index| thumbnail::frame_path(output, index));
+
+        for file in output_type
+            .output_files(output, args.gltf_buffer())
+            .into_iter()
+            .chain(frames)
+        {
+            if file.exists() {
+                bail!(
+                    "output file `{}` already exists (remove `--no-overwrite` to replace it)",
+                    file.display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// What the output takes from the mesh that the voxels were made from
+struct Source<'a> {
+    view: io::View,
+    /// The normals of `--winding`
+    normals: Option<&'a VoxelNormals>,
+    /// The bounds of `--emit-bounds`, in the space of the grid
+    bounds: Option<BoundingBox>,
+}
+
+/// Returns the bounds of `mesh` in the space of the grid if `--emit-bounds` is set
+fn emitted_bounds(
+    args: &Args,
+    mesh: &io::Mesh,
+    grid: &GridInfo,
+    options: &VoxelizeOptions,
+) -> Result<Option<BoundingBox>> {
+    if !args.emit_bounds {
+        return Ok(None);
+    }
+
+    Ok(Some(grid_bounds(mesh, &grid.bounds, grid.size, options)?))
+}
+
+fn save_octree(
+    args: &SaveArgs,
+    output_type: OutputType,
+    output: &str,
+    data: &mut Octree,
+    grid: &GridInfo,
+    source: Source,
+    profile: &mut Profile,
+) -> Result<()> {
+    profile.time("process", || {
+        extract_color(args, data, output);
+        remove_noise(args, data, output)
+    })?;
+
+    if let Some(color) = args.fill_color {
+        profile.time("fill", || {
+            data.fill_interior(color, args.fill_method, Some(&CANCELLED))
+        })?;
+    }
+
+    profile.time("process", || {
+        thin(args, data, output)?;
+        drain(args, data, output)?;
+
+        if let Some(lut) = &args.lut {
+            data.apply_lut(lut);
+        }
+
+        if let Some(max_colors) = args.max_colors {
+            data.posterize(usize::from(max_colors));
+        }
+
+        anyhow::Ok(())
+    })?;
+
+    profile.record_tree(data);
+    let export = Instant::now();
 
     match output_type {
-        OutputType::Gltf => {
-            data.save_as_gltf(&args.output, mesh.view, args.sparse, args.dim, true)?;
+        OutputType::Gltf | OutputType::Glb => {
+            let options = io::GltfOptions {
+                bounds: source.bounds,
+                ..args.gltf_options(source.normals)
+            };
+
+            data.save_as_gltf(output, source.view, grid.size, &options)?;
         }
         OutputType::MagicaVoxel => {
-            data.save_as_magica_voxel(&args.output)?;
+            data.save_as_magica_voxel(output, &args.vox_options())?;
+        }
+        OutputType::Octree => {
+            data.save_to_file(output, grid)?;
+        }
+        OutputType::Raw => {
+            let options = raw::RawOptions {
+                order: args.axis_order,
+                channels: args.raw_channels,
+                air_color: args.air_color,
+            };
+
+            data.save_as_raw(output, grid.size, &options)?;
+        }
+        OutputType::Ply => {
+            data.save_as_ply(output, grid.size, &args.ply_options())?;
+        }
+        OutputType::Goxel => {
+            data.save_as_goxel(output, args.source_up)?;
+        }
+    }
+
+    profile.add("export", export.elapsed());
+
+    if args.print_hash {
+        println!("Hash of `{output}`: {:016x}", data.content_hash());
+    }
+
+    if let Some(frames) = args.turntable {
+        profile.time("turntable", || data.save_turntable(output, frames))?;
+        println!("Saved {frames} turntable frames of `{output}`");
+    }
+
+    Ok(())
+}
+
+/// The durations of the stages of a run, and the counts of what they processed, which are
+/// written to `--profile-output`
+#[derive(Debug, Default)]
+struct Profile {
+    /// Whether `--profile-output` is set. The counts of the trees are only collected then
+    enabled: bool,
+    /// The total duration of every stage, in the order the stages first ran
+    stages: Vec<(&'static str, Duration)>,
+    triangles: usize,
+    nodes: usize,
+    tree_memory: usize,
+}
+
+impl Profile {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            ..Self::default()
+        }
+    }
+
+    /// Adds the duration of `f` to the stage
+    fn time<T>(&mut self, stage: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(stage, start.elapsed());
+
+        result
+    }
+
+    fn add(&mut self, stage: &'static str, duration: Duration) {
+        match self.stages.iter_mut().find(|(name, _)| *name == stage) {
+            Some((_, total)) => *total += duration,
+            None => self.stages.push((stage, duration)),
         }
     }
 
-    println!("Mesh is saved");
+    /// Adds the nodes and the memory of a tree that is saved
+    fn record_tree(&mut self, tree: &Octree) {
+        if self.enabled {
+            self.nodes += tree.collect_nodes().len();
+            self.tree_memory += tree.memory_usage();
+        }
+    }
+
+    /// Writes the profile of a run that took `total` as JSON, along with the arguments of the
+    /// run
+    fn save(&self, path: &str, total: Duration) -> Result<()> {
+        let mut stages = json::JsonValue::new_object();
+        for (name, duration) in &self.stages {
+            stages[*name] = duration.as_secs_f64().into();
+        }
+
+        let profile = json::object! {
+            arguments : std::env::args().collect::<Vec<_>>(),
+            stages : stages,
+            total : total.as_secs_f64(),
+            triangles : self.triangles,
+            nodes : self.nodes,
+            tree_memory : self.tree_memory,
+            peak_memory : peak_memory(),
+        };
+
+        std::fs::write(path, profile.pretty(2))
+            .with_context(|| format!("failed to write the profile to `{path}`"))
+    }
+}
+
+/// Runs `run` with a profile, which is written to `--profile-output` once it succeeds
+fn run_profiled(save: &SaveArgs, run: impl FnOnce(&mut Profile) -> Result<()>) -> Result<()> {
+    let start = Instant::now();
+    let mut profile = Profile::new(save.profile_output.is_some());
+
+    run(&mut profile)?;
+
+    if let Some(path) = &save.profile_output {
+        profile.save(path, start.elapsed())?;
+        println!("Saved the profile to `{path}`");
+    }
 
     Ok(())
 }
 
+/// Returns the largest amount of memory that the process has used, in bytes
+#[cfg(unix)]
+fn peak_memory() -> Option<u64> {
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &raw mut usage) } != 0 {
+        return None;
+    }
+
+    let max_rss = u64::try_from(usage.ru_maxrss).ok()?;
+
+    // macOS reports bytes, the other systems kilobytes
+    if cfg!(target_os = "macos") {
+        Some(max_rss)
+    } else {
+        Some(max_rss * 1024)
+    }
+}
+
+#[cfg(not(unix))]
+const fn peak_memory() -> Option<u64> {
+    None
+}
+
+/// Keeps only the voxels of `--extract-color` in the model saved as `name`, and reports how
+/// many were removed
+fn extract_color(args: &SaveArgs, data: &mut Octree, name: &str) {
+    if let Some(color) = args.extract_color {
+        let voxels = data.extract_color(color, args.extract_tolerance);
+
+        println!("Removed {voxels} voxels of other colors than `--extract-color` from `{name}`");
+    }
+}
+
+/// Removes the small groups of voxels of `--denoise` from the model saved as `name`, and
+/// reports how many were removed
+fn remove_noise(args: &SaveArgs, data: &mut Octree, name: &str) -> Result<()> {
+    if let Some(min_voxels) = args.denoise {
+        let (components, voxels) =
+            data.remove_small_components(min_voxels, args.connectivity, Some(&CANCELLED))?;
+
+        println!(
+            "Removed {voxels} voxels in {components} groups smaller than {min_voxels} voxels from `{name}`"
+        );
+    }
+
+    Ok(())
+}
+
+/// Thins the model saved as `name` to its skeleton if `--skeletonize` is set, and reports how
+/// many voxels were removed
+fn thin(args: &SaveArgs, data: &mut Octree, name: &str) -> Result<()> {
+    if args.skeletonize {
+        let voxels = data.skeletonize(Some(&CANCELLED))?;
+
+        println!("Removed {voxels} voxels while thinning `{name}` to its skeleton");
+    }
+
+    Ok(())
+}
+
+/// Punches the holes of `--drain-holes` through the model saved as `name`, and reports how
+/// many voxels were removed
+fn drain(args: &SaveArgs, data: &mut Octree, name: &str) -> Result<()> {
+    if let Some(count) = args.drain_holes {
+        let (holes, voxels) =
+            data.punch_drain_holes(count, args.drain_radius, args.source_up, Some(&CANCELLED))?;
+
+        println!("Removed {voxels} voxels while punching {holes} drain holes through `{name}`");
+    }
+
+    Ok(())
+}
+
+/// Returns the file that the part `key` of a mesh split with `--split-by` is saved to,
+/// i.e. `model_material3.vox` for `model.vox`
+fn split_path(output: &str, by: io::SplitBy, key: u32) -> String {
+    let path = std::path::Path::new(output);
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default();
+
+    let mut name = format!("{stem}_{}{key}", by.name());
+
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        name = format!("{name}.{extension}");
+    }
+
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Parses a fill color given as `nearest` or as a hex color, i.e. `#ff8000`
+fn parse_fill_color(color: &str) -> Result<FillColor> {
+    if color == "nearest" {
+        return Ok(FillColor::Nearest);
+    }
+
+    parse_hex_rgba(color, false)
+        .map(FillColor::Flat)
+        .context("the fill color has to be `nearest` or a hex color (`#rrggbb`)")
+}
+
+/// Parses the color of `--extract-color`, given as a hex color (`#rrggbb`)
+fn parse_extract_color(color: &str) -> Result<image::Rgba<u8>> {
+    parse_hex_rgba(color, false).context("the extracted color has to be a hex color (`#rrggbb`)")
+}
+
+/// Parses the air color of the `.raw` output, given as a hex color with or without alpha
+fn parse_air_color(color: &str) -> Result<image::Rgba<u8>> {
+    parse_hex_rgba(color, true)
+        .context("the air color has to be a hex color (`#rrggbbaa` or an opaque `#rrggbb`)")
+}
+
+/// Parses a hex color given as `#rrggbb`, or also as `#rrggbbaa` when `allow_alpha` is set.
+/// The leading `#` is optional and colors without alpha are opaque
+fn parse_hex_rgba(color: &str, allow_alpha: bool) -> Option<image::Rgba<u8>> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+
+    if !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let value = u32::from_str_radix(hex, 16).ok()?;
+
+    match hex.len() {
+        6 => {
+            let [_, r, g, b] = value.to_be_bytes();
+            Some(image::Rgba([r, g, b, 255]))
+        }
+        8 if allow_alpha => Some(image::Rgba(value.to_be_bytes())),
+        _ => None,
+    }
+}
+
+fn parse_lut(path: &str) -> Result<lut::Lut> {
+    // clap only prints the outermost error, so the causes are formatted into it
+    lut::Lut::load(path).map_err(|err| anyhow::anyhow!("{:#}", anyhow::Error::new(err)))
+}
+
+/// Parses a positive, finite number of voxels per unit
+fn parse_positive(value: &str) -> Result<f32> {
+    let value = value
+        .trim()
+        .parse::<f32>()
+        .context("the value has to be a number")?;
+
+    if !value.is_finite() || value <= 0.0 {
+        bail!("the value has to be a positive number");
+    }
+
+    Ok(value)
+}
+
+/// Parses a region given as `minx,miny,minz,maxx,maxy,maxz`
+fn parse_region(region: &str) -> Result<BoundingBox> {
+    let values = region
+        .split(',')
+        .map(|value| value.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .context("the region has to be a list of numbers")?;
+
+    let [min_x, min_y, min_z, max_x, max_y, max_z] = values[..] else {
+        bail!("the region has to be 6 numbers (`minx,miny,minz,maxx,maxy,maxz`)");
+    };
+
+    Ok(BoundingBox::from_points([
+        Vec3::new(min_x, min_y, min_z),
+        Vec3::new(max_x, max_y, max_z),
+    ]))
+}
+
+/// # Errors
+/// Fails if the path has no extension, or it isn't valid UTF-8
 pub fn get_extension(path: &str) -> Result<&str> {
     std::path::Path::new(path)
         .extension()
@@ -86,35 +1091,636 @@ pub fn get_extension(path: &str) -> Result<&str> {
 }
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    /// The input file that will be voxelized
-    #[arg(short, long)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Voxelize a generated cube and check the result, to test a build without input files
+    #[arg(long, hide = true, exclusive = true)]
+    self_test: bool,
+
+    /// Print the materials of an input file and the number of triangles using each one,
+    /// without voxelizing it
+    #[arg(long, value_name = "INPUT", exclusive = true)]
+    list_materials: Option<String>,
+
+    #[command(flatten)]
+    args: Option<Args>,
+
+    #[command(flatten)]
+    save: Option<SaveArgs>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Save a `.mtvox` file in another output format, without voxelizing the model again
+    Convert(ConvertArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ConvertArgs {
+    /// The `.mtvox` file that will be converted
     input: String,
 
-    /// The output file after voxelization
+    #[command(flatten)]
+    save: SaveArgs,
+}
+
+/// The options of the output, shared by voxelizing and `convert`
+#[derive(clap::Args, Debug)]
+#[allow(
+    clippy::struct_excessive_bools,
+    reason = "the command line switches, which clap parses into separate fields"
+)]
+struct SaveArgs {
+    /// The output file
     #[arg(short, long)]
     output: String,
 
-    /// The resolution of the output model
-    #[arg(long, default_value_t = 1022)]
-    dim: u32,
+    /// The format of the output file, inferred from its extension if not specified (`glb`
+    /// writes the binary glTF format, as does `gltf` into a `.glb` file)
+    #[arg(long, value_enum)]
+    output_format: Option<OutputType>,
 
     #[arg(long, action = clap::ArgAction::Set, default_value_t = true)]
     sparse: bool,
+
+    /// Remove the groups of connected voxels made of fewer than this many voxels, i.e. the stray
+    /// voxels left by thin triangles and noisy scans
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    denoise: Option<u64>,
+
+    /// Which voxels are connected for `--denoise`, the ones sharing a face (6) or the ones
+    /// sharing a face, an edge or a corner (26)
+    #[arg(long, value_enum, default_value_t = denoise::Connectivity::Corners)]
+    connectivity: denoise::Connectivity,
+
+    /// Keep only the voxels of a hex color (i.e. `#ff8000`), which isolates the regions painted
+    /// in it, like a logo or a decal. Combine it with `--split-by` to extract a color from
+    /// every part separately
+    #[arg(long, value_parser = parse_extract_color)]
+    extract_color: Option<image::Rgba<u8>>,
+
+    /// How much the red, green and blue channels of a voxel can differ from `--extract-color`
+    /// for the voxel to be kept
+    #[arg(long, default_value_t = 0, requires = "extract_color")]
+    extract_tolerance: u8,
+
+    /// Fill the inside of closed models with voxels of a hex color (i.e. `#ff8000`), or with
+    /// `nearest`, with the color of the nearest voxel of the surface
+    #[arg(long, value_parser = parse_fill_color)]
+    fill_color: Option<FillColor>,
+
+    /// How the inside of the model is found for `--fill-color`. `scanline` is faster on large
+    /// grids and doesn't need the padding, but can fill the wrong voxels around holes in the
+    /// surface
+    #[arg(long, value_enum, default_value_t = FillMethod::FloodFill)]
+    fill_method: FillMethod,
+
+    /// Thin the filled model down to a skeleton one voxel wide, which leaves the curves running
+    /// through the middle of its parts (i.e. the center lines of pipes or branches)
+    #[arg(long, requires = "fill_color")]
+    skeletonize: bool,
+
+    /// Punch this many holes through the bottom of the hollow model, so that the resin trapped
+    /// inside can drain out when it's printed. The holes go up from the lowest points of the
+    /// surface, along the axis of `--source-up`
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "fill_color")]
+    drain_holes: Option<u32>,
+
+    /// The radius of the holes of `--drain-holes`, in voxels
+    #[arg(long, default_value_t = 2, requires = "drain_holes")]
+    drain_radius: u32,
+
+    /// Remap the colors of the model through a lookup table, either a `256x1` image that maps
+    /// every channel separately, or a Hald CLUT (i.e. a `512x512` image for 64 colors per
+    /// channel). The colors are remapped before `--max-colors` limits them
+    #[arg(long, value_parser = parse_lut)]
+    lut: Option<lut::Lut>,
+
+    /// Limit the model to at most this many colors, chosen adaptively from the colors of
+    /// the model
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    max_colors: Option<u16>,
+
+    /// The axis pointing up in the input file. The `.vox` and Goxel `.txt` outputs are Z-up, so
+    /// Y-up input is turned a quarter turn around X, and the `.gltf` output is Y-up, so Z-up
+    /// input is turned back the other way
+    #[arg(long, value_enum, default_value_t = io::UpAxis::Y)]
+    source_up: io::UpAxis,
+
+    /// Add a floor grid under the model to the `.vox` output, with a line every this many
+    /// voxels, as a separate `grid` layer for judging the scale of the model in MagicaVoxel
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    vox_grid: Option<u32>,
+
+    /// The order of the axes of the `.raw` output, from the slowest to the fastest changing
+    /// index (i.e. `xyz` for an array indexed as `[x][y][z]`)
+    #[arg(long, value_enum, default_value_t = raw::AxisOrder::Xyz)]
+    axis_order: raw::AxisOrder,
+
+    /// What the `.raw` output holds for every voxel, its RGBA color or a single occupancy byte
+    /// (1 for a voxel, 0 for empty space)
+    #[arg(long, value_enum, default_value_t = raw::RawChannels::Rgba)]
+    raw_channels: raw::RawChannels,
+
+    /// The color of empty voxels in the RGBA `.raw` output, as `#rrggbbaa` or `#rrggbb`. Voxels are
+    /// always opaque, so the default fully transparent color tells empty space apart from black
+    /// voxels
+    #[arg(long, value_parser = parse_air_color, default_value = "#00000000")]
+    air_color: image::Rgba<u8>,
+
+    /// How the `.ply` output is encoded
+    #[arg(long, value_enum, default_value_t = ply::PlyFormat::Binary)]
+    ply_format: ply::PlyFormat,
+
+    /// Write the faces of the voxels to the `.ply` output as triangles, instead of a point at
+    /// the center of every voxel. Only the faces visible from the outside are written, unless
+    /// `--sparse false` is set
+    #[arg(long)]
+    ply_faces: bool,
+
+    /// Write `NORMAL` and `TANGENT` attributes to the `.gltf` output
+    #[arg(long)]
+    tangents: bool,
+
+    /// Darken the vertex colors of the `.gltf` output in corners occluded by neighboring
+    /// voxels, which gives depth to renders that ignore lighting
+    #[arg(long)]
+    bake_ao: bool,
+
+    /// Write the number of voxels occluding the corner of every vertex (0 to 3) as a custom
+    /// `_AO` attribute of the `.gltf` output, for engines that apply their own ambient occlusion
+    #[arg(long)]
+    ao_attribute: bool,
+
+    /// Write a primitive with its own material for every color of the `.gltf` output instead
+    /// of vertex colors. Combine with `--max-colors` to limit the number of primitives
+    #[arg(long)]
+    primitive_per_color: bool,
+
+    /// Merge the neighboring faces of the same color in the same plane of the `.gltf` output
+    /// into rectangles, which takes far fewer triangles for flat surfaces
+    #[arg(long)]
+    greedy: bool,
+
+    /// The color space of the colors of the `.gltf` output. glTF viewers expect linear colors,
+    /// so `srgb` is only needed for viewers that don't follow the specification
+    #[arg(long, value_enum, default_value_t = io::ColorSpace::Linear)]
+    color_space: io::ColorSpace,
+
+    /// The coordinate system of the engine that the `.gltf` output is made for, which is
+    /// converted to by the transform of the model
+    #[arg(long, value_enum, default_value_t = io::CoordinateSystem::Gltf)]
+    coordinate_system: io::CoordinateSystem,
+
+    /// Embed the buffer of the `.gltf` output into the file as a base64 data URI, instead of
+    /// writing it to a separate `.bin` file. A `.glb` output always holds its buffer in its
+    /// binary chunk, so it can't be combined with this
+    #[arg(long)]
+    embed_buffer: bool,
+
+    /// Print a hash of the voxels of every output, which is the same for identical voxels, so
+    /// that build systems can skip exporting again
+    #[arg(long)]
+    print_hash: bool,
+
+    /// Render the model from this many angles around its vertical axis, and save every render
+    /// next to the output as a `.png` file named by suffixing the output file (i.e.
+    /// `model_thumb_000.png` for `model.vox`)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=1000))]
+    turntable: Option<u32>,
+
+    /// Write the duration of every stage, the number of triangles and voxels, the memory used
+    /// and the arguments of the run to this `.json` file, for tracking the performance
+    #[arg(long)]
+    profile_output: Option<String>,
+
+    /// Replace the output files if they already exist (default)
+    #[arg(long, overrides_with = "no_overwrite")]
+    overwrite: bool,
+
+    /// Fail instead of replacing output files that already exist
+    #[arg(long, overrides_with = "overwrite")]
+    no_overwrite: bool,
+}
+
+impl SaveArgs {
+    /// Returns the format of the output, and fails if the output options conflict
+    fn output_type(&self) -> Result<OutputType> {
+        if self.primitive_per_color && self.bake_ao {
+            bail!(
+                "`--bake-ao` can't be used with `--primitive-per-color`, which has no vertex colors"
+            );
+        }
+
+        let output_type = match self.output_format {
+            Some(output_type) => output_type,
+            None => {
+                OutputType::from_file(&self.output).context("failed to infer output file type")?
+            }
+        };
+
+        if self.vox_grid.is_some() && !matches!(output_type, OutputType::MagicaVoxel) {
+            bail!("`--vox-grid` can only be used with the `.vox` output");
+        }
+
+        if self.embed_buffer && self.gltf_buffer() == gltf2::GltfBuffer::Binary {
+            bail!("`--embed-buffer` can't be used with the `.glb` output, which holds its buffer");
+        }
+
+        if self.greedy && !matches!(output_type, OutputType::Gltf | OutputType::Glb) {
+            bail!("`--greedy` can only be used with the `.gltf` output");
+        }
+
+        if self.greedy && (self.bake_ao || self.ao_attribute) {
+            bail!(
+                "`--greedy` can't be used with `--bake-ao` or `--ao-attribute`, which vary across the merged faces"
+            );
+        }
+
+        if self.ply_faces && !matches!(output_type, OutputType::Ply) {
+            bail!("`--ply-faces` can only be used with the `.ply` output");
+        }
+
+        Ok(output_type)
+    }
+
+    /// Returns where the buffer of the `.gltf` output is stored, which is inside of the file
+    /// for the `glb` output format or the `.glb` extension
+    fn gltf_buffer(&self) -> gltf2::GltfBuffer {
+        let binary = self.output_format == Some(OutputType::Glb)
+            || get_extension(&self.output)
+                .is_ok_and(|extension| extension.eq_ignore_ascii_case("glb"));
+
+        if binary {
+            gltf2::GltfBuffer::Binary
+        } else if self.embed_buffer {
+            gltf2::GltfBuffer::Embedded
+        } else {
+            gltf2::GltfBuffer::External
+        }
+    }
+
+    /// Returns the options of the `.gltf` output, without any bounds
+    fn gltf_options<'a>(&self, winding: Option<&'a VoxelNormals>) -> io::GltfOptions<'a> {
+        io::GltfOptions {
+            sparse: self.sparse,
+            float: true,
+            tangents: self.tangents,
+            buffer: self.gltf_buffer(),
+            bake_ao: self.bake_ao,
+            ao_attribute: self.ao_attribute,
+            winding,
+            primitive_per_color: self.primitive_per_color,
+            greedy: self.greedy,
+            color_space: self.color_space,
+            up: self.source_up,
+            coordinate_system: self.coordinate_system,
+            bounds: None,
+            cancel: Some(&CANCELLED),
+        }
+    }
+
+    /// Returns whether saving to `output_type` meshes only the faces visible from the outside,
+    /// which requires the padding
+    const fn sparse_meshing(&self, output_type: OutputType) -> bool {
+        self.sparse
+            && match output_type {
+                OutputType::Gltf | OutputType::Glb => true,
+                OutputType::Ply => self.ply_faces,
+                _ => false,
+            }
+    }
+
+    /// Returns the options of the `.ply` output
+    fn ply_options(&self) -> ply::PlyOptions<'static> {
+        ply::PlyOptions {
+            format: self.ply_format,
+            faces: self.ply_faces,
+            sparse: self.sparse,
+            cancel: Some(&CANCELLED),
+        }
+    }
+
+    /// Returns the options of the `.vox` output
+    const fn vox_options(&self) -> io::VoxOptions {
+        io::VoxOptions {
+            up: self.source_up,
+            grid: self.vox_grid,
+        }
+    }
 }
 
+#[derive(clap::Args, Debug)]
+#[allow(
+    clippy::struct_excessive_bools,
+    reason = "the switches of voxelizing, which clap parses into separate fields"
+)]
+struct Args {
+    /// The input file that will be voxelized
+    #[arg(short, long)]
+    input: String,
+
+    /// The format of the input file, inferred from its extension if not specified
+    #[arg(long, value_enum)]
+    input_format: Option<InputType>,
+
+    /// The resolution of the output model, at least 8 and at most 1073741822
+    #[arg(long, default_value_t = 1022, value_parser = clap::value_parser!(u32).range(i64::from(MIN_SIZE)..=i64::from(MAX_SIZE)))]
+    dim: u32,
+
+    /// The size of a voxel in the units of the input file, which picks the smallest `--dim`
+    /// with voxels at most this wide instead
+    #[arg(long, value_parser = parse_positive, conflicts_with_all = ["dim", "no_fit", "refine_dim", "append_to"])]
+    voxel_size: Option<f32>,
+
+    /// Scale the model to fill the whole grid instead of leaving a one voxel gap around it.
+    /// The gap is required by the sparse `.gltf` output, so this needs `--sparse false`
+    #[arg(long)]
+    no_padding: bool,
+
+    /// How the color of a voxel touched by multiple triangles is chosen
+    #[arg(long, value_enum, default_value_t = ConflictPolicy::First)]
+    conflict: ConflictPolicy,
+
+    /// How the model is fitted into the grid
+    #[arg(long, value_enum, default_value_t = io::Fit::Box)]
+    fit: io::Fit,
+
+    /// Map the units of the input file directly to voxels instead of fitting the model into the
+    /// grid, so that the point `(x, y, z)` lands in the voxel `(x, y, z)` of the model (the first
+    /// voxel after the padding being `(0, 0, 0)`). Fails if the model reaches outside of the
+    /// `--dim` voxels of the grid
+    #[arg(long, conflicts_with = "fit")]
+    no_fit: bool,
+
+    /// The number of voxels per unit of the input file for `--no-fit`
+    #[arg(long, value_parser = parse_positive, requires = "no_fit")]
+    unit_scale: Option<f32>,
+
+    /// The deepest level of the octree that voxels are stored at, which bounds its memory. The
+    /// voxels below it are averaged into blocks, twice as wide for every level less than the
+    /// `log2(dim + 2) - 1` levels of the full resolution
+    #[arg(long)]
+    max_depth: Option<u32>,
+
+    /// The largest number of lines a triangle is rasterized with. Larger triangles are
+    /// rasterized like with `--conservative` instead, which takes about as long, but doesn't
+    /// overlap the lines near the corner they start from
+    #[arg(long, default_value_t = voxelizer::DEFAULT_MAX_STEPS, value_parser = clap::value_parser!(u32).range(1..))]
+    max_steps: u32,
+
+    /// Fill every voxel that a triangle passes through, testing the triangle against the box
+    /// of every voxel instead of rasterizing it line by line. It's slower, but long and thin
+    /// triangles don't leave pinholes in the surface
+    #[arg(long, conflicts_with = "max_steps")]
+    conservative: bool,
+
+    /// Sample textures from a mip level matching the size of a voxel, which averages the
+    /// texels covered by a voxel instead of picking a single one
+    #[arg(long)]
+    mipmap: bool,
+
+    /// The point of a triangle that its texture (and its vertex colors) is sampled at for a
+    /// voxel. `plane` samples where the triangle passes through the voxel, which is more
+    /// accurate on thin, angled surfaces
+    #[arg(long, value_enum, default_value_t = voxelizer::UvSampling::Closest)]
+    uv_sampling: voxelizer::UvSampling,
+
+    /// How the color of a texture is read at the sampled point. `bilinear` blends the four
+    /// nearest texels, which smooths out textures with fewer texels than voxels, while
+    /// `nearest` keeps the hard edges of pixel art
+    #[arg(long, value_enum, default_value_t = voxelizer::TextureFilter::Nearest)]
+    texture_filter: voxelizer::TextureFilter,
+
+    /// A box in the space of the input file, as `minx,miny,minz,maxx,maxy,maxz`, that is
+    /// voxelized again at the higher resolution `--refine-dim`, replacing the voxels of `--dim`
+    #[arg(long, value_parser = parse_region, requires = "refine_dim", allow_hyphen_values = true)]
+    refine: Option<BoundingBox>,
+
+    /// The resolution of the `--refine` region, which has to be `--dim` times a power of two
+    #[arg(long, requires = "refine")]
+    refine_dim: Option<u32>,
+
+    /// A `.mtvox` file to voxelize the mesh into. The mesh has to fit into the grid of the file,
+    /// and `--dim` has to match the one used to create it
+    #[arg(long)]
+    append_to: Option<String>,
+
+    /// Fail if the input file uses features that would be ignored during voxelization
+    #[arg(long)]
+    strict: bool,
+
+    /// Color materials with an emissive texture (i.e. screens and displays) by that texture,
+    /// and export the resulting voxels with an emissive material
+    #[arg(long)]
+    screens: bool,
+
+    /// Give the vertices that the input file has no normals for the face normal of their
+    /// triangle, following its winding
+    #[arg(long)]
+    compute_normals: bool,
+
+    /// Save every material or every mesh of the input file to a separate file, named by
+    /// suffixing the output file (i.e. `model_material0.vox`)
+    #[arg(long, value_enum)]
+    split_by: Option<io::SplitBy>,
+
+    /// Put every material or every mesh of the input file into a separate, named layer of
+    /// the `.vox` output, or a separate, named node of the `.gltf` output
+    #[arg(long, value_enum)]
+    layers_by: Option<io::SplitBy>,
+
+    /// Orient the faces of the `.gltf` output by the winding of the source triangles, so that
+    /// the faces of single sided surfaces keep facing the same way as the source surface
+    #[arg(long)]
+    winding: bool,
+
+    /// Add the bounds of the source mesh to the `.gltf` output as a wireframe box, for
+    /// checking how the voxels line up with the source
+    #[arg(long)]
+    emit_bounds: bool,
+
+    /// Voxelize only the largest group of triangles connected through shared vertices,
+    /// dropping stray geometry like backdrop planes, which would otherwise take up the grid
+    #[arg(long)]
+    largest_component: bool,
+}
+
+impl Args {
+    /// Checks the combinations of arguments that can't be used together
+    fn check(&self, save: &SaveArgs, output_type: OutputType) -> Result<()> {
+        if self.no_padding && save.sparse_meshing(output_type) {
+            bail!(
+                "`--no-padding` can't be used with `--sparse true`, sparse meshing requires the padding"
+            );
+        }
+
+        if self.no_padding && save.fill_color.is_some() && save.fill_method == FillMethod::FloodFill
+        {
+            bail!(
+                "`--no-padding` can't be used with `--fill-color` and the default `--fill-method flood-fill`, the flood fill requires the padding"
+            );
+        }
+
+        if self.split_by.is_some() && self.append_to.is_some() {
+            bail!("`--split-by` can't be used with `--append-to`");
+        }
+
+        if self.winding && save.greedy {
+            bail!("`--greedy` can't be used with `--winding`, which flips single faces");
+        }
+
+        if self.emit_bounds && !matches!(output_type, OutputType::Gltf | OutputType::Glb) {
+            bail!("`--emit-bounds` can only be used with the `.gltf` output");
+        }
+
+        if let Some(by) = self.layers_by {
+            if !matches!(
+                output_type,
+                OutputType::MagicaVoxel | OutputType::Gltf | OutputType::Glb
+            ) {
+                bail!("`--layers-by` can only be used with the `.vox` and `.gltf` outputs");
+            }
+
+            if self.winding {
+                bail!("`--layers-by {}` can't be used with `--winding`", by.name());
+            }
+
+            if self.split_by.is_some() || self.append_to.is_some() {
+                bail!(
+                    "`--layers-by {}` can't be used with `--split-by` or `--append-to`",
+                    by.name()
+                );
+            }
+
+            if save.turntable.is_some() {
+                bail!(
+                    "`--layers-by {}` can't be used with `--turntable`",
+                    by.name()
+                );
+            }
+        }
+
+        if let Some(refine_dim) = self.refine_dim {
+            if refine_levels(self.dim, refine_dim).is_none() {
+                bail!(
+                    "`--refine-dim {refine_dim}` has to be `--dim {}` times a power of two, and at most {MAX_SIZE}",
+                    self.dim
+                );
+            }
+
+            if matches!(output_type, OutputType::Octree) {
+                bail!("`--refine` can't be used with the `.mtvox` output");
+            }
+
+            if self.split_by.is_some() || self.layers_by.is_some() || self.append_to.is_some() {
+                bail!("`--refine` can't be used with `--split-by`, `--layers-by` or `--append-to`");
+            }
+
+            if self.winding || self.emit_bounds {
+                bail!("`--refine` can't be used with `--winding` or `--emit-bounds`");
+            }
+        }
+
+        if self.max_depth.is_some() && (self.refine.is_some() || self.winding) {
+            bail!("`--max-depth` can't be used with `--refine` or `--winding`");
+        }
+
+        Ok(())
+    }
+
+    /// Returns the options of the voxelization
+    fn voxelize_options(&self) -> VoxelizeOptions {
+        VoxelizeOptions {
+            mode: if self.conservative {
+                VoxelizationMode::Conservative
+            } else {
+                VoxelizationMode::Triangles
+            },
+            conflict: self.conflict,
+            fit: self.fit,
+            max_steps: self.max_steps,
+            mipmap: self.mipmap,
+            uv_sampling: self.uv_sampling,
+            texture_filter: self.texture_filter,
+            padding: !self.no_padding,
+            unit_scale: self.no_fit.then(|| self.unit_scale.unwrap_or(1.0)),
+            max_depth: self.max_depth,
+        }
+    }
+
+    /// Returns the format of the input
+    fn input_type(&self) -> Result<InputType> {
+        self.input_format.map_or_else(
+            || InputType::from_file(&self.input).context("failed to infer input file type"),
+            Ok,
+        )
+    }
+}
+
+/// Set by Ctrl-C, which cancels the voxelization instead of killing the process, so that
+/// no partially written output is left behind
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn handle_interrupt() {
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        CANCELLED.store(true, Ordering::Relaxed);
+
+        // a second Ctrl-C kills the process as usual
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+    }
+
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_interrupt() {}
+
 fn main() -> Result<()> {
+    #[cfg(feature = "profile")]
     tracy_client::Client::start();
 
+    handle_interrupt();
+
     rayon::ThreadPoolBuilder::new()
-        .num_threads(
-            std::thread::available_parallelism()
-                .map(|t| t.get())
-                .unwrap_or(2),
-        )
+        .num_threads(std::thread::available_parallelism().map_or(2, std::num::NonZero::get))
         .build_global()?;
 
-    let args = Args::parse();
-    voxelize_mesh(&args)
+    let cli = Cli::parse();
+
+    if cli.self_test {
+        return self_test();
+    }
+
+    if let Some(input) = &cli.list_materials {
+        return list_materials(input);
+    }
+
+    match (&cli.command, &cli.args, &cli.save) {
+        (Some(Command::Convert(args)), _, _) => {
+            run_profiled(&args.save, |profile| convert(args, profile))
+        }
+        (None, Some(args), Some(save)) => {
+            run_profiled(save, |profile| voxelize_mesh(args, save, profile))
+        }
+        // clap requires the arguments of voxelization without a subcommand
+        _ => unreachable!(),
+    }
 }
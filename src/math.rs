@@ -3,7 +3,12 @@ pub use glam::*;
 /// Given a triangle `a, b, c`, and a point `p`, returns the point on the triangle
 /// that is the closest to the point `p`.
 ///
-/// https://github.com/embree/embree/blob/master/tutorials/common/math/closest_point.h
+/// <https://github.com/embree/embree/blob/master/tutorials/common/math/closest_point.h>
+#[must_use]
+#[allow(
+    clippy::many_single_char_names,
+    reason = "the names of the embree reference"
+)]
 pub fn closest_point_triangle(p: Vec3, tri: [Vec3; 3]) -> Vec3 {
     let [a, b, c] = tri;
 
@@ -15,35 +20,35 @@ pub fn closest_point_triangle(p: Vec3, tri: [Vec3; 3]) -> Vec3 {
     let d2 = ac.dot(ap);
     if d1 <= 0.0 && d2 <= 0.0 {
         return a;
-    };
+    }
 
     let bp = p - b;
     let d3 = ab.dot(bp);
     let d4 = ac.dot(bp);
     if d3 >= 0.0 && d4 <= d3 {
         return b;
-    };
+    }
 
     let cp = p - c;
     let d5 = ab.dot(cp);
     let d6 = ac.dot(cp);
     if d6 >= 0.0 && d5 <= d6 {
         return c;
-    };
+    }
 
-    let vc = d1 * d4 - d3 * d2;
+    let vc = d1.mul_add(d4, -d3 * d2);
     if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
         let v = d1 / (d1 - d3);
         return a + v * ab;
     }
 
-    let vb = d5 * d2 - d1 * d6;
+    let vb = d5.mul_add(d2, -d1 * d6);
     if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
         let v = d2 / (d2 - d6);
         return a + v * ac;
     }
 
-    let va = d3 * d6 - d5 * d4;
+    let va = d3.mul_add(d6, -d5 * d4);
     if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
         let v = (d4 - d3) / ((d4 - d3) + (d5 - d6));
         return b + v * (c - b);
@@ -52,10 +57,11 @@ pub fn closest_point_triangle(p: Vec3, tri: [Vec3; 3]) -> Vec3 {
     let denom = 1.0 / (va + vb + vc);
     let v = vb * denom;
     let w = vc * denom;
-    return a + v * ab + w * ac;
+    a + v * ab + w * ac
 }
 
 /// Returns the normal vector of the triangle `a, b, c`
+#[must_use]
 pub fn get_normal(tri: [Vec3; 3]) -> Vec3 {
     let [a, b, c] = tri;
 
@@ -64,7 +70,13 @@ pub fn get_normal(tri: [Vec3; 3]) -> Vec3 {
 
 /// Given a triangle `a, b, c`, and a point `p`, returns the barycentric coordinates of the point `p`.
 ///
-/// https://gamedev.stackexchange.com/questions/23743/whats-the-most-efficient-way-to-find-barycentric-coordinates
+/// <https://gamedev.stackexchange.com/questions/23743/whats-the-most-efficient-way-to-find-barycentric-coordinates>
+#[must_use]
+#[allow(
+    clippy::many_single_char_names,
+    clippy::similar_names,
+    reason = "the areas are named after their corners"
+)]
 pub fn get_barycentric_coordinates(p: Vec3, tri: [Vec3; 3]) -> Vec3 {
     let [a, b, c] = tri;
 
@@ -77,9 +89,57 @@ pub fn get_barycentric_coordinates(p: Vec3, tri: [Vec3; 3]) -> Vec3 {
     let x = area_pbc / area_abc;
     let y = area_pca / area_abc;
 
-    let bary = Vec3::new(x, y, 1.0 - (x + y));
+    Vec3::new(x, y, 1.0 - (x + y))
+}
+
+/// Returns whether the triangle passes through the voxel `voxel`, the box from `voxel` to
+/// `voxel + 1`
+///
+/// The test uses the separating axis theorem: the triangle misses the box if they don't
+/// overlap along one of the axes of the box, the normal of the triangle or the cross
+/// products of the axes of the box and the edges of the triangle. The box contains its lower
+/// sides but not the upper ones, so a triangle lying on the side between two voxels only
+/// passes through one of them
+///
+/// <https://fileadmin.cs.lth.se/cs/Personal/Tomas_Akenine-Moller/code/tribox_tam.pdf>
+#[must_use]
+pub fn triangle_intersects_voxel(tri: [Vec3; 3], voxel: IVec3) -> bool {
+    let min = voxel.as_vec3();
+    let tri_min = tri[0].min(tri[1]).min(tri[2]);
+    let tri_max = tri[0].max(tri[1]).max(tri[2]);
+
+    if tri_min.cmpge(min + 1.0).any() || tri_max.cmplt(min).any() {
+        return false;
+    }
+
+    // the rest of the axes are tested around the center of the box
+    let center = min + 0.5;
+    let [a, b, c] = tri.map(|vertex| vertex - center);
+    let edges = [b - a, c - b, a - c];
+
+    // the normal isn't normalized, so degenerate triangles are only tested along the edges
+    let normal = edges[0].cross(edges[1]);
+    let box_axes = [Vec3::X, Vec3::Y, Vec3::Z];
+
+    let axes = std::iter::once(normal).chain(
+        box_axes
+            .into_iter()
+            .flat_map(|axis| edges.map(|edge| axis.cross(edge))),
+    );
+
+    for axis in axes {
+        let projections = [a.dot(axis), b.dot(axis), c.dot(axis)];
+        let radius = axis.abs().element_sum() * 0.5;
+
+        let tri_min = projections[0].min(projections[1]).min(projections[2]);
+        let tri_max = projections[0].max(projections[1]).max(projections[2]);
+
+        if tri_min > radius || tri_max < -radius {
+            return false;
+        }
+    }
 
-    bary
+    true
 }
 
 #[must_use]
@@ -112,6 +172,7 @@ impl BoundingBox {
         this
     }
 
+    #[must_use]
     pub fn size(&self) -> Vec3 {
         self.max - self.min
     }
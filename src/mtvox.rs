@@ -0,0 +1,215 @@
+use crate::voxelizer::{MAX_SIZE, depth_for_size};
+use crate::{BoundingBox, MeshToVoxError, Octree, Result};
+use glam::Vec3;
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 4] = b"MTVX";
+
+/// The version of the files that are written. Version 1 files lack the checksum, and are
+/// still read without verifying it
+const VERSION: u32 = 2;
+
+/// Hashes the bytes passing through a reader or a writer with 64-bit FNV-1a, which is
+/// written after the data as the checksum of a file
+struct Checksummed<T> {
+    inner: T,
+    hash: u64,
+}
+
+impl<T> Checksummed<T> {
+    const fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hash: 0xcbf2_9ce4_8422_2325,
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash = (self.hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+impl<T: Read> Read for Checksummed<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.update(&buf[..read]);
+
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for Checksummed<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.update(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Describes how the grid of a serialized octree is placed in the world, so that more
+/// meshes can later be voxelized into the same grid
+#[derive(Debug, Clone, Copy)]
+pub struct GridInfo {
+    pub size: u32,
+    pub padding: bool,
+    pub bounds: BoundingBox,
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_vec3(reader: &mut impl Read) -> std::io::Result<Vec3> {
+    let mut bytes = [0; 12];
+    reader.read_exact(&mut bytes)?;
+    Ok(Vec3::from_array(bytemuck::cast(bytes)))
+}
+
+impl Octree {
+    /// Writes the octree into a `.mtvox` file, followed by a checksum of everything after the
+    /// version
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Io`] if the file can't be written
+    pub fn save_to_file(&self, file_path: &str, info: &GridInfo) -> Result<()> {
+        let write = || -> std::io::Result<()> {
+            let file = std::fs::File::create(file_path)?;
+            let mut file = std::io::BufWriter::new(file);
+
+            file.write_all(MAGIC)?;
+            file.write_all(&VERSION.to_le_bytes())?;
+
+            let mut writer = Checksummed::new(&mut file);
+            writer.write_all(&self.depth.to_le_bytes())?;
+            writer.write_all(&info.size.to_le_bytes())?;
+            writer.write_all(&u32::from(info.padding).to_le_bytes())?;
+            writer.write_all(bytemuck::bytes_of(&info.bounds.min.to_array()))?;
+            writer.write_all(bytemuck::bytes_of(&info.bounds.max.to_array()))?;
+
+            writer.write_all(&(self.data.len() as u64).to_le_bytes())?;
+            for value in &self.data {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+
+            let checksum = writer.hash;
+            file.write_all(&checksum.to_le_bytes())?;
+            file.flush()
+        };
+
+        write().map_err(MeshToVoxError::io(file_path))
+    }
+
+    /// Reads an octree written by [`Octree::save_to_file`], by this or an earlier version of
+    /// the crate. The checksum of the file is verified, which catches truncated and corrupted
+    /// files
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Io`] if the file can't be read, or [`crate::MeshToVoxError::InvalidOctree`]
+    /// if it's truncated, corrupted or written by a newer version of the crate
+    pub fn load_from_file(file_path: &str) -> Result<(Self, GridInfo)> {
+        let invalid = |reason: String| MeshToVoxError::InvalidOctree {
+            path: file_path.into(),
+            reason,
+        };
+
+        // running out of data means that the file is truncated, not that reading it failed
+        let read_error = |source: std::io::Error| {
+            if source.kind() == std::io::ErrorKind::UnexpectedEof {
+                invalid("the file is truncated".to_string())
+            } else {
+                MeshToVoxError::io(file_path)(source)
+            }
+        };
+
+        let file = std::fs::File::open(file_path).map_err(MeshToVoxError::io(file_path))?;
+        let mut file = std::io::BufReader::new(file);
+
+        let mut magic = [0; 4];
+        file.read_exact(&mut magic).map_err(read_error)?;
+        if &magic != MAGIC {
+            return Err(invalid("missing the `MTVX` header".to_string()));
+        }
+
+        let version = read_u32(&mut file).map_err(read_error)?;
+        if version > VERSION {
+            return Err(invalid(format!(
+                "version {version} was written by a newer version of the program, update it or voxelize the mesh again"
+            )));
+        }
+
+        if version == 0 {
+            return Err(invalid("unsupported version 0".to_string()));
+        }
+
+        let mut reader = Checksummed::new(&mut file);
+
+        let depth = read_u32(&mut reader).map_err(read_error)?;
+        let size = read_u32(&mut reader).map_err(read_error)?;
+
+        if size > MAX_SIZE || depth != depth_for_size(size) {
+            return Err(invalid(format!(
+                "invalid grid with size {size} and depth {depth}"
+            )));
+        }
+        let padding = read_u32(&mut reader).map_err(read_error)? != 0;
+        let min = read_vec3(&mut reader).map_err(read_error)?;
+        let max = read_vec3(&mut reader).map_err(read_error)?;
+
+        let mut len = [0; 8];
+        reader.read_exact(&mut len).map_err(read_error)?;
+        let len = u64::from_le_bytes(len);
+
+        // a corrupted length would otherwise allocate more memory than the file could fill
+        let file_size = reader
+            .inner
+            .get_ref()
+            .metadata()
+            .map_err(MeshToVoxError::io(file_path))?
+            .len();
+
+        if len.saturating_mul(size_of::<u32>() as u64) > file_size {
+            return Err(invalid("the file is truncated".to_string()));
+        }
+
+        let len = usize::try_from(len)
+            .map_err(|_| invalid("the file is too large to be loaded".to_string()))?;
+
+        let mut bytes = vec![0; len * size_of::<u32>()];
+        reader.read_exact(&mut bytes).map_err(read_error)?;
+
+        // the format is otherwise unchanged since version 1, which has no checksum
+        if version >= 2 {
+            let checksum = reader.hash;
+            let mut stored = [0; 8];
+            file.read_exact(&mut stored).map_err(read_error)?;
+
+            if u64::from_le_bytes(stored) != checksum {
+                return Err(invalid(
+                    "the checksum doesn't match, the file is corrupted".to_string(),
+                ));
+            }
+        }
+
+        let data = bytes
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect();
+
+        let info = GridInfo {
+            size,
+            padding,
+            bounds: BoundingBox { min, max },
+        };
+
+        Ok((Self { data, depth }, info))
+    }
+}
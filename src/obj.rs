@@ -0,0 +1,554 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::io::{
+    DEFAULT_ALPHA_CUTOFF, Emission, ImageOrColor, Material, TextureMapping, UnsupportedFeatures,
+    VertexExtras, View, WrapMode,
+};
+use crate::{BoundingBox, LoadOptions, Mesh, MeshToVoxError, Result};
+use glam::{Mat4, Vec2, Vec3};
+use rayon::prelude::*;
+
+/// The material index of the faces that don't use a material, which is replaced by the index
+/// of the default material once every material is known
+const DEFAULT_MATERIAL: u32 = u32::MAX;
+
+/// The parts of a material of an `.mtl` file that color the voxels. The lighting parameters
+/// (i.e. `Ka`, `Ks`, `Ns` and `illum`) don't affect the colors, and are ignored
+#[derive(Debug, Clone)]
+struct MtlMaterial {
+    name: String,
+    /// `Kd`
+    diffuse: Vec3,
+    /// `d`, or one minus `Tr`
+    alpha: f32,
+    /// `map_Kd`
+    diffuse_map: Option<TextureMap>,
+    /// `map_d`, which only marks the material as masked, the alpha is read from `map_Kd`
+    alpha_map: Option<TextureMap>,
+    /// `Ke`
+    emissive: Option<Vec3>,
+    /// `map_Ke`
+    emissive_map: Option<TextureMap>,
+}
+
+impl MtlMaterial {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            diffuse: Vec3::ONE,
+            alpha: 1.0,
+            diffuse_map: None,
+            alpha_map: None,
+            emissive: None,
+            emissive_map: None,
+        }
+    }
+}
+
+/// A texture of a material of an `.mtl` file
+#[derive(Debug, Clone)]
+struct TextureMap {
+    /// The path of the image, resolved relative to the `.mtl` file
+    path: PathBuf,
+    /// Set by `-clamp on`, which clamps the texture coordinates instead of repeating the
+    /// texture
+    clamp: bool,
+}
+
+impl TextureMap {
+    /// Parses the arguments of a `map_*` statement, which are options followed by the file
+    /// name. The file name can contain spaces, so it's the rest of the line after the options
+    fn parse(args: &str, folder: &Path, unsupported: &mut UnsupportedFeatures) -> Self {
+        let mut tokens = args.split_whitespace().peekable();
+        let mut clamp = false;
+
+        while let Some(option) = tokens.next_if(|token| token.starts_with('-')) {
+            // the number of values that follow every option
+            let values = match option {
+                "-o" | "-s" | "-t" => 3,
+                "-mm" => 2,
+                _ => 1,
+            };
+
+            let mut arguments = Vec::new();
+
+            for _ in 0..values {
+                match tokens.next_if(|token| option == "-clamp" || token.parse::<f32>().is_ok()) {
+                    Some(argument) => arguments.push(argument),
+                    None => break,
+                }
+            }
+
+            match option {
+                "-clamp" => clamp = arguments.first() == Some(&"on"),
+                "-o" | "-s" => unsupported.report(format!("`{option}` texture option")),
+                _ => {}
+            }
+        }
+
+        let name = tokens.collect::<Vec<_>>().join(" ");
+
+        Self {
+            path: folder.join(name.replace('\\', "/")),
+            clamp,
+        }
+    }
+
+    fn load(&self) -> Result<(image::RgbaImage, TextureMapping)> {
+        let image = image::open(&self.path).map_err(|source| MeshToVoxError::MissingTexture {
+            path: self.path.clone(),
+            source,
+        })?;
+
+        let wrap = if self.clamp {
+            WrapMode::ClampToEdge
+        } else {
+            WrapMode::Repeat
+        };
+
+        let mapping = TextureMapping {
+            wrap: [wrap; 2],
+            ..TextureMapping::DEFAULT
+        };
+
+        Ok((image.into_rgba8(), mapping))
+    }
+}
+
+/// Returns the statement of a line and its arguments, skipping comments and empty lines
+fn split_statement(line: &str) -> Option<(&str, &str)> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+
+    if line.is_empty() {
+        return None;
+    }
+
+    Some(
+        line.split_once(char::is_whitespace)
+            .map_or((line, ""), |(keyword, args)| (keyword, args.trim())),
+    )
+}
+
+/// Parses the first `N` numbers of the arguments of a statement
+fn parse_floats<const N: usize>(args: &str, file: &Path, line: usize) -> Result<[f32; N]> {
+    let mut values = args.split_whitespace().map(str::parse::<f32>);
+    let mut parsed = [0.0; N];
+
+    for value in &mut parsed {
+        *value = values
+            .next()
+            .and_then(std::result::Result::ok)
+            .ok_or_else(|| {
+                MeshToVoxError::InvalidData(format!(
+                    "`{}` has a malformed statement on line {line}",
+                    file.display()
+                ))
+            })?;
+    }
+
+    Ok(parsed)
+}
+
+/// Parses the materials of an `.mtl` file
+fn load_mtl(path: &Path, unsupported: &mut UnsupportedFeatures) -> Result<Vec<MtlMaterial>> {
+    let text = std::fs::read_to_string(path).map_err(MeshToVoxError::io(path))?;
+    let folder = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut materials: Vec<MtlMaterial> = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let Some((keyword, args)) = split_statement(line) else {
+            continue;
+        };
+
+        if keyword == "newmtl" {
+            materials.push(MtlMaterial::new(args));
+            continue;
+        }
+
+        let Some(material) = materials.last_mut() else {
+            continue;
+        };
+
+        match keyword {
+            "Kd" => material.diffuse = Vec3::from_array(parse_floats(args, path, index + 1)?),
+            "d" => material.alpha = parse_floats::<1>(args, path, index + 1)?[0],
+            "Tr" => material.alpha = 1.0 - parse_floats::<1>(args, path, index + 1)?[0],
+            "Ke" => {
+                material.emissive = Some(Vec3::from_array(parse_floats(args, path, index + 1)?));
+            }
+            "map_Kd" => material.diffuse_map = Some(TextureMap::parse(args, folder, unsupported)),
+            "map_Ke" => material.emissive_map = Some(TextureMap::parse(args, folder, unsupported)),
+            "map_d" => material.alpha_map = Some(TextureMap::parse(args, folder, unsupported)),
+            _ => {}
+        }
+    }
+
+    for material in &materials {
+        let diffuse = material.diffuse_map.as_ref().map(|map| &map.path);
+
+        if let Some(map) = &material.alpha_map
+            && Some(&map.path) != diffuse
+        {
+            unsupported.report("`map_d` with a texture other than `map_Kd`");
+        }
+    }
+
+    Ok(materials)
+}
+
+/// Turns a material of an `.mtl` file into a material of the mesh, the way `parse_material`
+/// of the glTF loader does: the emissive texture colors screens, the diffuse texture colors
+/// the rest, and the diffuse color is used without a texture. The emissive texture and color
+/// are added to the color otherwise
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    reason = "the channels are clamped between 0 and 1 first"
+)]
+fn parse_material(material: &MtlMaterial, options: LoadOptions) -> Result<Material> {
+    // the alpha only cuts out the voxels of materials that are transparent or masked
+    let alpha_cutoff =
+        (material.alpha < 1.0 || material.alpha_map.is_some()).then_some(DEFAULT_ALPHA_CUTOFF);
+
+    let named = |material_data: Material| Material {
+        name: Some(material.name.clone()),
+        alpha_cutoff,
+        ..material_data
+    };
+
+    if options.screens
+        && let Some(map) = &material.emissive_map
+    {
+        let (image, mapping) = map.load()?;
+
+        return Ok(named(Material {
+            mapping,
+            emissive: true,
+            ..Material::new(ImageOrColor::Image(image))
+        }));
+    }
+
+    // unlike in glTF, a texture isn't scaled by a missing factor, which would be black
+    let factor = material.emissive.unwrap_or(Vec3::ONE);
+
+    let emission = match &material.emissive_map {
+        _ if factor.max_element() <= 0.0 => None,
+        Some(map) => Some(Emission::scaled(Some(map.load()?), factor)),
+        None if material.emissive.is_some() => Some(Emission::scaled(None, factor)),
+        None => None,
+    };
+
+    if let Some(map) = &material.diffuse_map {
+        let (image, mapping) = map.load()?;
+
+        return Ok(named(Material {
+            mapping,
+            emission,
+            ..Material::new(ImageOrColor::Image(image))
+        }));
+    }
+
+    let [r, g, b] = material.diffuse.to_array();
+    let color = [r, g, b, material.alpha].map(|channel| (channel.clamp(0.0, 1.0) * 255.0) as u8);
+
+    Ok(named(Material {
+        emission,
+        ..Material::new(ImageOrColor::Color(image::Rgba(color)))
+    }))
+}
+
+/// Returns the index into a list of `count` elements that an `.obj` index refers to. Indices
+/// start at 1, and negative ones count back from the end of the list
+fn resolve_index(index: &str, count: usize, name: &str) -> Result<usize> {
+    let index = index
+        .parse::<i64>()
+        .map_err(|_| MeshToVoxError::InvalidData(format!("malformed {name} index `{index}`")))?;
+
+    let resolved = if index < 0 {
+        usize::try_from(index.unsigned_abs())
+            .ok()
+            .and_then(|back| count.checked_sub(back))
+    } else {
+        usize::try_from(index - 1).ok()
+    };
+
+    resolved
+        .filter(|&resolved| resolved < count)
+        .ok_or_else(|| MeshToVoxError::OutOfRange(format!("{name} index {index}")))
+}
+
+/// Loads a Wavefront `.obj` file, along with the materials of the `.mtl` files it references.
+/// Faces with more than three vertices are split into triangles, and every vertex of a face
+/// can reference its own position, texture coordinate and normal. The objects and groups of
+/// the file are the meshes of the result (see `SplitBy::Mesh`)
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "meshes and materials are indexed by `u32`"
+)]
+#[profiling::function]
+pub fn load_obj(path: &str, options: &LoadOptions) -> Result<Mesh> {
+    let text = std::fs::read_to_string(path).map_err(MeshToVoxError::io(path))?;
+    let file = Path::new(path);
+    let folder = file.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    let mut normals = Vec::new();
+
+    let mut mtl_materials = Vec::new();
+    let mut material_indices = HashMap::new();
+    let mut mesh_indices = HashMap::new();
+    let mut mesh_names = Vec::new();
+
+    let mut material_idx = DEFAULT_MATERIAL;
+    let mut mesh_idx = None;
+
+    let mut triangles = Vec::new();
+    let mut triangle_extras = Vec::new();
+    let mut unsupported = UnsupportedFeatures::default();
+
+    for (index, line) in text.lines().enumerate() {
+        let Some((keyword, args)) = split_statement(line) else {
+            continue;
+        };
+
+        match keyword {
+            "v" => {
+                positions.push(Vec3::from_array(parse_floats(args, file, index + 1)?));
+
+                if args.split_whitespace().count() >= 6 {
+                    unsupported.report("vertex colors");
+                }
+            }
+            "vt" => {
+                // the vertical texture coordinate of `.obj` files points up, unlike the one
+                // of glTF that the textures are sampled with
+                let [u, v] = parse_floats(args, file, index + 1)?;
+                uvs.push(Vec2::new(u, 1.0 - v));
+            }
+            "vn" => normals.push(Vec3::from_array(parse_floats(args, file, index + 1)?)),
+            "f" => {
+                let mesh_idx = *mesh_idx.get_or_insert_with(|| {
+                    mesh_names.push(None);
+                    mesh_names.len() as u32 - 1
+                });
+
+                let mut vertices = Vec::new();
+
+                for vertex in args.split_whitespace() {
+                    let mut indices = vertex.split('/');
+
+                    let position = indices.next().unwrap_or_default();
+                    let position = positions[resolve_index(position, positions.len(), "vertex")?];
+
+                    let uv = match indices.next().filter(|uv| !uv.is_empty()) {
+                        Some(uv) => Some(uvs[resolve_index(uv, uvs.len(), "texture coordinate")?]),
+                        None => None,
+                    };
+
+                    let normal = match indices.next().filter(|normal| !normal.is_empty()) {
+                        Some(normal) => {
+                            Some(normals[resolve_index(normal, normals.len(), "normal")?])
+                        }
+                        None => None,
+                    };
+
+                    vertices.push((
+                        position,
+                        VertexExtras::new(normal, uv, material_idx, mesh_idx),
+                    ));
+                }
+
+                if vertices.len() < 3 {
+                    unsupported.report("face with fewer than three vertices, skipped");
+                    continue;
+                }
+
+                // polygons are split into a fan of triangles around their first vertex
+                for pair in vertices[1..].windows(2) {
+                    let corners = [vertices[0], pair[0], pair[1]];
+
+                    triangles.push(corners.map(|(position, _)| position));
+                    triangle_extras.push(corners.map(|(_, extras)| extras));
+                }
+            }
+            "o" | "g" => {
+                let name = (!args.is_empty()).then(|| args.to_string());
+
+                mesh_idx = Some(*mesh_indices.entry(name.clone()).or_insert_with(|| {
+                    mesh_names.push(name);
+                    mesh_names.len() as u32 - 1
+                }));
+            }
+            "mtllib" => {
+                let mtl_path = folder.join(args.replace('\\', "/"));
+
+                match load_mtl(&mtl_path, &mut unsupported) {
+                    Ok(materials) => {
+                        for material in materials {
+                            material_indices
+                                .insert(material.name.clone(), mtl_materials.len() as u32);
+                            mtl_materials.push(material);
+                        }
+                    }
+                    Err(MeshToVoxError::Io { .. }) => {
+                        unsupported.report(format!(
+                            "missing material library `{}`, default material used",
+                            mtl_path.display()
+                        ));
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+            "usemtl" => {
+                material_idx = material_indices.get(args).copied().unwrap_or_else(|| {
+                    unsupported.report(format!(
+                        "undefined material `{args}`, default material used"
+                    ));
+                    DEFAULT_MATERIAL
+                });
+            }
+            "l" | "p" => unsupported.report(format!("non-triangle element (`{keyword}`), skipped")),
+            "s" => {}
+            _ => unsupported.report(format!("`{keyword}` statement")),
+        }
+    }
+
+    if triangles.is_empty() {
+        return Err(MeshToVoxError::EmptyMesh("the file has no triangles"));
+    }
+
+    let mut materials = mtl_materials
+        .par_iter()
+        .map(|material| parse_material(material, *options))
+        .collect::<Result<Vec<_>>>()?;
+
+    // i.e. default material
+    materials.push(Material::new(ImageOrColor::Color(image::Rgba([
+        255, 255, 255, 255,
+    ]))));
+
+    let default_material = materials.len() as u32 - 1;
+
+    for extras in triangle_extras.iter_mut().flatten() {
+        if extras.material_idx == DEFAULT_MATERIAL {
+            extras.material_idx = default_material;
+        }
+
+        let material = &materials[extras.material_idx as usize];
+
+        if extras.uv().is_none() && material.is_textured() {
+            unsupported.report("textured face without texture coordinates");
+        }
+    }
+
+    let bounds = BoundingBox::from_points(triangles.iter().flatten().copied());
+
+    let mut mesh = Mesh {
+        materials,
+        mesh_names,
+        triangles,
+        triangle_extras,
+        bounds,
+        view: View {
+            camera: None,
+            model_view_projection: Mat4::IDENTITY,
+        },
+        unsupported,
+    };
+
+    if options.compute_normals {
+        mesh.compute_missing_normals();
+    }
+
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_textured_and_colored_materials() {
+        let dir = crate::fixtures::test_dir("obj_materials");
+
+        // the left texel is red and the right one blue, and the textured triangle only samples
+        // the left one
+        let mut texture = image::RgbaImage::new(2, 1);
+        texture.put_pixel(0, 0, image::Rgba([200, 30, 30, 255]));
+        texture.put_pixel(1, 0, image::Rgba([30, 30, 200, 255]));
+        texture.save(dir.join("texture.png")).unwrap();
+
+        let mtl = "newmtl painted\nKd 1 1 1\nmap_Kd texture.png\n\nnewmtl plain\nKd 0 1 0\n";
+        std::fs::write(dir.join("model.mtl"), mtl).unwrap();
+
+        // the positions, texture coordinates and normals are indexed separately
+        let obj = "mtllib model.mtl\n\
+            v 0 0 0\nv 1 0 0\nv 0 1 0\nv 2 0 0\nv 3 0 0\nv 2 1 0\n\
+            vt 0.75 0.5\nvt 0.25 0.25\nvt 0.25 0.75\n\
+            vn 0 0 1\n\
+            usemtl painted\nf 1/2/1 2/3/1 3/2/1\n\
+            usemtl plain\nf 4//1 5//1 6//1\n";
+        let path = dir.join("model.obj");
+        std::fs::write(&path, obj).unwrap();
+
+        let mesh = load_obj(path.to_str().unwrap(), &LoadOptions::default()).unwrap();
+        let [painted, plain] = [0, 1].map(|tri| mesh.triangle_extras[tri][0].material_idx);
+        assert_ne!(painted, plain);
+
+        let tree = crate::mesh_to_octree(&mesh, 30, crate::VoxelizationMode::Triangles).unwrap();
+        let nodes = tree.collect_nodes();
+        assert!(!nodes.is_empty());
+
+        // the textured triangle is on the left third of the grid, the plain one on the right
+        for (node, color) in nodes {
+            let color = crate::octree::octree_header::to_color(color);
+            let expected = if node.coords.x < 15 {
+                [200, 30, 30]
+            } else {
+                [0, 255, 0]
+            };
+
+            assert_eq!(color.0[..3], expected, "the voxel {}", node.coords);
+        }
+    }
+
+    #[test]
+    fn only_masked_materials_cut_out_transparent_texels() {
+        let dir = crate::fixtures::test_dir("obj_alpha");
+
+        // both triangles only sample the left texel, which is transparent
+        let mut texture = image::RgbaImage::new(2, 1);
+        texture.put_pixel(0, 0, image::Rgba([200, 30, 30, 0]));
+        texture.put_pixel(1, 0, image::Rgba([30, 30, 200, 255]));
+        texture.save(dir.join("texture.png")).unwrap();
+
+        let mtl = "newmtl masked\nmap_Kd texture.png\nmap_d texture.png\n\n\
+            newmtl opaque\nd 1\nmap_Kd texture.png\n";
+        std::fs::write(dir.join("model.mtl"), mtl).unwrap();
+
+        let obj = "mtllib model.mtl\n\
+            v 0 0 0\nv 1 0 0\nv 0 1 0\nv 2 0 0\nv 3 0 0\nv 2 1 0\n\
+            vt 0.25 0.5\n\
+            usemtl masked\nf 1/1 2/1 3/1\n\
+            usemtl opaque\nf 4/1 5/1 6/1\n";
+        let path = dir.join("model.obj");
+        std::fs::write(&path, obj).unwrap();
+
+        let mesh = load_obj(path.to_str().unwrap(), &LoadOptions::default()).unwrap();
+        let cutoffs = mesh
+            .materials
+            .iter()
+            .map(|material| material.alpha_cutoff)
+            .collect::<Vec<_>>();
+        assert_eq!(cutoffs, [Some(DEFAULT_ALPHA_CUTOFF), None, None]);
+
+        // only the voxels of the opaque triangle, on the right of the grid, are kept
+        let tree = crate::mesh_to_octree(&mesh, 30, crate::VoxelizationMode::Triangles).unwrap();
+        let nodes = tree.collect_nodes();
+        assert!(!nodes.is_empty());
+        assert!(nodes.iter().all(|(node, _)| node.coords.x >= 15));
+    }
+}
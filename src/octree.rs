@@ -1,7 +1,11 @@
+use crate::error::{Result, check_cancelled};
 use crate::io::Vertex;
-use crate::space_filling::*;
-use glam::*;
-use std::collections::HashSet;
+use crate::space_filling::{MeshNode, bit_toggle};
+use glam::{I64Vec3, IVec3, Vec3};
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::sync::atomic::AtomicBool;
 
 #[derive(Debug, Clone, Copy, Eq, Hash, PartialEq)]
 pub struct OctreePos {
@@ -10,6 +14,7 @@ pub struct OctreePos {
 }
 
 impl OctreePos {
+    #[must_use]
     pub const fn zero(depth: u32) -> Self {
         Self {
             coords: IVec3::ZERO,
@@ -17,6 +22,7 @@ impl OctreePos {
         }
     }
 
+    #[must_use]
     pub const fn simplify(&self, max_depth: u32) -> Self {
         let cords = IVec3::new(
             self.coords.x & !((1 << (max_depth - self.depth)) - 1),
@@ -30,6 +36,7 @@ impl OctreePos {
         }
     }
 
+    #[must_use]
     pub const fn is_simple(&self, max_depth: u32) -> bool {
         const fn vaildate(dim: i32, depth: u32, max_depth: u32) -> bool {
             dim.trailing_zeros() >= (max_depth - depth)
@@ -43,35 +50,66 @@ impl OctreePos {
     }
 }
 
+/// How `Octree::fill_interior` colors the space enclosed by the model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FillColor {
+    /// Every enclosed voxel gets the same color
+    Flat(image::Rgba<u8>),
+    /// Every enclosed voxel gets the color of the nearest voxel of the model, which makes
+    /// cutaways of the model look natural. Away from the surface, the voxels of an enclosed
+    /// octant share the color nearest to its center
+    Nearest,
+}
+
+/// How `Octree::fill_interior` finds the space enclosed by the model
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FillMethod {
+    /// Flood fill the space around the model from the outside, and fill everything it can't
+    /// reach. This is exact, but needs the padding and is slow on large grids
+    #[default]
+    FloodFill,
+    /// Sweep the grid along its three axes, and fill the voxels between the crossings of the
+    /// surface along at least two of them. This is faster, but a hole in the surface or a
+    /// surface thinner than a voxel can fill (or leave empty) whole lines of voxels
+    Scanline,
+}
+
 #[derive(Debug, Clone)]
 pub struct Octree {
     pub data: Vec<u32>,
     pub depth: u32,
 }
 
-pub const fn get_octree_idx(cords: IVec3, depth: u32) -> i32 {
+#[must_use]
+pub const fn get_octree_idx(cords: IVec3, depth: u32) -> u32 {
     let x = (cords.x >> depth) & 1;
     let y = (cords.y >> depth) & 1;
     let z = (cords.z >> depth) & 1;
 
-    x | (y << 1) | (z << 2)
+    (x | (y << 1) | (z << 2)).unsigned_abs()
 }
 
 impl Octree {
-    pub const fn get_oct_inverted(&self, cords: IVec3, i: u32) -> i32 {
+    #[must_use]
+    pub const fn get_oct_inverted(&self, cords: IVec3, i: u32) -> u32 {
         let depth = self.depth - i;
         get_octree_idx(cords, depth)
     }
 
     pub fn store(&mut self, position: IVec3, val: image::Rgba<u8>) {
+        self.store_padded(position, val, 1);
+    }
+
+    /// Stores a voxel, unless it lays within `padding` voxels of the edge of the grid
+    pub fn store_padded(&mut self, position: IVec3, val: image::Rgba<u8>, padding: i32) {
         let node = OctreePos {
             coords: position,
             depth: self.depth,
         };
 
         // bc floating point error some erroneous voxels
-        if node.coords.min_element() < 1
-            || node.coords.max_element() >= ((1 << (self.depth + 1)) - 1)
+        if node.coords.min_element() < padding
+            || node.coords.max_element() >= ((1 << (self.depth + 1)) - padding)
         {
             return;
         }
@@ -79,8 +117,150 @@ impl Octree {
         self.insert(&node, val);
     }
 
-    pub fn fill_space(&self, max_size: u32) -> Vec<Vertex> {
-        let mut empty_tree = Octree::new(self.depth);
+    /// Meshes the faces of the model that border the outside space (see `sparse_faces`) into
+    /// rectangles, merged by `faces_to_vertices`
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Cancelled`] once `cancel` is set
+    pub fn fill_space(&self, max_size: u32, cancel: Option<&AtomicBool>) -> Result<Vec<Vertex>> {
+        let nodes = self.sparse_faces(cancel)?;
+
+        Ok(self.faces_to_vertices(&nodes, max_size))
+    }
+
+    /// Flood fills the space around the model from the outside, and returns every face of the
+    /// model that borders the outside space. `cancel` is checked after every layer of the
+    /// flood fill
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Cancelled`] once `cancel` is set
+    pub fn sparse_faces(
+        &self,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<Vec<(MeshNode, image::Rgba<u8>)>> {
+        let empty_tree = self.flood_fill_outside(cancel)?;
+
+        Ok(Self::empty_to_mesh(self, &empty_tree))
+    }
+
+    /// Returns the six faces of every node, including the ones hidden between neighboring
+    /// nodes, which unlike `sparse_faces` doesn't need the padding around the model
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "the depth of a tree fits a u8"
+    )]
+    #[must_use]
+    pub fn all_faces(&self) -> Vec<(MeshNode, image::Rgba<u8>)> {
+        let nodes = self.collect_nodes();
+        let mut faces = Vec::with_capacity(nodes.len() * 6);
+
+        for (node, color) in &nodes {
+            let color = octree_header::to_color(*color);
+
+            for i in 0..6 {
+                let node = MeshNode {
+                    cords: node.coords,
+                    dim: i / 2,
+                    positive: (i % 2) == 0,
+                    depth: node.depth as u8,
+                };
+
+                faces.push((node, color));
+            }
+        }
+
+        faces
+    }
+
+    /// Fills the space enclosed by the model, as found by `method`. Like sparse meshing, the
+    /// flood fill needs the padding around the model
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Cancelled`] once `cancel` is set
+    pub fn fill_interior(
+        &mut self,
+        color: FillColor,
+        method: FillMethod,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<()> {
+        let enclosed = match method {
+            FillMethod::FloodFill => {
+                let empty_tree = self.flood_fill_outside(cancel)?;
+
+                let mut enclosed = Vec::new();
+                self.collect_enclosed(
+                    &empty_tree,
+                    Some(0),
+                    Some(0),
+                    OctreePos::zero(0),
+                    &mut enclosed,
+                );
+
+                enclosed
+            }
+            FillMethod::Scanline => self.scanline_enclosed(cancel)?,
+        };
+
+        match color {
+            FillColor::Flat(color) => {
+                for octant in &enclosed {
+                    self.insert(octant, color);
+                }
+            }
+            FillColor::Nearest => self.fill_nearest(&enclosed, cancel)?,
+        }
+
+        Ok(())
+    }
+
+    /// Returns the color of the voxel at `position`, or `None` if it's empty
+    #[must_use]
+    pub fn color_at(&self, position: IVec3) -> Option<image::Rgba<u8>> {
+        self.node_at(position)
+            .map(|(_, color)| octree_header::to_color(color))
+    }
+
+    /// Returns the node covering the voxel at `position` along with its color, or `None` if
+    /// the voxel is empty. Nodes above the lowest level (i.e. the blocks of a refined tree)
+    /// cover more than one voxel
+    #[must_use]
+    pub fn node_at(&self, position: IVec3) -> Option<(OctreePos, u32)> {
+        if position.min_element() < 0 || position.max_element() >= (1 << (self.depth + 1)) {
+            return None;
+        }
+
+        let mut offset: u32 = 0;
+
+        for d in 0..=self.depth {
+            let header = self.data[offset as usize];
+            let oct = self.get_oct_inverted(position, d);
+
+            if !octree_header::get_exists(header, oct) {
+                return None;
+            }
+
+            let child = self.data[(offset + 1 + oct) as usize];
+
+            if octree_header::get_final(header, oct) {
+                let size = 1 << (self.depth - d);
+                let node = OctreePos {
+                    coords: position & !(size - 1),
+                    depth: d,
+                };
+
+                return Some((node, child));
+            }
+
+            offset = child;
+        }
+
+        None
+    }
+
+    /// Returns a tree of the empty space reachable from the corner of the grid, which lays
+    /// within the padding, i.e. the space around the model
+    fn flood_fill_outside(&self, cancel: Option<&AtomicBool>) -> Result<Self> {
+        let mut empty_tree = Self::new(self.depth);
         let mut current = HashSet::new();
         let mut next = HashSet::new();
 
@@ -92,33 +272,259 @@ impl Octree {
         };
         current.insert(start);
 
-        'outer: loop {
-            for cord in &current {
-                for i in 0..6 {
-                    let adjcent = self.min_adjcent_depth(&mut empty_tree, &mut next, cord, i);
-                    if adjcent.is_none() {
-                        continue;
+        // every layer is expanded in parallel without modifying the empty tree, and the empty
+        // octants that were found are marked afterwards. the octants are cells of the empty
+        // space of the model, so the flood fill reaches the same cells in any order, and the
+        // result is deterministic
+        while !current.is_empty() {
+            check_cancelled(cancel)?;
+
+            let found = current
+                .par_iter()
+                .flat_map_iter(|cord| {
+                    let mut found = Vec::new();
+                    for side in 0..6 {
+                        self.adjacent_empty(&empty_tree, cord, side, &mut found);
                     }
+                    found
+                })
+                .collect::<Vec<_>>();
 
-                    let adjcent = adjcent.unwrap();
-                    let mut thing = FillSpaceData {
-                        empty_tree: &mut empty_tree,
-                        next: &mut next,
-                    };
-
-                    self.recursive_collect(&adjcent, &mut thing);
+            for octant in found {
+                if Self::mark_empty(&mut empty_tree, &octant) {
+                    next.insert(octant);
                 }
             }
 
             core::mem::swap(&mut current, &mut next);
             next.clear();
-            if current.len() == 0 {
-                break 'outer;
+        }
+
+        Ok(empty_tree)
+    }
+
+    /// Collects the octants that are neither filled nor reached by the flood fill from the
+    /// outside. `None` offsets stand for parts of the grid that a tree has no nodes for
+    fn collect_enclosed(
+        &self,
+        empty: &Self,
+        filled_offset: Option<u32>,
+        empty_offset: Option<u32>,
+        cords: OctreePos,
+        found: &mut Vec<OctreePos>,
+    ) {
+        let filled_header = filled_offset.map_or(0, |offset| self.data[offset as usize]);
+        let empty_header = empty_offset.map_or(0, |offset| empty.data[offset as usize]);
+        let scale = 1 << (self.depth - cords.depth);
+
+        for oct in 0..8 {
+            if octree_header::get_final(filled_header | empty_header, oct) {
+                continue;
+            }
+
+            let child_coords = cords.coords + OCT_PERMS[oct as usize] * scale;
+
+            let filled_child = filled_offset
+                .filter(|_| octree_header::get_exists(filled_header, oct))
+                .map(|offset| self.data[(offset + 1 + oct) as usize]);
+            let empty_child = empty_offset
+                .filter(|_| octree_header::get_exists(empty_header, oct))
+                .map(|offset| empty.data[(offset + 1 + oct) as usize]);
+
+            if filled_child.is_none() && empty_child.is_none() {
+                found.push(OctreePos {
+                    coords: child_coords,
+                    depth: cords.depth,
+                });
+                continue;
+            }
+
+            let next_octant = OctreePos {
+                coords: child_coords,
+                depth: cords.depth + 1,
+            };
+
+            self.collect_enclosed(empty, filled_child, empty_child, next_octant, found);
+        }
+    }
+
+    /// Colors the enclosed octants with the colors of the nearest voxels of the model. The
+    /// voxels next to the model take the color of their first neighbor in it, and the rest of
+    /// every octant is filled whole with the color of the voxel nearest to its center, so the
+    /// memory and the time it takes grow with the surface of the model, not its volume
+    fn fill_nearest(&mut self, enclosed: &[OctreePos], cancel: Option<&AtomicBool>) -> Result<()> {
+        const NEIGHBORS: [IVec3; 6] = [
+            IVec3::X,
+            IVec3::Y,
+            IVec3::Z,
+            IVec3::NEG_X,
+            IVec3::NEG_Y,
+            IVec3::NEG_Z,
+        ];
+
+        // emissive surfaces don't turn the inside of the model emissive
+        let opaque = |color: image::Rgba<u8>| {
+            let [r, g, b, _] = color.0;
+            image::Rgba([r, g, b, 255])
+        };
+
+        let mut inside = Self::new(self.depth);
+
+        for octant in enclosed {
+            inside.insert(octant, image::Rgba([255; 4]));
+        }
+
+        let mut surface = self
+            .collect_nodes()
+            .par_iter()
+            .flat_map_iter(|(node, _)| self.face_neighbors(node))
+            .filter(|&voxel| inside.node_at(voxel).is_some())
+            .collect::<Vec<_>>();
+
+        surface.par_sort_unstable_by_key(IVec3::to_array);
+        surface.dedup();
+
+        check_cancelled(cancel)?;
+
+        let found = surface
+            .par_iter()
+            .filter_map(|&voxel| {
+                let color = NEIGHBORS
+                    .iter()
+                    .find_map(|&side| self.color_at(voxel + side))?;
+
+                Some((voxel, opaque(color)))
+            })
+            .collect::<Vec<_>>();
+
+        for (voxel, color) in found {
+            self.store(voxel, color);
+        }
+
+        check_cancelled(cancel)?;
+
+        let colors = enclosed
+            .par_iter()
+            .filter(|octant| !self.contains_point(octant))
+            .filter_map(|octant| {
+                let size = 1_i64 << (self.depth - octant.depth);
+                let center = octant.coords.as_i64vec3() * 2 + size;
+                let color = octree_header::to_color(self.nearest_node(center)?);
+
+                Some((*octant, opaque(color)))
+            })
+            .collect::<Vec<_>>();
+
+        for (octant, color) in colors {
+            self.fill_empty_parts(octant, color);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the voxels that share a side with the node, outside of it
+    fn face_neighbors(&self, node: &OctreePos) -> impl Iterator<Item = IVec3> {
+        let size = 1 << (self.depth - node.depth);
+        let coords = node.coords;
+
+        (0..3).flat_map(move |axis| {
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+
+            (0..size).flat_map(move |a| {
+                (0..size).flat_map(move |b| {
+                    let mut before = coords;
+                    before[u] += a;
+                    before[v] += b;
+
+                    let mut after = before;
+                    before[axis] -= 1;
+                    after[axis] += size;
+
+                    [before, after]
+                })
+            })
+        })
+    }
+
+    /// Returns the value of the leaf nearest to `point`, which is given in half voxels (i.e.
+    /// `2 * coords + 1` is the center of a voxel). The nodes are searched closest first, by
+    /// the distance of `point` from their bounds
+    fn nearest_node(&self, point: I64Vec3) -> Option<u32> {
+        // the grid can be `2^30` voxels wide, which is `2^31` half voxels
+        let distance = |coords: IVec3, size: i32| {
+            let low = coords.as_i64vec3() * 2;
+            let high = (coords + size).as_i64vec3() * 2;
+            let offset = (low - point).max(point - high).max(I64Vec3::ZERO);
+
+            offset.as_u64vec3().length_squared()
+        };
+
+        // the nodes are ordered by their distance, and the ties by their position, so the
+        // search always ends at the same leaf
+        let mut heap = BinaryHeap::from([Reverse((0, [0; 3], 0, 0, false))]);
+
+        while let Some(Reverse((_, coords, depth, offset, is_final))) = heap.pop() {
+            if is_final {
+                return Some(offset);
+            }
+
+            let header = self.data[offset as usize];
+            let size = 1 << (self.depth - depth);
+
+            for i in 0..8 {
+                if !octree_header::get_exists(header, i) {
+                    continue;
+                }
+
+                let coords = IVec3::from_array(coords) + OCT_PERMS[i as usize] * size;
+                let child = self.data[(offset + 1 + i) as usize];
+                let is_final = octree_header::get_final(header, i);
+                let depth = if is_final { depth } else { depth + 1 };
+
+                heap.push(Reverse((
+                    distance(coords, size),
+                    coords.to_array(),
+                    depth,
+                    child,
+                    is_final,
+                )));
             }
         }
 
-        let nodes = Self::empty_to_mesh(self, &empty_tree);
+        None
+    }
+
+    /// Fills the parts of the octant that hold no voxels yet with `color`
+    fn fill_empty_parts(&mut self, octant: OctreePos, color: image::Rgba<u8>) {
+        if self.contains_point(&octant) || self.insert(&octant, color).is_some() {
+            return;
+        }
+
+        // only a part of the octant is filled, so its children are filled one by one
+        let size = 1 << (self.depth - octant.depth - 1);
+
+        for offset in OCT_PERMS {
+            let child = OctreePos {
+                coords: octant.coords + offset * size,
+                depth: octant.depth + 1,
+            };
+
+            self.fill_empty_parts(child, color);
+        }
+    }
 
+    /// Converts faces into triangle vertices, six vertices per face
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        reason = "the depth fits a u8, grid sizes fit the f32 mantissa"
+    )]
+    #[must_use]
+    pub fn faces_to_vertices(
+        &self,
+        nodes: &[(MeshNode, image::Rgba<u8>)],
+        max_size: u32,
+    ) -> Vec<Vertex> {
         let triangles = nodes
             .iter()
             .map(|(node, color)| {
@@ -131,18 +537,19 @@ impl Octree {
                     Vertex { position, color }
                 };
 
-                let a = <[_; 3]>::try_from(&triangles[0..3]).unwrap().map(mapping);
-                let b = <[_; 3]>::try_from(&triangles[0..3]).unwrap().map(mapping);
+                let [a0, a1, a2, b0, b1, b2] = triangles.map(mapping);
 
-                [a, b]
+                [[a0, a1, a2], [b0, b1, b2]]
             })
             .collect::<Vec<_>>();
 
-        let triangles = bytemuck::cast_vec::<_, Vertex>(triangles);
-
-        triangles
+        bytemuck::cast_vec::<_, Vertex>(triangles)
     }
 
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "offsets into the tree are u32"
+    )]
     fn insert_max_start(&self, empty_tree: &mut Self, start: IVec3) -> u32 {
         let mut empty_pointer: u32 = 0;
         let mut filled_pointer: u32 = 0;
@@ -150,19 +557,19 @@ impl Octree {
         for d in 0..=self.depth {
             let filled_header = self.data[filled_pointer as usize];
             let empty_header = &mut empty_tree.data[empty_pointer as usize];
-            let oct = self.get_oct_inverted(start, d) as u32;
+            let oct = self.get_oct_inverted(start, d);
 
             //if octree_header::get_final(filled_header, oct as u32){panic!();}
 
-            if !octree_header::get_exists(filled_header, oct as u32) {
-                octree_header::set_final(empty_header, oct as u32);
-                octree_header::set_exists(empty_header, oct as u32);
+            if !octree_header::get_exists(filled_header, oct) {
+                octree_header::set_final(empty_header, oct);
+                octree_header::set_exists(empty_header, oct);
 
                 return d;
             }
 
-            if !octree_header::get_exists(*empty_header, oct as u32) {
-                octree_header::set_exists(empty_header, oct as u32);
+            if !octree_header::get_exists(*empty_header, oct) {
+                octree_header::set_exists(empty_header, oct);
 
                 let next = empty_tree.create_empty_oct(d);
                 empty_tree.data[(empty_pointer + 1 + oct) as usize] = next as u32;
@@ -175,182 +582,207 @@ impl Octree {
         panic!();
     }
 
-    fn min_adjcent_depth(
-        &self,
-        empty: &mut Self,
-        next: &mut CoordMap,
-        cord: &OctreePos,
-        side: u8,
-    ) -> Option<FilledIterStruct> {
+    /// Finds the empty octants next to the side `side` of `cord` that weren't reached by the
+    /// flood fill yet, without modifying the empty tree
+    fn adjacent_empty(&self, empty: &Self, cord: &OctreePos, side: u8, found: &mut Vec<OctreePos>) {
         let max_size = 1 << (self.depth + 1);
         let min_octant_size = 1 << (self.depth - cord.depth);
 
-        let mut base = cord.coords;
+        let mut adjcent = cord.coords;
         let dim = side % 3;
-        base[dim as usize] += if side < 3 { min_octant_size } else { -1 };
+        adjcent[dim as usize] += if side < 3 { min_octant_size } else { -1 };
 
-        if (base[dim as usize] >= max_size) || (base[dim as usize] < 0) {
-            return None;
+        if (adjcent[dim as usize] >= max_size) || (adjcent[dim as usize] < 0) {
+            return;
         }
 
-        let adjcent = base;
-
-        let mut empty_offset: u32 = 0;
+        // the empty tree doesn't have nodes for parts of the grid that weren't reached yet
+        let mut empty_offset = Some(0);
         let mut filled_offset: u32 = 0;
 
-        for d in 0..(cord.depth + 1) {
+        for d in 0..=cord.depth {
             let adjacent_oct = self.get_oct_inverted(adjcent, d);
 
-            let empty_header = empty.data[empty_offset as usize];
+            let empty_header = empty_offset.map_or(0, |offset| empty.data[offset as usize]);
             let filled_header = self.data[filled_offset as usize];
 
-            if octree_header::get_final(filled_header | empty_header, adjacent_oct as u32) {
-                return None;
+            if octree_header::get_final(filled_header | empty_header, adjacent_oct) {
+                return;
             }
 
-            if !octree_header::get_exists(filled_header, adjacent_oct as u32) {
-                let cord = OctreePos {
-                    coords: base,
+            if !octree_header::get_exists(filled_header, adjacent_oct) {
+                let octant = OctreePos {
+                    coords: adjcent,
                     depth: d,
                 };
-                next.insert(cord);
-
-                octree_header::set_exists(
-                    &mut empty.data[empty_offset as usize],
-                    adjacent_oct as u32,
-                );
-                octree_header::set_final(
-                    &mut empty.data[empty_offset as usize],
-                    adjacent_oct as u32,
-                );
-
-                return None;
+                found.push(octant.simplify(self.depth));
+                return;
             }
 
-            if !octree_header::get_exists(empty_header, adjacent_oct as u32) {
-                let next = empty.create_empty_oct(d);
-                octree_header::set_exists(
-                    &mut empty.data[empty_offset as usize],
-                    adjacent_oct as u32,
-                );
-                empty.data[(empty_offset + 1 + adjacent_oct as u32) as usize] = next as u32;
-            }
-
-            empty_offset = empty.data[(empty_offset + 1 + adjacent_oct as u32) as usize];
-            filled_offset = self.data[(filled_offset + 1 + adjacent_oct as u32) as usize];
+            empty_offset = empty_offset
+                .filter(|_| octree_header::get_exists(empty_header, adjacent_oct))
+                .map(|offset| empty.data[(offset + 1 + adjacent_oct) as usize]);
+            filled_offset = self.data[(filled_offset + 1 + adjacent_oct) as usize];
         }
 
-        let base = OctreePos {
+        let cords = OctreePos {
             coords: adjcent,
-            depth: (cord.depth + 1),
-        };
-        let new_cord = FilledIterStruct {
-            cords: base,
-            empty_offset,
-            filled_offset,
-            side,
+            depth: cord.depth + 1,
         };
 
-        return Some(new_cord);
+        self.collect_adjacent_empty(empty, &cords, filled_offset, empty_offset, side, found);
     }
 
-    fn recursive_collect(&self, adjcent: &FilledIterStruct, info: &mut FillSpaceData) {
-        let empty_header = info.empty_tree.data[adjcent.empty_offset as usize];
-        let filled_header = self.data[adjcent.filled_offset as usize];
-
-        for oct in ALL_OCTREE_SIDES[adjcent.side as usize] {
-            let oct = oct as u32;
-            if octree_header::get_final(filled_header, oct) {
-                continue;
-            }
-            if octree_header::get_final(empty_header, oct) {
+    /// Collects the empty octants of a filled node that lay on its side facing `side`
+    fn collect_adjacent_empty(
+        &self,
+        empty: &Self,
+        cords: &OctreePos,
+        filled_offset: u32,
+        empty_offset: Option<u32>,
+        side: u8,
+        found: &mut Vec<OctreePos>,
+    ) {
+        let empty_header = empty_offset.map_or(0, |offset| empty.data[offset as usize]);
+        let filled_header = self.data[filled_offset as usize];
+
+        for oct in ALL_OCTREE_SIDES[side as usize] {
+            let oct = u32::from(oct);
+            if octree_header::get_final(filled_header | empty_header, oct) {
                 continue;
             }
 
-            let pos = bit_toggle(adjcent.cords.coords, self.depth - adjcent.cords.depth, oct);
-            let octant = OctreePos {
-                coords: pos,
-                depth: adjcent.cords.depth,
-            };
+            let pos = bit_toggle(cords.coords, self.depth - cords.depth, oct);
 
             if !octree_header::get_exists(filled_header, oct) {
-                octree_header::set_exists(
-                    &mut info.empty_tree.data[adjcent.empty_offset as usize],
-                    oct,
-                );
-                octree_header::set_final(
-                    &mut info.empty_tree.data[adjcent.empty_offset as usize],
-                    oct,
-                );
-
-                let out = octant.simplify(self.depth);
-                info.next.insert(out);
+                let octant = OctreePos {
+                    coords: pos,
+                    depth: cords.depth,
+                };
+                found.push(octant.simplify(self.depth));
                 continue;
             }
 
-            if !octree_header::get_exists(empty_header, oct) {
-                octree_header::set_exists(
-                    &mut info.empty_tree.data[adjcent.empty_offset as usize],
-                    oct,
-                );
-                let next = info.empty_tree.create_empty_oct(adjcent.cords.depth);
-                info.empty_tree.data[(adjcent.empty_offset + 1 + oct) as usize] = next as u32;
-            }
-
-            let filled_offset = self.data[(adjcent.filled_offset + 1 + oct) as usize];
-            let empty_offset = info.empty_tree.data[(adjcent.empty_offset + 1 + oct) as usize];
+            let empty_offset = empty_offset
+                .filter(|_| octree_header::get_exists(empty_header, oct))
+                .map(|offset| empty.data[(offset + 1 + oct) as usize]);
+            let filled_offset = self.data[(filled_offset + 1 + oct) as usize];
 
             let next_octant = OctreePos {
                 coords: pos,
-                depth: adjcent.cords.depth + 1,
+                depth: cords.depth + 1,
             };
-            let new_adjcent = FilledIterStruct {
-                cords: next_octant,
+
+            self.collect_adjacent_empty(
+                empty,
+                &next_octant,
                 filled_offset,
                 empty_offset,
-                side: adjcent.side,
-            };
-            self.recursive_collect(&new_adjcent, info);
+                side,
+                found,
+            );
         }
     }
 
-    fn empty_to_mesh(filled: &Self, empty: &Self) -> Vec<(MeshNode, image::Rgba<u8>)> {
-        let mut mesh = Vec::new();
+    /// Marks an empty octant as reached by the flood fill. Returns `false` if it was
+    /// reached before
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "offsets into the tree are u32"
+    )]
+    fn mark_empty(empty: &mut Self, octant: &OctreePos) -> bool {
+        let mut offset: u32 = 0;
+
+        for d in 0..octant.depth {
+            let oct = empty.get_oct_inverted(octant.coords, d);
+            let header = empty.data[offset as usize];
+
+            if octree_header::get_final(header, oct) {
+                return false;
+            }
 
+            if !octree_header::get_exists(header, oct) {
+                octree_header::set_exists(&mut empty.data[offset as usize], oct);
+
+                let next = empty.create_empty_oct(d);
+                empty.data[(offset + 1 + oct) as usize] = next as u32;
+            }
+
+            offset = empty.data[(offset + 1 + oct) as usize];
+        }
+
+        let oct = empty.get_oct_inverted(octant.coords, octant.depth);
+        let header = &mut empty.data[offset as usize];
+
+        if octree_header::get_final(*header, oct) {
+            return false;
+        }
+
+        octree_header::set_exists(header, oct);
+        octree_header::set_final(header, oct);
+
+        true
+    }
+
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        reason = "the axes and depths are small and positive"
+    )]
+    fn empty_to_mesh(filled: &Self, empty: &Self) -> Vec<(MeshNode, image::Rgba<u8>)> {
         let nodes = filled.collect_nodes();
         let max_size = 1 << (filled.depth + 1);
 
-        for (cord, value) in &nodes {
-            let color = octree_header::to_color(*value);
+        nodes
+            .par_iter()
+            .flat_map_iter(|(cord, value)| {
+                let color = octree_header::to_color(*value);
+                let mut mesh = Vec::new();
 
-            for i in 0..6 {
-                let mut adjcent = cord.coords;
-                let dim = (i / 2) as usize;
-                let positive = (i % 2) == 0;
+                // nodes above the lowest level (i.e. the blocks of a refined tree) are meshed
+                // voxel by voxel, as only a part of their side may border the outside space
+                let node_size = 1 << (filled.depth - cord.depth);
 
-                adjcent[dim] += if positive { 1 } else { -1 };
-                if adjcent[dim] >= max_size || adjcent[dim] < 0 {
-                    continue;
-                }
-                let cords = adjcent;
-                let node = OctreePos {
-                    coords: cords,
-                    depth: filled.depth,
-                };
-
-                if empty.contains_point(&node) {
-                    let mesh_node = MeshNode {
-                        cords: cord.coords,
-                        dim: dim as u8,
-                        positive,
-                        depth: filled.depth as u8,
-                    };
-                    mesh.push((mesh_node, color));
+                for i in 0..6 {
+                    let dim = (i / 2) as usize;
+                    let positive = (i % 2) == 0;
+                    let [u, v] = [(dim + 1) % 3, (dim + 2) % 3];
+
+                    for offset_u in 0..node_size {
+                        for offset_v in 0..node_size {
+                            let mut voxel = cord.coords;
+                            voxel[u] += offset_u;
+                            voxel[v] += offset_v;
+                            if positive {
+                                voxel[dim] += node_size - 1;
+                            }
+
+                            let mut adjcent = voxel;
+                            adjcent[dim] += if positive { 1 } else { -1 };
+                            if adjcent[dim] >= max_size || adjcent[dim] < 0 {
+                                continue;
+                            }
+                            let node = OctreePos {
+                                coords: adjcent,
+                                depth: filled.depth,
+                            };
+
+                            if empty.contains_point(&node) {
+                                let mesh_node = MeshNode {
+                                    cords: voxel,
+                                    dim: dim as u8,
+                                    positive,
+                                    depth: filled.depth as u8,
+                                };
+                                mesh.push((mesh_node, color));
+                            }
+                        }
+                    }
                 }
-            }
-        }
 
-        mesh
+                mesh
+            })
+            .collect()
     }
 
     fn create_new_empty_oct(&mut self) -> usize {
@@ -380,46 +812,61 @@ pub mod octree_header {
     pub const COLOR_TAG: u8 = 118;
     pub const HEADER_TAG: u8 = 68;
 
+    /// The alpha of voxels created from emissive materials. The alpha of a voxel is only
+    /// used for the alpha cutoff, so every other voxel is stored with an alpha of `255`
+    pub const EMISSIVE_ALPHA: u8 = 254;
+
+    #[must_use]
+    pub const fn is_emissive(color: image::Rgba<u8>) -> bool {
+        color.0[3] == EMISSIVE_ALPHA
+    }
+
+    #[must_use]
     pub const fn from_color(color: image::Rgba<u8>) -> u32 {
         let [r, g, b, a] = color.0;
         u32::from_le_bytes([r, g, b, a])
     }
 
+    #[must_use]
     pub const fn to_color(offset: u32) -> image::Rgba<u8> {
         let [r, g, b, a] = offset.to_le_bytes();
         image::Rgba([r, g, b, a])
     }
 
+    #[must_use]
     pub const fn get_empty(header: u32, idx: u32) -> bool {
         ((header >> (EMPTY_OFFSET + idx)) & 1) != 0
     }
 
-    pub fn set_empty(header: &mut u32, idx: u32) {
+    pub const fn set_empty(header: &mut u32, idx: u32) {
         *header |= 1 << (EMPTY_OFFSET + idx);
     }
 
-    pub fn set_header_tag(header: &mut u32) {
+    pub const fn set_header_tag(header: &mut u32) {
         *header |= (HEADER_TAG as u32) << TAG_OFFSET;
     }
 
+    #[must_use]
     pub const fn is_header(header: u32) -> bool {
         (header >> TAG_OFFSET) == HEADER_TAG as u32
     }
 
+    #[must_use]
     pub const fn get_exists(header: u32, idx: u32) -> bool {
         ((header >> (idx + EXISTS_OFFSET)) & 1) != 0
     }
 
-    pub fn set_exists(header: &mut u32, idx: u32) {
+    pub const fn set_exists(header: &mut u32, idx: u32) {
         *header |= 1 << (idx + EXISTS_OFFSET);
     }
 
+    #[must_use]
     pub const fn get_final(header: u32, idx: u32) -> bool {
         ((header >> (idx + FINAL_OFFSET)) & 1) != 0
     }
 
-    pub fn set_final(header: &mut u32, idx: u32) {
-        *header |= 1 << (idx + FINAL_OFFSET)
+    pub const fn set_final(header: &mut u32, idx: u32) {
+        *header |= 1 << (idx + FINAL_OFFSET);
     }
 }
 
@@ -429,6 +876,7 @@ pub struct IterStruct {
     pub cords: OctreePos,
 }
 
+#[allow(clippy::cast_sign_loss, reason = "the counter runs over 0..8")]
 const fn gen_oct_perumations() -> [IVec3; 8] {
     let mut cube: [IVec3; 8] = [IVec3::new(0, 0, 0); 8];
     let mut counter: i32 = 0;
@@ -478,6 +926,7 @@ pub const ALL_OCTREE_SIDES: [[u8; 4]; 6] = gen_octree_sides();
 pub const OCT_PERMS: [IVec3; 8] = gen_oct_perumations();
 
 impl Octree {
+    #[must_use]
     pub fn new(depth: u32) -> Self {
         let mut output = Self {
             depth,
@@ -488,6 +937,82 @@ impl Octree {
         output
     }
 
+    /// Builds a tree from the voxels of a grid `1 << (depth + 1)` voxels wide. Voxels outside
+    /// of the grid are skipped, and if a position is given more than once, the first color is
+    /// kept
+    pub fn from_voxels(
+        voxels: impl IntoIterator<Item = (IVec3, image::Rgba<u8>)>,
+        depth: u32,
+    ) -> Self {
+        let mut tree = Self::new(depth);
+        let size = 1 << (depth + 1);
+
+        for (position, color) in voxels {
+            if position.min_element() < 0 || position.max_element() >= size {
+                continue;
+            }
+
+            let node = OctreePos {
+                coords: position,
+                depth,
+            };
+
+            tree.insert(&node, color);
+        }
+
+        tree.shrink();
+        tree
+    }
+
+    /// Returns the number of bytes allocated for the nodes of the tree, which includes the
+    /// spare capacity left after growing it (see `shrink`)
+    #[must_use]
+    pub const fn memory_usage(&self) -> usize {
+        self.data.capacity() * size_of::<u32>()
+    }
+
+    /// Returns the number of bytes taken up by the nodes of the tree
+    #[must_use]
+    pub const fn used_memory(&self) -> usize {
+        self.data.len() * size_of::<u32>()
+    }
+
+    /// Returns a hash of the voxels of the tree, which stays the same across runs, platforms and
+    /// versions of the compiler, so it can be compared with the hash of an earlier run. The
+    /// nodes are hashed in the order of `collect_nodes`, which only depends on their positions
+    #[must_use]
+    pub fn content_hash(&self) -> u64 {
+        // 64-bit FNV-1a, as the hashers of the standard library aren't stable
+        const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0100_0000_01b3;
+
+        let mut hash = OFFSET_BASIS;
+        let mut write = |bytes: [u8; 4]| {
+            for byte in bytes {
+                hash = (hash ^ u64::from(byte)).wrapping_mul(PRIME);
+            }
+        };
+
+        write(self.depth.to_le_bytes());
+
+        for (node, color) in self.collect_nodes() {
+            for coord in node.coords.to_array() {
+                write(coord.to_le_bytes());
+            }
+
+            write(node.depth.to_le_bytes());
+            write(color.to_le_bytes());
+        }
+
+        hash
+    }
+
+    /// Frees the spare capacity of the tree. The voxelizer calls this once it's done, as
+    /// growing the tree node by node leaves up to half of its allocation unused
+    pub fn shrink(&mut self) {
+        self.data.shrink_to_fit();
+    }
+
     pub fn create_new_oct(&mut self, mut header: u32) -> usize {
         self.data.reserve(9);
         let old_len = self.data.len();
@@ -497,25 +1022,26 @@ impl Octree {
             self.data.set_len(old_len + 9);
             self.data[old_len] = header;
             for i in 0..8 {
-                self.data[old_len + 1 + i] = 69420420;
+                self.data[old_len + 1 + i] = 69_420_420;
             }
         }
         old_len
     }
 
+    #[must_use]
     pub fn contains_point(&self, node: &OctreePos) -> bool {
         let mut currnet_pointer: u32 = 0;
         let mut current_oct;
         let mut current_header;
 
-        for d in 0..(node.depth + 1) {
+        for d in 0..=node.depth {
             current_header = self.data[currnet_pointer as usize];
-            current_oct = self.get_oct_inverted(node.coords, d) as u32;
+            current_oct = self.get_oct_inverted(node.coords, d);
 
-            if !octree_header::get_exists(current_header, current_oct as u32) {
+            if !octree_header::get_exists(current_header, current_oct) {
                 return false;
             }
-            if octree_header::get_final(current_header, current_oct as u32) {
+            if octree_header::get_final(current_header, current_oct) {
                 return true;
             }
 
@@ -524,6 +1050,7 @@ impl Octree {
         false
     }
 
+    #[must_use]
     pub fn contains_exact(&self, node: &OctreePos) -> bool {
         let mut currnet_pointer: u32 = 0;
         let mut current_oct;
@@ -531,12 +1058,12 @@ impl Octree {
 
         for d in 0..node.depth {
             current_header = self.data[currnet_pointer as usize];
-            current_oct = self.get_oct_inverted(node.coords, d) as u32;
+            current_oct = self.get_oct_inverted(node.coords, d);
 
-            if !octree_header::get_exists(current_header, current_oct as u32) {
+            if !octree_header::get_exists(current_header, current_oct) {
                 return false;
             }
-            if octree_header::get_final(current_header, current_oct as u32) {
+            if octree_header::get_final(current_header, current_oct) {
                 return false;
             }
 
@@ -544,114 +1071,440 @@ impl Octree {
         }
 
         current_header = self.data[currnet_pointer as usize];
-        current_oct = self.get_oct_inverted(node.coords, node.depth) as u32;
+        current_oct = self.get_oct_inverted(node.coords, node.depth);
 
-        if octree_header::get_final(current_header, current_oct as u32) {
-            return true;
-        } else {
-            false
-        }
+        octree_header::get_final(current_header, current_oct)
     }
 
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "offsets into the tree are u32"
+    )]
     pub fn insert(&mut self, node: &OctreePos, value: image::Rgba<u8>) -> Option<u32> {
         if node.depth > self.depth {
             return None;
         }
 
         let mut current_pointer: u32 = 0;
-        let mut current_oct = self.get_oct_inverted(node.coords, 0) as u32;
-        let mut current_node = current_pointer + 1 + current_oct as u32;
+        let mut current_oct = self.get_oct_inverted(node.coords, 0);
+        let mut current_node = current_pointer + 1 + current_oct;
         let mut inserted = true;
 
         for d in 0..node.depth {
             let current_header = self.data[current_pointer as usize];
-            let next_oct = self.get_oct_inverted(node.coords, d + 1) as u32;
+            let next_oct = self.get_oct_inverted(node.coords, d + 1);
 
-            current_pointer =
-                if octree_header::get_exists(current_header, current_oct as u32) && inserted {
-                    if octree_header::get_final(current_header, current_oct as u32) {
-                        return None;
-                    }
+            current_pointer = if octree_header::get_exists(current_header, current_oct) && inserted
+            {
+                if octree_header::get_final(current_header, current_oct) {
+                    return None;
+                }
 
-                    self.data[current_node as usize]
-                } else {
-                    let mut next_header = 0;
-                    octree_header::set_exists(&mut next_header, next_oct as u32);
-                    let next_pointer = self.create_new_oct(next_header) as u32;
-
-                    octree_header::set_exists(
-                        &mut self.data[current_pointer as usize],
-                        current_oct as u32,
-                    );
-                    self.data[current_node as usize] = next_pointer;
-                    inserted = false;
-
-                    next_pointer
-                };
+                self.data[current_node as usize]
+            } else {
+                let mut next_header = 0;
+                octree_header::set_exists(&mut next_header, next_oct);
+                let next_pointer = self.create_new_oct(next_header) as u32;
+
+                octree_header::set_exists(&mut self.data[current_pointer as usize], current_oct);
+                self.data[current_node as usize] = next_pointer;
+                inserted = false;
 
-            current_node = current_pointer + 1 + next_oct as u32;
+                next_pointer
+            };
+
+            current_node = current_pointer + 1 + next_oct;
             current_oct = next_oct;
         }
 
-        let next_node = current_pointer + 1 + current_oct as u32;
-        let current_header = self.data.get_mut(current_pointer as usize);
-
-        let current_header = current_header.unwrap();
+        let next_node = current_pointer + 1 + current_oct;
+        let current_header = &mut self.data[current_pointer as usize];
 
-        if octree_header::get_exists(*current_header, current_oct as u32) && inserted {
+        if octree_header::get_exists(*current_header, current_oct) && inserted {
             return None;
         }
 
-        octree_header::set_exists(current_header, current_oct as u32);
-        octree_header::set_final(current_header, current_oct as u32);
+        octree_header::set_exists(current_header, current_oct);
+        octree_header::set_final(current_header, current_oct);
 
         self.data[next_node as usize] = octree_header::from_color(value);
 
         Some(next_node)
     }
 
-    //replace with non recursive implementation
-    fn collect_recursive(&self, nodes: &mut Vec<(OctreePos, u32)>, iter_level: IterStruct) {
-        let header = self.data[iter_level.offset as usize];
+    /// Replaces the color of every voxel with the result of `f`
+    pub fn map_colors(&mut self, mut f: impl FnMut(image::Rgba<u8>) -> image::Rgba<u8>) {
+        let mut stack = vec![0_u32];
+
+        while let Some(offset) = stack.pop() {
+            let header = self.data[offset as usize];
+
+            for i in 0..8 {
+                if !octree_header::get_exists(header, i) {
+                    continue;
+                }
+
+                let child = (offset + 1 + i) as usize;
+
+                if octree_header::get_final(header, i) {
+                    let color = octree_header::to_color(self.data[child]);
+                    self.data[child] = octree_header::from_color(f(color));
+                } else {
+                    stack.push(self.data[child]);
+                }
+            }
+        }
+    }
+
+    /// Collects the leaves of the subtree of `root` in depth-first order. The nodes being walked
+    /// are kept on a stack on the heap, each with the octant to resume it at, instead of
+    /// recursing into every subtree
+    fn collect_subtree(&self, nodes: &mut Vec<(OctreePos, u32)>, root: IterStruct) {
+        let mut stack = vec![(root, 0)];
+
+        'nodes: while let Some((iter_level, first_octant)) = stack.pop() {
+            let header = self.data[iter_level.offset as usize];
+
+            for i in first_octant..8 {
+                if !octree_header::get_exists(header, i) {
+                    continue;
+                }
+
+                let scale = 1 << (self.depth - iter_level.cords.depth);
+                let new_position = iter_level.cords.coords + OCT_PERMS[i as usize] * scale;
+                let offset = self.data[(iter_level.offset + 1 + i) as usize];
+
+                if octree_header::get_final(header, i) {
+                    let cords = OctreePos {
+                        coords: new_position,
+                        depth: iter_level.cords.depth,
+                    };
+                    nodes.push((cords, offset));
+                } else {
+                    let cords = OctreePos {
+                        coords: new_position,
+                        depth: iter_level.cords.depth + 1,
+                    };
+
+                    // the rest of the octants are collected after the whole child
+                    stack.push((iter_level.clone(), i + 1));
+                    stack.push((IterStruct { offset, cords }, 0));
+                    continue 'nodes;
+                }
+            }
+        }
+    }
+
+    /// Returns every leaf of the tree with its color. The subtrees of the root are collected
+    /// in parallel, and concatenated in the order of their octants, so the order of the nodes
+    /// doesn't depend on the scheduling of the threads
+    pub fn collect_nodes(&self) -> Vec<(OctreePos, u32)> {
+        let header = self.data[0];
+        let scale = 1 << self.depth;
+
+        let subtrees = (0..8_u32)
+            .into_par_iter()
+            .filter(|&i| octree_header::get_exists(header, i))
+            .map(|i| {
+                let coords = OCT_PERMS[i as usize] * scale;
+                let offset = self.data[(1 + i) as usize];
+
+                if octree_header::get_final(header, i) {
+                    return vec![(OctreePos { coords, depth: 0 }, offset)];
+                }
+
+                let mut collected = Vec::new();
+                let root = IterStruct {
+                    offset,
+                    cords: OctreePos { coords, depth: 1 },
+                };
+                self.collect_subtree(&mut collected, root);
+
+                collected
+            })
+            .collect::<Vec<_>>();
+
+        let mut collected = Vec::with_capacity(subtrees.iter().map(Vec::len).sum());
+        for subtree in subtrees {
+            collected.extend(subtree);
+        }
+
+        collected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxelizer::depth_for_size;
+
+    /// Collects the leaves below `iter_level` recursively, as `collect_nodes` did before it
+    /// used a stack of its own
+    fn collect_recursive(
+        tree: &Octree,
+        nodes: &mut Vec<(OctreePos, u32)>,
+        iter_level: &IterStruct,
+    ) {
+        let header = tree.data[iter_level.offset as usize];
 
         for i in 0..8 {
             if !octree_header::get_exists(header, i) {
                 continue;
             }
 
-            let scale = 1 << (self.depth - iter_level.cords.depth);
-            let coords = OCT_PERMS[i as usize] * scale;
-            let new_position = iter_level.cords.coords + coords;
-            let offset = self.data[(iter_level.offset + 1 + i) as usize];
+            let scale = 1 << (tree.depth - iter_level.cords.depth);
+            let position = iter_level.cords.coords + OCT_PERMS[i as usize] * scale;
+            let offset = tree.data[(iter_level.offset + 1 + i) as usize];
 
             if octree_header::get_final(header, i) {
-                let cords = OctreePos {
-                    coords: new_position,
+                let node = OctreePos {
+                    coords: position,
                     depth: iter_level.cords.depth,
                 };
-                nodes.push((cords, offset));
+                nodes.push((node, offset));
             } else {
                 let cords = OctreePos {
-                    coords: new_position,
+                    coords: position,
                     depth: iter_level.cords.depth + 1,
                 };
-                let new_iter = IterStruct { cords, offset };
-                self.collect_recursive(nodes, new_iter);
+                collect_recursive(tree, nodes, &IterStruct { offset, cords });
             }
         }
     }
 
-    pub fn collect_nodes(&self) -> Vec<(OctreePos, u32)> {
-        let length = self.data.len() / 9;
-        let mut collected: Vec<(OctreePos, u32)> = Vec::with_capacity(length);
-        let cords = OctreePos {
-            coords: IVec3::ZERO,
-            depth: 0,
+    #[test]
+    fn collects_the_same_nodes_as_recursion() {
+        let depth = depth_for_size(1022);
+        let mut tree = Octree::new(depth);
+
+        // voxels in the corners and the middle of the grid, and blocks of the levels above them
+        // (i.e. of a refined tree), in different subtrees of the root
+        let nodes = [
+            (IVec3::ZERO, depth),
+            (IVec3::new(1, 0, 0), depth),
+            (IVec3::splat(1023), depth),
+            (IVec3::new(517, 3, 900), depth),
+            (IVec3::new(64, 64, 0), depth - 2),
+            (IVec3::new(0, 512, 512), depth - 5),
+            (IVec3::new(512, 0, 0), 1),
+        ];
+
+        let mut expected = Vec::new();
+
+        for ((coords, depth), red) in nodes.into_iter().zip((0_u8..).step_by(30)) {
+            let node = OctreePos { coords, depth };
+            let color = image::Rgba([red, 100, 200, 255]);
+
+            tree.insert(&node, color).unwrap();
+            expected.push((coords.to_array(), depth, color));
+        }
+
+        let mut recursive = Vec::new();
+        let root = IterStruct {
+            offset: 0,
+            cords: OctreePos {
+                coords: IVec3::ZERO,
+                depth: 0,
+            },
         };
-        let first_iter = IterStruct { cords, offset: 0 };
+        collect_recursive(&tree, &mut recursive, &root);
 
-        self.collect_recursive(&mut collected, first_iter);
+        let mut collected = tree.collect_nodes();
 
-        collected
+        collected.sort_unstable_by_key(|(node, _)| (node.coords.to_array(), node.depth));
+        recursive.sort_unstable_by_key(|(node, _)| (node.coords.to_array(), node.depth));
+        assert_eq!(collected, recursive);
+
+        let mut collected = collected
+            .into_iter()
+            .map(|(node, color)| {
+                (
+                    node.coords.to_array(),
+                    node.depth,
+                    octree_header::to_color(color),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        expected.sort_unstable_by_key(|(coords, depth, _)| (*coords, *depth));
+        collected.sort_unstable_by_key(|(coords, depth, _)| (*coords, *depth));
+        assert_eq!(collected, expected);
+    }
+
+    #[test]
+    fn nearest_fill_takes_the_nearest_colors() {
+        const SIZE: i32 = 30;
+
+        let red = image::Rgba([255, 0, 0, 255]);
+        let blue = image::Rgba([0, 0, 255, 128]);
+
+        // a hollow box with a red side at the lowest X, and the rest of it emissive blue
+        let shell = (1..=SIZE).flat_map(|x| {
+            (1..=SIZE).flat_map(move |y| (1..=SIZE).map(move |z| IVec3::new(x, y, z)))
+        });
+        let shell = shell
+            .filter(|voxel| voxel.min_element() == 1 || voxel.max_element() == SIZE)
+            .map(|voxel| (voxel, if voxel.x == 1 { red } else { blue }));
+
+        let mut tree = Octree::from_voxels(shell, depth_for_size(30));
+        tree.fill_interior(FillColor::Nearest, FillMethod::FloodFill, None)
+            .unwrap();
+
+        let opaque_blue = image::Rgba([0, 0, 255, 255]);
+
+        // next to the sides, and in the middle of the model, which is nearest to a blue side
+        assert_eq!(tree.color_at(IVec3::new(2, 15, 15)), Some(red));
+        assert_eq!(tree.color_at(IVec3::new(29, 15, 15)), Some(opaque_blue));
+        assert_eq!(tree.color_at(IVec3::new(2, 2, 2)), Some(red));
+        assert_eq!(tree.color_at(IVec3::new(20, 20, 20)), Some(opaque_blue));
+
+        let voxels = tree
+            .collect_nodes()
+            .into_iter()
+            .map(|(node, _)| 1 << (3 * (tree.depth - node.depth)))
+            .sum::<i32>();
+        assert_eq!(voxels, SIZE.pow(3));
+    }
+
+    #[test]
+    fn shrink_trims_the_capacity() {
+        let mut tree = Octree::new(depth_for_size(62));
+
+        // a diagonal line of voxels, every one in nodes of its own down from the root
+        for coord in 1..=62 {
+            tree.store(IVec3::splat(coord), image::Rgba([255, 0, 0, 255]));
+        }
+
+        let used = tree.used_memory();
+        assert!(tree.memory_usage() > used, "the tree has no spare capacity");
+
+        tree.shrink();
+
+        assert_eq!(tree.memory_usage(), used);
+        assert_eq!(tree.used_memory(), used);
+    }
+
+    /// Returns the voxels of the tree with their colors, sorted by their positions
+    fn sorted_voxels(tree: &Octree) -> Vec<(IVec3, image::Rgba<u8>)> {
+        let mut voxels = tree
+            .collect_nodes()
+            .into_iter()
+            .map(|(node, color)| {
+                assert_eq!(
+                    node.depth, tree.depth,
+                    "the voxel {} is a block",
+                    node.coords
+                );
+                (node.coords, octree_header::to_color(color))
+            })
+            .collect::<Vec<_>>();
+
+        voxels.sort_unstable_by_key(|(position, _)| position.to_array());
+        voxels
+    }
+
+    #[test]
+    fn from_voxels_round_trips() {
+        // voxels of different colors in different octants, some of them next to each other
+        let mut voxels = (0..64)
+            .map(|index| {
+                let position = IVec3::new(index % 4, index / 4 % 4, index / 16) * 7;
+                let channel = u8::try_from(index * 4).unwrap();
+                (position, image::Rgba([channel, 255 - channel, 0, 255]))
+            })
+            .chain([
+                (IVec3::new(1, 0, 0), image::Rgba([1, 2, 3, 255])),
+                (IVec3::new(0, 1, 0), image::Rgba([4, 5, 6, 255])),
+            ])
+            .collect::<Vec<_>>();
+
+        let tree = Octree::from_voxels(voxels.clone(), depth_for_size(30));
+
+        voxels.sort_unstable_by_key(|(position, _)| position.to_array());
+        assert_eq!(sorted_voxels(&tree), voxels);
+    }
+
+    #[test]
+    fn from_voxels_keeps_the_first_duplicate() {
+        let [first, second] = [[255, 0, 0, 255], [0, 0, 255, 255]].map(image::Rgba);
+        let voxels = [
+            (IVec3::splat(5), first),
+            (IVec3::splat(5), second),
+            (IVec3::splat(6), second),
+            (IVec3::splat(6), first),
+        ];
+
+        let tree = Octree::from_voxels(voxels, depth_for_size(30));
+
+        assert_eq!(
+            sorted_voxels(&tree),
+            [(IVec3::splat(5), first), (IVec3::splat(6), second)]
+        );
+    }
+
+    #[test]
+    fn from_voxels_skips_the_outside_of_the_grid() {
+        // the grid of the depth is 32 voxels wide
+        let depth = depth_for_size(30);
+        let color = image::Rgba([255, 255, 255, 255]);
+        let inside = [IVec3::ZERO, IVec3::splat(31), IVec3::new(31, 0, 31)];
+        let outside = [
+            IVec3::splat(-1),
+            IVec3::new(32, 0, 0),
+            IVec3::new(0, 31, 32),
+        ];
+
+        let voxels = inside
+            .into_iter()
+            .chain(outside)
+            .map(|position| (position, color));
+        let tree = Octree::from_voxels(voxels, depth);
+
+        let mut expected = inside.map(|position| (position, color));
+        expected.sort_unstable_by_key(|(position, _)| position.to_array());
+        assert_eq!(sorted_voxels(&tree), expected);
+    }
+
+    #[test]
+    fn cube_surface_is_closed() {
+        const SIZE: u32 = 32;
+
+        let mesh = crate::fixtures::generate_cube_mesh();
+        let tree = crate::mesh_to_octree(&mesh, SIZE, crate::VoxelizationMode::Triangles).unwrap();
+
+        // every axis of a closed surface has as much area facing one way as the other, and the
+        // faces of the cube cover its six sides
+        let mut area = [[0_u64; 2]; 3];
+
+        for (face, _) in tree.sparse_faces(None).unwrap() {
+            let face_size = 1_u64 << (tree.depth - u32::from(face.depth));
+            area[usize::from(face.dim)][usize::from(face.positive)] += face_size * face_size;
+        }
+
+        let side_area = u64::from(SIZE).pow(2);
+        assert_eq!(area, [[side_area; 2]; 3]);
+    }
+
+    #[test]
+    fn single_voxel_has_twelve_triangles() {
+        let voxel = (IVec3::ONE, image::Rgba([255, 255, 255, 255]));
+        let tree = Octree::from_voxels([voxel], depth_for_size(30));
+        let vertices = tree.fill_space(29, None).unwrap();
+
+        // the six sides of the voxel are two different triangles each
+        let triangles = vertices
+            .chunks_exact(3)
+            .map(|triangle| {
+                let mut corners = triangle
+                    .iter()
+                    .map(|vertex| vertex.position.to_array())
+                    .collect::<Vec<_>>();
+                corners.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                format!("{corners:?}")
+            })
+            .collect::<std::collections::HashSet<_>>();
+
+        assert_eq!(vertices.len(), 36);
+        assert_eq!(triangles.len(), 12);
     }
 }
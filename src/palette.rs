@@ -0,0 +1,219 @@
+use crate::octree::{Octree, octree_header};
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+/// A set of colors, each with the number of voxels that use it
+type WeightedColors = Vec<([u8; 4], u32)>;
+
+fn channel_range(colors: &WeightedColors, channel: usize) -> u8 {
+    let min = colors.iter().map(|(c, _)| c[channel]).min().unwrap_or(0);
+    let max = colors.iter().map(|(c, _)| c[channel]).max().unwrap_or(0);
+
+    max - min
+}
+
+fn widest_channel(colors: &WeightedColors) -> (usize, u8) {
+    (0..4)
+        .map(|channel| (channel, channel_range(colors, channel)))
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
+
+fn average(colors: &WeightedColors) -> image::Rgba<u8> {
+    let mut sum = [0_u64; 4];
+    let mut total = 0_u64;
+
+    for (color, count) in colors {
+        for channel in 0..4 {
+            sum[channel] += u64::from(color[channel]) * u64::from(*count);
+        }
+        total += u64::from(*count);
+    }
+
+    image::Rgba(sum.map(|s| u8::try_from((s + total / 2) / total.max(1)).unwrap_or(u8::MAX)))
+}
+
+/// Counts how many voxels use each color
+#[must_use]
+pub fn count_colors(tree: &Octree) -> HashMap<[u8; 4], u32> {
+    let mut counts = HashMap::new();
+
+    for (_, color) in tree.collect_nodes() {
+        *counts.entry(octree_header::to_color(color).0).or_default() += 1;
+    }
+
+    counts
+}
+
+/// Reduces the colors to at most `max_colors` entries using median cut quantization.
+/// Every color is weighted by the number of voxels that use it
+pub fn median_cut<S: BuildHasher>(
+    colors: &HashMap<[u8; 4], u32, S>,
+    max_colors: usize,
+) -> Vec<image::Rgba<u8>> {
+    // the colors are sorted, so that the palette doesn't depend on the order of the hash map
+    let mut colors: WeightedColors = colors.iter().map(|(c, n)| (*c, *n)).collect();
+    colors.sort_unstable();
+
+    if colors.len() <= max_colors {
+        return colors
+            .iter()
+            .map(|(color, _)| image::Rgba(*color))
+            .collect();
+    }
+
+    let mut boxes: Vec<WeightedColors> = vec![colors];
+
+    while boxes.len() < max_colors {
+        // split the box with the widest channel range, boxes with a single color can't be split
+        let Some((index, channel)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .map(|(index, colors)| (index, widest_channel(colors)))
+            .max_by_key(|(_, (_, range))| *range)
+            .map(|(index, (channel, _))| (index, channel))
+        else {
+            break;
+        };
+
+        let mut colors = boxes.swap_remove(index);
+        colors.sort_unstable_by_key(|(color, _)| color[channel]);
+
+        let total: u64 = colors.iter().map(|(_, count)| u64::from(*count)).sum();
+        let mut accumulated = 0;
+        let mut split = colors.len() - 1;
+
+        for (i, (_, count)) in colors.iter().enumerate() {
+            accumulated += u64::from(*count);
+            if accumulated * 2 >= total {
+                split = i + 1;
+                break;
+            }
+        }
+
+        let upper = colors.split_off(split.clamp(1, colors.len() - 1));
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    boxes.iter().map(average).collect()
+}
+
+/// Reduces the colors to at most `max_colors` entries like `median_cut`
+///
+/// Emissive and regular colors are quantized separately so that no entry mixes both. If both
+/// kinds are present, each one keeps at least one entry
+#[must_use]
+pub fn quantize<S: BuildHasher>(
+    colors: &HashMap<[u8; 4], u32, S>,
+    max_colors: usize,
+) -> Vec<image::Rgba<u8>> {
+    let (emissive, regular): (HashMap<_, _>, HashMap<_, _>) = colors
+        .iter()
+        .map(|(color, count)| (*color, *count))
+        .partition(|(color, _)| octree_header::is_emissive(image::Rgba(*color)));
+
+    if emissive.is_empty() || regular.is_empty() {
+        return median_cut(colors, max_colors);
+    }
+
+    // split the palette proportionally to the number of distinct colors of each kind
+    let emissive_colors =
+        (max_colors * emissive.len() / colors.len()).clamp(1, max_colors.saturating_sub(1).max(1));
+    let regular_colors = max_colors.saturating_sub(emissive_colors).max(1);
+
+    let mut palette = median_cut(&regular, regular_colors);
+    palette.extend(median_cut(&emissive, emissive_colors));
+
+    palette
+}
+
+/// Returns the index of the palette entry closest to `color`. Emissive colors are only
+/// matched with emissive entries, and regular colors with regular entries
+#[must_use]
+pub fn nearest(palette: &[image::Rgba<u8>], color: image::Rgba<u8>) -> usize {
+    let distance = |entry: &image::Rgba<u8>| {
+        entry
+            .0
+            .iter()
+            .zip(color.0)
+            .map(|(a, b)| (i32::from(*a) - i32::from(b)).pow(2))
+            .sum::<i32>()
+    };
+
+    palette
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| {
+            octree_header::is_emissive(**entry) == octree_header::is_emissive(color)
+        })
+        .min_by_key(|(_, entry)| distance(entry))
+        .map_or(0, |(index, _)| index)
+}
+
+/// Limits multiple models to at most `max_colors` colors in total, replacing the color of
+/// every voxel with the closest entry of an adaptive palette shared by all of them
+pub fn posterize_all(trees: &mut [Octree], max_colors: usize) {
+    let mut colors = HashMap::<[u8; 4], u32>::new();
+    for tree in trees.iter() {
+        for (color, count) in count_colors(tree) {
+            *colors.entry(color).or_default() += count;
+        }
+    }
+
+    let palette = quantize(&colors, max_colors);
+
+    let mapping = colors
+        .keys()
+        .map(|color| (*color, palette[nearest(&palette, image::Rgba(*color))]))
+        .collect::<HashMap<_, _>>();
+
+    for tree in trees {
+        tree.map_colors(|color| mapping[&color.0]);
+    }
+}
+
+impl Octree {
+    /// Limits the model to at most `max_colors` colors, replacing the color of every voxel
+    /// with the closest entry of an adaptive palette
+    pub fn posterize(&mut self, max_colors: usize) {
+        posterize_all(std::slice::from_mut(self), max_colors);
+    }
+
+    /// Removes every voxel whose color differs from `target` by more than `tolerance` in any
+    /// of its red, green or blue channels, which isolates the regions painted in one color
+    /// (i.e. a logo or a decal). Returns the number of removed voxels
+    pub fn extract_color(&mut self, target: image::Rgba<u8>, tolerance: u8) -> u64 {
+        let matches = |color: image::Rgba<u8>| {
+            color.0[..3]
+                .iter()
+                .zip(&target.0[..3])
+                .all(|(a, b)| a.abs_diff(*b) <= tolerance)
+        };
+
+        let mut removed = 0;
+        let mut kept = Vec::new();
+
+        for (node, color) in self.collect_nodes() {
+            if matches(octree_header::to_color(color)) {
+                kept.push((node, color));
+            } else {
+                removed += 1_u64 << (3 * (self.depth - node.depth));
+            }
+        }
+
+        if removed > 0 {
+            let mut tree = Self::new(self.depth);
+
+            for (node, color) in &kept {
+                tree.insert(node, octree_header::to_color(*color));
+            }
+
+            tree.shrink();
+            *self = tree;
+        }
+
+        removed
+    }
+}
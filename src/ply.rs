@@ -0,0 +1,161 @@
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+
+use crate::io::Vertex;
+use crate::octree::octree_header;
+use crate::{MeshToVoxError, Octree, Result};
+use glam::{IVec3, Vec3};
+
+/// How the elements of the `.ply` output are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PlyFormat {
+    /// `binary_little_endian`, which is smaller and faster to read
+    #[default]
+    Binary,
+    /// Human readable text
+    Ascii,
+}
+
+impl PlyFormat {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Binary => "binary_little_endian",
+            Self::Ascii => "ascii",
+        }
+    }
+}
+
+/// Options of the `.ply` output
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlyOptions<'a> {
+    pub format: PlyFormat,
+
+    /// Write the faces of the voxels as triangles instead of one point per voxel
+    pub faces: bool,
+
+    /// Only write the faces visible from the outside of the model (see `Octree::sparse_faces`),
+    /// which requires the padding around the model. Ignored without `faces`
+    pub sparse: bool,
+
+    pub cancel: Option<&'a AtomicBool>,
+}
+
+impl Octree {
+    /// Saves the voxels as a `.ply` file, either as a point cloud with a point at the center of
+    /// every voxel, or as a mesh of the faces of the voxels. The positions are mapped the same
+    /// way as by `save_as_gltf`, and every vertex has the RGB color of its voxel
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Io`] if the file can't be written
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "the vertices of a `.ply` file are indexed by `u32`"
+    )]
+    pub fn save_as_ply(&self, file_path: &str, size: u32, options: &PlyOptions) -> Result<()> {
+        let max_size = size - 1;
+
+        let vertices = if options.faces {
+            let faces = if options.sparse {
+                self.sparse_faces(options.cancel)?
+            } else {
+                self.all_faces()
+            };
+
+            self.faces_to_vertices(&faces, max_size)
+        } else {
+            self.voxel_centers(max_size)
+        };
+
+        let write = || -> std::io::Result<()> {
+            let file = std::fs::File::create(file_path)?;
+            let mut writer = std::io::BufWriter::new(file);
+
+            writeln!(writer, "ply")?;
+            writeln!(writer, "format {} 1.0", options.format.name())?;
+            writeln!(writer, "comment written by mesh_to_vox")?;
+            writeln!(writer, "element vertex {}", vertices.len())?;
+
+            for property in ["float x", "float y", "float z"] {
+                writeln!(writer, "property {property}")?;
+            }
+
+            for property in ["uchar red", "uchar green", "uchar blue"] {
+                writeln!(writer, "property {property}")?;
+            }
+
+            // the faces are triangles of consecutive vertices
+            let triangles = if options.faces { vertices.len() / 3 } else { 0 };
+
+            if options.faces {
+                writeln!(writer, "element face {triangles}")?;
+                writeln!(writer, "property list uchar uint vertex_indices")?;
+            }
+
+            writeln!(writer, "end_header")?;
+
+            for Vertex { position, color } in &vertices {
+                let [r, g, b, _] = *color;
+
+                match options.format {
+                    PlyFormat::Binary => {
+                        writer.write_all(bytemuck::bytes_of(&position.to_array()))?;
+                        writer.write_all(&[r, g, b])?;
+                    }
+                    PlyFormat::Ascii => {
+                        let Vec3 { x, y, z } = *position;
+                        writeln!(writer, "{x} {y} {z} {r} {g} {b}")?;
+                    }
+                }
+            }
+
+            for triangle in 0..triangles as u32 {
+                let indices = [triangle * 3, triangle * 3 + 1, triangle * 3 + 2];
+
+                match options.format {
+                    PlyFormat::Binary => {
+                        writer.write_all(&[3])?;
+                        writer.write_all(bytemuck::bytes_of(&indices))?;
+                    }
+                    PlyFormat::Ascii => {
+                        let [a, b, c] = indices;
+                        writeln!(writer, "3 {a} {b} {c}")?;
+                    }
+                }
+            }
+
+            writer.flush()
+        };
+
+        write().map_err(MeshToVoxError::io(file_path))
+    }
+
+    /// Returns a vertex at the center of every voxel, expanding the nodes above the lowest level
+    /// (i.e. the blocks of a refined tree) into their voxels
+    #[allow(
+        clippy::cast_precision_loss,
+        reason = "the resolution is exact in `f32` up to `2^24`, like for the faces"
+    )]
+    fn voxel_centers(&self, max_size: u32) -> Vec<Vertex> {
+        let mut vertices = Vec::new();
+
+        for (node, color) in self.collect_nodes() {
+            let color = octree_header::to_color(color).0;
+            let node_size = 1 << (self.depth - node.depth);
+
+            for x in 0..node_size {
+                for y in 0..node_size {
+                    for z in 0..node_size {
+                        let center =
+                            (node.coords + IVec3::new(x, y, z) + IVec3::NEG_ONE).as_vec3() + 0.5;
+                        let position =
+                            (center / max_size as f32).mul_add(Vec3::splat(2.0), Vec3::NEG_ONE);
+
+                        vertices.push(Vertex { position, color });
+                    }
+                }
+            }
+        }
+
+        vertices
+    }
+}
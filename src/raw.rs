@@ -0,0 +1,263 @@
+use crate::octree::octree_header;
+use crate::{MeshToVoxError, Octree, Result};
+use glam::{IVec3, UVec3};
+
+/// The order in which the axes of the grid index into the flat array of the `.raw` output,
+/// from the slowest to the fastest changing one. `xyz` matches a C-ordered array indexed
+/// as `[x][y][z]`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum AxisOrder {
+    #[default]
+    Xyz,
+    Xzy,
+    Yxz,
+    Yzx,
+    Zxy,
+    Zyx,
+}
+
+impl AxisOrder {
+    /// Returns the indices of the axes, from the slowest to the fastest changing one
+    #[must_use]
+    pub const fn axes(self) -> [usize; 3] {
+        match self {
+            Self::Xyz => [0, 1, 2],
+            Self::Xzy => [0, 2, 1],
+            Self::Yxz => [1, 0, 2],
+            Self::Yzx => [1, 2, 0],
+            Self::Zxy => [2, 0, 1],
+            Self::Zyx => [2, 1, 0],
+        }
+    }
+
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Xyz => "xyz",
+            Self::Xzy => "xzy",
+            Self::Yxz => "yxz",
+            Self::Yzx => "yzx",
+            Self::Zxy => "zxy",
+            Self::Zyx => "zyx",
+        }
+    }
+}
+
+/// What is written for every voxel of the `.raw` output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RawChannels {
+    /// The RGBA color of the voxel, with empty voxels written as the air color
+    #[default]
+    Rgba,
+    /// A single byte, 1 for occupied voxels and 0 for empty ones
+    Occupancy,
+}
+
+impl RawChannels {
+    /// Returns the number of bytes written for every voxel
+    #[must_use]
+    pub const fn count(self) -> usize {
+        match self {
+            Self::Rgba => 4,
+            Self::Occupancy => 1,
+        }
+    }
+}
+
+/// Options of the `.raw` output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawOptions {
+    pub order: AxisOrder,
+    pub channels: RawChannels,
+    /// The color written for empty voxels with `RawChannels::Rgba`, fully transparent by
+    /// default so that empty voxels can't be mistaken for black ones
+    pub air_color: image::Rgba<u8>,
+}
+
+impl Default for RawOptions {
+    fn default() -> Self {
+        Self {
+            order: AxisOrder::Xyz,
+            channels: RawChannels::Rgba,
+            air_color: image::Rgba([0, 0, 0, 0]),
+        }
+    }
+}
+
+/// Returns the path of the descriptor that `save_as_raw` writes next to the `.raw` file
+#[must_use]
+pub fn descriptor_path(raw_path: &str) -> std::path::PathBuf {
+    std::path::Path::new(raw_path).with_extension("json")
+}
+
+impl Octree {
+    /// Calls `f` with every voxel inside of a grid `side` voxels wide and its color, expanding
+    /// the nodes above the lowest level (i.e. the blocks of a refined tree) into the voxels
+    /// they cover
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_possible_wrap,
+        reason = "the width of the grid fits an i32"
+    )]
+    fn for_each_grid_voxel(&self, side: usize, mut f: impl FnMut(IVec3, u32)) {
+        for (node, color) in self.collect_nodes() {
+            let node_size = 1 << (self.depth - node.depth);
+
+            for x in 0..node_size {
+                for y in 0..node_size {
+                    for z in 0..node_size {
+                        let voxel = node.coords + IVec3::new(x, y, z);
+
+                        if voxel.cmplt(IVec3::ZERO).any()
+                            || voxel.cmpge(IVec3::splat(side as i32)).any()
+                        {
+                            continue;
+                        }
+
+                        f(voxel, color);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the voxels as a dense grid in memory, for code that consumes the voxels
+    /// directly instead of reading an output file. The grid is `size + 2` voxels wide, which
+    /// covers the padding around a model of the resolution `size`, and is returned with its
+    /// dimensions. The voxel `(x, y, z)` is at the index `(z * height + y) * width + x`, and
+    /// is either empty or the RGB color of the voxel.
+    ///
+    /// The grid holds every voxel of the cube, so it takes `4 * (size + 2)^3` bytes (i.e.
+    /// 4 GiB at the default resolution of 1022). For large grids, walk the sparse nodes of
+    /// `collect_nodes` instead
+    ///
+    /// # Panics
+    ///
+    /// If the number of voxels of the grid doesn't fit into a `usize`
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        reason = "the voxels are inside a grid of u32 width"
+    )]
+    #[must_use]
+    pub fn to_dense_grid(&self, size: u32) -> (UVec3, Vec<Option<[u8; 3]>>) {
+        let width = size as usize + 2;
+        let len = width
+            .checked_pow(3)
+            .expect("the dense grid has more voxels than fit into a `usize`");
+
+        let mut grid = vec![None; len];
+
+        self.for_each_grid_voxel(width, |voxel, color| {
+            let [r, g, b, _] = octree_header::to_color(color).0;
+            let index = (voxel.z as usize * width + voxel.y as usize) * width + voxel.x as usize;
+
+            grid[index] = Some([r, g, b]);
+        });
+
+        (UVec3::splat(width as u32), grid)
+    }
+
+    /// Saves the voxels as a dense grid of RGBA bytes (with voxels fully opaque and empty
+    /// voxels set to the air color) or of occupancy bytes, and a `.json` descriptor of the grid
+    /// next to it. The grid is `size + 2` voxels wide, which covers the padding around a model
+    /// of the resolution `size`
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::OutOfRange`] if the dense grid doesn't fit in memory, or
+    /// [`crate::MeshToVoxError::Io`] if the grid or its descriptor can't be written
+    #[allow(
+        clippy::cast_sign_loss,
+        reason = "the voxels are inside the grid, so not negative"
+    )]
+    pub fn save_as_raw(&self, file_path: &str, size: u32, options: &RawOptions) -> Result<()> {
+        let width = size as usize + 2;
+        let axes = options.order.axes();
+        let channels = options.channels.count();
+
+        let len = width
+            .checked_pow(3)
+            .and_then(|voxels| voxels.checked_mul(channels))
+            .ok_or_else(|| {
+                MeshToVoxError::OutOfRange(format!("a dense grid of {width}^3 voxels"))
+            })?;
+
+        let mut grid = match options.channels {
+            RawChannels::Rgba => options.air_color.0.repeat(len / channels),
+            RawChannels::Occupancy => vec![0_u8; len],
+        };
+
+        self.for_each_grid_voxel(width, |voxel, color| {
+            let [r, g, b, _] = octree_header::to_color(color).0;
+            let value = match options.channels {
+                RawChannels::Rgba => [r, g, b, u8::MAX],
+                RawChannels::Occupancy => [1, 0, 0, 0],
+            };
+
+            let index = axes
+                .iter()
+                .fold(0, |index, &axis| index * width + voxel[axis] as usize);
+
+            grid[index * channels..][..channels].copy_from_slice(&value[..channels]);
+        });
+
+        let empty = match options.channels {
+            RawChannels::Rgba => json::from(&options.air_color.0[..]),
+            RawChannels::Occupancy => json::from(&[0][..]),
+        };
+
+        let descriptor = json::object! {
+            shape : [width, width, width, channels],
+            axis_order : options.order.name(),
+            dtype : "uint8",
+            empty : empty,
+        };
+
+        std::fs::write(file_path, grid).map_err(MeshToVoxError::io(file_path))?;
+
+        let descriptor_path = descriptor_path(file_path);
+        std::fs::write(&descriptor_path, descriptor.pretty(2))
+            .map_err(MeshToVoxError::io(&descriptor_path))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxelizer::depth_for_size;
+
+    #[test]
+    fn dense_grid_holds_every_voxel() {
+        const SIZE: u32 = 30;
+
+        // voxels in the corners of the grid, which include the padding, and in its middle
+        let voxels = [
+            (IVec3::ZERO, [255, 0, 0]),
+            (IVec3::new(31, 0, 0), [0, 255, 0]),
+            (IVec3::new(0, 31, 0), [0, 0, 255]),
+            (IVec3::new(0, 0, 31), [255, 255, 0]),
+            (IVec3::splat(31), [0, 255, 255]),
+            (IVec3::new(3, 17, 9), [255, 255, 255]),
+        ];
+
+        let tree = Octree::from_voxels(
+            voxels.map(|(position, [r, g, b])| (position, image::Rgba([r, g, b, 255]))),
+            depth_for_size(SIZE),
+        );
+        let (dimensions, grid) = tree.to_dense_grid(SIZE);
+
+        let side = 32;
+        let mut expected = vec![None; side * side * side];
+        for (position, color) in voxels {
+            let [x, y, z] = position
+                .to_array()
+                .map(|coord| usize::try_from(coord).unwrap());
+            expected[(z * side + y) * side + x] = Some(color);
+        }
+
+        assert_eq!(dimensions, UVec3::splat(32));
+        assert!(grid == expected, "the grid differs from the voxels");
+    }
+}
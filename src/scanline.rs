@@ -0,0 +1,293 @@
+use std::sync::atomic::AtomicBool;
+
+use crate::error::{Result, check_cancelled};
+use crate::octree::{OCT_PERMS, Octree, OctreePos, octree_header};
+use glam::{IVec2, IVec3};
+use rayon::prelude::*;
+
+/// Returns the coordinates of `voxel` along the two axes other than `axis`
+const fn line_key(voxel: IVec3, axis: usize) -> IVec2 {
+    match axis {
+        0 => IVec2::new(voxel.y, voxel.z),
+        1 => IVec2::new(voxel.z, voxel.x),
+        _ => IVec2::new(voxel.x, voxel.y),
+    }
+}
+
+/// Returns the voxel at `position` along the line `key` parallel to `axis`
+const fn line_voxel(key: IVec2, position: i32, axis: usize) -> IVec3 {
+    match axis {
+        0 => IVec3::new(position, key.x, key.y),
+        1 => IVec3::new(key.y, position, key.x),
+        _ => IVec3::new(key.x, key.y, position),
+    }
+}
+
+/// Finds the ranges of a line that lay between the runs of surface voxels `positions` (sorted
+/// along the line), which alternate between the outside and the inside. Lines crossing the
+/// surface an odd number of times (i.e. grazing the model, or passing through a hole of an
+/// open surface) can't be told apart, and return `None`
+fn inside_ranges(positions: impl Iterator<Item = i32>) -> Option<Vec<[i32; 2]>> {
+    let mut surface_runs: Vec<[i32; 2]> = Vec::new();
+
+    for position in positions {
+        match surface_runs.last_mut() {
+            Some(run) if run[1] + 1 >= position => run[1] = position,
+            _ => surface_runs.push([position, position]),
+        }
+    }
+
+    if !surface_runs.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let ranges = surface_runs
+        .chunks_exact(2)
+        .map(|pair| [pair[0][1] + 1, pair[1][0] - 1])
+        .filter(|[start, end]| start <= end)
+        .collect();
+
+    Some(ranges)
+}
+
+/// The inside ranges of every line of the grid parallel to one axis, stored densely over the
+/// bounds of the surface, so that the line of a voxel is found without hashing
+struct Sweep {
+    axis: usize,
+    min: IVec2,
+    size: IVec2,
+    /// The start and the end of the ranges of every line in `ranges`, or `None` for the lines
+    /// that can't tell the inside apart
+    lines: Vec<Option<[u32; 2]>>,
+    ranges: Vec<[i32; 2]>,
+}
+
+impl Sweep {
+    /// Sweeps the grid along `axis`, given every voxel of the surface
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        reason = "the size is positive and the ranges fit a u32"
+    )]
+    fn new(surface: &mut [IVec3], axis: usize) -> Self {
+        let keys = surface.iter().map(|&voxel| line_key(voxel, axis));
+        let min = keys.clone().reduce(IVec2::min).unwrap_or_default();
+        let max = keys.fold(min, IVec2::max);
+        let size = max - min + 1;
+
+        surface.par_sort_unstable_by_key(|&voxel| {
+            let key = line_key(voxel, axis);
+            (key.y, key.x, voxel[axis])
+        });
+
+        let mut sweep = Self {
+            axis,
+            min,
+            size,
+            lines: vec![Some([0, 0]); size.x as usize * size.y as usize],
+            ranges: Vec::new(),
+        };
+
+        for line in surface.chunk_by(|a, b| line_key(*a, axis) == line_key(*b, axis)) {
+            let Some(index) = sweep.index(line_key(line[0], axis)) else {
+                continue;
+            };
+
+            sweep.lines[index] =
+                inside_ranges(line.iter().map(|voxel| voxel[axis])).map(|ranges| {
+                    let start = sweep.ranges.len() as u32;
+                    sweep.ranges.extend(ranges);
+                    [start, sweep.ranges.len() as u32]
+                });
+        }
+
+        sweep
+    }
+
+    /// Returns the index of the line `key` in `lines`, or `None` if it misses the surface
+    #[allow(
+        clippy::cast_sign_loss,
+        reason = "the offset was checked to be inside the slice"
+    )]
+    fn index(&self, key: IVec2) -> Option<usize> {
+        let offset = key - self.min;
+
+        if offset.cmplt(IVec2::ZERO).any() || offset.cmpge(self.size).any() {
+            return None;
+        }
+
+        Some(offset.y as usize * self.size.x as usize + offset.x as usize)
+    }
+
+    /// Returns whether `voxel` lays inside of one of the ranges of its line, or `None` if the
+    /// line can't tell
+    fn is_inside(&self, voxel: IVec3) -> Option<bool> {
+        let Some(index) = self.index(line_key(voxel, self.axis)) else {
+            return Some(false);
+        };
+
+        let [start, end] = self.lines[index]?;
+        let ranges = &self.ranges[start as usize..end as usize];
+
+        let position = voxel[self.axis];
+        let index = ranges.partition_point(|&[_, end]| end < position);
+
+        Some(
+            ranges
+                .get(index)
+                .is_some_and(|&[start, _]| start <= position),
+        )
+    }
+}
+
+/// Returns whether a voxel is enclosed, given the answers of the lines of the three sweeps
+/// through it. Lines that can't tell don't count
+fn is_enclosed(votes: [Option<bool>; 3]) -> bool {
+    let inside = votes.iter().filter(|&&vote| vote == Some(true)).count();
+    let outside = votes.iter().filter(|&&vote| vote == Some(false)).count();
+
+    inside > outside
+}
+
+impl Octree {
+    /// Finds the empty voxels inside of the model with three sweeps along the axes of the
+    /// grid. Every sweep marks the voxels between the crossings of the surface as inside, and
+    /// a voxel is enclosed when more sweeps find it inside than outside, not counting the lines
+    /// that can't tell. Unlike the flood fill, this doesn't need the padding, but a hole in the
+    /// surface can fill (or leave empty) whole lines of voxels. `cancel` is checked before and
+    /// after the sweeps
+    pub(crate) fn scanline_enclosed(&self, cancel: Option<&AtomicBool>) -> Result<Vec<OctreePos>> {
+        let mut surface = Vec::new();
+
+        for (node, _) in self.collect_nodes() {
+            // nodes above the lowest level (i.e. the blocks of a refined tree) cover
+            // multiple voxels
+            let node_size = 1 << (self.depth - node.depth);
+
+            for offset in 0..node_size * node_size * node_size {
+                surface.push(
+                    node.coords
+                        + IVec3::new(
+                            offset % node_size,
+                            (offset / node_size) % node_size,
+                            offset / (node_size * node_size),
+                        ),
+                );
+            }
+        }
+
+        check_cancelled(cancel)?;
+        let sweeps = [0, 1, 2].map(|axis| Sweep::new(&mut surface, axis));
+        check_cancelled(cancel)?;
+
+        let mut enclosed = Vec::new();
+        self.collect_swept(&sweeps, 0, OctreePos::zero(0), &mut enclosed);
+
+        Ok(enclosed)
+    }
+
+    /// Collects the enclosed octants of the empty space of the tree, like `collect_enclosed`
+    fn collect_swept(
+        &self,
+        sweeps: &[Sweep; 3],
+        offset: u32,
+        cords: OctreePos,
+        found: &mut Vec<OctreePos>,
+    ) {
+        let header = self.data[offset as usize];
+        let scale = 1 << (self.depth - cords.depth);
+
+        for oct in 0..8 {
+            if octree_header::get_final(header, oct) {
+                continue;
+            }
+
+            let child_coords = cords.coords + OCT_PERMS[oct as usize] * scale;
+
+            if octree_header::get_exists(header, oct) {
+                let child = self.data[(offset + 1 + oct) as usize];
+                let next_octant = OctreePos {
+                    coords: child_coords,
+                    depth: cords.depth + 1,
+                };
+
+                self.collect_swept(sweeps, child, next_octant, found);
+            } else {
+                let octant = OctreePos {
+                    coords: child_coords,
+                    depth: cords.depth,
+                };
+
+                self.collect_swept_empty(sweeps, octant, found);
+            }
+        }
+    }
+
+    /// Collects the enclosed parts of an empty octant. The lines through an empty octant
+    /// don't cross the surface inside of it, so the octant is enclosed (or not) as a whole
+    /// when every combination of the answers of the lines through it agrees, and is split
+    /// into its eight children otherwise
+    fn collect_swept_empty(
+        &self,
+        sweeps: &[Sweep; 3],
+        octant: OctreePos,
+        found: &mut Vec<OctreePos>,
+    ) {
+        const VOTES: [Option<bool>; 3] = [Some(true), Some(false), None];
+
+        let size = 1 << (self.depth - octant.depth);
+
+        // the answers found along every axis, in the order of `VOTES`
+        let mut answers = [[false; 3]; 3];
+
+        for (sweep, answers) in sweeps.iter().zip(&mut answers) {
+            let key = line_key(octant.coords, sweep.axis);
+            let position = octant.coords[sweep.axis];
+
+            'lines: for u in 0..size {
+                for v in 0..size {
+                    let voxel = line_voxel(key + IVec2::new(u, v), position, sweep.axis);
+
+                    let answer = match sweep.is_inside(voxel) {
+                        Some(true) => 0,
+                        Some(false) => 1,
+                        None => 2,
+                    };
+
+                    answers[answer] = true;
+
+                    if answers.iter().all(|&found| found) {
+                        break 'lines;
+                    }
+                }
+            }
+        }
+
+        let mut decisions = [false; 2];
+
+        for x in (0..3).filter(|&x| answers[0][x]) {
+            for y in (0..3).filter(|&y| answers[1][y]) {
+                for z in (0..3).filter(|&z| answers[2][z]) {
+                    decisions[usize::from(is_enclosed([VOTES[x], VOTES[y], VOTES[z]]))] = true;
+                }
+            }
+        }
+
+        match decisions {
+            [false, true] => found.push(octant),
+            [true, false] => {}
+            _ => {
+                let size = size / 2;
+
+                for offset in OCT_PERMS {
+                    let child = OctreePos {
+                        coords: octant.coords + offset * size,
+                        depth: octant.depth + 1,
+                    };
+
+                    self.collect_swept_empty(sweeps, child, found);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+
+use crate::error::{Result, check_cancelled};
+use crate::octree::{Octree, octree_header};
+use glam::IVec3;
+
+/// The directions that the border voxels are peeled from, one after another, so that a
+/// shape is thinned evenly from every side
+const DIRECTIONS: [IVec3; 6] = [
+    IVec3::Y,
+    IVec3::NEG_Y,
+    IVec3::X,
+    IVec3::NEG_X,
+    IVec3::Z,
+    IVec3::NEG_Z,
+];
+
+/// The 3x3x3 neighborhood of a voxel, indexed by `(x + 1) + (y + 1) * 3 + (z + 1) * 9`
+type Neighborhood = [bool; 27];
+
+#[allow(
+    clippy::cast_sign_loss,
+    reason = "the offsets are between -1 and 1, so the index is between 0 and 26"
+)]
+const fn neighborhood_index(offset: IVec3) -> usize {
+    ((offset.x + 1) + (offset.y + 1) * 3 + (offset.z + 1) * 9) as usize
+}
+
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_possible_wrap,
+    reason = "the index is between 0 and 26"
+)]
+const fn neighborhood_offset(index: usize) -> IVec3 {
+    let index = index as i32;
+    IVec3::new(index % 3 - 1, (index / 3) % 3 - 1, index / 9 - 1)
+}
+
+/// Returns the number of connected groups of the positions of the neighborhood (besides the
+/// center) for which `include` is set. Positions are connected if they share a face, or with
+/// `corners`, if they share a face, an edge or a corner
+fn count_components(include: impl Fn(usize) -> bool, corners: bool) -> usize {
+    let mut visited = [false; 27];
+    let mut components = 0;
+
+    for start in (0..27).filter(|index| *index != 13) {
+        if visited[start] || !include(start) {
+            continue;
+        }
+
+        components += 1;
+        visited[start] = true;
+        let mut stack = vec![start];
+
+        while let Some(index) = stack.pop() {
+            let offset = neighborhood_offset(index);
+
+            for other in (0..27).filter(|other| *other != 13) {
+                if visited[other] || !include(other) {
+                    continue;
+                }
+
+                let distance = (neighborhood_offset(other) - offset).abs();
+
+                let adjacent = if corners {
+                    distance.max_element() == 1
+                } else {
+                    distance.element_sum() == 1
+                };
+
+                if adjacent {
+                    visited[other] = true;
+                    stack.push(other);
+                }
+            }
+        }
+    }
+
+    components
+}
+
+/// Returns whether removing the center of the neighborhood keeps the topology of the model,
+/// where voxels are connected through corners and empty space through faces. That's the case
+/// if the voxels around the center form a single group, and so does the empty space in the
+/// 18 voxels sharing a face or an edge with the center that touches one of its faces
+fn is_simple(neighborhood: &Neighborhood) -> bool {
+    if count_components(|index| neighborhood[index], true) != 1 {
+        return false;
+    }
+
+    // the empty space is only counted in the 18-neighborhood, and only the groups that
+    // touch a face of the center
+    let in_18 = |index: usize| neighborhood_offset(index).abs().element_sum() <= 2;
+    let empty = |index: usize| !neighborhood[index] && in_18(index);
+
+    let mut visited = [false; 27];
+    let mut components = 0;
+
+    for start in DIRECTIONS.map(neighborhood_index) {
+        if visited[start] || !empty(start) {
+            continue;
+        }
+
+        components += 1;
+        visited[start] = true;
+        let mut stack = vec![start];
+
+        while let Some(index) = stack.pop() {
+            for direction in DIRECTIONS {
+                let other = neighborhood_offset(index) + direction;
+
+                if other.abs().max_element() > 1 || other == IVec3::ZERO {
+                    continue;
+                }
+
+                let other = neighborhood_index(other);
+
+                if !visited[other] && empty(other) {
+                    visited[other] = true;
+                    stack.push(other);
+                }
+            }
+        }
+    }
+
+    components == 1
+}
+
+impl Octree {
+    /// Thins the model down to a skeleton one voxel wide, by peeling off the voxels on its
+    /// surface from every side in turn, as long as that keeps the model in one piece and
+    /// doesn't open new holes. The ends of the skeleton (voxels with a single neighbor) are
+    /// kept, so that lines aren't shortened down to single voxels. Thinning a solid model (see
+    /// `fill_interior`) leaves its medial axis. Returns the number of removed voxels. `cancel`
+    /// is checked between the passes
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::Cancelled`] once `cancel` is set
+    pub fn skeletonize(&mut self, cancel: Option<&AtomicBool>) -> Result<u64> {
+        let mut voxels = HashMap::new();
+
+        for (node, color) in self.collect_nodes() {
+            // nodes above the lowest level (i.e. the blocks of a refined tree) are thinned
+            // voxel by voxel
+            let node_size = 1 << (self.depth - node.depth);
+
+            for x in 0..node_size {
+                for y in 0..node_size {
+                    for z in 0..node_size {
+                        voxels.insert(node.coords + IVec3::new(x, y, z), color);
+                    }
+                }
+            }
+        }
+
+        let neighborhood = |voxels: &HashMap<IVec3, u32>, position: IVec3| {
+            let mut neighborhood = [false; 27];
+
+            for (index, solid) in neighborhood.iter_mut().enumerate() {
+                *solid = voxels.contains_key(&(position + neighborhood_offset(index)));
+            }
+
+            neighborhood
+        };
+
+        let mut removed = 0_u64;
+
+        loop {
+            let mut removed_in_pass = 0;
+
+            for direction in DIRECTIONS {
+                check_cancelled(cancel)?;
+
+                let mut border = voxels
+                    .keys()
+                    .copied()
+                    .filter(|position| !voxels.contains_key(&(*position + direction)))
+                    .collect::<Vec<_>>();
+
+                // the voxels are removed one by one, so the result depends on their order
+                border.sort_unstable_by_key(IVec3::to_array);
+
+                for position in border {
+                    let neighborhood = neighborhood(&voxels, position);
+                    let neighbors = neighborhood.iter().filter(|solid| **solid).count() - 1;
+
+                    if neighbors > 1 && is_simple(&neighborhood) {
+                        voxels.remove(&position);
+                        removed_in_pass += 1;
+                    }
+                }
+            }
+
+            if removed_in_pass == 0 {
+                break;
+            }
+
+            removed += removed_in_pass;
+        }
+
+        if removed > 0 {
+            let voxels = voxels
+                .into_iter()
+                .map(|(position, color)| (position, octree_header::to_color(color)));
+
+            *self = Self::from_voxels(voxels, self.depth);
+        }
+
+        Ok(removed)
+    }
+}
@@ -1,5 +1,5 @@
-use crate::octree::*;
-use crate::*;
+use crate::octree::OctreePos;
+use glam::{IVec3, Vec3, Vec4};
 use std::collections::HashSet;
 
 pub type CoordMap = HashSet<OctreePos>;
@@ -12,6 +12,7 @@ pub struct MeshNode {
     pub depth: u8,
 }
 
+#[must_use]
 pub const fn bit_toggle(cords: IVec3, depth: u32, oct: u32) -> IVec3 {
     pub const fn thing(dim: i32, depth: u32, set: bool) -> i32 {
         if set {
@@ -22,13 +23,39 @@ pub const fn bit_toggle(cords: IVec3, depth: u32, oct: u32) -> IVec3 {
     }
 
     IVec3::new(
-        thing(cords.x, depth, ((oct >> 0) & 1) != 0),
+        thing(cords.x, depth, (oct & 1) != 0),
         thing(cords.y, depth, ((oct >> 1) & 1) != 0),
         thing(cords.z, depth, ((oct >> 2) & 1) != 0),
     )
 }
 
 impl MeshNode {
+    /// Returns the outward facing normal of the face
+    #[must_use]
+    pub fn normal(&self) -> Vec3 {
+        let mut normal = Vec3::ZERO;
+        normal[self.dim as usize] = if self.positive { 1.0 } else { -1.0 };
+        normal
+    }
+
+    /// Returns the tangent of the face, with the handedness of the tangent space stored in `w`.
+    ///
+    /// The texture coordinates of a face are laid out so that `u` runs along the axis after the
+    /// normal axis, and `v` along the axis after that (i.e. `u = y, v = z` for faces along `x`)
+    #[must_use]
+    pub fn tangent(&self) -> Vec4 {
+        let mut tangent = Vec4::ZERO;
+        tangent[(self.dim as usize + 1) % 3] = 1.0;
+        tangent.w = if self.positive { 1.0 } else { -1.0 };
+        tangent
+    }
+
+    /// Returns the two opposite corners of the face
+    ///
+    /// # Panics
+    ///
+    /// If `dim` isn't one of the axes `0, 1, 2`
+    #[must_use]
     pub const fn to_square(&self, octree_depth: u8) -> [IVec3; 2] {
         let size = 1 << (octree_depth - self.depth);
         let mut base = self.cords;
@@ -56,6 +83,7 @@ impl MeshNode {
         [base, opposite]
     }
 
+    #[must_use]
     pub const fn to_vertices(&self, octree_depth: u8) -> [IVec3; 6] {
         let size = 1 << (octree_depth - self.depth);
         let [base, opposite] = self.to_square(octree_depth);
@@ -74,21 +102,12 @@ impl MeshNode {
             corner2.y += size;
         }
 
-        [base, corner1, opposite, base, corner2, opposite]
+        // both triangles are wound counterclockwise around the normal of the face. the corners
+        // span the normal `corner1 x corner2`, which is negative for faces along `y`
+        if (self.dim != 1) == self.positive {
+            [base, corner1, opposite, base, opposite, corner2]
+        } else {
+            [base, opposite, corner1, base, corner2, opposite]
+        }
     }
 }
-
-#[derive(Debug)]
-pub struct FillSpaceData<'a, 'b> {
-    pub next: &'a mut CoordMap,
-    pub empty_tree: &'b mut Octree,
-}
-
-#[derive(Debug)]
-pub struct FilledIterStruct {
-    pub filled_offset: u32,
-    pub empty_offset: u32,
-
-    pub cords: OctreePos,
-    pub side: u8,
-}
@@ -0,0 +1,197 @@
+use crate::io::{ImageOrColor, Material, UnsupportedFeatures, VertexExtras, View};
+use crate::{BoundingBox, Mesh, MeshToVoxError, Result, get_normal};
+use glam::{Mat4, Vec3};
+
+/// The size of the header of a binary `.stl` file, followed by the number of triangles
+const HEADER_SIZE: usize = 80;
+
+/// The size of a triangle of a binary `.stl` file: the normal, the three vertices and the
+/// attribute byte count
+const TRIANGLE_SIZE: usize = 50;
+
+/// A triangle of an `.stl` file, with the normal stored for it and the index of its solid
+type Facet = ([Vec3; 3], Vec3, u32);
+
+/// Returns the normal of a facet, which is computed from its vertices when the file leaves
+/// it zeroed (as many exporters do)
+fn facet_normal(normal: Vec3, triangle: [Vec3; 3]) -> Option<Vec3> {
+    let normal = if normal.is_finite() && normal != Vec3::ZERO {
+        normal.normalize()
+    } else {
+        get_normal(triangle)
+    };
+
+    // degenerate triangles have no normal
+    normal.is_finite().then_some(normal)
+}
+
+/// Reads the triangles of a binary `.stl` file, whose size was checked to match the number of
+/// triangles in its header
+fn parse_binary(bytes: &[u8], unsupported: &mut UnsupportedFeatures) -> Vec<([Vec3; 3], Vec3)> {
+    let float = |bytes: &[u8], index: usize| {
+        f32::from_le_bytes([
+            bytes[index * 4],
+            bytes[index * 4 + 1],
+            bytes[index * 4 + 2],
+            bytes[index * 4 + 3],
+        ])
+    };
+    let vector = |bytes: &[u8], index: usize| {
+        Vec3::new(
+            float(bytes, index),
+            float(bytes, index + 1),
+            float(bytes, index + 2),
+        )
+    };
+
+    bytes[HEADER_SIZE + 4..]
+        .chunks_exact(TRIANGLE_SIZE)
+        .map(|facet| {
+            // some exporters store the color of the facet in the attribute byte count
+            if facet[48..] != [0, 0] {
+                unsupported.report("facet attributes (i.e. colors)");
+            }
+
+            let triangle = [vector(facet, 3), vector(facet, 6), vector(facet, 9)];
+
+            (triangle, vector(facet, 0))
+        })
+        .collect()
+}
+
+/// Parses the three numbers of a `facet normal` or a `vertex` statement
+fn parse_vector(args: &[&str]) -> Option<Vec3> {
+    match args {
+        [x, y, z, ..] => Some(Vec3::new(x.parse().ok()?, y.parse().ok()?, z.parse().ok()?)),
+        _ => None,
+    }
+}
+
+/// Reads the triangles of an ASCII `.stl` file, along with the solid that each of them
+/// belongs to and the names of the solids. Facets with more than three vertices are split
+/// into a fan of triangles around their first vertex
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "the solids are materials, which are indexed by `u32`"
+)]
+fn parse_ascii(text: &str, path: &str) -> Result<(Vec<Facet>, Vec<Option<String>>)> {
+    let malformed = |line: usize| {
+        MeshToVoxError::InvalidData(format!("`{path}` has a malformed statement on line {line}"))
+    };
+
+    let mut facets = Vec::new();
+    let mut solid_names = Vec::new();
+
+    let mut normal = Vec3::ZERO;
+    let mut vertices = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+
+        match tokens.as_slice() {
+            ["solid", name @ ..] => {
+                solid_names.push((!name.is_empty()).then(|| name.join(" ")));
+            }
+            ["facet", "normal", args @ ..] => {
+                normal = parse_vector(args).ok_or_else(|| malformed(index + 1))?;
+                vertices.clear();
+            }
+            ["vertex", args @ ..] => {
+                vertices.push(parse_vector(args).ok_or_else(|| malformed(index + 1))?);
+            }
+            ["endfacet"] => {
+                // the facets before the first `solid` statement belong to an unnamed one
+                if solid_names.is_empty() {
+                    solid_names.push(None);
+                }
+
+                let solid = solid_names.len() as u32 - 1;
+
+                if vertices.len() >= 3 {
+                    for pair in vertices[1..].windows(2) {
+                        facets.push(([vertices[0], pair[0], pair[1]], normal, solid));
+                    }
+                }
+
+                vertices.clear();
+            }
+            _ => {}
+        }
+    }
+
+    Ok((facets, solid_names))
+}
+
+/// Loads a binary or an ASCII `.stl` file, which are told apart by their content
+///
+/// The file has no materials, so every triangle uses the default white material, and the
+/// normal stored with every facet is used for its vertices
+///
+/// # Errors
+/// Returns [`crate::MeshToVoxError::Io`] if the file can't be read,
+/// [`crate::MeshToVoxError::InvalidData`] if it's malformed, or
+/// [`crate::MeshToVoxError::EmptyMesh`] if it has no triangles
+pub fn load_stl(path: &str) -> Result<Mesh> {
+    let bytes = std::fs::read(path).map_err(MeshToVoxError::io(path))?;
+    let mut unsupported = UnsupportedFeatures::default();
+
+    // binary files can start with `solid` too, so the size implied by the number of
+    // triangles is checked first
+    let binary_count = bytes
+        .get(HEADER_SIZE..HEADER_SIZE + 4)
+        .map(|count| u32::from_le_bytes([count[0], count[1], count[2], count[3]]) as usize);
+
+    let is_binary =
+        binary_count.is_some_and(|count| bytes.len() == HEADER_SIZE + 4 + count * TRIANGLE_SIZE);
+
+    let (facets, mesh_names) = if is_binary {
+        let facets = parse_binary(&bytes, &mut unsupported)
+            .into_iter()
+            .map(|(triangle, normal)| (triangle, normal, 0))
+            .collect();
+
+        (facets, vec![None])
+    } else {
+        let text = std::str::from_utf8(&bytes)
+            .ok()
+            .filter(|text| text.trim_start().starts_with("solid"))
+            .ok_or_else(|| {
+                MeshToVoxError::InvalidData(format!(
+                    "`{path}` is neither an ASCII `.stl` file, nor a binary one of the size given by its number of triangles"
+                ))
+            })?;
+
+        parse_ascii(text, path)?
+    };
+
+    if facets.is_empty() {
+        return Err(MeshToVoxError::EmptyMesh("the file has no triangles"));
+    }
+
+    let mut triangles = Vec::with_capacity(facets.len());
+    let mut triangle_extras = Vec::with_capacity(facets.len());
+
+    for (triangle, normal, mesh_idx) in facets {
+        let normal = facet_normal(normal, triangle);
+
+        triangles.push(triangle);
+        triangle_extras.push([VertexExtras::new(normal, None, 0, mesh_idx); 3]);
+    }
+
+    let bounds = BoundingBox::from_points(triangles.iter().flatten().copied());
+
+    Ok(Mesh {
+        materials: vec![Material::new(ImageOrColor::Color(image::Rgba([
+            255, 255, 255, 255,
+        ])))],
+        mesh_names,
+        triangles,
+        triangle_extras,
+        bounds,
+        view: View {
+            camera: None,
+            model_view_projection: Mat4::IDENTITY,
+        },
+        unsupported,
+    })
+}
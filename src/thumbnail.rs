@@ -0,0 +1,188 @@
+use crate::octree::{OctreePos, octree_header};
+use crate::{MeshToVoxError, Octree, Result};
+use glam::{IVec3, Mat3, Vec2, Vec3};
+use rayon::prelude::*;
+
+/// The side of every frame of a turntable, in pixels
+pub const THUMBNAIL_SIZE: u32 = 256;
+
+/// The angle that the camera looks down at the model at, which is the one of an isometric view
+/// (i.e. `atan(1 / sqrt(2))`)
+const ELEVATION: f32 = 0.615_479_7;
+
+/// How far the edges of the faces are widened, so that no pixel falls between adjacent faces
+const EDGE: f32 = 1e-4;
+
+/// Returns the file that the frame `index` of the turntable of `output` is saved to, i.e.
+/// `model_thumb_003.png` for `model.vox`
+#[must_use]
+pub fn frame_path(output: &str, index: u32) -> std::path::PathBuf {
+    let path = std::path::Path::new(output);
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default();
+
+    path.with_file_name(format!("{stem}_thumb_{index:03}.png"))
+}
+
+impl Octree {
+    /// Renders the voxels from `frames` angles evenly spread around the Y axis, and saves
+    /// every render as a `THUMBNAIL_SIZE` wide `.png` file next to `output` (see `frame_path`)
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::ImageSave`] if a frame can't be saved
+    #[allow(clippy::cast_precision_loss, reason = "the frame counts are small")]
+    pub fn save_turntable(&self, output: &str, frames: u32) -> Result<()> {
+        let nodes = self.collect_nodes();
+
+        (0..frames).into_par_iter().try_for_each(|index| {
+            let angle = std::f32::consts::TAU * index as f32 / frames as f32;
+            let image = render_frame(&nodes, self.depth, angle, THUMBNAIL_SIZE);
+
+            let path = frame_path(output, index);
+            image
+                .save(&path)
+                .map_err(|source| MeshToVoxError::ImageSave { path, source })
+        })
+    }
+}
+
+/// Renders the nodes with an orthographic camera that looks down at them at `ELEVATION` after
+/// rotating them by `angle` around the Y axis. Every node is drawn as a cube, which is shaded
+/// by a light coming from the top left, and the model is scaled to fit into the image at any
+/// angle. Emissive voxels aren't shaded
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss,
+    reason = "small sizes, and the shaded channels stay in 0..=255"
+)]
+fn render_frame(nodes: &[(OctreePos, u32)], depth: u32, angle: f32, size: u32) -> image::RgbaImage {
+    let mut image = image::RgbaImage::new(size, size);
+
+    let node_size = |node: &OctreePos| 1 << (depth - node.depth);
+
+    let Some(min) = nodes.iter().map(|(node, _)| node.coords).reduce(IVec3::min) else {
+        return image;
+    };
+
+    let max = nodes
+        .iter()
+        .map(|(node, _)| node.coords + node_size(node))
+        .fold(min, IVec3::max);
+
+    let center = (min + max).as_vec3() * 0.5;
+    let radius = (max - min).as_vec3().length() * 0.5;
+    let half_size = size as f32 * 0.5;
+    let scale = (half_size - 1.0) / radius.max(1.0);
+
+    let rotation = Mat3::from_rotation_x(ELEVATION) * Mat3::from_rotation_y(angle);
+    let light = Vec3::new(-0.4, 0.7, 0.6).normalize();
+
+    // the x and y of the projection are in pixels, and z grows towards the camera
+    let project = |point: Vec3| {
+        let point = rotation * (point - center) * scale;
+        Vec3::new(half_size + point.x, half_size - point.y, point.z)
+    };
+
+    let mut depth_buffer = vec![f32::NEG_INFINITY; (size * size) as usize];
+
+    for (node, color) in nodes {
+        let color = octree_header::to_color(*color);
+        let node_size = node_size(node) as f32;
+        let corner = node.coords.as_vec3();
+
+        for axis in 0..3 {
+            let mut normal = Vec3::ZERO;
+            normal[axis] = 1.0;
+
+            // only one of the two faces along every axis can face the camera
+            let mut view_normal = rotation * normal;
+            let mut origin = corner;
+
+            if view_normal.z < 0.0 {
+                view_normal = -view_normal;
+            } else {
+                origin[axis] += node_size;
+            }
+
+            let mut u = Vec3::ZERO;
+            u[(axis + 1) % 3] = node_size;
+            let mut v = Vec3::ZERO;
+            v[(axis + 2) % 3] = node_size;
+
+            let brightness = if octree_header::is_emissive(color) {
+                1.0
+            } else {
+                view_normal.dot(light).max(0.0).mul_add(0.5, 0.5)
+            };
+
+            let [red, green, blue, _] = color
+                .0
+                .map(|channel| (f32::from(channel) * brightness).round() as u8);
+            let shaded = image::Rgba([red, green, blue, u8::MAX]);
+
+            draw_face(
+                &mut image,
+                &mut depth_buffer,
+                [project(origin), project(origin + u), project(origin + v)],
+                shaded,
+            );
+        }
+    }
+
+    image
+}
+
+/// Draws the parallelogram spanned by the projected corners `[origin, origin + u, origin + v]`
+/// of a face, keeping only the pixels closer to the camera than the ones already drawn
+#[allow(
+    clippy::cast_precision_loss,
+    reason = "image sizes are far below the f32 mantissa"
+)]
+fn draw_face(
+    image: &mut image::RgbaImage,
+    depth_buffer: &mut [f32],
+    [origin, u, v]: [Vec3; 3],
+    color: image::Rgba<u8>,
+) {
+    let (u, v) = (u - origin, v - origin);
+    let det = u.truncate().perp_dot(v.truncate());
+
+    // faces seen edge-on cover no pixels
+    if det.abs() < 1e-6 {
+        return;
+    }
+
+    let corners = [origin, origin + u, origin + v, origin + u + v].map(Vec3::truncate);
+    let min = corners.into_iter().reduce(Vec2::min).unwrap_or_default();
+    let max = corners.into_iter().reduce(Vec2::max).unwrap_or_default();
+
+    let (width, height) = image.dimensions();
+    let min = min.floor().max(Vec2::ZERO).as_uvec2();
+    let max = max
+        .ceil()
+        .min(Vec2::new(width as f32, height as f32))
+        .as_uvec2();
+
+    for y in min.y..max.y {
+        for x in min.x..max.x {
+            let offset = Vec2::new(x as f32 + 0.5, y as f32 + 0.5) - origin.truncate();
+            let s = offset.perp_dot(v.truncate()) / det;
+            let t = u.truncate().perp_dot(offset) / det;
+
+            if !(-EDGE..=1.0 + EDGE).contains(&s) || !(-EDGE..=1.0 + EDGE).contains(&t) {
+                continue;
+            }
+
+            let depth = origin.z + s * u.z + t * v.z;
+            let index = (y * width + x) as usize;
+
+            if depth > depth_buffer[index] {
+                depth_buffer[index] = depth;
+                image.put_pixel(x, y, color);
+            }
+        }
+    }
+}
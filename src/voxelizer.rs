@@ -1,17 +1,272 @@
-use crate::io::{ImageOrColor, Mesh};
-use crate::math::{closest_point_triangle, get_barycentric_coordinates};
-use crate::octree::*;
-use glam::*;
+use crate::error::{MeshToVoxError, Result, check_cancelled};
+use crate::io::{Fit, ImageOrColor, Mesh, TextureMapping, VertexExtras, WrapMode};
+use crate::math::{
+    BoundingBox, closest_point_triangle, get_barycentric_coordinates, triangle_intersects_voxel,
+};
+use crate::octree::{FillColor, FillMethod, Octree, OctreePos, octree_header};
+use glam::{IVec3, Vec2, Vec3, Vec4};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::AtomicBool;
 
-fn voxelize_wireframe(store: &mut Octree, shading: &Shading, tri_pos: [Vec3; 3]) {
+/// A destination for the voxels produced by the rasterization functions
+pub trait VoxelStore {
+    fn store(&mut self, position: IVec3, color: image::Rgba<u8>);
+}
+
+impl VoxelStore for Octree {
+    fn store(&mut self, position: IVec3, color: image::Rgba<u8>) {
+        Self::store(self, position, color);
+    }
+}
+
+/// Stores voxels into an octree, discarding the ones that lay within `padding` voxels of the
+/// edge of the grid, or outside of `region` (from the first corner up to, but excluding,
+/// the second one). If `levels` is above zero, every voxel fills the whole block of
+/// `2^levels` voxels containing it, stored as a single node `levels` above the lowest level
+struct GridStore<'a> {
+    tree: &'a mut Octree,
+    padding: i32,
+    region: Option<[IVec3; 2]>,
+    levels: u32,
+}
+
+impl VoxelStore for GridStore<'_> {
+    fn store(&mut self, position: IVec3, color: image::Rgba<u8>) {
+        if let Some([min, max]) = self.region
+            && (position.cmplt(min).any() || position.cmpge(max).any())
+        {
+            return;
+        }
+
+        if self.levels == 0 {
+            self.tree.store_padded(position, color, self.padding);
+            return;
+        }
+
+        let block = OctreePos {
+            coords: block_corner(position, self.levels),
+            depth: self.tree.depth - self.levels,
+        };
+
+        let side = 1 << (self.tree.depth + 1);
+        if block.coords.min_element() < self.padding
+            || block.coords.max_element() >= side - self.padding
+        {
+            return;
+        }
+
+        self.tree.insert(&block, color);
+    }
+}
+
+/// Returns the first voxel of the block of `2^levels` voxels containing `position`
+fn block_corner(position: IVec3, levels: u32) -> IVec3 {
+    let ratio = IVec3::splat(1 << levels);
+    position.div_euclid(ratio) * ratio
+}
+
+/// Maps the space of the mesh into the voxel grid
+#[derive(Debug, Clone, Copy)]
+struct GridTransform {
+    min: Vec3,
+    scale: Vec3,
+    offset: f32,
+}
+
+impl GridTransform {
+    /// Fits `bounds` into a grid of `size` voxels, leaving `padding` voxels around it. With
+    /// `Fit::Stretch`, every axis is scaled to span the grid on its own, except for the flat
+    /// ones (i.e. of a plane), which are scaled like the largest side
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss,
+        reason = "the padding and grid size are small and positive"
+    )]
+    fn new(bounds: &BoundingBox, size: u32, padding: i32, fit: Fit) -> Self {
+        let max_size = size + 1 - 2 * padding as u32;
+        let bounds_size = bounds.size();
+        let uniform = max_size as f32 / bounds_size.max_element();
+
+        let scale = if fit == Fit::Stretch {
+            bounds_size.map(|side| {
+                if side > f32::EPSILON * bounds_size.max_element() {
+                    max_size as f32 / side
+                } else {
+                    uniform
+                }
+            })
+        } else {
+            Vec3::splat(uniform)
+        };
+
+        Self {
+            min: bounds.min,
+            scale,
+            offset: padding as f32,
+        }
+    }
+
+    /// Maps the space of the mesh to a grid of `size` voxels without fitting it, `scale` voxels
+    /// per unit, with the point `(0, 0, 0)` at the corner of the first voxel after the padding.
+    /// Fails if the mesh reaches outside of the grid
+    #[allow(
+        clippy::cast_possible_wrap,
+        clippy::cast_precision_loss,
+        reason = "the grid size is at most MAX_SIZE"
+    )]
+    fn unfitted(mesh_bounds: &BoundingBox, scale: f32, size: u32, padding: i32) -> Result<Self> {
+        let transform = Self {
+            min: Vec3::ZERO,
+            scale: Vec3::splat(scale),
+            offset: padding as f32,
+        };
+
+        let min = (mesh_bounds.min * scale).floor().as_ivec3();
+        let max = (mesh_bounds.max * scale).floor().as_ivec3();
+
+        if min.min_element() < 0 || max.max_element() >= size as i32 {
+            return Err(MeshToVoxError::OutOfRange(format!(
+                "the mesh, spanning the voxels {min} to {max} of a grid of {size} voxels,"
+            )));
+        }
+
+        Ok(transform)
+    }
+
+    /// Returns the transform chosen by `unit_scale` (see `VoxelizeOptions::unit_scale`), or
+    /// by `fit` without it
+    fn for_options(
+        mesh: &Mesh,
+        bounds: &BoundingBox,
+        size: u32,
+        padding: i32,
+        fit: Fit,
+        unit_scale: Option<f32>,
+    ) -> Result<Self> {
+        unit_scale.map_or_else(
+            || Ok(Self::new(bounds, size, padding, fit)),
+            |scale| Self::unfitted(&mesh.bounds, scale, size, padding),
+        )
+    }
+
+    /// Returns the transform that `voxelize_into` maps `mesh` into the grid with, with the
+    /// padding of `options` widened to a block below `VoxelizeOptions::max_depth`
+    fn for_voxelize(
+        mesh: &Mesh,
+        bounds: &BoundingBox,
+        size: u32,
+        options: &VoxelizeOptions,
+    ) -> Result<(Self, i32)> {
+        let padding = i32::from(options.padding) << block_levels(size, options);
+        let transform =
+            Self::for_options(mesh, bounds, size, padding, options.fit, options.unit_scale)?;
+
+        Ok((transform, padding))
+    }
+
+    fn apply(&self, vertex: Vec3) -> Vec3 {
+        (vertex - self.min) * self.scale + Vec3::splat(self.offset)
+    }
+}
+
+impl Octree {
+    /// Returns the color of the voxel containing the point `p` of the space of `mesh`, or
+    /// `None` if it's empty. The point is mapped into the grid the way `voxelize_into` maps the
+    /// mesh, so the tree has to be voxelized from `mesh` with the same `bounds`, `size` and
+    /// `options`
+    ///
+    /// # Errors
+    /// Returns [`crate::MeshToVoxError::OutOfRange`] if `voxelize_into` would fail to fit the
+    /// mesh with `VoxelizeOptions::unit_scale`
+    pub fn sample_world(
+        &self,
+        p: Vec3,
+        mesh: &Mesh,
+        bounds: &BoundingBox,
+        size: u32,
+        options: &VoxelizeOptions,
+    ) -> Result<Option<image::Rgb<u8>>> {
+        let (transform, _) = GridTransform::for_voxelize(mesh, bounds, size, options)?;
+        let position = transform.apply(p);
+        if !position.is_finite() {
+            return Ok(None);
+        }
+
+        Ok(self.color_at(position.floor().as_ivec3()).map(|color| {
+            let [r, g, b, _] = color.0;
+            image::Rgb([r, g, b])
+        }))
+    }
+}
+
+/// The sum of the normals of the triangles that touched every voxel, which tells the side
+/// the source surface was facing at the voxel
+pub type VoxelNormals = HashMap<IVec3, Vec3>;
+
+/// What a triangle attaches to the voxels it produces
+#[derive(Debug, Clone, Copy)]
+struct Tag {
+    /// Tags the voxel as emissive or not
+    alpha: u8,
+    normal: Vec3,
+
+    /// Colors with a lower alpha aren't stored (see `Material::alpha_cutoff`)
+    alpha_cutoff: u8,
+}
+
+/// Replaces the alpha of every stored voxel with the alpha of the tag, and adds the normal
+/// of the tag to `normals`, if they're recorded. Colors below the alpha cutoff of the tag are
+/// dropped
+struct TaggedStore<'a, 'n, S> {
+    store: &'a mut S,
+    tag: Tag,
+    normals: Option<&'n mut VoxelNormals>,
+}
+
+impl<S: VoxelStore> VoxelStore for TaggedStore<'_, '_, S> {
+    fn store(&mut self, position: IVec3, mut color: image::Rgba<u8>) {
+        if color.0[3] < self.tag.alpha_cutoff {
+            return;
+        }
+
+        if let Some(normals) = self.normals.as_deref_mut() {
+            *normals.entry(position).or_default() += self.tag.normal;
+        }
+
+        color.0[3] = self.tag.alpha;
+        self.store.store(position, color);
+    }
+}
+
+fn voxelize_wireframe(store: &mut impl VoxelStore, shading: &Shading, tri_pos: [Vec3; 3]) {
     voxelize_line(store, shading, tri_pos[0], tri_pos[1]);
     voxelize_line(store, shading, tri_pos[1], tri_pos[2]);
     voxelize_line(store, shading, tri_pos[0], tri_pos[2]);
 }
 
-fn voxelize_triangle(store: &mut Octree, shading: &Shading, tri_pos: [Vec3; 3]) {
+/// Rasterizes the triangle as a fan of lines from its corner opposite of its longest side.
+/// Triangles that would need more than `max_steps` lines are rasterized by the columns of
+/// their plane instead (see `voxelize_conservative`), and triangles with a non-finite corner
+/// are skipped
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    reason = "the step count is bounded by the grid size"
+)]
+fn voxelize_triangle(
+    store: &mut impl VoxelStore,
+    shading: &Shading,
+    tri_pos: [Vec3; 3],
+    max_steps: u32,
+) {
     const LINES: [(usize, usize); 3] = [(1, 2), (0, 2), (0, 1)];
 
+    // an infinite corner would make every line (or column) walk through the whole `i32` range
+    if !tri_pos.iter().all(|vertex| vertex.is_finite()) {
+        return;
+    }
+
     let (a, b, ab) = LINES
         .map(|(a, b)| (a, b, tri_pos[a].distance_squared(tri_pos[b])))
         .into_iter()
@@ -24,6 +279,13 @@ fn voxelize_triangle(store: &mut Octree, shading: &Shading, tri_pos: [Vec3; 3])
     // ab is the longest line, c is the point that doesn't lay on it
     // we want to cast a bunch of lines from the point c onto the longest line ab
 
+    // the lines of a fan overlap more and more towards its tip, so huge triangles are
+    // rasterized by columns instead, which test every voxel near the plane once
+    if f64::from(ab.ceil()) > f64::from(max_steps.max(1)) {
+        voxelize_conservative(store, shading, tri_pos);
+        return;
+    }
+
     let num_steps = (ab.ceil() as i32).max(1);
     let dir = (tri_pos[b] - tri_pos[a]) / num_steps as f32;
 
@@ -33,8 +295,61 @@ fn voxelize_triangle(store: &mut Octree, shading: &Shading, tri_pos: [Vec3; 3])
     }
 }
 
+/// Stores every voxel that the triangle passes through (see `triangle_intersects_voxel`).
+/// The voxels are looked for in the columns of the bounds of the triangle along the axis
+/// that it faces the most, between the heights of its plane at the edges of every column
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    reason = "voxel coordinates are small and within the grid"
+)]
+fn voxelize_conservative(store: &mut impl VoxelStore, shading: &Shading, tri_pos: [Vec3; 3]) {
+    let [a, b, c] = tri_pos;
+    let min = a.min(b).min(c).floor().as_ivec3();
+    let max = a.max(b).max(c).floor().as_ivec3();
+
+    let normal = (b - a).cross(c - a);
+    let axis = normal.abs().max_position();
+    let [axis_u, axis_v] = [(axis + 1) % 3, (axis + 2) % 3];
+
+    for column_u in min[axis_u]..=max[axis_u] {
+        for column_v in min[axis_v]..=max[axis_v] {
+            // degenerate triangles have no plane, so their whole bounds are tested
+            let (low, high) = if normal[axis] == 0.0 {
+                (min[axis], max[axis])
+            } else {
+                let heights = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(du, dv)| {
+                    let offset_u = (column_u + du) as f32 - a[axis_u];
+                    let offset_v = (column_v + dv) as f32 - a[axis_v];
+                    a[axis]
+                        - normal[axis_u].mul_add(offset_u, normal[axis_v] * offset_v) / normal[axis]
+                });
+
+                let low = heights.into_iter().fold(f32::INFINITY, f32::min);
+                let high = heights.into_iter().fold(f32::NEG_INFINITY, f32::max);
+
+                (
+                    (low.floor() as i32).max(min[axis]),
+                    (high.floor() as i32).min(max[axis]),
+                )
+            };
+
+            for height in low..=high {
+                let mut position = IVec3::ZERO;
+                position[axis] = height;
+                position[axis_u] = column_u;
+                position[axis_v] = column_v;
+
+                if triangle_intersects_voxel(tri_pos, position) {
+                    store.store(position, shading.get_color(position));
+                }
+            }
+        }
+    }
+}
+
 /// Voxelizes a line going from `p1` to `p2` with the provided shading using a DDA algorythm
-fn voxelize_line(store: &mut Octree, shading: &Shading, p1: Vec3, p2: Vec3) {
+fn voxelize_line(store: &mut impl VoxelStore, shading: &Shading, p1: Vec3, p2: Vec3) {
     let end = p2.as_ivec3();
     let ray_pos = p1;
 
@@ -61,51 +376,167 @@ fn voxelize_line(store: &mut Octree, shading: &Shading, p1: Vec3, p2: Vec3) {
     let mut t_max = (next_pos - ray_pos) * inv_dir;
 
     loop {
-        let color = shading.get_color(map_pos);
-
-        // alpha cutoff
-        if color.0[3] > 128 {
-            store.store(map_pos, color);
-        }
+        store.store(map_pos, shading.get_color(map_pos));
 
         if map_pos == end {
             break;
         }
 
-        let smallest = t_max.min_position();
+        // never step past the end along an axis, which a tie with the crossing of another axis
+        // at the end (or a `NaN` from an axis that the line doesn't move along) would
+        // otherwise do, leaving the end unreachable
+        let smallest = Vec3::select(map_pos.cmpeq(end), Vec3::INFINITY, t_max).min_position();
 
         t_max[smallest] += t_delta[smallest];
         map_pos[smallest] += step[smallest];
     }
 }
 
+/// Returns the barycentric coordinates of the point of the triangle that's sampled for the
+/// voxel `map_pos`
+fn sample_barycentric(sampling: UvSampling, map_pos: IVec3, vertices: [Vec3; 3]) -> Vec3 {
+    match sampling {
+        UvSampling::Closest => {
+            let point = closest_point_triangle(map_pos.as_vec3(), vertices);
+            get_barycentric_coordinates(point, vertices)
+        }
+        UvSampling::Plane => plane_barycentric(map_pos, vertices),
+    }
+}
+
 #[derive(Debug)]
 struct TexturedShading<'a> {
     pub image: &'a image::RgbaImage,
     pub vertices: [Vec3; 3],
     pub uvs: [Vec2; 3],
+    pub wrap: [WrapMode; 2],
+    pub sampling: UvSampling,
+    pub filter: TextureFilter,
+}
+
+/// The shading of a material multiplied by the colors of the vertices of a triangle (see
+/// `VertexExtras::color`), which are interpolated like the texture coordinates
+#[derive(Debug)]
+struct VertexColorShading<'a> {
+    pub material: Shading<'a>,
+    pub vertices: [Vec3; 3],
+    pub colors: [Vec4; 3],
+    pub sampling: UvSampling,
+}
+
+/// Decides at which point of a triangle the texture (and the vertex colors) is sampled for a
+/// voxel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum UvSampling {
+    /// The point of the triangle closest to the voxel
+    #[default]
+    Closest,
+    /// The point where the plane of the triangle passes through the voxel, clamped into the
+    /// triangle. This is more accurate on thin, angled surfaces, where the closest point of
+    /// the triangle is often on one of its edges
+    Plane,
+}
+
+/// How the color of a texture is read at a texture coordinate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TextureFilter {
+    /// The color of a single texel, which keeps the hard edges of pixel art
+    #[default]
+    Nearest,
+    /// The four texels around the coordinate blended by its distance to their centers, which
+    /// smooths out the colors of textures with fewer texels than voxels
+    Bilinear,
+}
+
+/// Blends the four texels around the texture coordinate `cords` (in the `0..=1` range), with
+/// the texels past the edges of the texture mapped by `wrap`
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss,
+    reason = "the channels are clamped to 0..=255 before the cast"
+)]
+fn sample_bilinear(image: &image::RgbaImage, cords: Vec2, wrap: [WrapMode; 2]) -> image::Rgba<u8> {
+    let (width, height) = image.dimensions();
+
+    // the centers of the texels are at the halves of the texel coordinates
+    let position = cords * Vec2::new(width as f32, height as f32) - 0.5;
+    let first = position.floor();
+    let weight = position - first;
+    let first = first.as_ivec2();
+
+    let texel = |offset_x: i32, offset_y: i32| {
+        let x = wrap[0].apply_texel(first.x + offset_x, width);
+        let y = wrap[1].apply_texel(first.y + offset_y, height);
+        Vec4::from_array(image.get_pixel(x, y).0.map(f32::from))
+    };
+
+    let top = texel(0, 0).lerp(texel(1, 0), weight.x);
+    let bottom = texel(0, 1).lerp(texel(1, 1), weight.x);
+    let color = top.lerp(bottom, weight.y).round();
+
+    image::Rgba(
+        color
+            .to_array()
+            .map(|channel| channel.clamp(0.0, 255.0) as u8),
+    )
+}
+
+/// Returns the barycentric coordinates of the point where the plane of the triangle crosses
+/// the line through the center of the voxel, along the axis that the triangle faces the most
+/// (i.e. the point where the triangle passes through the voxel). Points outside of the
+/// triangle are clamped into it
+fn plane_barycentric(map_pos: IVec3, tri: [Vec3; 3]) -> Vec3 {
+    let [a, b, c] = tri;
+    let normal = (b - a).cross(c - a);
+    let axis = normal.abs().max_position();
+
+    let mut point = map_pos.as_vec3() + 0.5;
+    let offset = normal.dot(a - point) / normal[axis];
+
+    // degenerate triangles have no plane, so their closest point is used instead
+    if !offset.is_finite() {
+        return get_barycentric_coordinates(closest_point_triangle(point, tri), tri);
+    }
+
+    point[axis] += offset;
+
+    let barycentric = get_barycentric_coordinates(point, tri).max(Vec3::ZERO);
+    barycentric / barycentric.element_sum()
 }
 
 #[derive(Debug)]
 enum Shading<'a> {
     Texture(TexturedShading<'a>),
     Color(image::Rgba<u8>),
+    VertexColor(Box<VertexColorShading<'a>>),
+    /// The first shading with the emission of the second one added to it (see
+    /// `Material::emission`)
+    Emissive(Box<[Self; 2]>),
 }
 
 impl Shading<'_> {
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss,
+        reason = "texture coordinates are in 0..=1 and sizes are small"
+    )]
     pub fn get_color(&self, map_pos: IVec3) -> image::Rgba<u8> {
         match self {
             Shading::Texture(texture) => {
-                let point = closest_point_triangle(map_pos.as_vec3(), texture.vertices);
-
-                let barycentric = get_barycentric_coordinates(point, texture.vertices);
+                let barycentric = sample_barycentric(texture.sampling, map_pos, texture.vertices);
 
                 let mut texture_cords = (texture.uvs[0] * barycentric.x)
                     + (texture.uvs[1] * barycentric.y)
                     + (texture.uvs[2] * barycentric.z);
 
-                texture_cords.x = texture_cords.x.rem_euclid(1.0);
-                texture_cords.y = texture_cords.y.rem_euclid(1.0);
+                texture_cords.x = texture.wrap[0].apply(texture_cords.x);
+                texture_cords.y = texture.wrap[1].apply(texture_cords.y);
+
+                if texture.filter == TextureFilter::Bilinear {
+                    return sample_bilinear(texture.image, texture_cords, texture.wrap);
+                }
 
                 let (x, y) = texture.image.dimensions();
                 let x = (((x - 1) as f32) * texture_cords.x) as u32;
@@ -115,6 +546,40 @@ impl Shading<'_> {
             }
 
             Shading::Color(color) => *color,
+
+            Shading::VertexColor(vertex_colors) => {
+                let VertexColorShading {
+                    material,
+                    vertices,
+                    colors,
+                    sampling,
+                } = &**vertex_colors;
+
+                let barycentric = sample_barycentric(*sampling, map_pos, *vertices);
+                let factor = (colors[0] * barycentric.x)
+                    + (colors[1] * barycentric.y)
+                    + (colors[2] * barycentric.z);
+
+                let color = Vec4::from_array(material.get_color(map_pos).0.map(f32::from));
+                let color = (color * factor / 255.0)
+                    .round()
+                    .clamp(Vec4::ZERO, Vec4::splat(255.0));
+
+                image::Rgba(color.to_array().map(|channel| channel as u8))
+            }
+
+            Shading::Emissive(shadings) => {
+                let [color, emission] = &**shadings;
+                let mut color = color.get_color(map_pos);
+                let emission = emission.get_color(map_pos);
+
+                // the alpha of the emission is ignored
+                for channel in 0..3 {
+                    color.0[channel] = color.0[channel].saturating_add(emission.0[channel]);
+                }
+
+                color
+            }
         }
     }
 }
@@ -124,71 +589,1262 @@ pub enum VoxelizationMode {
     Triangles,
     Lines,
     Points,
+    /// The triangles, with the inside of the closed parts of the mesh filled with the color of
+    /// the nearest voxel of the surface (see `Octree::fill_interior`). Requires the padding
+    Solid,
+    /// Every voxel that a triangle passes through, tested against its box (see
+    /// `triangle_intersects_voxel`) instead of rasterizing the triangle line by line, which
+    /// can miss voxels of long and thin triangles. Slower, but never leaves pinholes
+    Conservative,
+}
+
+/// Decides the color of a voxel touched by more than one triangle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// The first triangle that touches the voxel sets its color
+    #[default]
+    First,
+    /// The colors of all triangles touching the voxel are averaged, weighted by how much
+    /// of the voxel each triangle covers
+    Coverage,
+    /// The color covering most of the voxel wins, with similar colors counted together. This
+    /// keeps color boundaries crisp, unlike `coverage`, and doesn't depend on the order of
+    /// the triangles, unlike `first`
+    Majority,
+}
+
+/// What happened to the triangles of a voxelization, for reporting them to the user
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VoxelizeStats {
+    /// The triangles that were skipped, as a vertex of theirs has a non-finite coordinate in
+    /// the space of the grid
+    pub skipped_triangles: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VoxelizeOptions {
+    pub mode: VoxelizationMode,
+    pub conflict: ConflictPolicy,
+
+    /// How `voxelize` fits the model into the grid
+    pub fit: Fit,
+
+    /// The largest number of lines a triangle is rasterized with. Triangles that would need
+    /// more are rasterized like in `VoxelizationMode::Conservative` instead, by the columns of
+    /// their plane. This doesn't make them any cheaper, as both ways visit every voxel that
+    /// the triangle covers, but the columns don't overlap near the corner the lines start from
+    pub max_steps: u32,
+
+    /// Sample textures from a mip level that matches the size of a voxel, instead of sampling
+    /// a single texel of the full resolution texture
+    pub mipmap: bool,
+
+    /// The point of a triangle that its texture is sampled at for a voxel
+    pub uv_sampling: UvSampling,
+
+    /// How the color of a texture is read at the sampled point
+    pub texture_filter: TextureFilter,
+
+    /// Leave a one voxel gap around the model. The gap is required by everything that
+    /// flood fills the grid from the outside (i.e. sparse meshing and
+    /// `VoxelizationMode::Solid`), without it the model is scaled to use the whole grid
+    pub padding: bool,
+
+    /// Map the space of the mesh directly to the grid, this many voxels per unit, instead of
+    /// fitting the bounds into it. The voxel `(0, 0, 0)` of the mesh is the first voxel after
+    /// the padding, and voxelizing fails if the mesh reaches outside of the grid
+    pub unit_scale: Option<f32>,
+
+    /// The deepest level of the tree that voxels are stored at, which caps the memory of the
+    /// tree at the cost of detail. Below it, the voxels of every node are averaged into it
+    /// (see `ConflictPolicy::Coverage`), and the node is stored as a single block. The padding
+    /// is one block wide then
+    pub max_depth: Option<u32>,
+}
+
+/// The default limit of lines per triangle, which is only reached by triangles spanning more
+/// than 4096 voxels
+pub const DEFAULT_MAX_STEPS: u32 = 4096;
+
+impl Default for VoxelizeOptions {
+    fn default() -> Self {
+        Self {
+            mode: VoxelizationMode::Triangles,
+            conflict: ConflictPolicy::First,
+            fit: Fit::Box,
+            max_steps: DEFAULT_MAX_STEPS,
+            mipmap: false,
+            uv_sampling: UvSampling::Closest,
+            texture_filter: TextureFilter::Nearest,
+            padding: true,
+            unit_scale: None,
+            max_depth: None,
+        }
+    }
 }
 
-pub fn voxelize_point(store: &mut Octree, point: Vec3) {
+/// The number of coverage samples taken per voxel along the longest edge of a triangle
+const COVERAGE_SUBSAMPLES: f32 = 4.0;
+
+/// The weight given to voxels that were touched by the rasterizer, but didn't receive any
+/// coverage sample (i.e. the triangle only grazes them)
+const MIN_COVERAGE: f32 = 1e-4;
+
+/// The number of low bits of every color channel ignored when colors are bucketed for
+/// `ConflictPolicy::Majority`, which leaves 8 levels per channel
+const MAJORITY_QUANTIZATION: u8 = 5;
+
+/// Accumulates `color * weight` per voxel and finalizes to the weighted average. If `by_color`
+/// is set, the colors of a voxel are accumulated in buckets of similar colors, and only the
+/// bucket with the largest total weight is averaged. If `levels` is above zero, the voxels are
+/// accumulated per block of `2^levels` voxels, which is finalized at its first voxel
+#[derive(Debug, Default)]
+struct ColorAccumulator {
+    by_color: bool,
+    levels: u32,
+    voxels: HashMap<(IVec3, u32), (Vec4, f32)>,
+}
+
+impl ColorAccumulator {
+    fn new(by_color: bool, levels: u32) -> Self {
+        Self {
+            by_color,
+            levels,
+            voxels: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, position: IVec3, color: image::Rgba<u8>, weight: f32) {
+        let position = block_corner(position, self.levels);
+
+        // the alpha is kept exact, so emissive and regular colors are never mixed
+        let bucket = if self.by_color {
+            let [r, g, b, a] = color.0;
+            u32::from_le_bytes([
+                r >> MAJORITY_QUANTIZATION,
+                g >> MAJORITY_QUANTIZATION,
+                b >> MAJORITY_QUANTIZATION,
+                a,
+            ])
+        } else {
+            0
+        };
+
+        let color = Vec4::from_array(color.0.map(f32::from));
+        let (sum, total) = self
+            .voxels
+            .entry((position, bucket))
+            .or_insert((Vec4::ZERO, 0.0));
+
+        *sum += color * weight;
+        *total += weight;
+    }
+
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        reason = "the averaged channels stay in 0..=255"
+    )]
+    fn finalize(self, store: &mut impl VoxelStore) {
+        let mut heaviest = HashMap::<IVec3, (f32, u32, Vec4)>::new();
+
+        for ((position, bucket), (sum, total)) in self.voxels {
+            let candidate = (total, bucket, sum / total);
+
+            // ties are broken by the bucket, so the result doesn't depend on the hash order
+            heaviest
+                .entry(position)
+                .and_modify(|best| {
+                    if total.total_cmp(&best.0).then(bucket.cmp(&best.1)).is_gt() {
+                        *best = candidate;
+                    }
+                })
+                .or_insert(candidate);
+        }
+
+        for (position, (_, _, color)) in heaviest {
+            let color = color.round().clamp(Vec4::ZERO, Vec4::splat(255.0));
+            store.store(position, image::Rgba(color.to_array().map(|c| c as u8)));
+        }
+    }
+}
+
+/// Accumulates every voxel with the same weight, for the voxels of `ConflictPolicy::First`
+/// averaged into blocks
+impl VoxelStore for ColorAccumulator {
+    fn store(&mut self, position: IVec3, color: image::Rgba<u8>) {
+        self.add(position, color, 1.0);
+    }
+}
+
+/// Estimates the area of the triangle inside every voxel it passes through by sampling
+/// the triangle on a regular barycentric grid
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss,
+    reason = "the subsample counts are small and positive"
+)]
+fn estimate_coverage(tri_pos: [Vec3; 3]) -> HashMap<IVec3, f32> {
+    let [a, b, c] = tri_pos;
+    let ab = b - a;
+    let ac = c - a;
+
+    let longest = ab.length().max(ac.length()).max((c - b).length());
+    let n = ((longest * COVERAGE_SUBSAMPLES).ceil() as u32).max(1);
+
+    let area = ab.cross(ac).length() * 0.5;
+    let sample_area = area / (n * n) as f32;
+
+    let mut coverage = HashMap::new();
+    let mut add_sample = |u: f32, v: f32| {
+        let point = a + ab * (u / n as f32) + ac * (v / n as f32);
+        *coverage.entry(point.floor().as_ivec3()).or_insert(0.0) += sample_area;
+    };
+
+    // every cell of the grid is split into two sub-triangles, and each one is sampled at its
+    // centroid, so that the `n * n` samples cover the whole triangle evenly
+    for i in 0..n {
+        for j in 0..(n - i) {
+            add_sample(i as f32 + 1.0 / 3.0, j as f32 + 1.0 / 3.0);
+
+            if i + j + 1 < n {
+                add_sample(i as f32 + 2.0 / 3.0, j as f32 + 2.0 / 3.0);
+            }
+        }
+    }
+
+    coverage
+}
+
+pub fn voxelize_point(store: &mut impl VoxelStore, point: Vec3) {
     let point = point.round().as_ivec3();
     store.store(point, image::Rgba([32, 32, 32, 255]));
 }
 
+/// The largest supported resolution
+///
+/// Voxel coordinates are stored as `i32`, and the grid of a model of this resolution is
+/// `2^30` voxels wide, so every coordinate and the grid size itself still fit into an `i32`
+pub const MAX_SIZE: u32 = (1 << 30) - 2;
+
+/// The smallest supported resolution. Smaller grids leave almost no room for the model
+/// between the padding, and the math of the tree depth breaks down around a single voxel
+pub const MIN_SIZE: u32 = 8;
+
+/// Fails if the resolution `size` isn't between `MIN_SIZE` and `MAX_SIZE`
+fn check_size(size: u32) -> Result<()> {
+    if (MIN_SIZE..=MAX_SIZE).contains(&size) {
+        Ok(())
+    } else {
+        Err(MeshToVoxError::InvalidResolution(size))
+    }
+}
+
+/// Fails with [`MeshToVoxError::ConflictingOptions`] if `options` can't be used together
+const fn check_options(options: &VoxelizeOptions) -> Result<()> {
+    // the inside is whatever a flood fill from the outside can't reach, and the fill goes
+    // around the model through the padding
+    if matches!(options.mode, VoxelizationMode::Solid) && !options.padding {
+        return Err(MeshToVoxError::ConflictingOptions(
+            "the solid voxelization requires the padding",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Builds the mip chain of a texture, without the full resolution level. Every level is half
+/// the size of the previous one, down to a single texel
+fn build_mip_chain(image: &image::RgbaImage) -> Vec<image::RgbaImage> {
+    let mut levels = Vec::new();
+    let mut current = image;
+
+    while current.width() > 1 || current.height() > 1 {
+        let width = (current.width() / 2).max(1);
+        let height = (current.height() / 2).max(1);
+
+        levels.push(image::imageops::resize(
+            current,
+            width,
+            height,
+            image::imageops::FilterType::Triangle,
+        ));
+        current = levels.last().unwrap();
+    }
+
+    levels
+}
+
+/// Selects the mip level in which one texel covers roughly one voxel of the triangle
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss,
+    reason = "the level is clamped to the levels of the texture"
+)]
+fn select_mip<'a>(
+    image: &'a image::RgbaImage,
+    levels: &'a [image::RgbaImage],
+    vertices: [Vec3; 3],
+    uvs: [Vec2; 3],
+) -> &'a image::RgbaImage {
+    let world_area = (vertices[1] - vertices[0])
+        .cross(vertices[2] - vertices[0])
+        .length();
+
+    let texture_size = Vec2::new(image.width() as f32, image.height() as f32);
+    let uv_area = ((uvs[1] - uvs[0]) * texture_size)
+        .perp_dot((uvs[2] - uvs[0]) * texture_size)
+        .abs();
+
+    // both areas are doubled, which cancels out
+    let texels_per_voxel = (uv_area / world_area).sqrt();
+
+    if !texels_per_voxel.is_finite() || texels_per_voxel <= 1.0 {
+        return image;
+    }
+
+    let level = (texels_per_voxel.log2().round() as usize).min(levels.len());
+
+    if level == 0 {
+        image
+    } else {
+        &levels[level - 1]
+    }
+}
+
+/// The number of triangles rasterized in parallel between checks of the cancellation flag.
+/// Their voxels are kept until the whole batch is stored, so this also bounds that memory
+const TRIANGLE_BATCH: usize = 4096;
+
+/// Returns the depth of the octree used to store a model of the resolution `size`, which
+/// has to be between `MIN_SIZE` and `MAX_SIZE`
+///
+/// `size + 1` doesn't have to be a power of two: the grid of the tree is `2^(depth + 1)`
+/// voxels wide, which is always at least `size + 2`, so the model and the padding around it
+/// fit, and the rest of the grid stays empty
+#[must_use]
+pub const fn depth_for_size(size: u32) -> u32 {
+    (size + 1).ilog2()
+}
+
+/// Voxelizes the mesh into a new tree, fitting its bounds into a grid of `size` voxels (see
+/// `voxelize_into`, which also returns the triangles that were skipped)
 #[profiling::function]
-pub fn voxelize(mesh: &Mesh, size: u32, mode: VoxelizationMode) -> Octree {
-    let num_tris = mesh.triangles.len();
+pub fn voxelize(
+    mesh: &Mesh,
+    size: u32,
+    options: &VoxelizeOptions,
+    cancel: Option<&AtomicBool>,
+) -> Result<Octree> {
+    let mut tree = Octree::new(depth_for_size(size));
 
-    // leave one voxel gap around model to allow for inside/outside checking
-    let max_size = size - 1;
-    let depth = 31 - (size + 1).leading_zeros();
+    voxelize_into(
+        &mut tree,
+        None,
+        mesh,
+        &mesh.fit_bounds(options.fit),
+        size,
+        options,
+        cancel,
+    )?;
 
-    let largest_dim = mesh.bounds.size().max_element();
+    Ok(tree)
+}
+
+/// Voxelizes the mesh with the default options besides `mode`, fitting its bounds into a grid
+/// of `dim` voxels.
+///
+/// `dim` can be anything between `MIN_SIZE` and `MAX_SIZE`. The tree is a grid
+/// `2^(depth + 1)` voxels wide (see `depth_for_size`), which has to fit the model and the one
+/// voxel of padding on each side, so `dim = 2^n - 2` (i.e. 30, 62 or 126) is the largest
+/// resolution of a depth, and one more voxel doubles the grid
+///
+/// # Errors
+/// Returns [`crate::MeshToVoxError::InvalidResolution`] if `dim` is out of range
+pub fn mesh_to_octree(mesh: &Mesh, dim: u32, mode: VoxelizationMode) -> Result<Octree> {
+    let options = VoxelizeOptions {
+        mode,
+        ..VoxelizeOptions::default()
+    };
+
+    voxelize(mesh, dim, &options, None)
+}
+
+/// Voxelizes the mesh into an existing tree. The grid of the tree is fitted to `bounds`
+/// instead of the bounds of the mesh, which allows voxelizing multiple meshes into the same grid.
+/// The normals of the triangles are added to `normals` if it's provided.
+///
+/// Fails with [`crate::MeshToVoxError::InvalidResolution`] if `size` is out of range, with
+/// [`crate::MeshToVoxError::ConflictingOptions`] if `options.mode` is
+/// `VoxelizationMode::Solid` without `options.padding`, and with
+/// [`crate::MeshToVoxError::OutOfRange`] if `options.unit_scale` is set and the mesh reaches
+/// outside of the grid. Returns what happened to the triangles (see `VoxelizeStats`). `cancel` is checked periodically, and the function fails with
+/// [`crate::MeshToVoxError::Cancelled`] once it's set, leaving a partially voxelized tree
+#[profiling::function]
+pub fn voxelize_into(
+    tree: &mut Octree,
+    normals: Option<&mut VoxelNormals>,
+    mesh: &Mesh,
+    bounds: &BoundingBox,
+    size: u32,
+    options: &VoxelizeOptions,
+    cancel: Option<&AtomicBool>,
+) -> Result<VoxelizeStats> {
+    check_size(size)?;
+    check_options(options)?;
+
+    // leave one voxel (or block) gap around model to allow for inside/outside checking
+    let levels = block_levels(size, options);
+    let (transform, padding) = GridTransform::for_voxelize(mesh, bounds, size, options)?;
+
+    let store = GridStore {
+        tree: &mut *tree,
+        padding,
+        region: None,
+        levels,
+    };
+
+    let stats = voxelize_transformed(store, normals, mesh, &transform, options, cancel)?;
+    fill_solid(tree, options, cancel)?;
+
+    // the tree is most likely done growing, and saved or kept around next
+    tree.shrink();
+
+    Ok(stats)
+}
+
+/// Returns the bounds of `mesh` in the space of the grid that `voxelize_into` voxelizes it
+/// into with the same arguments, where the voxel `(x, y, z)` spans `(x, y, z)` to
+/// `(x + 1, y + 1, z + 1)`
+///
+/// # Errors
+/// Returns [`crate::MeshToVoxError::OutOfRange`] if `voxelize_into` would fail to fit the
+/// mesh with `VoxelizeOptions::unit_scale`
+pub fn grid_bounds(
+    mesh: &Mesh,
+    bounds: &BoundingBox,
+    size: u32,
+    options: &VoxelizeOptions,
+) -> Result<BoundingBox> {
+    let (transform, _) = GridTransform::for_voxelize(mesh, bounds, size, options)?;
+
+    Ok(BoundingBox {
+        min: transform.apply(mesh.bounds.min),
+        max: transform.apply(mesh.bounds.max),
+    })
+}
+
+/// Returns the number of levels of the tree of the resolution `size` below
+/// `VoxelizeOptions::max_depth`, whose voxels are averaged into blocks
+fn block_levels(size: u32, options: &VoxelizeOptions) -> u32 {
+    options.max_depth.map_or(0, |max_depth| {
+        depth_for_size(size).saturating_sub(max_depth)
+    })
+}
+
+/// Returns the smallest resolution at which the voxels are at most `voxel_size` wide, for a
+/// model whose fitted bounds (see `Mesh::fit_bounds`) are `extent` wide along their largest
+/// side
+///
+/// Resolutions below `MIN_SIZE` are rounded up to it, and larger voxels are used then
+///
+/// # Errors
+/// Returns [`crate::MeshToVoxError::OutOfRange`] if the resolution is above `MAX_SIZE`
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    reason = "the size was checked against MAX_SIZE before the cast"
+)]
+pub fn size_for_voxel_size(extent: f32, voxel_size: f32, padding: bool) -> Result<u32> {
+    let voxels = f64::from(extent / voxel_size).ceil();
+
+    // the fitted bounds span `size + 1 - 2 * padding` voxels (see `GridTransform::new`)
+    let size = voxels - 1.0 + if padding { 2.0 } else { 0.0 };
+
+    if size > f64::from(MAX_SIZE) {
+        return Err(MeshToVoxError::OutOfRange(format!(
+            "the resolution of {voxels} voxels needed for voxels {voxel_size} wide"
+        )));
+    }
+
+    Ok((size as u32).max(MIN_SIZE))
+}
+
+/// Returns the number of times the resolution `size` has to be doubled to get `refine_size`,
+/// or `None` if `refine_size` isn't a larger power of two multiple of `size` that fits into
+/// the grid
+#[must_use]
+pub const fn refine_levels(size: u32, refine_size: u32) -> Option<u32> {
+    if size == 0 || refine_size > MAX_SIZE || !refine_size.is_multiple_of(size) {
+        return None;
+    }
+
+    let ratio = refine_size / size;
 
-    let scale = max_size as f32 / largest_dim;
+    if ratio < 2 || !ratio.is_power_of_two() {
+        return None;
+    }
+
+    Some(ratio.trailing_zeros())
+}
+
+/// Voxelizes the mesh at the resolution `size`, except for the part inside `region` (in the
+/// space of the mesh), which is voxelized at the higher resolution `refine_size` instead.
+/// `refine_size` has to be a power of two multiple of `size` (see `refine_levels`), otherwise
+/// the function fails with [`crate::MeshToVoxError::InvalidResolution`].
+///
+/// The returned tree has the resolution `refine_size`. A voxel of the lower resolution covers
+/// a block of the grid, which is stored as a single larger node of the tree, and the blocks
+/// that touch `region` are replaced by the voxels of the higher resolution. Returns the tree
+/// with what happened to the triangles (see `VoxelizeStats`)
+#[allow(clippy::cast_precision_loss, reason = "the refinement ratio is small")]
+#[profiling::function]
+pub fn voxelize_refined(
+    mesh: &Mesh,
+    bounds: &BoundingBox,
+    size: u32,
+    refine_size: u32,
+    region: &BoundingBox,
+    options: &VoxelizeOptions,
+    cancel: Option<&AtomicBool>,
+) -> Result<(Octree, VoxelizeStats)> {
+    check_size(size)?;
+    check_options(options)?;
+
+    let levels =
+        refine_levels(size, refine_size).ok_or(MeshToVoxError::InvalidResolution(refine_size))?;
+    let ratio = 1 << levels;
+
+    // the padding is a whole block of the lower resolution, so the blocks of both resolutions
+    // line up with the nodes of the tree, and the model is still surrounded by empty space
+    let padding = i32::from(options.padding);
+    let fine = GridTransform::for_options(
+        mesh,
+        bounds,
+        refine_size,
+        padding * ratio,
+        options.fit,
+        options.unit_scale.map(|scale| scale * ratio as f32),
+    )?;
+    let coarse = GridTransform {
+        min: fine.min,
+        scale: fine.scale / ratio as f32,
+        offset: fine.offset / ratio as f32,
+    };
+
+    let depth = depth_for_size(refine_size);
+
+    let mut coarse_tree = Octree::new(depth - levels);
+    let store = GridStore {
+        tree: &mut coarse_tree,
+        padding,
+        region: None,
+        levels: 0,
+    };
+    let coarse_stats = voxelize_transformed(store, None, mesh, &coarse, options, cancel)?;
+
+    // the region is extended to whole blocks, which are replaced entirely
+    let min = coarse.apply(region.min).floor().as_ivec3();
+    let max = coarse.apply(region.max).floor().as_ivec3();
 
     let mut tree = Octree::new(depth);
+    let store = GridStore {
+        tree: &mut tree,
+        padding: padding * ratio,
+        region: Some([min * ratio, (max + 1) * ratio]),
+        levels: 0,
+    };
+    let fine_stats = voxelize_transformed(store, None, mesh, &fine, options, cancel)?;
 
-    for tri in 0..num_tris {
-        // we have to translate every vertex into a position relative to
-        // the bounds of the storage, and then scaled to fit as well as
-        // possible
-        let vertices = mesh.triangles[tri]
-            .map(|vertex| vertex - mesh.bounds.min)
-            .map(|vertex| vertex * scale)
-            .map(|vertex| vertex + Vec3::ONE);
-
-        let mat_id = mesh.triangle_extras[tri][0].material_idx;
-        let material = mesh
-            .materials
-            .get(mat_id as usize)
-            .unwrap_or(&mesh.materials[0]);
-
-        let shading = match material {
-            ImageOrColor::Image(image) => {
-                let uvs = mesh.triangle_extras[tri].map(|extras| extras.uv().unwrap());
-
-                let texture = TexturedShading {
-                    image,
-                    vertices,
-                    uvs,
-                };
+    // both passes go through every triangle, so a triangle can be skipped by either
+    // of them or by both
+    let stats = VoxelizeStats {
+        skipped_triangles: coarse_stats
+            .skipped_triangles
+            .max(fine_stats.skipped_triangles),
+    };
 
-                Shading::Texture(texture)
-            }
-            ImageOrColor::Color(color) => Shading::Color(*color),
+    for (node, color) in coarse_tree.collect_nodes() {
+        if node.coords.cmpge(min).all() && node.coords.cmple(max).all() {
+            continue;
+        }
+
+        let block = OctreePos {
+            coords: node.coords * ratio,
+            depth: node.depth,
         };
 
-        match mode {
-            VoxelizationMode::Triangles => {
-                voxelize_triangle(&mut tree, &shading, vertices);
+        tree.insert(&block, octree_header::to_color(color));
+    }
+
+    fill_solid(&mut tree, options, cancel)?;
+    tree.shrink();
+
+    Ok((tree, stats))
+}
+
+/// Fills the inside of the voxelized mesh if `options.mode` is `VoxelizationMode::Solid`
+fn fill_solid(
+    tree: &mut Octree,
+    options: &VoxelizeOptions,
+    cancel: Option<&AtomicBool>,
+) -> Result<()> {
+    // the padding was checked by `check_options`
+    if matches!(options.mode, VoxelizationMode::Solid) {
+        tree.fill_interior(FillColor::Nearest, FillMethod::FloodFill, cancel)?;
+    }
+
+    Ok(())
+}
+
+/// Rasterizes a triangle in the space of the grid with the mode of `options`
+fn rasterize(
+    store: &mut impl VoxelStore,
+    shading: &Shading,
+    vertices: [Vec3; 3],
+    options: &VoxelizeOptions,
+) {
+    match options.mode {
+        VoxelizationMode::Triangles | VoxelizationMode::Solid => {
+            voxelize_triangle(store, shading, vertices, options.max_steps);
+        }
+        VoxelizationMode::Lines => {
+            voxelize_wireframe(store, shading, vertices);
+        }
+        VoxelizationMode::Conservative => {
+            voxelize_conservative(store, shading, vertices);
+        }
+        VoxelizationMode::Points => {
+            for point in vertices {
+                voxelize_point(store, point);
             }
-            VoxelizationMode::Lines => {
-                voxelize_wireframe(&mut tree, &shading, vertices);
+        }
+    }
+}
+
+/// The voxels stored by a single triangle, recorded so that triangles can be rasterized in
+/// parallel and stored in their order afterwards. Every color stored at a position is kept
+/// once, in the order they were first stored, with the number of times it was stored
+#[derive(Debug, Default)]
+struct RecordedVoxels {
+    voxels: Vec<(IVec3, image::Rgba<u8>, u32)>,
+    index: HashMap<(IVec3, [u8; 4]), usize>,
+}
+
+impl VoxelStore for RecordedVoxels {
+    fn store(&mut self, position: IVec3, color: image::Rgba<u8>) {
+        let next = self.voxels.len();
+        let index = *self.index.entry((position, color.0)).or_insert(next);
+
+        if index == next {
+            self.voxels.push((position, color, 1));
+        } else {
+            self.voxels[index].2 += 1;
+        }
+    }
+}
+
+/// A rasterized triangle (see `rasterize_triangle`)
+struct RasterizedTriangle {
+    /// The normal of the tag of the triangle, added to the normals once per stored voxel
+    normal: Vec3,
+
+    /// The voxels of the triangle, with the alpha and the alpha cutoff of its tag applied
+    voxels: Vec<(IVec3, image::Rgba<u8>, u32)>,
+
+    /// The area of the triangle in every voxel, for the policies other than
+    /// `ConflictPolicy::First`
+    coverage: Option<HashMap<IVec3, f32>>,
+}
+
+/// Returns the shading of a triangle by `color`, mapped with `mapping` if it's an image, which
+/// is sampled at the level of `levels` (i.e. its mip chain) that fits the size of the triangle
+fn shade<'a>(
+    color: &'a ImageOrColor,
+    mapping: &TextureMapping,
+    levels: Option<&'a [image::RgbaImage]>,
+    vertices: [Vec3; 3],
+    extras: &[VertexExtras; 3],
+    options: &VoxelizeOptions,
+) -> Shading<'a> {
+    match color {
+        ImageOrColor::Image(image) => {
+            // primitives without texture coordinates sample the corner of the texture
+            let uvs = extras.map(|extras| {
+                let uv = extras.uv().unwrap_or_default();
+                mapping.transform.transform_point2(uv)
+            });
+
+            let image = levels.map_or(image, |levels| select_mip(image, levels, vertices, uvs));
+
+            let texture = TexturedShading {
+                image,
+                vertices,
+                uvs,
+                wrap: mapping.wrap,
+                sampling: options.uv_sampling,
+                filter: options.texture_filter,
+            };
+
+            Shading::Texture(texture)
+        }
+        ImageOrColor::Color(color) => Shading::Color(*color),
+    }
+}
+
+/// Rasterizes the triangle `tri` of the mesh into the grid of `transform`, recording its
+/// voxels instead of storing them. Returns `None` if a vertex of the triangle isn't finite
+fn rasterize_triangle(
+    mesh: &Mesh,
+    tri: usize,
+    transform: &GridTransform,
+    region: Option<[IVec3; 2]>,
+    mip_chains: Option<&[[Vec<image::RgbaImage>; 2]]>,
+    options: &VoxelizeOptions,
+) -> Option<RasterizedTriangle> {
+    // we have to translate every vertex into a position relative to
+    // the bounds of the storage, and then scaled to fit as well as
+    // possible
+    let vertices = mesh.triangles[tri].map(|vertex| transform.apply(vertex));
+
+    // a single corrupt vertex would make the rasterizer walk through (almost) the whole
+    // `i32` range, so such triangles are skipped instead
+    if !vertices.iter().all(|vertex| vertex.is_finite()) {
+        return None;
+    }
+
+    // the grid is only scaled and translated, so the winding of the triangle is kept
+    let normal = (vertices[1] - vertices[0])
+        .cross(vertices[2] - vertices[0])
+        .normalize_or_zero();
+
+    let mut rasterized = RasterizedTriangle {
+        normal,
+        voxels: Vec::new(),
+        coverage: None,
+    };
+
+    // triangles that can't touch the region are skipped without rasterizing them
+    if let Some([min, max]) = region {
+        let bounds = BoundingBox::from_points(vertices);
+
+        if bounds.max.floor().as_ivec3().cmplt(min).any()
+            || bounds.min.floor().as_ivec3().cmpge(max).any()
+        {
+            return Some(rasterized);
+        }
+    }
+
+    let mat_id = mesh.triangle_extras[tri][0].material_idx as usize;
+    let mat_id = if mat_id < mesh.materials.len() {
+        mat_id
+    } else {
+        0
+    };
+    let material = &mesh.materials[mat_id];
+
+    // the mip chains of the color and of the emission of the material
+    let [levels, emission_levels] = mip_chains.map_or([None; 2], |mip_chains| {
+        mip_chains[mat_id]
+            .each_ref()
+            .map(|levels| Some(levels.as_slice()))
+    });
+
+    let extras = &mesh.triangle_extras[tri];
+    let mut shading = shade(
+        &material.color,
+        &material.mapping,
+        levels,
+        vertices,
+        extras,
+        options,
+    );
+
+    // the colors of the vertices are white by default, which leaves the material as it is
+    if extras.iter().any(|extras| extras.color != [u8::MAX; 4]) {
+        shading = Shading::VertexColor(Box::new(VertexColorShading {
+            material: shading,
+            vertices,
+            colors: extras.map(|extras| Vec4::from_array(extras.color.map(f32::from))),
+            sampling: options.uv_sampling,
+        }));
+    }
+
+    if let Some(emission) = &material.emission {
+        let emission = shade(
+            &emission.color,
+            &emission.mapping,
+            emission_levels,
+            vertices,
+            extras,
+            options,
+        );
+
+        shading = Shading::Emissive(Box::new([shading, emission]));
+    }
+
+    let alpha = if material.emissive {
+        octree_header::EMISSIVE_ALPHA
+    } else {
+        u8::MAX
+    };
+
+    let tag = Tag {
+        alpha,
+        normal,
+        alpha_cutoff: material.alpha_cutoff.unwrap_or(0),
+    };
+
+    let mut recorded = RecordedVoxels::default();
+    let mut store = TaggedStore {
+        store: &mut recorded,
+        tag,
+        normals: None,
+    };
+
+    rasterize(&mut store, &shading, vertices, options);
+
+    rasterized.voxels = recorded.voxels;
+    rasterized.coverage =
+        (options.conflict != ConflictPolicy::First).then(|| estimate_coverage(vertices));
+
+    Some(rasterized)
+}
+
+/// Voxelizes the triangles in batches, each rasterized in parallel. The voxels of every batch
+/// are stored in the order of the triangles, so the result is the same as if the triangles
+/// were voxelized one after another. Triangles with a non-finite vertex are skipped, and
+/// counted in the returned stats
+fn voxelize_transformed(
+    mut store: GridStore,
+    mut normals: Option<&mut VoxelNormals>,
+    mesh: &Mesh,
+    transform: &GridTransform,
+    options: &VoxelizeOptions,
+    cancel: Option<&AtomicBool>,
+) -> Result<VoxelizeStats> {
+    let num_tris = mesh.triangles.len();
+
+    let mut accumulator =
+        ColorAccumulator::new(options.conflict == ConflictPolicy::Majority, store.levels);
+    let mut stats = VoxelizeStats::default();
+
+    let mip_chains = options.mipmap.then(|| {
+        mesh.materials
+            .iter()
+            .map(|material| {
+                let emission = material.emission.as_ref().map(|emission| &emission.color);
+
+                [Some(&material.color), emission].map(|color| match color {
+                    Some(ImageOrColor::Image(image)) => build_mip_chain(image),
+                    _ => Vec::new(),
+                })
+            })
+            .collect::<Vec<_>>()
+    });
+
+    for batch in (0..num_tris).step_by(TRIANGLE_BATCH) {
+        check_cancelled(cancel)?;
+
+        let rasterized = (batch..num_tris.min(batch + TRIANGLE_BATCH))
+            .into_par_iter()
+            .map(|tri| {
+                let region = store.region;
+                rasterize_triangle(mesh, tri, transform, region, mip_chains.as_deref(), options)
+            })
+            .collect::<Vec<_>>();
+
+        for triangle in rasterized {
+            let Some(triangle) = triangle else {
+                stats.skipped_triangles += 1;
+                continue;
+            };
+
+            if let Some(normals) = normals.as_deref_mut() {
+                for (position, _, count) in &triangle.voxels {
+                    let normal = normals.entry(*position).or_default();
+
+                    for _ in 0..*count {
+                        *normal += triangle.normal;
+                    }
+                }
             }
-            VoxelizationMode::Points => {
-                for point in vertices {
-                    voxelize_point(&mut tree, point);
+
+            if let Some(coverage) = triangle.coverage {
+                // only the first color of the triangle at every position is weighted
+                let mut weighted = HashSet::new();
+
+                for (position, color, _) in triangle.voxels {
+                    if weighted.insert(position) {
+                        let weight = coverage.get(&position).copied().unwrap_or(0.0);
+                        accumulator.add(position, color, weight.max(MIN_COVERAGE));
+                    }
+                }
+            } else if store.levels > 0 {
+                // the voxels of blocks are averaged, regardless of the triangle that came first
+                for (position, color, count) in triangle.voxels {
+                    for _ in 0..count {
+                        accumulator.add(position, color, 1.0);
+                    }
+                }
+            } else {
+                for (position, color, _) in triangle.voxels {
+                    store.store(position, color);
                 }
             }
         }
     }
 
-    tree
+    accumulator.finalize(&mut store);
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::{UVec3, ivec3, vec3};
+
+    /// Records the voxels of a line, and fails once there are more of them than the line spans
+    struct LineVoxels(Vec<IVec3>);
+
+    impl VoxelStore for LineVoxels {
+        fn store(&mut self, position: IVec3, _: image::Rgba<u8>) {
+            assert!(self.0.len() < 16, "the line stepped past its end");
+            self.0.push(position);
+        }
+    }
+
+    /// Returns the number of voxels of the tree, counting the voxels of every node above the
+    /// lowest level
+    fn voxel_count(tree: &Octree) -> u64 {
+        tree.collect_nodes()
+            .iter()
+            .map(|(node, _)| 1_u64 << (3 * (tree.depth - node.depth)))
+            .sum()
+    }
+
+    #[test]
+    fn line_stops_at_its_end() {
+        // the end lies on the edge of its voxel along X and Y, so the last crossings along
+        // both axes tie, and stepping along X first would move past the end voxel
+        let mut voxels = LineVoxels(Vec::new());
+        let shading = Shading::Color(image::Rgba([255; 4]));
+
+        voxelize_line(
+            &mut voxels,
+            &shading,
+            vec3(3.5, 1.5, 0.5),
+            vec3(1.0, 2.0, 0.5),
+        );
+
+        let expected = [
+            ivec3(3, 1, 0),
+            ivec3(2, 1, 0),
+            ivec3(1, 1, 0),
+            ivec3(1, 2, 0),
+        ];
+        assert_eq!(voxels.0, expected);
+    }
+
+    #[test]
+    fn huge_triangles_are_rasterized_by_columns() {
+        let shading = Shading::Color(image::Rgba([255; 4]));
+
+        // neither the lines nor the columns can walk to an infinite corner
+        let mut voxels = LineVoxels(Vec::new());
+        let infinite = [Vec3::ZERO, vec3(f32::INFINITY, 1.0, 0.0), Vec3::Y];
+        voxelize_triangle(&mut voxels, &shading, infinite, 4);
+        assert!(voxels.0.is_empty());
+
+        // every side of the cube is wider than the limit, and still a closed shell
+        let options = VoxelizeOptions {
+            max_steps: 4,
+            ..VoxelizeOptions::default()
+        };
+        let tree = voxelize(&crate::fixtures::generate_cube_mesh(), 32, &options, None).unwrap();
+
+        assert_eq!(voxel_count(&tree), 32_u64.pow(3) - 30_u64.pow(3));
+    }
+
+    #[test]
+    fn counts_skipped_triangles() {
+        let mut mesh = crate::fixtures::generate_cube_mesh();
+        mesh.triangles[0][1].x = f32::NAN;
+
+        let bounds = BoundingBox::from_points([Vec3::ZERO, Vec3::ONE]);
+        let mut tree = Octree::new(depth_for_size(30));
+        let options = VoxelizeOptions::default();
+        let stats = voxelize_into(&mut tree, None, &mesh, &bounds, 30, &options, None).unwrap();
+
+        assert_eq!(stats.skipped_triangles, 1);
+    }
+
+    #[test]
+    fn size_limits() {
+        let mesh = crate::fixtures::generate_cube_mesh();
+
+        for size in [MIN_SIZE - 1, MAX_SIZE + 1] {
+            let result = mesh_to_octree(&mesh, size, VoxelizationMode::Triangles);
+            assert!(matches!(result, Err(MeshToVoxError::InvalidResolution(s)) if s == size));
+        }
+
+        // the largest resolution is only checked, voxelizing it would take too long
+        assert!(check_size(MAX_SIZE).is_ok());
+
+        let tree = mesh_to_octree(&mesh, MIN_SIZE, VoxelizationMode::Triangles).unwrap();
+        let nodes = tree.collect_nodes();
+        let min = nodes.iter().map(|(node, _)| node.coords).reduce(IVec3::min);
+        let max = nodes.iter().map(|(node, _)| node.coords).reduce(IVec3::max);
+
+        // the cube fills the grid inside of the padding
+        let last = UVec3::splat(MIN_SIZE).as_ivec3();
+        assert_eq!((min, max), (Some(IVec3::ONE), Some(last)));
+    }
+
+    #[test]
+    fn size_needs_no_power_of_two() {
+        // `size + 1` isn't a power of two, so the tree is wider than the model and the voxels
+        // have to stay inside of the padding
+        const SIZE: u32 = 500;
+
+        let mesh = crate::fixtures::generate_cube_mesh();
+        let tree = mesh_to_octree(&mesh, SIZE, VoxelizationMode::Triangles).unwrap();
+        let last = UVec3::splat(SIZE).as_ivec3();
+
+        let mut min = IVec3::MAX;
+        let mut max = IVec3::MIN;
+
+        for (node, _) in tree.collect_nodes() {
+            let node_size = 1 << (tree.depth - node.depth);
+            min = min.min(node.coords);
+            max = max.max(node.coords + node_size - 1);
+        }
+
+        // every voxel lies in `1..=SIZE`, and the cube reaches both ends of the range
+        assert_eq!((min, max), (IVec3::ONE, last));
+    }
+
+    #[test]
+    fn cube_voxel_counts() {
+        const SIZE: u64 = 32;
+
+        // the sides of the cube lie on the sides of the voxels, which only belong to one of
+        // them, so both surface modes give a shell one voxel thick
+        let shell = SIZE.pow(3) - (SIZE - 2).pow(3);
+        let mesh = crate::fixtures::generate_cube_mesh();
+
+        for (mode, expected) in [
+            (VoxelizationMode::Triangles, shell),
+            (VoxelizationMode::Conservative, shell),
+            (VoxelizationMode::Solid, SIZE.pow(3)),
+        ] {
+            let tree = mesh_to_octree(&mesh, 32, mode).unwrap();
+            assert_eq!(voxel_count(&tree), expected, "in the mode {mode:?}");
+        }
+    }
+
+    /// Weights the first color stored at every position by the coverage of the triangle,
+    /// like the batches of `voxelize_transformed` do
+    struct CoverageStore<'a> {
+        accumulator: &'a mut ColorAccumulator,
+        coverage: HashMap<IVec3, f32>,
+        weighted: HashSet<IVec3>,
+    }
+
+    impl VoxelStore for CoverageStore<'_> {
+        fn store(&mut self, position: IVec3, color: image::Rgba<u8>) {
+            if self.weighted.insert(position) {
+                let weight = self.coverage.get(&position).copied().unwrap_or(0.0);
+                self.accumulator
+                    .add(position, color, weight.max(MIN_COVERAGE));
+            }
+        }
+    }
+
+    /// Voxelizes a mesh of plain colors one triangle after another, storing the voxels
+    /// straight into the tree (or the accumulator of the policies other than
+    /// `ConflictPolicy::First`) while the triangle is rasterized
+    fn voxelize_serially(mesh: &Mesh, size: u32, options: &VoxelizeOptions) -> Octree {
+        let mut tree = Octree::new(depth_for_size(size));
+        let bounds = mesh.fit_bounds(options.fit);
+        let (transform, padding) =
+            GridTransform::for_voxelize(mesh, &bounds, size, options).unwrap();
+
+        let mut store = GridStore {
+            tree: &mut tree,
+            padding,
+            region: None,
+            levels: 0,
+        };
+        let mut accumulator =
+            ColorAccumulator::new(options.conflict == ConflictPolicy::Majority, 0);
+
+        for (vertices, extras) in mesh.triangles.iter().zip(&mesh.triangle_extras) {
+            let vertices = vertices.map(|vertex| transform.apply(vertex));
+            let material = &mesh.materials[extras[0].material_idx as usize];
+            let shading = shade(
+                &material.color,
+                &material.mapping,
+                None,
+                vertices,
+                extras,
+                options,
+            );
+
+            let tag = Tag {
+                alpha: u8::MAX,
+                normal: Vec3::ZERO,
+                alpha_cutoff: material.alpha_cutoff.unwrap_or(0),
+            };
+
+            if options.conflict == ConflictPolicy::First {
+                let mut tagged = TaggedStore {
+                    store: &mut store,
+                    tag,
+                    normals: None,
+                };
+                rasterize(&mut tagged, &shading, vertices, options);
+            } else {
+                let mut weighted = CoverageStore {
+                    accumulator: &mut accumulator,
+                    coverage: estimate_coverage(vertices),
+                    weighted: HashSet::new(),
+                };
+                let mut tagged = TaggedStore {
+                    store: &mut weighted,
+                    tag,
+                    normals: None,
+                };
+                rasterize(&mut tagged, &shading, vertices, options);
+            }
+        }
+
+        accumulator.finalize(&mut store);
+
+        tree
+    }
+
+    #[test]
+    fn parallel_voxels_match_the_serial_ones() {
+        // more triangles than are rasterized in one batch, in two materials
+        let mesh = crate::fixtures::generate_test_mesh(64);
+
+        for conflict in [
+            ConflictPolicy::First,
+            ConflictPolicy::Coverage,
+            ConflictPolicy::Majority,
+        ] {
+            let options = VoxelizeOptions {
+                conflict,
+                ..VoxelizeOptions::default()
+            };
+
+            let parallel = voxelize(&mesh, 30, &options, None).unwrap();
+            let serial = voxelize_serially(&mesh, 30, &options);
+
+            assert!(
+                parallel.collect_nodes() == serial.collect_nodes(),
+                "the parallel voxels of {conflict:?} differ from the serial ones"
+            );
+        }
+    }
+
+    #[test]
+    fn solid_fill_grows_with_the_surface() {
+        const SIZE: u32 = 254;
+
+        let start = std::time::Instant::now();
+        let mesh = crate::fixtures::generate_cube_mesh();
+        let tree = mesh_to_octree(&mesh, SIZE, VoxelizationMode::Solid).unwrap();
+        let elapsed = start.elapsed();
+
+        let nodes = tree.collect_nodes();
+        let voxels = voxel_count(&tree);
+        let volume = u64::from(SIZE).pow(3);
+        let surface = 6 * u64::from(SIZE).pow(2);
+
+        // the inside is filled in whole octants, except for the voxels next to the surface,
+        // where storing every voxel would take at least 36 bytes per 8 voxels
+        assert_eq!(voxels, volume);
+        assert!((nodes.len() as u64) < 4 * surface, "{} nodes", nodes.len());
+        assert!(
+            (tree.used_memory() as u64) < volume,
+            "{} bytes",
+            tree.used_memory()
+        );
+        assert!(elapsed.as_secs() < 120, "took {elapsed:?}");
+    }
+
+    #[test]
+    fn samples_the_world_like_it_was_voxelized() {
+        const SIZE: u32 = 30;
+
+        // a cube stretched 4 times along X, stretched back into a cube of voxels
+        let mut mesh = crate::fixtures::generate_cube_mesh();
+        for triangle in &mut mesh.triangles {
+            for vertex in triangle {
+                vertex.x *= 4.0;
+            }
+        }
+        mesh.bounds = BoundingBox::from_points([Vec3::ZERO, vec3(4.0, 1.0, 1.0)]);
+
+        let options = VoxelizeOptions {
+            fit: Fit::Stretch,
+            padding: false,
+            ..VoxelizeOptions::default()
+        };
+        let bounds = mesh.fit_bounds(options.fit);
+        let tree = voxelize(&mesh, SIZE, &options, None).unwrap();
+
+        let [r, g, b, _] = match &mesh.materials[0].color {
+            ImageOrColor::Color(color) => color.0,
+            ImageOrColor::Image(_) => unreachable!("the test cube is a single color"),
+        };
+        let color = Some(image::Rgb([r, g, b]));
+
+        // the corners and sides of the hollow box, its inside, and points outside of it
+        let points = [
+            (Vec3::ZERO, color),
+            (vec3(4.0, 1.0, 1.0), color),
+            (vec3(2.0, 0.5, 1.0), color),
+            (vec3(2.0, 0.5, 0.5), None),
+            (vec3(2.0, 0.5, 0.9), None),
+            (vec3(-0.1, 0.5, 0.5), None),
+            (Vec3::NAN, None),
+        ];
+
+        for (point, expected) in points {
+            let sampled = tree
+                .sample_world(point, &mesh, &bounds, SIZE, &options)
+                .unwrap();
+            assert_eq!(sampled, expected, "at the point {point}");
+        }
+    }
+
+    #[test]
+    fn refine_size_must_be_a_multiple() {
+        let mesh = crate::fixtures::generate_cube_mesh();
+        let region = BoundingBox::from_points([Vec3::ZERO, Vec3::splat(0.5)]);
+        let options = VoxelizeOptions::default();
+
+        let result = voxelize_refined(&mesh, &mesh.bounds, 30, 45, &region, &options, None);
+
+        assert!(matches!(result, Err(MeshToVoxError::InvalidResolution(45))));
+    }
+
+    #[test]
+    fn solid_needs_padding() {
+        let options = VoxelizeOptions {
+            mode: VoxelizationMode::Solid,
+            padding: false,
+            ..VoxelizeOptions::default()
+        };
+
+        let result = voxelize(&crate::fixtures::generate_cube_mesh(), 30, &options, None);
+
+        assert!(matches!(result, Err(MeshToVoxError::ConflictingOptions(_))));
+    }
 }